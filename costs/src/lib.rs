@@ -3,10 +3,10 @@
 
 use std::ops::{Add, AddAssign};
 
-use storage::rocksdb_storage::RocksDbStorage;
+use storage::build_prefix_hash_count;
 
 /// Piece of data representing affected computer resources (approximately).
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct OperationCost {
     /// How many storage seeks were done.
     pub seek_count: u16,
@@ -22,6 +22,28 @@ pub struct OperationCost {
     pub hash_node_calls: u16,
 }
 
+/// Height of Merk trees assumed by worst case estimations, an AVL tree of this
+/// height holds billions of elements.
+pub const WORST_CASE_MERK_HEIGHT: u32 = 46;
+
+/// Size of keys assumed by worst case estimations for nodes which are not known
+/// in advance, Merk keys are shorter than 256 bytes.
+pub const WORST_CASE_KEY_SIZE: u32 = 255;
+
+/// Size of values assumed by worst case estimations for nodes which are not
+/// known in advance.
+pub const WORST_CASE_VALUE_SIZE: u32 = 1024;
+
+/// Size of an encoded Merk node apart from its value: aggregate data of summed
+/// or counted nodes, two links to children with keys of the worst case size
+/// and two hashes of the node itself.
+const WORST_CASE_NODE_OVERHEAD: u32 = 1 + 8 + 2 * (1 + 1 + WORST_CASE_KEY_SIZE + 32 + 2 + 8) + 64;
+
+/// Size of an encoded Merk node holding a value of `value_len` bytes.
+pub fn worst_case_node_size(value_len: u32) -> u32 {
+    value_len + WORST_CASE_NODE_OVERHEAD
+}
+
 impl OperationCost {
     /// Add worst case for getting a merk tree
     pub fn add_worst_case_get_merk<'p, P>(&mut self, path: P)
@@ -29,20 +51,23 @@ impl OperationCost {
         P: IntoIterator<Item = &'p [u8]>,
         <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
     {
-        self.seek_count += 1;
+        // Root key is loaded first and then the root node
+        self.seek_count += 2;
         self.storage_written_bytes += 0;
         self.storage_loaded_bytes += 0;
-        self.loaded_bytes += 0;
-        self.hash_byte_calls += RocksDbStorage::build_prefix_hash_count(path) as u32;
+        self.loaded_bytes += WORST_CASE_KEY_SIZE + worst_case_node_size(WORST_CASE_VALUE_SIZE);
+        self.hash_byte_calls += build_prefix_hash_count(path) as u32;
         self.hash_node_calls += 0;
     }
 
-    /// Add worst case for getting a merk tree
+    /// Add worst case for checking if an element exists in a merk tree, which
+    /// costs as much as getting it
     pub fn add_worst_case_merk_has_element(&mut self, key: &[u8]) {
+        // Nodes which are not loaded yet are fetched by key in a single seek
         self.seek_count += 1;
         self.storage_written_bytes += 0;
         self.storage_loaded_bytes += 0;
-        self.loaded_bytes += key.len() as u32;
+        self.loaded_bytes += key.len() as u32 + worst_case_node_size(WORST_CASE_VALUE_SIZE);
         self.hash_byte_calls += 0;
         self.hash_node_calls += 0;
     }
@@ -54,37 +79,165 @@ impl OperationCost {
         self.storage_loaded_bytes += 0;
         self.loaded_bytes += 0;
         self.hash_byte_calls += 0;
-        self.hash_node_calls += 0;
-    }
-
-    /// Add worst case for opening a root meta storage
-    pub fn add_worst_case_open_root_meta_storage(&mut self) {
-        self.seek_count += 0;
-        self.storage_written_bytes += 0;
+        self.hash_node_calls += 1;
+    }
+
+    /// Add worst case for inserting or updating a value of `value_len` bytes
+    /// under a key of `key_len` bytes in a merk tree, including rebalancing
+    /// and rehashing of the nodes on the way to the root
+    pub fn add_worst_case_merk_insert(&mut self, key_len: u32, value_len: u32) {
+        // Walking down from the root fetches a node per level, while
+        // rebalancing after an insertion takes a double rotation at most, which
+        // may fetch a node per rotation
+        let fetched_nodes = WORST_CASE_MERK_HEIGHT - 1 + 2;
+        // Nodes on the way to the root along with rotated ones are rewritten
+        let rewritten_nodes = WORST_CASE_MERK_HEIGHT + 2;
+        self.seek_count += fetched_nodes as u16;
+        // The node itself is written under its key, the root key is updated
+        // as well
+        self.storage_written_bytes += key_len
+            + worst_case_node_size(value_len)
+            + rewritten_nodes * worst_case_node_size(WORST_CASE_VALUE_SIZE)
+            + WORST_CASE_KEY_SIZE;
         self.storage_loaded_bytes += 0;
-        self.loaded_bytes += 0;
+        self.loaded_bytes += fetched_nodes * worst_case_node_size(WORST_CASE_VALUE_SIZE);
         self.hash_byte_calls += 0;
-        self.hash_node_calls += 0;
-    }
-
-    /// Add worst case for saving the root tree
-    pub fn add_worst_case_save_root_leaves(&mut self) {
-        self.seek_count += 0;
-        self.storage_written_bytes += 0;
+        // Value hash and key/value hash of the node and node hashes of the
+        // rewritten ones
+        self.hash_node_calls += 2 + rewritten_nodes as u16;
+    }
+
+    /// Add worst case for deleting a key of `key_len` bytes from a merk tree,
+    /// including rebalancing and rehashing of the nodes on the way to the root
+    pub fn add_worst_case_merk_delete(&mut self, key_len: u32) {
+        // Walking down to the node and then to the edge of its taller subtree
+        // fetches a node per level and the other child, while rebalancing may
+        // take a double rotation on every level on the way back
+        let fetched_nodes = WORST_CASE_MERK_HEIGHT + 2 * WORST_CASE_MERK_HEIGHT;
+        let rewritten_nodes = fetched_nodes;
+        // Removed node is read to be deleted, the root key might be read too
+        self.seek_count += fetched_nodes as u16 + 2;
+        self.storage_written_bytes +=
+            (rewritten_nodes + 1) * worst_case_node_size(WORST_CASE_VALUE_SIZE) + key_len;
         self.storage_loaded_bytes += 0;
-        self.loaded_bytes += 0;
+        self.loaded_bytes += fetched_nodes * worst_case_node_size(WORST_CASE_VALUE_SIZE);
         self.hash_byte_calls += 0;
-        self.hash_node_calls += 0;
+        self.hash_node_calls += rewritten_nodes as u16;
+    }
+
+    /// Add average case for getting a merk tree with a root key of
+    /// `root_key_len` bytes and a root node of `root_node_size` bytes, which
+    /// are not loaded for an empty tree
+    pub fn add_average_case_get_merk<'p, P>(
+        &mut self,
+        path: P,
+        root_key_len: u32,
+        root_node_size: u32,
+        empty: bool,
+    ) where
+        P: IntoIterator<Item = &'p [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        self.hash_byte_calls += build_prefix_hash_count(path) as u32;
+        self.seek_count += 1;
+        if !empty {
+            self.seek_count += 1;
+            self.loaded_bytes += root_key_len + root_node_size;
+        }
     }
 
-    /// Add worst case for loading the root tree
-    pub fn add_worst_case_load_root_leaves(&mut self) {
-        self.seek_count += 0;
-        self.storage_written_bytes += 0;
-        self.storage_loaded_bytes += 0;
-        self.loaded_bytes += 0;
-        self.hash_byte_calls += 0;
-        self.hash_node_calls += 0;
+    /// Add average case for getting an element of a merk tree of `height`
+    /// with nodes of `node_size` bytes
+    pub fn add_average_case_merk_get(&mut self, height: u32, node_size: u32) {
+        // Only the root node is loaded along with the tree, other nodes are
+        // fetched by key in a single seek
+        if height > 1 {
+            self.seek_count += 1;
+            self.loaded_bytes += node_size;
+        }
+    }
+
+    /// Add average case for getting a merk tree root hash
+    pub fn add_average_case_merk_root_hash(&mut self) {
+        self.hash_node_calls += 1;
+    }
+
+    /// Add average case for inserting a node of `inserted_node_size` bytes
+    /// under a key of `key_len` bytes into a merk tree of `height` with nodes
+    /// of `node_size` bytes
+    pub fn add_average_case_merk_insert(
+        &mut self,
+        height: u32,
+        key_len: u32,
+        inserted_node_size: u32,
+        node_size: u32,
+    ) {
+        // A node is fetched for each level on the way down but the root, and
+        // the nodes on the way back are rewritten, rebalancing is rare enough
+        // to be left out
+        let fetched_nodes = height.saturating_sub(1);
+        self.seek_count += fetched_nodes as u16;
+        self.loaded_bytes += fetched_nodes * node_size;
+        self.storage_written_bytes += key_len + inserted_node_size + height * node_size;
+        // Value hash and key/value hash of the node and node hashes of the
+        // rewritten ones
+        self.hash_node_calls += 2 + height as u16;
+    }
+
+    /// Add average case for deleting a key of `key_len` bytes from a merk
+    /// tree of `height` with nodes of `node_size` bytes
+    pub fn add_average_case_merk_delete(&mut self, height: u32, key_len: u32, node_size: u32) {
+        // Most of the nodes are close to the bottom of the tree, so the walk
+        // down takes a node per level, the removed node is read once more to
+        // be deleted
+        let fetched_nodes = height.saturating_sub(1);
+        self.seek_count += fetched_nodes as u16 + 1;
+        self.loaded_bytes += fetched_nodes * node_size;
+        self.storage_written_bytes += key_len + height * node_size;
+        self.hash_node_calls += fetched_nodes as u16;
+    }
+
+    /// Add average case for iterating over `results_count` elements with keys
+    /// of `key_len` bytes in a range of a merk tree
+    pub fn add_average_case_merk_range(&mut self, results_count: u32, key_len: u32) {
+        // A seek to the start of the range and one per element
+        self.seek_count += 1 + results_count as u16;
+        self.loaded_bytes += results_count * key_len;
+    }
+}
+
+/// Height of a balanced AVL tree holding `elements_count` elements, which is
+/// what Merk trees tend to on average.
+pub fn average_case_merk_height(elements_count: u64) -> u32 {
+    u64::BITS - elements_count.leading_zeros()
+}
+
+impl OperationCost {
+    /// Whether any of the cost's dimensions is above the one of `limit`.
+    pub fn exceeds(&self, limit: &OperationCost) -> bool {
+        self.seek_count > limit.seek_count
+            || self.storage_written_bytes > limit.storage_written_bytes
+            || self.storage_loaded_bytes > limit.storage_loaded_bytes
+            || self.loaded_bytes > limit.loaded_bytes
+            || self.hash_byte_calls > limit.hash_byte_calls
+            || self.hash_node_calls > limit.hash_node_calls
+    }
+
+    /// What is left of the cost taken as a limit once `spent` is spent, with
+    /// each dimension stopping at zero.
+    pub fn saturating_sub(&self, spent: &OperationCost) -> OperationCost {
+        OperationCost {
+            seek_count: self.seek_count.saturating_sub(spent.seek_count),
+            storage_written_bytes: self
+                .storage_written_bytes
+                .saturating_sub(spent.storage_written_bytes),
+            storage_loaded_bytes: self
+                .storage_loaded_bytes
+                .saturating_sub(spent.storage_loaded_bytes),
+            loaded_bytes: self.loaded_bytes.saturating_sub(spent.loaded_bytes),
+            hash_byte_calls: self.hash_byte_calls.saturating_sub(spent.hash_byte_calls),
+            hash_node_calls: self.hash_node_calls.saturating_sub(spent.hash_node_calls),
+        }
     }
 }
 
@@ -249,8 +402,8 @@ impl<T> CostsExt for T {}
 /// 2. Because of 1. `Result` is removed from the equation;
 /// 3. `CostContext` if removed too because it is added to external cost
 ///    accumulator;
-/// 4. Early termination uses external cost accumulator so previous
-///    costs won't be lost.
+/// 4. Early termination uses external cost accumulator so previous costs won't
+///    be lost.
 #[macro_export]
 macro_rules! cost_return_on_error {
     ( &mut $cost:ident, $($body:tt)+ ) => {
@@ -565,4 +718,33 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_cost_limit() {
+        let limit = OperationCost {
+            seek_count: 3,
+            loaded_bytes: 100,
+            ..Default::default()
+        };
+        let spent = OperationCost {
+            seek_count: 3,
+            loaded_bytes: 40,
+            ..Default::default()
+        };
+        assert!(!spent.exceeds(&limit));
+        assert_eq!(
+            limit.saturating_sub(&spent),
+            OperationCost {
+                loaded_bytes: 60,
+                ..Default::default()
+            }
+        );
+
+        let spent = OperationCost {
+            hash_node_calls: 1,
+            ..Default::default()
+        };
+        assert!(spent.exceeds(&limit));
+        assert_eq!(limit.saturating_sub(&spent), limit);
+    }
 }
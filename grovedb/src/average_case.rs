@@ -0,0 +1,223 @@
+//! Average case cost estimations.
+//!
+//! Unlike worst case estimations these rely on hints on the layout of subtrees
+//! operations touch: the number of elements a subtree holds tells the height
+//! of its Merk tree and average sizes of keys and values tell how many bytes
+//! are loaded and written along the way.
+
+use std::collections::HashMap;
+
+use costs::{
+    average_case_merk_height, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
+};
+use merk::proofs::query::QueryItem;
+use storage::Storage;
+
+use crate::{Element, Error, GroveDb, PathQuery};
+
+/// Estimated layout of a subtree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EstimatedLayerInformation {
+    /// Estimated number of elements in the subtree
+    pub elements_count: u64,
+    /// Average size of keys of the subtree in bytes
+    pub average_key_size: u32,
+    /// Average size of serialized elements of the subtree in bytes
+    pub average_value_size: u32,
+}
+
+/// Estimated layouts of subtrees by their paths
+pub type EstimatedLayerInformationByPath = HashMap<Vec<Vec<u8>>, EstimatedLayerInformation>;
+
+impl EstimatedLayerInformation {
+    /// Average height of the subtree's Merk tree
+    pub fn height(&self) -> u32 {
+        average_case_merk_height(self.elements_count)
+    }
+
+    /// Average size of a node of the subtree's Merk tree
+    pub fn node_size(&self) -> u32 {
+        Element::calculate_node_byte_size(
+            self.average_value_size as usize,
+            self.average_key_size as usize,
+        ) as u32
+    }
+
+    /// Adds average case of opening the subtree at `path`
+    pub(crate) fn add_get_merk(&self, cost: &mut OperationCost, path: &[Vec<u8>]) {
+        cost.add_average_case_get_merk(
+            path.iter().map(|x| x.as_slice()),
+            self.average_key_size,
+            self.node_size(),
+            self.elements_count == 0,
+        );
+    }
+
+    /// Adds average case of getting an element of the opened subtree
+    pub(crate) fn add_merk_get(&self, cost: &mut OperationCost) {
+        cost.add_average_case_merk_get(self.height(), self.node_size());
+    }
+}
+
+/// Estimated layout of a subtree at `path`
+pub(crate) fn estimated_layer<'a>(
+    estimated_layers: &'a EstimatedLayerInformationByPath,
+    path: &[Vec<u8>],
+) -> Result<&'a EstimatedLayerInformation, Error> {
+    estimated_layers.get(path).ok_or(Error::MissingParameter(
+        "estimated layer information of a subtree",
+    ))
+}
+
+/// Adds average case of getting an element without following references
+pub(crate) fn add_average_case_get_raw(
+    cost: &mut OperationCost,
+    path: &[Vec<u8>],
+    estimated_layers: &EstimatedLayerInformationByPath,
+) -> Result<(), Error> {
+    // The subtree is checked to exist in its parent first
+    if let Some((_, parent_path)) = path.split_last() {
+        let parent_layer = estimated_layer(estimated_layers, parent_path)?;
+        parent_layer.add_get_merk(cost, parent_path);
+        parent_layer.add_merk_get(cost);
+    }
+    let layer = estimated_layer(estimated_layers, path)?;
+    layer.add_get_merk(cost, path);
+    layer.add_merk_get(cost);
+    Ok(())
+}
+
+/// Adds average case of updating root hashes of the ancestors of a subtree at
+/// `path` up to the root tree
+fn add_average_case_propagation(
+    cost: &mut OperationCost,
+    path: &[Vec<u8>],
+    estimated_layers: &EstimatedLayerInformationByPath,
+) -> Result<(), Error> {
+    let mut path = path;
+    while let Some((key, parent_path)) = path.split_last() {
+        // The tree element is read to keep its flags and written back with the
+        // new root hash
+        let parent_layer = estimated_layer(estimated_layers, parent_path)?;
+        parent_layer.add_get_merk(cost, parent_path);
+        parent_layer.add_merk_get(cost);
+        // Kind of the parent tree is told by its element in the grandparent
+        if let Some((_, grandparent_path)) = parent_path.split_last() {
+            let grandparent_layer = estimated_layer(estimated_layers, grandparent_path)?;
+            grandparent_layer.add_get_merk(cost, grandparent_path);
+            grandparent_layer.add_merk_get(cost);
+        }
+        cost.add_average_case_merk_insert(
+            parent_layer.height(),
+            key.len() as u32,
+            parent_layer.node_size(),
+            parent_layer.node_size(),
+        );
+        cost.add_average_case_merk_root_hash();
+        path = parent_path;
+    }
+    Ok(())
+}
+
+impl<S: Storage> GroveDb<S> {
+    /// Average case of getting an element without following references
+    pub fn average_case_for_get(
+        &self,
+        path: &[Vec<u8>],
+        estimated_layers: &EstimatedLayerInformationByPath,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+        add_average_case_get_raw(&mut cost, path, estimated_layers).wrap_with_cost(cost)
+    }
+
+    /// Average case of inserting an element under `key` into a subtree at
+    /// `path`, including propagation of root hashes
+    pub fn average_case_for_insert(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        element: &Element,
+        estimated_layers: &EstimatedLayerInformationByPath,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        // The previous element is read to keep references in sync. `insert` gets
+        // it with `get_raw_optional` and opens the subtree once again to write,
+        // so the subtree is accounted to be opened twice.
+        cost_return_on_error_no_add!(
+            &cost,
+            add_average_case_get_raw(&mut cost, path, estimated_layers)
+        );
+        let layer = cost_return_on_error_no_add!(&cost, estimated_layer(estimated_layers, path));
+        layer.add_get_merk(&mut cost, path);
+        cost.add_average_case_merk_insert(
+            layer.height(),
+            key.len() as u32,
+            element.node_byte_size(key.len()) as u32,
+            layer.node_size(),
+        );
+        cost.add_average_case_merk_root_hash();
+        add_average_case_propagation(&mut cost, path, estimated_layers).wrap_with_cost(cost)
+    }
+
+    /// Average case of deleting an element under `key` from a subtree at
+    /// `path`, including propagation of root hashes
+    pub fn average_case_for_delete(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        estimated_layers: &EstimatedLayerInformationByPath,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        // The element is read to find out what is deleted. `delete` gets it with
+        // `get_raw` and opens the subtree once again to delete from it, so the
+        // subtree is accounted to be opened twice.
+        cost_return_on_error_no_add!(
+            &cost,
+            add_average_case_get_raw(&mut cost, path, estimated_layers)
+        );
+        let layer = cost_return_on_error_no_add!(&cost, estimated_layer(estimated_layers, path));
+        layer.add_get_merk(&mut cost, path);
+        cost.add_average_case_merk_delete(layer.height(), key.len() as u32, layer.node_size());
+        cost.add_average_case_merk_root_hash();
+        add_average_case_propagation(&mut cost, path, estimated_layers).wrap_with_cost(cost)
+    }
+
+    /// Average case of a query on the subtree at the path of `path_query`,
+    /// subqueries are not accounted for
+    pub fn average_case_for_query(
+        &self,
+        path_query: &PathQuery,
+        estimated_layers: &EstimatedLayerInformationByPath,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let layer = cost_return_on_error_no_add!(
+            &cost,
+            estimated_layer(estimated_layers, &path_query.path)
+        );
+
+        // The subtree is opened once for all query items
+        layer.add_get_merk(&mut cost, &path_query.path);
+        let mut limit = path_query.query.limit.map(u64::from).unwrap_or(u64::MAX);
+        for item in path_query.query.query.items.iter() {
+            if limit == 0 {
+                break;
+            }
+            if let QueryItem::Key(_) = item {
+                layer.add_merk_get(&mut cost);
+                limit -= 1;
+            } else {
+                // Ranges are assumed to span the whole subtree unless limited
+                let results_count = layer.elements_count.min(limit);
+                cost.add_average_case_merk_range(
+                    u32::try_from(results_count).unwrap_or(u32::MAX),
+                    layer.average_key_size,
+                );
+                limit -= results_count;
+            }
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+}
@@ -5,61 +5,175 @@ use std::{
     cmp::Ordering,
     collections::{BTreeMap, HashMap, HashSet},
     hash::Hash,
+    sync::Mutex,
 };
 
 use costs::{
     cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
+    WORST_CASE_VALUE_SIZE,
 };
-use merk::Merk;
+use merk::{AggregateData, Merk};
 use nohash_hasher::IntMap;
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use storage::{Storage, StorageBatch, StorageContext};
 use visualize::{DebugByteVectors, DebugBytes, Drawer, Visualize};
 
-use crate::{Element, Error, GroveDb, TransactionArg, ROOT_LEAFS_SERIALIZED_KEY};
+use crate::{
+    average_case::{add_average_case_get_raw, estimated_layer},
+    operations::{
+        delete::{find_subtrees_elements_in_storage, find_subtrees_in_storage},
+        references::{
+            add_average_case_reference_links, add_worst_case_get_raw,
+            add_worst_case_reference_links, read_has_referrers, read_referrers, write_referrers,
+            Referrers,
+        },
+    },
+    subtree::PathKeyElementTrio,
+    util::{batch_storage_context_optional_tx, check_cost_limit, with_total_cost_on_limit},
+    DefaultStorage, Element, Error, EstimatedLayerInformation, EstimatedLayerInformationByPath,
+    GroveDb, ReferenceChain, TransactionArg, TreeType,
+};
 
+/// ONLY APPEND TO THIS LIST!!! Because
+/// of how serialization works.
+///
+/// Serde form of operations is unversioned and may change between releases,
+/// `GroveDbOp::encode_batch` is the one to use for transmitting or recording
+/// batches.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Op {
-    ReplaceTreeHash { hash: [u8; 32] },
-    Insert { element: Element },
+    /// Internal to batch application, is neither serialized nor deserialized
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "reject_serialize_replace_tree_hash",
+            deserialize_with = "reject_deserialize_replace_tree_hash"
+        )
+    )]
+    ReplaceTreeHash {
+        hash: [u8; 32],
+        aggregate_data: Option<AggregateData>,
+    },
+    Insert {
+        element: Element,
+    },
     Delete,
+    /// Deletes a tree element along with all subtrees nested in it
+    DeleteTree,
+    /// Inserts an element only if there is none under the key yet
+    InsertIfAbsent {
+        element: Element,
+    },
+    /// Replaces an element only if there is one under the key already
+    ReplaceIfPresent {
+        element: Element,
+    },
+    /// Deletes an element only if there is one under the key
+    DeleteIfExists,
+    /// Changes nothing, but requires the element under the key to be equal to
+    /// the given one
+    AssertEquals {
+        element: Element,
+    },
+}
+
+// The variant keeps its place in the serde form, so tags of the following
+// variants don't shift
+#[cfg(feature = "serde")]
+fn reject_serialize_replace_tree_hash<S: serde::Serializer>(
+    _hash: &[u8; 32],
+    _aggregate_data: &Option<AggregateData>,
+    _serializer: S,
+) -> Result<S::Ok, S::Error> {
+    Err(serde::ser::Error::custom(
+        "replace tree hash operation cannot be serialized",
+    ))
+}
+
+#[cfg(feature = "serde")]
+fn reject_deserialize_replace_tree_hash<'de, D: serde::Deserializer<'de>>(
+    _deserializer: D,
+) -> Result<([u8; 32], Option<AggregateData>), D::Error> {
+    Err(serde::de::Error::custom(
+        "replace tree hash operation cannot be deserialized",
+    ))
 }
 
 impl Op {
-    fn worst_case_cost(&self, _key: Vec<u8>) -> OperationCost {
+    /// Element written by the operation, if any
+    pub(crate) fn inserted_element(&self) -> Option<&Element> {
         match self {
-            Op::ReplaceTreeHash { .. } => OperationCost {
-                seek_count: 0,
-                storage_written_bytes: 0,
-                storage_loaded_bytes: 0,
-                loaded_bytes: 0,
-                hash_byte_calls: 0,
-                hash_node_calls: 0,
-            },
-            Op::Insert { .. } => OperationCost {
-                seek_count: 0,
-                storage_written_bytes: 0,
-                storage_loaded_bytes: 0,
-                loaded_bytes: 0,
-                hash_byte_calls: 0,
-                hash_node_calls: 0,
-            },
-            Op::Delete => OperationCost {
-                seek_count: 0,
-                storage_written_bytes: 0,
-                storage_loaded_bytes: 0,
-                loaded_bytes: 0,
-                hash_byte_calls: 0,
-                hash_node_calls: 0,
-            },
+            Op::Insert { element }
+            | Op::InsertIfAbsent { element }
+            | Op::ReplaceIfPresent { element } => Some(element),
+            _ => None,
+        }
+    }
+
+    /// Whether the operation deletes the element under its key
+    pub(crate) fn is_delete(&self) -> bool {
+        matches!(self, Op::Delete | Op::DeleteTree | Op::DeleteIfExists)
+    }
+
+    /// Whether the operation is applied only if a condition on the element
+    /// under its key holds
+    fn has_precondition(&self) -> bool {
+        matches!(
+            self,
+            Op::InsertIfAbsent { .. }
+                | Op::ReplaceIfPresent { .. }
+                | Op::DeleteIfExists
+                | Op::AssertEquals { .. }
+        )
+    }
+
+    /// Checks the precondition of the operation against the `stored` element
+    /// under its key, operations without preconditions always pass
+    fn precondition_holds(&self, stored: Option<&Element>) -> bool {
+        match self {
+            Op::InsertIfAbsent { .. } => stored.is_none(),
+            Op::ReplaceIfPresent { .. } | Op::DeleteIfExists => stored.is_some(),
+            Op::AssertEquals { element } => stored == Some(element),
+            _ => true,
         }
     }
+
+    /// Worst case cost of applying the operation under `key` to an opened
+    /// Merk
+    fn worst_case_cost(&self, key: Vec<u8>) -> OperationCost {
+        let mut cost = OperationCost::default();
+        let key_len = key.len() as u32;
+        match self {
+            // The tree element is read to keep its flags and written back with
+            // the new root hash
+            Op::ReplaceTreeHash { .. } => {
+                cost.add_worst_case_merk_has_element(&key);
+                cost.add_worst_case_merk_insert(key_len, WORST_CASE_VALUE_SIZE);
+            }
+            Op::Insert { element }
+            | Op::InsertIfAbsent { element }
+            | Op::ReplaceIfPresent { element } => {
+                cost.add_worst_case_merk_insert(key_len, element.serialized_byte_size() as u32)
+            }
+            // Storage of nested subtrees is cleared without touching Merk
+            // trees above them, so it isn't accounted for here
+            Op::Delete | Op::DeleteTree | Op::DeleteIfExists => {
+                cost.add_worst_case_merk_delete(key_len)
+            }
+            Op::AssertEquals { .. } => {}
+        }
+        cost
+    }
 }
 
 impl PartialOrd for Op {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self, other) {
-            (Op::Delete, Op::Insert { .. }) => Some(Ordering::Less),
-            (Op::Insert { .. }, Op::Delete) => Some(Ordering::Greater),
+        match (self.is_delete(), other.is_delete()) {
+            (true, false) if other.inserted_element().is_some() => Some(Ordering::Less),
+            (false, true) if self.inserted_element().is_some() => Some(Ordering::Greater),
             _ => Some(Ordering::Equal),
         }
     }
@@ -73,6 +187,7 @@ impl Ord for Op {
 
 /// Batch operation
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GroveDbOp {
     /// Path to a subtree - subject to an operation
     pub path: Vec<Vec<u8>>,
@@ -104,8 +219,19 @@ impl fmt::Debug for GroveDbOp {
             Op::Insert {
                 element: Element::Tree(..),
             } => "Insert tree",
+            Op::Insert {
+                element: Element::SumTree(..),
+            } => "Insert sum tree",
+            Op::Insert {
+                element: Element::CountTree(..),
+            } => "Insert count tree",
             Op::Insert { .. } => "Insert",
             Op::Delete => "Delete",
+            Op::DeleteTree => "Delete tree",
+            Op::InsertIfAbsent { .. } => "Insert if absent",
+            Op::ReplaceIfPresent { .. } => "Replace if present",
+            Op::DeleteIfExists => "Delete if exists",
+            Op::AssertEquals { .. } => "Assert equals",
             Op::ReplaceTreeHash { .. } => "Replace Tree Hash",
         };
 
@@ -132,14 +258,73 @@ impl GroveDbOp {
             op: Op::Delete,
         }
     }
+
+    /// Deletes a tree under `key` with everything in it, unlike `delete` it
+    /// works for non-empty trees too
+    pub fn delete_tree(path: Vec<Vec<u8>>, key: Vec<u8>) -> Self {
+        Self {
+            path,
+            key,
+            op: Op::DeleteTree,
+        }
+    }
+
+    /// Inserts an element, the batch is rejected if the key is taken
+    pub fn insert_if_absent(path: Vec<Vec<u8>>, key: Vec<u8>, element: Element) -> Self {
+        Self {
+            path,
+            key,
+            op: Op::InsertIfAbsent { element },
+        }
+    }
+
+    /// Replaces an element, the batch is rejected if there is none under the
+    /// key
+    pub fn replace_if_present(path: Vec<Vec<u8>>, key: Vec<u8>, element: Element) -> Self {
+        Self {
+            path,
+            key,
+            op: Op::ReplaceIfPresent { element },
+        }
+    }
+
+    /// Deletes an element, the batch is rejected if there is none under the
+    /// key
+    pub fn delete_if_exists(path: Vec<Vec<u8>>, key: Vec<u8>) -> Self {
+        Self {
+            path,
+            key,
+            op: Op::DeleteIfExists,
+        }
+    }
+
+    /// Rejects the batch unless the element under the key is equal to
+    /// `element` before the batch is applied
+    pub fn assert_equals(path: Vec<Vec<u8>>, key: Vec<u8>, element: Element) -> Self {
+        Self {
+            path,
+            key,
+            op: Op::AssertEquals { element },
+        }
+    }
 }
 
 /// Cache for Merk trees by their paths.
 struct TreeCacheMerkByPath<S, F> {
     merks: HashMap<Vec<Vec<u8>>, Merk<S>>,
+    /// Kinds of subtrees inserted by the batch
+    inserted_tree_types: HashMap<Vec<Vec<u8>>, TreeType>,
+    /// Serialized elements pointed to by references inserted by the batch,
+    /// by full paths of the references
+    referenced_values: HashMap<Vec<Vec<u8>>, Vec<u8>>,
     get_merk_fn: F,
 }
 
+/// Cache for Merk trees of a batch applied in parallel. Subtrees of a level
+/// are processed by a thread pool, each one by a Merk opened on its own, all
+/// of them writing into the same storage batch.
+struct TreeCacheParallelMerkByPath<S, F>(TreeCacheMerkByPath<S, F>);
+
 /// Cache for subtee paths for worst case scenario costs.
 #[derive(Default)]
 struct TreeCacheKnownPaths {
@@ -152,12 +337,31 @@ impl<S, F> fmt::Debug for TreeCacheMerkByPath<S, F> {
     }
 }
 
+impl<S, F> fmt::Debug for TreeCacheParallelMerkByPath<S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeCacheParallelMerkByPath").finish()
+    }
+}
+
 impl fmt::Debug for TreeCacheKnownPaths {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TreeCacheKnownPaths").finish()
     }
 }
 
+/// Cache for subtree paths for average case costs, which also holds estimated
+/// layouts of the subtrees.
+struct TreeCacheEstimatedPaths<'a> {
+    paths: HashSet<Vec<Vec<u8>>>,
+    estimated_layers: &'a EstimatedLayerInformationByPath,
+}
+
+impl fmt::Debug for TreeCacheEstimatedPaths<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeCacheEstimatedPaths").finish()
+    }
+}
+
 trait TreeCache {
     fn insert(&mut self, op: &GroveDbOp) -> CostResult<(), Error>;
 
@@ -166,9 +370,37 @@ trait TreeCache {
         path: &[Vec<u8>],
         ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
         batch_apply_options: &BatchApplyOptions,
-    ) -> CostResult<[u8; 32], Error>;
+    ) -> CostResult<([u8; 32], Option<AggregateData>), Error>;
+
+    /// Executes operations on all subtrees of one level, stopping once their
+    /// cost goes over `cost_limit`. Subtrees of a level don't depend on each
+    /// other until their root hashes are propagated, by default they are
+    /// processed one after another.
+    fn execute_ops_on_level(
+        &mut self,
+        ops_at_level: BTreeMap<Vec<Vec<u8>>, BTreeMap<Vec<u8>, Op>>,
+        batch_apply_options: &BatchApplyOptions,
+        cost_limit: Option<&OperationCost>,
+    ) -> CostResult<SubtreeRoots, Error> {
+        let mut cost = OperationCost::default();
+
+        let mut roots = Vec::with_capacity(ops_at_level.len());
+        for (path, ops_at_path) in ops_at_level.into_iter() {
+            let (root_hash, aggregate_data) = cost_return_on_error!(
+                &mut cost,
+                self.execute_ops_on_path(&path, ops_at_path, batch_apply_options)
+            );
+            roots.push((path, root_hash, aggregate_data));
+            cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
+        }
+        Ok(roots).wrap_with_cost(cost)
+    }
 }
 
+/// Root hashes and aggregate data of subtrees modified by a batch, by paths of
+/// the subtrees
+type SubtreeRoots = Vec<(Vec<Vec<u8>>, [u8; 32], Option<AggregateData>)>;
+
 impl<'db, S, F> TreeCache for TreeCacheMerkByPath<S, F>
 where
     F: Fn(&[Vec<u8>]) -> CostResult<Merk<S>, Error>,
@@ -180,6 +412,12 @@ where
         let mut inserted_path = op.path.clone();
         inserted_path.push(op.key.clone());
         let merk = cost_return_on_error!(&mut cost, (self.get_merk_fn)(&inserted_path));
+        if let Some(element) = op.op.inserted_element() {
+            self.inserted_tree_types.insert(
+                inserted_path.clone(),
+                element.tree_type().unwrap_or_default(),
+            );
+        }
         self.merks.insert(inserted_path, merk);
 
         Ok(()).wrap_with_cost(cost)
@@ -190,9 +428,10 @@ where
         path: &[Vec<u8>],
         ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
         batch_apply_options: &BatchApplyOptions,
-    ) -> CostResult<[u8; 32], Error> {
+    ) -> CostResult<([u8; 32], Option<AggregateData>), Error> {
         let mut cost = OperationCost::default();
 
+        let tree_type = cost_return_on_error!(&mut cost, self.tree_type(path));
         let merk_wrapped = self
             .merks
             .remove(path)
@@ -202,34 +441,236 @@ where
 
         for (key, op) in ops_at_path_by_key.into_iter() {
             match op {
-                Op::Insert { element } => {
-                    if batch_apply_options.validate_tree_insertion_does_not_override {
-                        let inserted = cost_return_on_error!(
+                Op::Insert { element }
+                | Op::InsertIfAbsent { element }
+                | Op::ReplaceIfPresent { element } => {
+                    if batch_apply_options.validate_tree_insertion_does_not_override
+                        && cost_return_on_error!(
                             &mut cost,
-                            element.insert_if_not_exists(&mut merk, key.as_slice())
+                            element.element_at_key_already_exists(&mut merk, key.as_slice())
+                        )
+                    {
+                        return Err(Error::InvalidBatchOperation(
+                            "attempting to overwrite a tree",
+                        ))
+                        .wrap_with_cost(cost);
+                    }
+                    if element.is_reference() {
+                        let mut reference_full_path = path.to_vec();
+                        reference_full_path.push(key.clone());
+                        let referenced_value = cost_return_on_error_no_add!(
+                            &cost,
+                            self.referenced_values.remove(&reference_full_path).ok_or(
+                                Error::InternalError("value of a batch reference is not resolved")
+                            )
+                        );
+                        cost_return_on_error!(
+                            &mut cost,
+                            element.insert_reference(&mut merk, key, referenced_value, tree_type)
                         );
-                        if !inserted {
-                            return Err(Error::InvalidBatchOperation(
-                                "attempting to overwrite a tree",
-                            ))
-                            .wrap_with_cost(cost);
-                        }
                     } else {
-                        cost_return_on_error!(&mut cost, element.insert(&mut merk, key));
+                        cost_return_on_error!(&mut cost, element.insert(&mut merk, key, tree_type));
                     }
                 }
-                Op::Delete => {
+                Op::Delete | Op::DeleteTree | Op::DeleteIfExists => {
                     cost_return_on_error!(&mut cost, Element::delete(&mut merk, key));
                 }
-                Op::ReplaceTreeHash { hash } => {
+                Op::AssertEquals { .. } => {}
+                Op::ReplaceTreeHash {
+                    hash,
+                    aggregate_data,
+                } => {
                     cost_return_on_error!(
                         &mut cost,
-                        GroveDb::update_tree_item_preserve_flag(&mut merk, key.as_slice(), hash,)
+                        GroveDb::<DefaultStorage>::update_tree_item_preserve_flag(
+                            &mut merk,
+                            key.as_slice(),
+                            hash,
+                            aggregate_data,
+                            tree_type
+                        )
                     );
                 }
             }
         }
-        merk.root_hash().add_cost(cost).map(Ok)
+        let aggregate_data = merk.aggregate_data();
+        merk.root_hash()
+            .add_cost(cost)
+            .map(|root_hash| Ok((root_hash, aggregate_data)))
+    }
+}
+
+impl<'db, S, F> TreeCacheMerkByPath<S, F>
+where
+    F: Fn(&[Vec<u8>]) -> CostResult<Merk<S>, Error>,
+    S: StorageContext<'db>,
+{
+    /// Kind of the subtree at `path`, which is known for subtrees inserted by
+    /// the batch and is told by the parent element otherwise
+    fn tree_type(&self, path: &[Vec<u8>]) -> CostResult<TreeType, Error> {
+        if let Some(tree_type) = self.inserted_tree_types.get(path) {
+            return Ok(*tree_type).wrap_with_cost(Default::default());
+        }
+        match path.split_last() {
+            Some((key, parent_path)) => (self.get_merk_fn)(parent_path).flat_map_ok(|parent| {
+                Element::get(&parent, key).map_ok(|element| element.tree_type().unwrap_or_default())
+            }),
+            None => Ok(TreeType::NormalTree).wrap_with_cost(Default::default()),
+        }
+    }
+
+    /// Clears storage of subtrees deleted by the batch. Merks of the subtrees
+    /// are opened the same way as for operations of the batch, which see the
+    /// subtrees empty then.
+    fn clear_subtrees(&self, subtree_paths: Vec<Vec<Vec<u8>>>) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        for subtree_path in subtree_paths {
+            let mut subtree = cost_return_on_error!(&mut cost, (self.get_merk_fn)(&subtree_path));
+            cost_return_on_error!(
+                &mut cost,
+                subtree.clear().map_err(|e| {
+                    Error::CorruptedData(format!("unable to cleanup tree from storage: {}", e))
+                })
+            );
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+}
+
+/// Part of the state of a batch needed to execute operations on one subtree:
+/// operations on it, its kind if the batch inserts it and values of
+/// references the batch inserts into it
+type SubtreeTask = (
+    BTreeMap<Vec<u8>, Op>,
+    HashMap<Vec<Vec<u8>>, TreeType>,
+    HashMap<Vec<Vec<u8>>, Vec<u8>>,
+);
+
+/// Executes operations on a subtree with a Merk opened on its own
+fn execute_subtree_task<'db, S, F>(
+    get_merk_fn: &F,
+    path: &[Vec<u8>],
+    (ops_at_path_by_key, inserted_tree_types, referenced_values): SubtreeTask,
+    batch_apply_options: &BatchApplyOptions,
+) -> CostResult<([u8; 32], Option<AggregateData>), Error>
+where
+    F: Fn(&[Vec<u8>]) -> CostResult<Merk<S>, Error>,
+    S: StorageContext<'db>,
+{
+    TreeCacheMerkByPath {
+        merks: Default::default(),
+        inserted_tree_types,
+        referenced_values,
+        get_merk_fn,
+    }
+    .execute_ops_on_path(path, ops_at_path_by_key, batch_apply_options)
+}
+
+impl<S, F> TreeCacheParallelMerkByPath<S, F> {
+    /// Takes the state of the batch related to the subtree at `path` out of
+    /// the cache so the subtree could be processed on its own
+    fn subtree_task(
+        &mut self,
+        path: &[Vec<u8>],
+        ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
+    ) -> SubtreeTask {
+        let inserted_tree_types = self
+            .0
+            .inserted_tree_types
+            .remove_entry(path)
+            .into_iter()
+            .collect();
+        let mut referenced_values = HashMap::new();
+        for key in ops_at_path_by_key.keys() {
+            let mut reference_full_path = path.to_vec();
+            reference_full_path.push(key.clone());
+            if let Some(value) = self.0.referenced_values.remove(&reference_full_path) {
+                referenced_values.insert(reference_full_path, value);
+            }
+        }
+        (ops_at_path_by_key, inserted_tree_types, referenced_values)
+    }
+}
+
+impl<'db, S, F> TreeCache for TreeCacheParallelMerkByPath<S, F>
+where
+    F: Fn(&[Vec<u8>]) -> CostResult<Merk<S>, Error> + Sync,
+    S: StorageContext<'db>,
+{
+    fn insert(&mut self, op: &GroveDbOp) -> CostResult<(), Error> {
+        // Merks of inserted subtrees are opened once operations on them are
+        // executed
+        if let Some(element) = op.op.inserted_element() {
+            let mut inserted_path = op.path.clone();
+            inserted_path.push(op.key.clone());
+            self.0
+                .inserted_tree_types
+                .insert(inserted_path, element.tree_type().unwrap_or_default());
+        }
+        Ok(()).wrap_with_cost(Default::default())
+    }
+
+    fn execute_ops_on_path(
+        &mut self,
+        path: &[Vec<u8>],
+        ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
+        batch_apply_options: &BatchApplyOptions,
+    ) -> CostResult<([u8; 32], Option<AggregateData>), Error> {
+        let task = self.subtree_task(path, ops_at_path_by_key);
+        execute_subtree_task(&self.0.get_merk_fn, path, task, batch_apply_options)
+    }
+
+    fn execute_ops_on_level(
+        &mut self,
+        ops_at_level: BTreeMap<Vec<Vec<u8>>, BTreeMap<Vec<u8>, Op>>,
+        batch_apply_options: &BatchApplyOptions,
+        cost_limit: Option<&OperationCost>,
+    ) -> CostResult<SubtreeRoots, Error> {
+        let mut cost = OperationCost::default();
+
+        let tasks: Vec<(Vec<Vec<u8>>, SubtreeTask)> = ops_at_level
+            .into_iter()
+            .map(|(path, ops_at_path)| {
+                let task = self.subtree_task(&path, ops_at_path);
+                (path, task)
+            })
+            .collect();
+        let get_merk_fn = &self.0.get_merk_fn;
+        // Cost of the subtrees processed so far, no more subtrees are taken
+        // once it goes over the limit
+        let spent_cost = Mutex::new(OperationCost::default());
+        let execute = |(path, task): (Vec<Vec<u8>>, SubtreeTask)| {
+            let within_limit = check_cost_limit(&spent_cost.lock().unwrap(), cost_limit);
+            let result = match within_limit {
+                Ok(()) => execute_subtree_task(get_merk_fn, &path, task, batch_apply_options),
+                Err(e) => Err(e).wrap_with_cost(Default::default()),
+            };
+            *spent_cost.lock().unwrap() += result.cost.clone();
+            (path, result)
+        };
+        // A single subtree is not worth handing over to the thread pool
+        let results: Vec<_> = if tasks.len() > 1 {
+            tasks.into_par_iter().map(execute).collect()
+        } else {
+            tasks.into_iter().map(execute).collect()
+        };
+
+        let mut roots = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for (path, result) in results {
+            match result.unwrap_add_cost(&mut cost) {
+                Ok((root_hash, aggregate_data)) => roots.push((path, root_hash, aggregate_data)),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e).wrap_with_cost(cost);
+        }
+        cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
+        Ok(roots).wrap_with_cost(cost)
     }
 }
 
@@ -247,19 +688,107 @@ impl TreeCache for TreeCacheKnownPaths {
         &mut self,
         path: &[Vec<u8>],
         ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
-        _batch_apply_options: &BatchApplyOptions,
-    ) -> CostResult<[u8; 32], Error> {
+        batch_apply_options: &BatchApplyOptions,
+    ) -> CostResult<([u8; 32], Option<AggregateData>), Error> {
         let mut cost = OperationCost::default();
 
         if !self.paths.remove(path) {
+            // Kind of the tree is told by its element in the parent tree
+            if let Some((key, parent_path)) = path.split_last() {
+                cost.add_worst_case_get_merk(parent_path.iter().map(|k| k.as_slice()));
+                cost.add_worst_case_merk_has_element(key);
+            }
             // Then we have to get the tree
             let path_slices = path.iter().map(|k| k.as_slice()).collect::<Vec<&[u8]>>();
             cost.add_worst_case_get_merk(path_slices);
         }
         for (key, op) in ops_at_path_by_key.into_iter() {
+            if batch_apply_options.validate_tree_insertion_does_not_override
+                && op.inserted_element().is_some()
+            {
+                cost.add_worst_case_merk_has_element(&key);
+            }
             cost += op.worst_case_cost(key);
         }
-        Ok([0u8; 32]).wrap_with_cost(cost)
+        cost.add_worst_case_merk_root_hash();
+        Ok(([0u8; 32], None)).wrap_with_cost(cost)
+    }
+}
+
+impl TreeCache for TreeCacheEstimatedPaths<'_> {
+    fn insert(&mut self, op: &GroveDbOp) -> CostResult<(), Error> {
+        let mut inserted_path = op.path.clone();
+        inserted_path.push(op.key.clone());
+        self.paths.insert(inserted_path);
+
+        Ok(()).wrap_with_cost(OperationCost::default())
+    }
+
+    fn execute_ops_on_path(
+        &mut self,
+        path: &[Vec<u8>],
+        ops_at_path_by_key: BTreeMap<Vec<u8>, Op>,
+        batch_apply_options: &BatchApplyOptions,
+    ) -> CostResult<([u8; 32], Option<AggregateData>), Error> {
+        let mut cost = OperationCost::default();
+
+        // Subtrees inserted by the batch start empty unless told otherwise
+        let inserted = self.paths.remove(path);
+        let layer = match self.estimated_layers.get(path) {
+            Some(layer) => *layer,
+            None if inserted => EstimatedLayerInformation::default(),
+            None => {
+                return Err(Error::MissingParameter(
+                    "estimated layer information of a subtree",
+                ))
+                .wrap_with_cost(cost)
+            }
+        };
+        if !inserted {
+            // Kind of the tree is told by its element in the parent tree
+            if let Some((_, parent_path)) = path.split_last() {
+                let parent_layer = cost_return_on_error_no_add!(
+                    &cost,
+                    estimated_layer(self.estimated_layers, parent_path)
+                );
+                parent_layer.add_get_merk(&mut cost, parent_path);
+                parent_layer.add_merk_get(&mut cost);
+            }
+            layer.add_get_merk(&mut cost, path);
+        }
+        for (key, op) in ops_at_path_by_key.into_iter() {
+            let key_len = key.len() as u32;
+            match op {
+                Op::Insert { element }
+                | Op::InsertIfAbsent { element }
+                | Op::ReplaceIfPresent { element } => {
+                    if batch_apply_options.validate_tree_insertion_does_not_override {
+                        layer.add_merk_get(&mut cost);
+                    }
+                    cost.add_average_case_merk_insert(
+                        layer.height(),
+                        key_len,
+                        element.node_byte_size(key.len()) as u32,
+                        layer.node_size(),
+                    );
+                }
+                Op::Delete | Op::DeleteTree | Op::DeleteIfExists => {
+                    cost.add_average_case_merk_delete(layer.height(), key_len, layer.node_size())
+                }
+                Op::AssertEquals { .. } => {}
+                Op::ReplaceTreeHash { .. } => {
+                    layer.add_merk_get(&mut cost);
+                    cost.add_average_case_merk_insert(
+                        layer.height(),
+                        key_len,
+                        layer.node_size(),
+                        layer.node_size(),
+                    );
+                }
+            }
+        }
+        cost.add_average_case_merk_root_hash();
+        Ok(([0u8; 32], None)).wrap_with_cost(cost)
     }
 }
 
@@ -316,16 +845,15 @@ where
         for op in ops.into_iter() {
             let op_cost = OperationCost::default();
             let op_result = match &op.op {
-                Op::Insert { element } => {
-                    if let Element::Tree(..) = element {
+                Op::ReplaceTreeHash { .. } => Err(Error::InvalidBatchOperation(
+                    "replace tree hash is an internal operation only",
+                )),
+                op_kind => {
+                    if matches!(op_kind.inserted_element(), Some(element) if element.is_tree()) {
                         merk_tree_cache.insert(&op);
                     }
                     Ok(())
                 }
-                Op::Delete => Ok(()),
-                Op::ReplaceTreeHash { .. } => Err(Error::InvalidBatchOperation(
-                    "replace tree hash is an internal operation only",
-                )),
             };
             if op_result.is_err() {
                 return Err(op_result.err().unwrap()).wrap_with_cost(op_cost);
@@ -361,19 +889,68 @@ where
     }
 }
 
+/// Targets and hop limits of references inserted by a batch, by full paths of
+/// the references
+type BatchReferences = BTreeMap<Vec<Vec<u8>>, (Vec<Vec<u8>>, usize)>;
+
+/// Orders references inserted by a batch so that a reference pointing to
+/// another reference of the batch comes after it, cycles are rejected
+fn batch_references_order(references: &BatchReferences) -> Result<Vec<Vec<Vec<u8>>>, Error> {
+    let mut order = Vec::with_capacity(references.len());
+    let mut ordered = HashSet::new();
+    for start in references.keys() {
+        // Walk down to a target which is either ordered already or not a
+        // reference of the batch, then order the walked references backwards
+        let mut chain: ReferenceChain = Vec::new();
+        let mut on_chain = HashSet::new();
+        let mut current = start;
+        while let Some((target, _)) = references.get(current) {
+            if ordered.contains(current) {
+                break;
+            }
+            if !on_chain.insert(current) {
+                chain.push(current.clone());
+                return Err(Error::CyclicReference(chain));
+            }
+            chain.push(current.clone());
+            current = target;
+        }
+        for path in chain.into_iter().rev() {
+            ordered.insert(path.clone());
+            order.push(path);
+        }
+    }
+    Ok(order)
+}
+
+/// References bookkeeping of a batch, prepared before the batch is applied
+#[derive(Debug, Default)]
+struct BatchReferenceLinks {
+    /// Serialized elements pointed to by references inserted by the batch,
+    /// by full paths of the references
+    referenced_values: HashMap<Vec<Vec<u8>>, Vec<u8>>,
+    /// Referrers of elements affected by the batch, by full paths of the
+    /// elements
+    referrers: HashMap<Vec<Vec<u8>>, Referrers>,
+}
+
+/// Paths of subtrees deleted by a batch along with its reference links
+type PreparedBatch = (Vec<Vec<Vec<u8>>>, BatchReferenceLinks);
+
 #[derive(Debug, Default)]
 pub struct BatchApplyOptions {
     pub validate_tree_insertion_does_not_override: bool,
 }
 
-impl GroveDb {
-    /// Method to propagate updated subtree root hashes up to GroveDB root
+impl<S: Storage> GroveDb<S> {
+    /// Method to propagate updated subtree root hashes up to GroveDB root,
+    /// returns the root hash of the root tree
     fn apply_batch_structure<C: TreeCache>(
         &self,
         batch_structure: BatchStructure<C>,
-        temp_root_leaves: &mut BTreeMap<Vec<u8>, usize>,
         batch_apply_options: Option<BatchApplyOptions>,
-    ) -> CostResult<(), Error> {
+        cost_limit: Option<&OperationCost>,
+    ) -> CostResult<[u8; 32], Error> {
         let mut cost = OperationCost::default();
         let BatchStructure {
             mut ops_by_level_path,
@@ -381,104 +958,139 @@ impl GroveDb {
             last_level,
         } = batch_structure;
         let mut current_level = last_level;
+        let mut root_tree_hash = [0u8; 32];
 
         let batch_apply_options = batch_apply_options.unwrap_or_default();
         // We will update up the tree
         while let Some(ops_at_level) = ops_by_level_path.remove(&current_level) {
-            for (path, ops_at_path) in ops_at_level.into_iter() {
-                if current_level == 0 {
-                    for (key, op) in ops_at_path.into_iter() {
-                        match op {
-                            Op::Insert { .. } => {
-                                if temp_root_leaves.get(key.as_slice()).is_none() {
-                                    temp_root_leaves.insert(key, temp_root_leaves.len());
-                                }
-                            }
-                            Op::Delete => {
-                                return Err(Error::InvalidBatchOperation(
-                                    "deletion of root tree not possible",
-                                ))
-                                .wrap_with_cost(cost);
-                            }
-                            Op::ReplaceTreeHash { .. } => {}
+            if current_level == 0 {
+                // Root tree leaves are subtrees only
+                for op in ops_at_level
+                    .values()
+                    .flat_map(|ops_at_path| ops_at_path.values())
+                {
+                    if let Some(element) = op.inserted_element() {
+                        if !element.is_tree() {
+                            return Err(Error::InvalidBatchOperation(
+                                "only subtrees are allowed as root tree's leaves",
+                            ))
+                            .wrap_with_cost(cost);
                         }
                     }
-                } else {
-                    let root_hash = cost_return_on_error!(
-                        &mut cost,
-                        merk_tree_cache.execute_ops_on_path(
-                            &path,
-                            ops_at_path,
-                            &batch_apply_options,
-                        )
-                    );
+                }
+            }
 
-                    if current_level > 1 {
-                        // We need to propagate up this root hash, this means adding grove_db
-                        // operations up for the level above
-                        if let Some((key, parent_path)) = path.split_last() {
-                            if let Some(ops_at_level_above) =
-                                ops_by_level_path.get_mut(&(current_level - 1))
-                            {
-                                if let Some(ops_on_path) = ops_at_level_above.get_mut(parent_path) {
-                                    if let Some(op) = ops_on_path.remove(key) {
-                                        let new_op = match op {
-                                            Op::ReplaceTreeHash { .. } => {
-                                                Op::ReplaceTreeHash { hash: root_hash }
-                                            }
-                                            Op::Insert { element } => {
-                                                if let Element::Tree(_, storage_flags) = element {
-                                                    Op::Insert {
-                                                        element: Element::new_tree_with_flags(
-                                                            root_hash,
-                                                            storage_flags,
-                                                        ),
-                                                    }
-                                                } else {
+            let remaining_cost_limit = cost_limit.map(|limit| limit.saturating_sub(&cost));
+            let roots = cost_return_on_error!(
+                &mut cost,
+                merk_tree_cache.execute_ops_on_level(
+                    ops_at_level,
+                    &batch_apply_options,
+                    remaining_cost_limit.as_ref()
+                )
+            );
+
+            for (path, root_hash, aggregate_data) in roots.into_iter() {
+                if current_level == 0 {
+                    root_tree_hash = root_hash;
+                } else {
+                    // We need to propagate up this root hash, this means adding grove_db
+                    // operations up for the level above
+                    if let Some((key, parent_path)) = path.split_last() {
+                        if let Some(ops_at_level_above) =
+                            ops_by_level_path.get_mut(&(current_level - 1))
+                        {
+                            if let Some(ops_on_path) = ops_at_level_above.get_mut(parent_path) {
+                                if let Some(op) = ops_on_path.remove(key) {
+                                    let new_op = match op {
+                                        Op::ReplaceTreeHash { .. } => Op::ReplaceTreeHash {
+                                            hash: root_hash,
+                                            aggregate_data,
+                                        },
+                                        Op::Insert { ref element }
+                                        | Op::InsertIfAbsent { ref element }
+                                        | Op::ReplaceIfPresent { ref element } => {
+                                            let element = match element
+                                                .with_subtree_root(root_hash, aggregate_data)
+                                            {
+                                                Some(element) => element,
+                                                None => {
                                                     return Err(Error::InvalidBatchOperation(
                                                         "insertion of element under a non tree",
                                                     ))
                                                     .wrap_with_cost(cost);
                                                 }
-                                            }
-                                            Op::Delete => {
-                                                if root_hash != [0u8; 32] {
-                                                    return Err(Error::InvalidBatchOperation(
-                                                        "modification of tree when it will be \
-                                                         deleted",
-                                                    ))
-                                                    .wrap_with_cost(cost);
-                                                } else {
-                                                    op
+                                            };
+                                            // Conditional insertions keep their
+                                            // preconditions
+                                            match op {
+                                                Op::InsertIfAbsent { .. } => {
+                                                    Op::InsertIfAbsent { element }
+                                                }
+                                                Op::ReplaceIfPresent { .. } => {
+                                                    Op::ReplaceIfPresent { element }
                                                 }
+                                                _ => Op::Insert { element },
                                             }
-                                        };
-                                        ops_on_path.insert(key.clone(), new_op);
-                                    } else {
-                                        ops_on_path.insert(
-                                            key.clone(),
-                                            Op::ReplaceTreeHash { hash: root_hash },
-                                        );
-                                    }
+                                        }
+                                        Op::Delete | Op::DeleteIfExists => {
+                                            if root_hash != [0u8; 32] {
+                                                return Err(Error::InvalidBatchOperation(
+                                                    "modification of tree when it will be deleted",
+                                                ))
+                                                .wrap_with_cost(cost);
+                                            } else {
+                                                op
+                                            }
+                                        }
+                                        Op::DeleteTree => {
+                                            return Err(Error::InvalidBatchOperation(
+                                                "modification of tree when it will be deleted",
+                                            ))
+                                            .wrap_with_cost(cost);
+                                        }
+                                        Op::AssertEquals { .. } => {
+                                            return Err(Error::InvalidBatchOperation(
+                                                "modification of tree which is asserted to be \
+                                                 unchanged",
+                                            ))
+                                            .wrap_with_cost(cost);
+                                        }
+                                    };
+                                    ops_on_path.insert(key.clone(), new_op);
                                 } else {
-                                    let mut ops_on_path: BTreeMap<Vec<u8>, Op> = BTreeMap::new();
                                     ops_on_path.insert(
                                         key.clone(),
-                                        Op::ReplaceTreeHash { hash: root_hash },
+                                        Op::ReplaceTreeHash {
+                                            hash: root_hash,
+                                            aggregate_data,
+                                        },
                                     );
-                                    ops_at_level_above.insert(parent_path.to_vec(), ops_on_path);
                                 }
                             } else {
                                 let mut ops_on_path: BTreeMap<Vec<u8>, Op> = BTreeMap::new();
-                                ops_on_path
-                                    .insert(key.clone(), Op::ReplaceTreeHash { hash: root_hash });
-                                let mut ops_on_level: BTreeMap<
-                                    Vec<Vec<u8>>,
-                                    BTreeMap<Vec<u8>, Op>,
-                                > = BTreeMap::new();
-                                ops_on_level.insert(parent_path.to_vec(), ops_on_path);
-                                ops_by_level_path.insert(current_level - 1, ops_on_level);
+                                ops_on_path.insert(
+                                    key.clone(),
+                                    Op::ReplaceTreeHash {
+                                        hash: root_hash,
+                                        aggregate_data,
+                                    },
+                                );
+                                ops_at_level_above.insert(parent_path.to_vec(), ops_on_path);
                             }
+                        } else {
+                            let mut ops_on_path: BTreeMap<Vec<u8>, Op> = BTreeMap::new();
+                            ops_on_path.insert(
+                                key.clone(),
+                                Op::ReplaceTreeHash {
+                                    hash: root_hash,
+                                    aggregate_data,
+                                },
+                            );
+                            let mut ops_on_level: BTreeMap<Vec<Vec<u8>>, BTreeMap<Vec<u8>, Op>> =
+                                BTreeMap::new();
+                            ops_on_level.insert(parent_path.to_vec(), ops_on_path);
+                            ops_by_level_path.insert(current_level - 1, ops_on_level);
                         }
                     }
                 }
@@ -487,80 +1099,670 @@ impl GroveDb {
                 current_level -= 1;
             }
         }
-        Ok(()).wrap_with_cost(cost)
+        Ok(root_tree_hash).wrap_with_cost(cost)
     }
 
     /// Method to propagate updated subtree root hashes up to GroveDB root
-    fn apply_body<'db, S: StorageContext<'db>>(
+    fn apply_body(
         &self,
         ops: Vec<GroveDbOp>,
-        temp_root_leaves: &mut BTreeMap<Vec<u8>, usize>,
         batch_apply_options: Option<BatchApplyOptions>,
-        get_merk_fn: impl Fn(&[Vec<u8>]) -> CostResult<Merk<S>, Error>,
-    ) -> CostResult<(), Error> {
+        cost_limit: Option<&OperationCost>,
+        merk_tree_cache: impl TreeCache,
+    ) -> CostResult<[u8; 32], Error> {
         let mut cost = OperationCost::default();
-        let batch_structure = cost_return_on_error!(
-            &mut cost,
-            BatchStructure::from_ops(
-                ops,
-                TreeCacheMerkByPath {
-                    merks: Default::default(),
-                    get_merk_fn,
-                }
-            )
-        );
-        self.apply_batch_structure(batch_structure, temp_root_leaves, batch_apply_options)
+        let batch_structure =
+            cost_return_on_error!(&mut cost, BatchStructure::from_ops(ops, merk_tree_cache));
+        self.apply_batch_structure(batch_structure, batch_apply_options, cost_limit)
             .add_cost(cost)
     }
 
-    /// Applies batch of operations on GroveDB
-    pub fn apply_batch(
+    /// Checks a batch and extends it to keep references consistent, returns
+    /// paths of subtrees deleted by the batch, which are to be cleared, along
+    /// with what the batch needs to know about references
+    fn prepare_batch(
         &self,
-        ops: Vec<GroveDbOp>,
-        batch_apply_options: Option<BatchApplyOptions>,
-        transaction: TransactionArg,
-    ) -> CostResult<(), Error> {
+        ops: &mut Vec<GroveDbOp>,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<PreparedBatch, Error> {
         let mut cost = OperationCost::default();
 
-        // Helper function to store updated root leaves
-        fn save_root_leaves<'db, S>(
-            storage: S,
-            temp_root_leaves: &BTreeMap<Vec<u8>, usize>,
-        ) -> CostResult<(), Error>
-        where
-            S: StorageContext<'db>,
-            Error: From<<S as storage::StorageContext<'db>>::Error>,
-        {
-            let cost = OperationCost::default();
-
-            let root_leaves_serialized = cost_return_on_error_no_add!(
-                &cost,
-                bincode::serialize(&temp_root_leaves).map_err(|_| {
-                    Error::CorruptedData(String::from("unable to serialize root leaves data"))
-                })
-            );
-            storage
-                .put_meta(ROOT_LEAFS_SERIALIZED_KEY, &root_leaves_serialized)
-                .map_err(|e| e.into())
-                .wrap_with_cost(OperationCost {
-                    storage_written_bytes: ROOT_LEAFS_SERIALIZED_KEY.len() as u32
-                        + root_leaves_serialized.len() as u32,
-                    ..Default::default()
-                })
+        cost_return_on_error!(
+            &mut cost,
+            self.check_batch_preconditions(ops, storage_batch, transaction)
+        );
+        let deleted_subtrees = cost_return_on_error!(
+            &mut cost,
+            self.deleted_subtrees(ops, storage_batch, transaction)
+        );
+        let deleted_subtrees_elements = cost_return_on_error!(
+            &mut cost,
+            self.find_subtrees_elements_batched(&deleted_subtrees, storage_batch, transaction)
+        );
+        let links = cost_return_on_error!(
+            &mut cost,
+            self.prepare_reference_links(
+                ops,
+                deleted_subtrees_elements,
+                storage_batch,
+                transaction
+            )
+        );
+        Ok((deleted_subtrees, links)).wrap_with_cost(cost)
+    }
+
+    /// Writes referrers of elements affected by a batch into its storage batch
+    fn write_batch_referrers(
+        &self,
+        referrers: &HashMap<Vec<Vec<u8>>, Referrers>,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        for (target, target_referrers) in referrers.iter() {
+            let (key, path) = target.split_last().expect("full path is never empty");
+            let path = path.iter().map(|x| x.as_slice());
+            batch_storage_context_optional_tx!(
+                self.db,
+                path,
+                storage_batch,
+                transaction,
+                storage,
+                {
+                    cost_return_on_error!(
+                        &mut cost,
+                        write_referrers(&storage, key, target_referrers)
+                    );
+                }
+            );
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Checks preconditions of conditional operations against GroveDB as it is
+    /// before the batch, so nothing is applied if any of them fails. A
+    /// conditional operation has to be the only operation on its key, as
+    /// otherwise one of them would silently replace the other.
+    fn check_batch_preconditions(
+        &self,
+        ops: &[GroveDbOp],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut ops_count_by_key: HashMap<(&[Vec<u8>], &[u8]), usize> = HashMap::new();
+        for op in ops.iter() {
+            *ops_count_by_key
+                .entry((op.path.as_slice(), op.key.as_slice()))
+                .or_default() += 1;
+        }
+        for op in ops.iter().filter(|op| op.op.has_precondition()) {
+            if ops_count_by_key[&(op.path.as_slice(), op.key.as_slice())] > 1 {
+                return Err(Error::InvalidBatchOperation(
+                    "conditional operation on a key with other operations",
+                ))
+                .wrap_with_cost(cost);
+            }
+            let stored = cost_return_on_error!(
+                &mut cost,
+                self.get_raw_optional_batched(&op.path, &op.key, storage_batch, transaction)
+            );
+            if !op.op.precondition_holds(stored.as_ref()) {
+                return Err(Error::BatchPreconditionFailed(Box::new(op.clone())))
+                    .wrap_with_cost(cost);
+            }
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Paths of trees deleted by the batch along with paths of all subtrees
+    /// nested in them, no other operation of the batch may be under these.
+    /// Plain deletions delete nested subtrees of root leaves only, other trees
+    /// have to be emptied by the same batch or deleted with `DeleteTree`.
+    fn deleted_subtrees(
+        &self,
+        ops: &[GroveDbOp],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<Vec<Vec<u8>>>, Error> {
+        let mut cost = OperationCost::default();
+
+        // The last operation on a key wins the same way it does in batch structure
+        let last_ops: HashMap<(&[Vec<u8>], &[u8]), &Op> = ops
+            .iter()
+            .map(|op| ((op.path.as_slice(), op.key.as_slice()), &op.op))
+            .collect();
+
+        let mut subtrees = Vec::new();
+        for (&(path, key), op) in last_ops.iter() {
+            match op {
+                Op::DeleteTree => {
+                    let element = cost_return_on_error!(
+                        &mut cost,
+                        self.get_raw_batched(path, key, storage_batch, transaction)
+                    );
+                    if !element.is_tree() {
+                        return Err(Error::InvalidBatchOperation(
+                            "deletion of a tree under a non tree element",
+                        ))
+                        .wrap_with_cost(cost);
+                    }
+                }
+                Op::Delete | Op::DeleteIfExists => {
+                    match cost_return_on_error!(
+                        &mut cost,
+                        self.get_raw_optional_batched(path, key, storage_batch, transaction)
+                    ) {
+                        Some(element) if element.is_tree() => {}
+                        _ => continue,
+                    }
+                    // Root leaves are deleted with everything nested in them, other trees
+                    // may be deleted this way only if the batch empties them
+                    if !path.is_empty() {
+                        let mut subtree_path = path.to_vec();
+                        subtree_path.push(key.to_vec());
+                        let keys = cost_return_on_error!(
+                            &mut cost,
+                            self.subtree_keys_batched(&subtree_path, storage_batch, transaction)
+                        );
+                        let emptied = keys.iter().all(|key| {
+                            matches!(
+                                last_ops.get(&(subtree_path.as_slice(), key.as_slice())),
+                                Some(Op::Delete | Op::DeleteIfExists | Op::DeleteTree)
+                            )
+                        });
+                        if !emptied {
+                            return Err(Error::InvalidBatchOperation(
+                                "deletion of a non empty tree, use DeleteTree",
+                            ))
+                            .wrap_with_cost(cost);
+                        }
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            let mut subtree_path = path.to_vec();
+            subtree_path.push(key.to_vec());
+            subtrees.extend(cost_return_on_error!(
+                &mut cost,
+                self.find_subtrees_batched(subtree_path, storage_batch, transaction)
+            ));
+        }
+
+        // Storage of deleted subtrees is cleared before operations of the batch
+        // are applied, so none of them may touch it
+        let deleted_paths: HashSet<&[Vec<u8>]> = subtrees.iter().map(|x| x.as_slice()).collect();
+        for op in ops.iter() {
+            if (1..=op.path.len()).any(|len| deleted_paths.contains(&op.path[..len])) {
+                return Err(Error::InvalidBatchOperation(
+                    "modification of tree when it will be deleted",
+                ))
+                .wrap_with_cost(cost);
+            }
+        }
+        Ok(subtrees).wrap_with_cost(cost)
+    }
+
+    /// Keeps references consistent with a batch: references to elements
+    /// deleted by the batch are deleted too, references to elements updated by
+    /// the batch are re-inserted to be re-hashed, back-links of inserted and
+    /// removed references are updated and elements pointed to by references
+    /// are resolved against the batch. Elements of subtrees deleted by the
+    /// batch, `deleted_subtrees_elements`, are treated as deleted as well.
+    fn prepare_reference_links(
+        &self,
+        ops: &mut Vec<GroveDbOp>,
+        deleted_subtrees_elements: Vec<PathKeyElementTrio>,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<BatchReferenceLinks, Error> {
+        let mut cost = OperationCost::default();
+
+        // The last operation on a key wins the same way it does in batch
+        // structure, assertions change nothing
+        let mut pending: BTreeMap<Vec<Vec<u8>>, Op> = deleted_subtrees_elements
+            .into_iter()
+            .map(|(mut full_path, key, _)| {
+                full_path.push(key);
+                (full_path, Op::Delete)
+            })
+            .collect();
+        pending.extend(
+            ops.iter()
+                .filter(|op| !matches!(op.op, Op::AssertEquals { .. }))
+                .map(|op| {
+                    let mut full_path = op.path.clone();
+                    full_path.push(op.key.clone());
+                    (full_path, op.op.clone())
+                }),
+        );
+
+        // Referrers are looked for only in subtrees which ever had any
+        let mut subtrees_with_referrers: HashMap<Vec<Vec<u8>>, bool> = HashMap::new();
+        let mut queue: Vec<Vec<Vec<u8>>> = pending.keys().rev().cloned().collect();
+        while let Some(target) = queue.pop() {
+            let (key, path) = target.split_last().expect("full path is never empty");
+            let has_referrers = match subtrees_with_referrers.get(path) {
+                Some(has_referrers) => *has_referrers,
+                None => {
+                    let has_referrers = cost_return_on_error!(
+                        &mut cost,
+                        self.has_referrers_batched(path, storage_batch, transaction)
+                    );
+                    subtrees_with_referrers.insert(path.to_vec(), has_referrers);
+                    has_referrers
+                }
+            };
+            if !has_referrers {
+                continue;
+            }
+            let target_deleted = matches!(pending.get(&target), Some(op) if op.is_delete());
+            let target_referrers = cost_return_on_error!(
+                &mut cost,
+                self.get_referrers_batched(path, key, storage_batch, transaction)
+            );
+            for referrer in target_referrers {
+                if pending.contains_key(&referrer) {
+                    continue;
+                }
+                let (referrer_key, referrer_path) =
+                    referrer.split_last().expect("full path is never empty");
+                let element = match cost_return_on_error!(
+                    &mut cost,
+                    self.get_raw_optional_batched(
+                        referrer_path,
+                        referrer_key,
+                        storage_batch,
+                        transaction
+                    )
+                ) {
+                    Some(element) => element,
+                    None => continue,
+                };
+                if cost_return_on_error_no_add!(
+                    &cost,
+                    element.absolute_reference_path(referrer_path, referrer_key)
+                ) != Some(target.clone())
+                {
+                    continue;
+                }
+                let op = if target_deleted {
+                    Op::Delete
+                } else {
+                    Op::Insert { element }
+                };
+                ops.push(GroveDbOp {
+                    path: referrer_path.to_vec(),
+                    key: referrer_key.to_vec(),
+                    op: op.clone(),
+                });
+                pending.insert(referrer.clone(), op);
+                queue.push(referrer);
+            }
+        }
+
+        let mut links = BatchReferenceLinks::default();
+        for (full_path, op) in pending.iter() {
+            if op.is_delete() {
+                links.referrers.insert(full_path.clone(), Referrers::new());
+            }
+        }
+        for (full_path, op) in pending.iter() {
+            let (key, path) = full_path.split_last().expect("full path is never empty");
+            let previous = cost_return_on_error!(
+                &mut cost,
+                self.get_raw_optional_batched(path, key, storage_batch, transaction)
+            );
+            let previous_target = match previous {
+                Some(element) => {
+                    cost_return_on_error_no_add!(&cost, element.absolute_reference_path(path, key))
+                }
+                None => None,
+            };
+            let new_target = match op.inserted_element() {
+                Some(element) => {
+                    cost_return_on_error_no_add!(&cost, element.absolute_reference_path(path, key))
+                }
+                None => None,
+            };
+            if let Some(target) = &new_target {
+                if matches!(pending.get(target), Some(op) if op.is_delete()) {
+                    return Err(Error::InvalidBatchOperation(
+                        "reference to an element deleted by the batch",
+                    ))
+                    .wrap_with_cost(cost);
+                }
+            }
+            if previous_target == new_target {
+                continue;
+            }
+            if let Some(target) = previous_target {
+                if !matches!(pending.get(&target), Some(op) if op.is_delete()) {
+                    let target_referrers = cost_return_on_error!(
+                        &mut cost,
+                        self.batch_referrers(
+                            &mut links.referrers,
+                            &target,
+                            storage_batch,
+                            transaction
+                        )
+                    );
+                    target_referrers.remove(full_path);
+                }
+            }
+            if let Some(target) = new_target {
+                let target_referrers = cost_return_on_error!(
+                    &mut cost,
+                    self.batch_referrers(&mut links.referrers, &target, storage_batch, transaction)
+                );
+                target_referrers.insert(full_path.clone());
+            }
+        }
+        links.referenced_values = cost_return_on_error!(
+            &mut cost,
+            self.resolve_batch_references(&pending, storage_batch, transaction)
+        );
+        Ok(links).wrap_with_cost(cost)
+    }
+
+    /// Resolves elements pointed to by references inserted by the batch.
+    /// References are resolved in dependency order, so a reference to another
+    /// reference of the batch reuses the element resolved for the latter.
+    fn resolve_batch_references(
+        &self,
+        pending: &BTreeMap<Vec<Vec<u8>>, Op>,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<HashMap<Vec<Vec<u8>>, Vec<u8>>, Error> {
+        let mut cost = OperationCost::default();
+
+        let mut references: BatchReferences = BTreeMap::new();
+        for (full_path, op) in pending.iter() {
+            let element = match op.inserted_element() {
+                Some(element) => element,
+                None => continue,
+            };
+            let (key, path) = full_path.split_last().expect("full path is never empty");
+            let target =
+                cost_return_on_error_no_add!(&cost, element.absolute_reference_path(path, key));
+            if let (Some(target), Some(max_hops)) = (target, element.max_reference_hops()) {
+                if max_hops == 0 {
+                    return Err(Error::InvalidInput(
+                        "reference must be allowed to take at least one hop",
+                    ))
+                    .wrap_with_cost(cost);
+                }
+                references.insert(full_path.clone(), (target, max_hops));
+            }
+        }
+
+        let order = cost_return_on_error_no_add!(&cost, batch_references_order(&references));
+
+        // Serialized referenced elements along with numbers of hops taken to
+        // reach them, by full paths of the references
+        let mut resolved: HashMap<Vec<Vec<u8>>, (Vec<u8>, usize)> = HashMap::new();
+        for full_path in order {
+            let (target, max_hops) = &references[&full_path];
+            let (value, hops) = match resolved.get(target) {
+                Some((value, hops)) => (value.clone(), hops + 1),
+                None => {
+                    let (element, hops) = cost_return_on_error!(
+                        &mut cost,
+                        self.follow_reference_in_batch(
+                            pending,
+                            vec![full_path.clone()],
+                            target.clone(),
+                            *max_hops,
+                            storage_batch,
+                            transaction
+                        )
+                    );
+                    (
+                        cost_return_on_error_no_add!(&cost, element.serialize()),
+                        hops,
+                    )
+                }
+            };
+            if hops > *max_hops {
+                let mut chain = vec![full_path];
+                let mut next = target;
+                while let Some((next_target, _)) = references.get(next) {
+                    chain.push(next.clone());
+                    next = next_target;
+                }
+                chain.push(next.clone());
+                return Err(Error::ReferenceLimit(chain)).wrap_with_cost(cost);
+            }
+            resolved.insert(full_path, (value, hops));
+        }
+        Ok(resolved
+            .into_iter()
+            .map(|(full_path, (value, _))| (full_path, value))
+            .collect())
+        .wrap_with_cost(cost)
+    }
+
+    /// Referrers of an element at `target` full path updated by the batch so
+    /// far, loaded from storage on first access
+    fn batch_referrers<'a>(
+        &self,
+        referrers: &'a mut HashMap<Vec<Vec<u8>>, Referrers>,
+        target: &[Vec<u8>],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<&'a mut Referrers, Error> {
+        let mut cost = OperationCost::default();
+
+        if !referrers.contains_key(target) {
+            let (key, path) = cost_return_on_error_no_add!(
+                &cost,
+                target
+                    .split_last()
+                    .ok_or(Error::CorruptedPath("empty reference path"))
+            );
+            let stored = cost_return_on_error!(
+                &mut cost,
+                self.get_referrers_batched(path, key, storage_batch, transaction)
+            );
+            referrers.insert(target.to_vec(), stored);
+        }
+        Ok(referrers.get_mut(target).expect("inserted above")).wrap_with_cost(cost)
+    }
+
+    /// Follows a reference the way `follow_reference_chain` does, but elements
+    /// inserted or deleted by the batch take precedence over stored ones.
+    /// Returns the element found along with the number of hops taken.
+    fn follow_reference_in_batch(
+        &self,
+        pending: &BTreeMap<Vec<Vec<u8>>, Op>,
+        mut chain: ReferenceChain,
+        mut path: Vec<Vec<u8>>,
+        mut max_hops: usize,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(Element, usize), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut hops = 0;
+        loop {
+            if chain.contains(&path) {
+                chain.push(path);
+                return Err(Error::CyclicReference(chain)).wrap_with_cost(cost);
+            }
+            if hops >= max_hops {
+                chain.push(path);
+                return Err(Error::ReferenceLimit(chain)).wrap_with_cost(cost);
+            }
+            let (key, subtree_path) = cost_return_on_error_no_add!(
+                &cost,
+                path.split_last().ok_or(Error::CorruptedPath("empty path"))
+            );
+            let pending_op = pending.get(&path);
+            let element = match pending_op.and_then(Op::inserted_element) {
+                Some(element) => element.clone(),
+                None if matches!(pending_op, Some(op) if op.is_delete()) => {
+                    return Err(Error::InvalidBatchOperation(
+                        "reference to an element deleted by the batch",
+                    ))
+                    .wrap_with_cost(cost)
+                }
+                None => cost_return_on_error!(
+                    &mut cost,
+                    self.get_raw_batched(subtree_path, key, storage_batch, transaction)
+                ),
+            };
+            let next_path = cost_return_on_error_no_add!(
+                &cost,
+                element.absolute_reference_path(subtree_path, key)
+            );
+            hops += 1;
+            chain.push(path);
+            match (next_path, element.max_reference_hops()) {
+                (Some(reference_path), Some(element_max_hops)) => {
+                    max_hops = max_hops.min(hops + element_max_hops);
+                    path = reference_path;
+                }
+                _ => return Ok((element, hops)).wrap_with_cost(cost),
+            }
         }
+    }
+
+    /// Applies batch of operations on GroveDB. Without a transaction
+    /// operations on subtrees of the same level are executed in parallel, as
+    /// transactions can't be shared between threads.
+    pub fn apply_batch(
+        &self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        self.apply_batch_with_cost_limit(ops, batch_apply_options, None, transaction)
+    }
 
+    /// Applies batch of operations on GroveDB the way `apply_batch` does, but
+    /// stops with `CostLimitExceeded` once the cost of the batch goes over
+    /// `cost_limit`, if any, in which case nothing is written to storage.
+    ///
+    /// Written bytes are accounted for as the batch is prepared, when Merks
+    /// put their changes into the storage batch, so committing it adds no cost
+    /// of its own. The work the storage backend does on commit (such as
+    /// compaction) is not measured and so is not limited. Subtrees being
+    /// processed in parallel when the limit is exceeded are finished first.
+    pub fn apply_batch_with_cost_limit(
+        &self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
         if ops.is_empty() {
-            return Ok(()).wrap_with_cost(cost);
+            return Ok(()).wrap_with_cost(OperationCost::default());
+        }
+
+        self.preview_batch_with_cost_limit(ops, batch_apply_options, cost_limit, transaction)
+            .flat_map_ok(|(_, storage_batch)| {
+                self.commit_batch(storage_batch, transaction)
+                    .wrap_with_cost(OperationCost::default())
+            })
+    }
+
+    /// Runs a batch of operations the way `apply_batch` does, but instead of
+    /// writing the result returns the root hash GroveDB would have along with
+    /// the pending storage batch, which could be committed with
+    /// `commit_batch` or dropped. The storage batch is only valid as long as
+    /// nothing else is written to GroveDB (or to the transaction) in between.
+    pub fn preview_batch(
+        &self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<([u8; 32], StorageBatch), Error> {
+        self.preview_batch_with_cost_limit(ops, batch_apply_options, None, transaction)
+    }
+
+    /// Previews a batch the way `preview_batch` does, but stops with
+    /// `CostLimitExceeded` once the cost of the batch goes over `cost_limit`,
+    /// if any
+    pub fn preview_batch_with_cost_limit(
+        &self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<([u8; 32], StorageBatch), Error> {
+        with_total_cost_on_limit(self.preview_batch_internal(
+            ops,
+            batch_apply_options,
+            cost_limit,
+            StorageBatch::new(),
+            transaction,
+        ))
+    }
+
+    /// Writes a storage batch made by `preview_batch`
+    pub fn commit_batch(
+        &self,
+        storage_batch: StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> Result<(), Error> {
+        self.db
+            .commit_multi_context_batch(storage_batch, transaction)
+            .map_err(|e| Error::StorageError(e.into()))
+    }
+
+    /// Starts applying several batches to be committed to storage at once,
+    /// in the transaction if there is one, see [`MultiBatch`]
+    pub fn start_multi_batch<'db>(
+        &'db self,
+        transaction: TransactionArg<'db, 'db, S>,
+    ) -> MultiBatch<'db, S> {
+        MultiBatch {
+            grove_db: self,
+            storage_batch: StorageBatch::new(),
+            transaction,
         }
+    }
+
+    /// Runs a batch on top of writes already pending in `storage_batch`,
+    /// which are seen by the batch, and returns the root hash along with the
+    /// storage batch extended with writes of the batch
+    fn preview_batch_internal(
+        &self,
+        mut ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        cost_limit: Option<&OperationCost>,
+        storage_batch: StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<([u8; 32], StorageBatch), Error> {
+        let mut cost = OperationCost::default();
 
-        let mut temp_root_leaves =
-            cost_return_on_error!(&mut cost, self.get_root_leaf_keys(transaction));
+        if ops.is_empty() {
+            let root_hash = cost_return_on_error!(
+                &mut cost,
+                self.root_hash_batched(&storage_batch, transaction)
+            );
+            return Ok((root_hash.unwrap_or_default(), storage_batch)).wrap_with_cost(cost);
+        }
 
-        // `StorageBatch` allows us to collect operations on different subtrees before
-        // execution
-        let storage_batch = StorageBatch::new();
+        let (
+            deleted_subtrees,
+            BatchReferenceLinks {
+                referenced_values,
+                referrers,
+            },
+        ) = cost_return_on_error!(
+            &mut cost,
+            self.prepare_batch(&mut ops, &storage_batch, transaction)
+        );
+        cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
+        let remaining_cost_limit = cost_limit.map(|limit| limit.saturating_sub(&cost));
 
         // With the only one difference (if there is a transaction) do the following:
+        // 1. Clear storage of deleted subtrees;
         // 2. If nothing left to do and we were on a non-leaf subtree or we're done with
         //    one subtree and moved to another then add propagation operation to the
         //    operations tree and drop Merk handle;
@@ -568,12 +1770,13 @@ impl GroveDb {
         //    context;
         // 4. Apply operation to the Merk;
         // 5. Remove operation from the tree, repeat until there are operations to do;
-        // 6. Add root leaves save operation to the batch
-        // 7. Apply storage batch
-        if let Some(tx) = transaction {
-            cost_return_on_error!(
-                &mut cost,
-                self.apply_body(ops, &mut temp_root_leaves, batch_apply_options, |path| {
+        // 6. Leave storage batch to be applied by the caller
+        let root_hash = if let Some(tx) = transaction {
+            let merk_tree_cache = TreeCacheMerkByPath {
+                merks: Default::default(),
+                inserted_tree_types: Default::default(),
+                referenced_values,
+                get_merk_fn: |path: &[Vec<u8>]| {
                     let storage = self.db.get_batch_transactional_storage_context(
                         path.iter().map(|x| x.as_slice()),
                         &storage_batch,
@@ -581,53 +1784,61 @@ impl GroveDb {
                     );
                     Merk::open(storage)
                         .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
-                })
-            );
-
-            let meta_storage = self.db.get_batch_transactional_storage_context(
-                std::iter::empty(),
-                &storage_batch,
-                tx,
-            );
-
-            cost_return_on_error!(&mut cost, save_root_leaves(meta_storage, &temp_root_leaves));
-
-            // TODO: compute batch costs
-            cost_return_on_error_no_add!(
-                &cost,
-                self.db
-                    .commit_multi_context_batch(storage_batch, Some(tx))
-                    .map_err(|e| e.into())
-            );
-        } else {
+                },
+            };
+            cost_return_on_error!(&mut cost, merk_tree_cache.clear_subtrees(deleted_subtrees));
             cost_return_on_error!(
                 &mut cost,
-                self.apply_body(ops, &mut temp_root_leaves, batch_apply_options, |path| {
+                self.apply_body(
+                    ops,
+                    batch_apply_options,
+                    remaining_cost_limit.as_ref(),
+                    merk_tree_cache
+                )
+            )
+        } else {
+            let merk_tree_cache = TreeCacheMerkByPath {
+                merks: Default::default(),
+                inserted_tree_types: Default::default(),
+                referenced_values,
+                get_merk_fn: |path: &[Vec<u8>]| {
                     let storage = self.db.get_batch_storage_context(
                         path.iter().map(|x| x.as_slice()),
                         &storage_batch,
                     );
                     Merk::open(storage)
                         .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
-                })
-            );
-
-            let meta_storage = self
-                .db
-                .get_batch_storage_context(std::iter::empty(), &storage_batch);
-            cost_return_on_error!(&mut cost, save_root_leaves(meta_storage, &temp_root_leaves));
-
-            // TODO: compute batch costs
-            cost_return_on_error_no_add!(
-                &cost,
-                self.db
-                    .commit_multi_context_batch(storage_batch, None)
-                    .map_err(|e| e.into())
-            );
-        }
-        Ok(()).wrap_with_cost(cost)
+                },
+            };
+            cost_return_on_error!(&mut cost, merk_tree_cache.clear_subtrees(deleted_subtrees));
+            // Subtrees of the same level are processed in parallel, writing
+            // into the same storage batch and reading each other's writes
+            // through it. Transactions can't be shared between threads, so
+            // with a transaction they are processed one by one.
+            cost_return_on_error!(
+                &mut cost,
+                self.apply_body(
+                    ops,
+                    batch_apply_options,
+                    remaining_cost_limit.as_ref(),
+                    TreeCacheParallelMerkByPath(merk_tree_cache)
+                )
+            )
+        };
+        cost_return_on_error!(
+            &mut cost,
+            self.write_batch_referrers(&referrers, &storage_batch, transaction)
+        );
+        cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
+        Ok((root_hash, storage_batch)).wrap_with_cost(cost)
     }
 
+    /// Upper bound of the cost of applying a batch, estimated without
+    /// accessing storage from sizes of keys and values and depths of paths.
+    /// Merk trees are assumed to be not higher than `WORST_CASE_MERK_HEIGHT`
+    /// and to hold values of `WORST_CASE_VALUE_SIZE` bytes at most. Updates
+    /// of references to elements changed by the batch are not accounted for,
+    /// as they can't be known in advance.
     pub fn worst_case_operations_for_batch(
         &self,
         ops: Vec<GroveDbOp>,
@@ -639,42 +1850,951 @@ impl GroveDb {
             return Ok(()).wrap_with_cost(cost);
         }
 
-        cost.add_worst_case_save_root_leaves();
+        for op in ops.iter() {
+            // Preconditions are checked against stored elements before anything
+            // is applied
+            if op.op.has_precondition() {
+                add_worst_case_get_raw(&mut cost, &op.path, &op.key);
+            }
+            add_worst_case_reference_links(&mut cost, &op.path, &op.key, &op.op);
+        }
 
-        let mut temp_root_leaves: BTreeMap<Vec<u8>, usize> = BTreeMap::new();
         let batch_structure = cost_return_on_error!(
             &mut cost,
             BatchStructure::from_ops(ops, TreeCacheKnownPaths::default())
         );
         cost_return_on_error!(
             &mut cost,
-            self.apply_batch_structure(
-                batch_structure,
-                &mut temp_root_leaves,
-                batch_apply_options,
-            )
+            self.apply_batch_structure(batch_structure, batch_apply_options, None)
         );
 
-        cost.add_worst_case_open_root_meta_storage();
-        cost.add_worst_case_save_root_leaves();
+        // nothing for the commit multi batch?
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Expected cost of applying a batch given estimated layouts of the
+    /// subtrees it touches. Subtrees inserted by the batch may be left out
+    /// and are assumed to be empty, while for other subtrees, including the
+    /// root tree, the estimations are required. Updates of references to
+    /// elements changed by the batch are not accounted for.
+    pub fn average_case_operations_for_batch(
+        &self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        estimated_layers: &EstimatedLayerInformationByPath,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        if ops.is_empty() {
+            return Ok(()).wrap_with_cost(cost);
+        }
+
+        // Subtrees inserted by the batch have no layer information to read from
+        let inserted_paths: HashSet<Vec<Vec<u8>>> = ops
+            .iter()
+            .filter(|op| matches!(op.op.inserted_element(), Some(element) if element.is_tree()))
+            .map(|op| {
+                let mut path = op.path.clone();
+                path.push(op.key.clone());
+                path
+            })
+            .collect();
+        for op in ops.iter() {
+            if op.op.has_precondition() {
+                cost_return_on_error_no_add!(
+                    &cost,
+                    add_average_case_get_raw(&mut cost, &op.path, estimated_layers)
+                );
+            }
+            cost_return_on_error_no_add!(
+                &cost,
+                add_average_case_reference_links(
+                    &mut cost,
+                    &op.path,
+                    &op.key,
+                    &op.op,
+                    estimated_layers,
+                    &inserted_paths,
+                )
+            );
+        }
+
+        let batch_structure = cost_return_on_error!(
+            &mut cost,
+            BatchStructure::from_ops(
+                ops,
+                TreeCacheEstimatedPaths {
+                    paths: HashSet::new(),
+                    estimated_layers,
+                }
+            )
+        );
+        self.apply_batch_structure(batch_structure, batch_apply_options, None)
+            .map_ok(|_| ())
+            .add_cost(cost)
+    }
+}
+
+/// Reads made while preparing a batch. They go through batched storage
+/// contexts, so writes already pending in the storage batch, such as the ones
+/// of batches applied before in a [`MultiBatch`], are seen.
+impl<S: Storage> GroveDb<S> {
+    /// Gets an element without following references the way `get_raw` does
+    fn get_raw_batched(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Element, Error> {
+        let mut cost = OperationCost::default();
+
+        if let Some((parent_key, parent_path)) = path.split_last() {
+            let parent_path = parent_path.iter().map(|x| x.as_slice());
+            batch_storage_context_optional_tx!(
+                self.db,
+                parent_path,
+                storage_batch,
+                transaction,
+                storage,
+                {
+                    let parent = cost_return_on_error!(
+                        &mut cost,
+                        Merk::open(storage)
+                            .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
+                    );
+                    match Element::get(&parent, parent_key).unwrap_add_cost(&mut cost) {
+                        Ok(element) if element.is_tree() => {}
+                        Ok(_) | Err(Error::PathKeyNotFound(_)) => {
+                            return Err(Error::PathNotFound("subtree doesn't exist"))
+                                .wrap_with_cost(cost)
+                        }
+                        Err(e) => return Err(e).wrap_with_cost(cost),
+                    }
+                }
+            );
+        }
+        let path = path.iter().map(|x| x.as_slice());
+        batch_storage_context_optional_tx!(self.db, path, storage_batch, transaction, storage, {
+            let subtree = cost_return_on_error!(
+                &mut cost,
+                Merk::open(storage)
+                    .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
+            );
+            Element::get(&subtree, key).add_cost(cost)
+        })
+    }
+
+    /// Gets an element the way `get_raw_optional` does
+    fn get_raw_optional_batched(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Option<Element>, Error> {
+        self.get_raw_batched(path, key, storage_batch, transaction)
+            .map(|result| match result {
+                Ok(element) => Ok(Some(element)),
+                Err(Error::PathKeyNotFound(_) | Error::PathNotFound(_)) => Ok(None),
+                Err(e) => Err(e),
+            })
+    }
+
+    /// Gets keys of elements of the subtree at `path`
+    fn subtree_keys_batched(
+        &self,
+        path: &[Vec<u8>],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<Vec<u8>>, Error> {
+        let mut cost = OperationCost::default();
+
+        let path = path.iter().map(|x| x.as_slice());
+        batch_storage_context_optional_tx!(self.db, path, storage_batch, transaction, storage, {
+            let mut keys = Vec::new();
+            let mut raw_iter = Element::iterator(storage.raw_iter()).unwrap_add_cost(&mut cost);
+            while let Some((key, _)) = cost_return_on_error!(&mut cost, raw_iter.next()) {
+                keys.push(key);
+            }
+            Ok(keys).wrap_with_cost(cost)
+        })
+    }
+
+    /// Tells whether elements of the subtree at `path` may have referrers
+    fn has_referrers_batched(
+        &self,
+        path: &[Vec<u8>],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<bool, Error> {
+        let path = path.iter().map(|x| x.as_slice());
+        batch_storage_context_optional_tx!(self.db, path, storage_batch, transaction, storage, {
+            read_has_referrers(&storage)
+        })
+    }
+
+    /// Gets full paths of references pointing to an element
+    fn get_referrers_batched(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Referrers, Error> {
+        let path = path.iter().map(|x| x.as_slice());
+        batch_storage_context_optional_tx!(self.db, path, storage_batch, transaction, storage, {
+            read_referrers(&storage, key)
+        })
+    }
+
+    /// Finds paths of subtrees nested in the subtree at `path` the way
+    /// `find_subtrees` does
+    fn find_subtrees_batched(
+        &self,
+        path: Vec<Vec<u8>>,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<Vec<Vec<u8>>>, Error> {
+        if let Some(tx) = transaction {
+            find_subtrees_in_storage(path, |path: &[Vec<u8>]| {
+                self.db.get_batch_transactional_storage_context(
+                    path.iter().map(|x| x.as_slice()),
+                    storage_batch,
+                    tx,
+                )
+            })
+        } else {
+            find_subtrees_in_storage(path, |path: &[Vec<u8>]| {
+                self.db
+                    .get_batch_storage_context(path.iter().map(|x| x.as_slice()), storage_batch)
+            })
+        }
+    }
+
+    /// Finds elements of given subtrees the way `find_subtrees_elements` does
+    fn find_subtrees_elements_batched(
+        &self,
+        subtrees_paths: &[Vec<Vec<u8>>],
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<PathKeyElementTrio>, Error> {
+        if let Some(tx) = transaction {
+            find_subtrees_elements_in_storage(subtrees_paths, |path: &[Vec<u8>]| {
+                self.db.get_batch_transactional_storage_context(
+                    path.iter().map(|x| x.as_slice()),
+                    storage_batch,
+                    tx,
+                )
+            })
+        } else {
+            find_subtrees_elements_in_storage(subtrees_paths, |path: &[Vec<u8>]| {
+                self.db
+                    .get_batch_storage_context(path.iter().map(|x| x.as_slice()), storage_batch)
+            })
+        }
+    }
+
+    /// Root hash of GroveDB the way `root_hash` returns it
+    fn root_hash_batched(
+        &self,
+        storage_batch: &StorageBatch,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Option<[u8; 32]>, Error> {
+        let mut cost = OperationCost::default();
+
+        let path = std::iter::empty();
+        batch_storage_context_optional_tx!(self.db, path, storage_batch, transaction, storage, {
+            let subtree = cost_return_on_error!(
+                &mut cost,
+                Merk::open(storage)
+                    .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
+            );
+            if subtree.is_empty_tree() {
+                Ok(None).wrap_with_cost(cost)
+            } else {
+                let root_hash = subtree.root_hash().unwrap_add_cost(&mut cost);
+                Ok(Some(root_hash)).wrap_with_cost(cost)
+            }
+        })
+    }
+}
+
+/// Batches applied one after another and committed to storage in one atomic
+/// write.
+///
+/// Writes of all the batches are accumulated in a single `StorageBatch`.
+/// Everything a batch reads goes through batched storage contexts, so it sees
+/// the effects of the batches applied before it, including root leaves and
+/// subtrees they inserted or deleted. Nothing is visible outside until
+/// `commit` writes the storage batch at once, in the transaction the
+/// `MultiBatch` was started with, if any; dropping the `MultiBatch` without
+/// committing discards everything.
+///
+/// Each batch is applied to a copy of the pending writes, which replaces them
+/// once the batch succeeds, so a failed batch leaves no effects.
+pub struct MultiBatch<'db, S: Storage> {
+    grove_db: &'db GroveDb<S>,
+    storage_batch: StorageBatch,
+    transaction: TransactionArg<'db, 'db, S>,
+}
+
+impl<'db, S: Storage> MultiBatch<'db, S> {
+    /// Applies a batch on top of the batches applied so far. A failed batch
+    /// leaves no effects, the previous ones are kept.
+    pub fn apply_batch(
+        &mut self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+    ) -> CostResult<(), Error> {
+        self.apply_batch_with_cost_limit(ops, batch_apply_options, None)
+    }
+
+    /// Applies a batch the way `apply_batch` does, but stops with
+    /// `CostLimitExceeded` once the cost of the batch goes over `cost_limit`,
+    /// if any
+    pub fn apply_batch_with_cost_limit(
+        &mut self,
+        ops: Vec<GroveDbOp>,
+        batch_apply_options: Option<BatchApplyOptions>,
+        cost_limit: Option<&OperationCost>,
+    ) -> CostResult<(), Error> {
+        if ops.is_empty() {
+            return Ok(()).wrap_with_cost(OperationCost::default());
+        }
+
+        with_total_cost_on_limit(self.grove_db.preview_batch_internal(
+            ops,
+            batch_apply_options,
+            cost_limit,
+            self.storage_batch.clone(),
+            self.transaction,
+        ))
+        .map_ok(|(_, storage_batch)| {
+            self.storage_batch = storage_batch;
+        })
+    }
+
+    /// Adds a write of aux data to be committed along with the batches
+    pub fn put_aux<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let transaction = self.transaction;
+        batch_storage_context_optional_tx!(
+            self.grove_db.db,
+            std::iter::empty(),
+            &self.storage_batch,
+            transaction,
+            aux_storage,
+            {
+                cost_return_on_error_no_add!(
+                    &cost,
+                    aux_storage
+                        .put_aux(key.as_ref(), value)
+                        .map_err(|e| Error::StorageError(e.into()))
+                );
+            }
+        );
+
+        cost.seek_count = 1;
+        cost.storage_written_bytes = key.as_ref().len() as u32 + value.len() as u32;
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Adds a deletion of aux data to be committed along with the batches
+    pub fn delete_aux<K: AsRef<[u8]>>(&self, key: K) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let transaction = self.transaction;
+        batch_storage_context_optional_tx!(
+            self.grove_db.db,
+            std::iter::empty(),
+            &self.storage_batch,
+            transaction,
+            aux_storage,
+            {
+                cost_return_on_error_no_add!(
+                    &cost,
+                    aux_storage
+                        .delete_aux(key.as_ref())
+                        .map_err(|e| Error::StorageError(e.into()))
+                );
+            }
+        );
+
+        cost.seek_count = 1;
+        cost.storage_written_bytes = key.as_ref().len() as u32;
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Gets aux data the way it will be after `commit`
+    pub fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> CostResult<Option<Vec<u8>>, Error> {
+        let mut cost = OperationCost::default();
+
+        let transaction = self.transaction;
+        batch_storage_context_optional_tx!(
+            self.grove_db.db,
+            std::iter::empty(),
+            &self.storage_batch,
+            transaction,
+            aux_storage,
+            {
+                let value = cost_return_on_error_no_add!(
+                    &cost,
+                    aux_storage
+                        .get_aux(key)
+                        .map_err(|e| Error::StorageError(e.into()))
+                );
+
+                cost = OperationCost {
+                    seek_count: 1,
+                    loaded_bytes: value.as_ref().map(|v| v.len()).unwrap_or(0) as u32,
+                    ..Default::default()
+                };
+
+                Ok(value).wrap_with_cost(cost)
+            }
+        )
+    }
+
+    /// Root hash GroveDB will have once the batches applied so far are
+    /// committed
+    pub fn root_hash(&self) -> CostResult<Option<[u8; 32]>, Error> {
+        self.grove_db
+            .root_hash_batched(&self.storage_batch, self.transaction)
+    }
+
+    /// Writes all the batches and aux data at once
+    pub fn commit(self) -> Result<(), Error> {
+        self.grove_db
+            .commit_batch(self.storage_batch, self.transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::RawIterator;
+
+    use super::*;
+    use crate::tests::{make_grovedb, ANOTHER_TEST_LEAF, TEST_LEAF};
+
+    #[test]
+    fn test_batch_validation_ok() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+        let element2 = Element::new_item(b"ayy2".to_vec());
+        let ops = vec![
+            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec()],
+                b"key3".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec()],
+                b"key2".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
+                b"key2".to_vec(),
+                element2.clone(),
+            ),
+        ];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+
+        // visualize_stderr(&db);
+        db.get([], b"key1", None)
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref()], b"key2", None)
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref(), b"key2"], b"key3", None)
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
+            .unwrap()
+            .expect("cannot get element");
+
+        assert_eq!(
+            db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
+                .unwrap()
+                .expect("cannot get element"),
+            element
+        );
+        assert_eq!(
+            db.get([TEST_LEAF, b"key1"], b"key2", None)
+                .unwrap()
+                .expect("cannot get element"),
+            element2
+        );
+    }
+
+    #[test]
+    fn test_batch_validation_ok_on_transaction() {
+        let db = make_grovedb();
+        let tx = db.start_transaction();
+
+        db.insert(vec![], b"keyb", Element::empty_tree(), Some(&tx))
+            .unwrap()
+            .expect("successful root tree leaf insert");
+
+        let element = Element::new_item(b"ayy".to_vec());
+        let element2 = Element::new_item(b"ayy2".to_vec());
+        let ops = vec![
+            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec()],
+                b"key2".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec()],
+                b"key3".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
+                b"key2".to_vec(),
+                element2.clone(),
+            ),
+        ];
+        db.apply_batch(ops, None, Some(&tx))
+            .unwrap()
+            .expect("cannot apply batch");
+        db.get([], b"keyb", None)
+            .unwrap()
+            .expect_err("we should not get an element");
+        db.get([], b"keyb", Some(&tx))
+            .unwrap()
+            .expect("we should get an element");
+
+        db.get([], b"key1", None)
+            .unwrap()
+            .expect_err("we should not get an element");
+        db.get([], b"key1", Some(&tx))
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref()], b"key2", Some(&tx))
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref(), b"key2"], b"key3", Some(&tx))
+            .unwrap()
+            .expect("cannot get element");
+        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", Some(&tx))
+            .unwrap()
+            .expect("cannot get element");
+
+        assert_eq!(
+            db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", Some(&tx))
+                .unwrap()
+                .expect("cannot get element"),
+            element
+        );
+        assert_eq!(
+            db.get([TEST_LEAF, b"key1"], b"key2", Some(&tx))
+                .unwrap()
+                .expect("cannot get element"),
+            element2
+        );
+    }
+
+    #[test]
+    fn test_batch_validation_broken_chain() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+        let ops = vec![
+            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec()],
+                b"key2".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+        assert!(db.get([b"key1".as_ref()], b"key2", None).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_batch_validation_broken_chain_aborts_whole_batch() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
+                b"key2".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec()],
+                b"key2".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+        assert!(db.get([b"key1".as_ref()], b"key2", None).unwrap().is_err());
+        assert!(db
+            .get([TEST_LEAF, b"key1"], b"key2", None)
+            .unwrap()
+            .is_err(),);
+    }
+
+    #[test]
+    fn test_batch_validation_deletion_brokes_chain() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+
+        db.insert([], b"key1", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert a subtree");
+        db.insert([b"key1".as_ref()], b"key2", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert a subtree");
+
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec()],
+                b"key3".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::delete(vec![b"key1".to_vec()], b"key2".to_vec()),
+        ];
+        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_batch_validation_insertion_under_deleted_tree() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+        let ops = vec![
+            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
+                b"key4".to_vec(),
+                element.clone(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec(), b"key2".to_vec()],
+                b"key3".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![b"key1".to_vec()],
+                b"key2".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::delete(vec![b"key1".to_vec()], b"key2".to_vec()),
+        ];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect_err("insertion of element under a deleted tree should not be allowed");
+        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
+            .unwrap()
+            .expect_err("nothing should have been inserted");
+    }
+
+    #[test]
+    fn test_batch_validation_insert_into_existing_tree() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+
+        db.insert([TEST_LEAF], b"invalid", element.clone(), None)
+            .unwrap()
+            .expect("cannot insert value");
+        db.insert([TEST_LEAF], b"valid", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert value");
+
+        // Insertion into scalar is invalid
+        let ops = vec![GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"invalid".to_vec()],
+            b"key1".to_vec(),
+            element.clone(),
+        )];
+        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+
+        // Insertion into a tree is correct
+        let ops = vec![GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"valid".to_vec()],
+            b"key1".to_vec(),
+            element.clone(),
+        )];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+        assert_eq!(
+            db.get([TEST_LEAF, b"valid"], b"key1", None)
+                .unwrap()
+                .expect("cannot get element"),
+            element
+        );
+    }
+
+    #[test]
+    fn test_batch_validation_nested_subtree_overwrite() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+        let element2 = Element::new_item(b"ayy2".to_vec());
+        db.insert([TEST_LEAF], b"key_subtree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert a subtree");
+        db.insert([TEST_LEAF, b"key_subtree"], b"key2", element, None)
+            .unwrap()
+            .expect("cannot insert an item");
+
+        // TEST_LEAF can not be overwritten
+        let ops = vec![
+            GroveDbOp::insert(vec![], TEST_LEAF.to_vec(), element2.clone()),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"key_subtree".to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db
+            .apply_batch(
+                ops,
+                Some(BatchApplyOptions {
+                    validate_tree_insertion_does_not_override: true
+                }),
+                None
+            )
+            .unwrap()
+            .is_err());
+
+        // TEST_LEAF will be deleted so you can not insert underneath it
+        let ops = vec![
+            GroveDbOp::delete(vec![], TEST_LEAF.to_vec()),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+
+        // TEST_LEAF will be deleted so you can not insert underneath it
+        // We are testing with the batch apply option
+        // validate_tree_insertion_does_not_override set to true
+        let ops = vec![
+            GroveDbOp::delete(vec![], TEST_LEAF.to_vec()),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db
+            .apply_batch(
+                ops,
+                Some(BatchApplyOptions {
+                    validate_tree_insertion_does_not_override: true
+                }),
+                None
+            )
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_validation_root_leaf_removal() {
+        let db = make_grovedb();
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![],
+                TEST_LEAF.to_vec(),
+                Element::new_item(b"ayy".to_vec()),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::empty_tree(),
+            ),
+        ];
+        assert!(db
+            .apply_batch(
+                ops,
+                Some(BatchApplyOptions {
+                    validate_tree_insertion_does_not_override: true
+                }),
+                None
+            )
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_root_leaf_deletion() {
+        let db = make_grovedb();
+        let root_hash = db.root_hash(None).unwrap().unwrap();
+        let ops = vec![GroveDbOp::delete(vec![], TEST_LEAF.to_vec())];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+        assert!(matches!(
+            db.get([], TEST_LEAF, None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert!(db.get([], ANOTHER_TEST_LEAF, None).unwrap().is_ok());
+        assert_ne!(root_hash, db.root_hash(None).unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_batch_root_leaf_deletion_clears_nested_subtrees() {
+        let db = make_grovedb();
+        db.insert([ANOTHER_TEST_LEAF], b"tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert a subtree");
+        db.insert(
+            [ANOTHER_TEST_LEAF, b"tree"],
+            b"key",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("cannot insert an item");
+        db.insert(
+            [ANOTHER_TEST_LEAF],
+            b"key",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("cannot insert an item");
+
+        let ops = vec![GroveDbOp::delete(vec![], ANOTHER_TEST_LEAF.to_vec())];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+
+        let ops = vec![GroveDbOp::insert(
+            vec![],
+            ANOTHER_TEST_LEAF.to_vec(),
+            Element::empty_tree(),
+        )];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+        assert!(db
+            .is_empty_tree([ANOTHER_TEST_LEAF], None)
+            .unwrap()
+            .expect("root leaf should exist"));
+        assert!(matches!(
+            db.get([ANOTHER_TEST_LEAF], b"key", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        let grove_db: &GroveDb = &db;
+        let storage = grove_db
+            .db
+            .get_storage_context([ANOTHER_TEST_LEAF, b"tree"]);
+        let mut iter = storage.raw_iter();
+        iter.seek_to_first();
+        assert!(!iter.valid(), "nested subtree storage should be cleared");
+        assert_eq!(
+            db.root_hash(None).unwrap().unwrap(),
+            make_grovedb().root_hash(None).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merk_data_is_deleted() {
+        let db = make_grovedb();
+        let element = Element::new_item(b"ayy".to_vec());
+
+        db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert a subtree");
+        db.insert([TEST_LEAF, b"key1"], b"key2", element.clone(), None)
+            .unwrap()
+            .expect("cannot insert an item");
+        let ops = vec![GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"key1".to_vec(),
+            Element::new_item(b"ayy2".to_vec()),
+        )];
 
-        // nothing for the commit multi batch?
-        Ok(()).wrap_with_cost(cost)
+        assert_eq!(
+            db.get([TEST_LEAF, b"key1"], b"key2", None)
+                .unwrap()
+                .expect("cannot get item"),
+            element
+        );
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+        assert!(db
+            .get([TEST_LEAF, b"key1"], b"key2", None)
+            .unwrap()
+            .is_err());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::tests::{make_grovedb, ANOTHER_TEST_LEAF, TEST_LEAF};
 
     #[test]
-    fn test_batch_validation_ok() {
+    fn test_multi_tree_insertion_deletion_with_propagation_no_tx() {
         let db = make_grovedb();
+        db.insert([], b"key1", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert root leaf");
+        db.insert([], b"key2", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert root leaf");
+        db.insert([ANOTHER_TEST_LEAF], b"key1", Element::empty_tree(), None)
+            .unwrap()
+            .expect("cannot insert root leaf");
+
+        let hash = db
+            .root_hash(None)
+            .unwrap()
+            .ok()
+            .flatten()
+            .expect("cannot get root hash");
         let element = Element::new_item(b"ayy".to_vec());
         let element2 = Element::new_item(b"ayy2".to_vec());
+
         let ops = vec![
-            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
             GroveDbOp::insert(
                 vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
                 b"key4".to_vec(),
@@ -690,34 +2810,14 @@ mod tests {
                 b"key2".to_vec(),
                 Element::empty_tree(),
             ),
-            GroveDbOp::insert(
-                vec![TEST_LEAF.to_vec()],
-                b"key1".to_vec(),
-                Element::empty_tree(),
-            ),
-            GroveDbOp::insert(
-                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
-                b"key2".to_vec(),
-                element2.clone(),
-            ),
+            GroveDbOp::insert(vec![TEST_LEAF.to_vec()], b"key".to_vec(), element2.clone()),
+            GroveDbOp::delete(vec![ANOTHER_TEST_LEAF.to_vec()], b"key1".to_vec()),
         ];
         db.apply_batch(ops, None, None)
             .unwrap()
             .expect("cannot apply batch");
 
-        // visualize_stderr(&db);
-        db.get([], b"key1", None)
-            .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref()], b"key2", None)
-            .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref(), b"key2"], b"key3", None)
-            .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
-            .unwrap()
-            .expect("cannot get element");
+        assert!(db.get([ANOTHER_TEST_LEAF], b"key1", None).unwrap().is_err());
 
         assert_eq!(
             db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
@@ -726,523 +2826,1346 @@ mod tests {
             element
         );
         assert_eq!(
-            db.get([TEST_LEAF, b"key1"], b"key2", None)
+            db.get([TEST_LEAF], b"key", None)
                 .unwrap()
                 .expect("cannot get element"),
             element2
         );
+        assert_ne!(
+            db.root_hash(None)
+                .unwrap()
+                .ok()
+                .flatten()
+                .expect("cannot get root hash"),
+            hash
+        );
+        for leaf in [TEST_LEAF, ANOTHER_TEST_LEAF, b"key1", b"key2"] {
+            assert!(matches!(
+                db.get([], leaf, None).unwrap(),
+                Ok(Element::Tree(..))
+            ));
+        }
     }
 
     #[test]
-    fn test_batch_validation_ok_on_transaction() {
+    fn test_nested_batch_insertion_corrupts_state() {
         let db = make_grovedb();
-        let tx = db.start_transaction();
+        let full_path = vec![
+            b"leaf1".to_vec(),
+            b"sub1".to_vec(),
+            b"sub2".to_vec(),
+            b"sub3".to_vec(),
+            b"sub4".to_vec(),
+            b"sub5".to_vec(),
+        ];
+        let mut acc_path: Vec<Vec<u8>> = vec![];
+        for p in full_path.into_iter() {
+            db.insert(
+                acc_path.iter().map(|x| x.as_slice()),
+                &p,
+                Element::empty_tree(),
+                None,
+            )
+            .unwrap()
+            .expect("expected to insert");
+            acc_path.push(p);
+        }
 
-        db.insert(vec![], b"keyb", Element::empty_tree(), Some(&tx))
+        let element = Element::new_item(b"ayy".to_vec());
+        let batch = vec![GroveDbOp::insert(
+            acc_path.clone(),
+            b"key".to_vec(),
+            element.clone(),
+        )];
+        db.apply_batch(batch, None, None)
             .unwrap()
-            .expect("successful root tree leaf insert");
+            .expect("cannot apply batch");
+
+        let batch = vec![GroveDbOp::insert(
+            acc_path,
+            b"key".to_vec(),
+            element.clone(),
+        )];
+        db.apply_batch(batch, None, None)
+            .unwrap()
+            .expect("cannot apply same batch twice");
+    }
+
+    #[test]
+    fn test_apply_sorted_pre_validated_batch_propagation() {
+        let db = make_grovedb();
+        let full_path = vec![b"leaf1".to_vec(), b"sub1".to_vec()];
+        let mut acc_path: Vec<Vec<u8>> = vec![];
+        for p in full_path.into_iter() {
+            db.insert(
+                acc_path.iter().map(|x| x.as_slice()),
+                &p,
+                Element::empty_tree(),
+                None,
+            )
+            .unwrap()
+            .expect("expected to insert");
+            acc_path.push(p);
+        }
+
+        let root_hash = db.root_hash(None).unwrap().unwrap();
 
         let element = Element::new_item(b"ayy".to_vec());
-        let element2 = Element::new_item(b"ayy2".to_vec());
+        let batch = vec![GroveDbOp::insert(
+            acc_path.clone(),
+            b"key".to_vec(),
+            element.clone(),
+        )];
+        db.apply_batch(batch, None, None)
+            .unwrap()
+            .expect("cannot apply batch");
+
+        assert_ne!(db.root_hash(None).unwrap().unwrap(), root_hash);
+    }
+
+    #[test]
+    fn test_batch_deletes_node_rewritten_by_earlier_operation() {
+        let db = make_grovedb();
+        let sequential_db = make_grovedb();
+        for db in [&db, &sequential_db] {
+            for key in [b"key1", b"key2", b"key3"] {
+                db.insert([TEST_LEAF], key, Element::new_item(b"ayy".to_vec()), None)
+                    .unwrap()
+                    .expect("successful insert");
+            }
+        }
+
+        // Operations on a subtree are applied one after another, each Merk
+        // commit going into the same storage batch, so the node of `key2`
+        // rewritten by the first operation is deleted by the second one
         let ops = vec![
-            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
             GroveDbOp::insert(
-                vec![b"key1".to_vec()],
-                b"key2".to_vec(),
-                Element::empty_tree(),
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::new_item(b"ayy2".to_vec()),
             ),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec()],
+            GroveDbOp::delete(vec![TEST_LEAF.to_vec()], b"key2".to_vec()),
+        ];
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("successful batch apply");
+
+        sequential_db
+            .insert(
+                [TEST_LEAF],
+                b"key1",
+                Element::new_item(b"ayy2".to_vec()),
+                None,
+            )
+            .unwrap()
+            .expect("successful insert");
+        sequential_db
+            .delete([TEST_LEAF], b"key2", None)
+            .unwrap()
+            .expect("successful delete");
+
+        assert!(matches!(
+            db.get([TEST_LEAF], b"key2", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert_eq!(
+            db.root_hash(None).unwrap().unwrap(),
+            sequential_db.root_hash(None).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_batch_conditional_operations_pass() {
+        let db = make_grovedb();
+        db.insert(
+            [TEST_LEAF],
+            b"key1",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful insert");
+        db.insert(
+            [TEST_LEAF],
+            b"key2",
+            Element::new_item(b"ayy2".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful insert");
+        db.insert(
+            [TEST_LEAF],
+            b"key4",
+            Element::new_item(b"ayy6".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful insert");
+
+        let ops = vec![
+            GroveDbOp::insert_if_absent(
+                vec![TEST_LEAF.to_vec()],
                 b"key3".to_vec(),
+                Element::new_item(b"ayy3".to_vec()),
+            ),
+            GroveDbOp::replace_if_present(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::new_item(b"ayy4".to_vec()),
+            ),
+            GroveDbOp::delete_if_exists(vec![TEST_LEAF.to_vec()], b"key2".to_vec()),
+            GroveDbOp::assert_equals(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                vec![],
                 Element::empty_tree(),
             ),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
+            GroveDbOp::insert_if_absent(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                b"tree".to_vec(),
+                Element::empty_tree(),
             ),
             GroveDbOp::insert(
+                vec![ANOTHER_TEST_LEAF.to_vec(), b"tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"ayy5".to_vec()),
+            ),
+        ];
+        // The assertion on a missing key is expected to fail, the rest to pass
+        assert!(matches!(
+            db.apply_batch(ops.clone(), None, None).unwrap(),
+            Err(Error::BatchPreconditionFailed(op)) if *op == ops[3]
+        ));
+        let ops = ops
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, op)| (i != 3).then_some(op))
+            .chain(std::iter::once(GroveDbOp::assert_equals(
+                vec![TEST_LEAF.to_vec()],
+                b"key4".to_vec(),
+                Element::new_item(b"ayy6".to_vec()),
+            )))
+            .collect::<Vec<_>>();
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("successful batch apply");
+
+        assert_eq!(
+            db.get([TEST_LEAF], b"key1", None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"ayy4".to_vec())
+        );
+        assert!(matches!(
+            db.get([TEST_LEAF], b"key2", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert_eq!(
+            db.get([TEST_LEAF], b"key3", None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"ayy3".to_vec())
+        );
+        assert_eq!(
+            db.get([ANOTHER_TEST_LEAF, b"tree"], b"key", None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"ayy5".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_batch_conditional_operations_reject_whole_batch() {
+        let db = make_grovedb();
+        db.insert(
+            [TEST_LEAF],
+            b"key1",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful insert");
+        let root_hash = db.root_hash(None).unwrap().unwrap();
+
+        let insert = GroveDbOp::insert(
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            b"key".to_vec(),
+            Element::new_item(b"ayy2".to_vec()),
+        );
+        let failing_ops = vec![
+            GroveDbOp::insert_if_absent(
                 vec![TEST_LEAF.to_vec()],
                 b"key1".to_vec(),
-                Element::empty_tree(),
+                Element::new_item(b"ayy3".to_vec()),
             ),
-            GroveDbOp::insert(
-                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
+            GroveDbOp::replace_if_present(
+                vec![TEST_LEAF.to_vec()],
                 b"key2".to_vec(),
-                element2.clone(),
+                Element::new_item(b"ayy3".to_vec()),
+            ),
+            GroveDbOp::delete_if_exists(vec![TEST_LEAF.to_vec()], b"key2".to_vec()),
+            GroveDbOp::assert_equals(
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
+                Element::new_item(b"ayy3".to_vec()),
             ),
         ];
-        db.apply_batch(ops, None, Some(&tx))
-            .unwrap()
-            .expect("cannot apply batch");
-        db.get([], b"keyb", None)
-            .unwrap()
-            .expect_err("we should not get an element");
-        db.get([], b"keyb", Some(&tx))
-            .unwrap()
-            .expect("we should get an element");
+        for failing_op in failing_ops {
+            let ops = vec![insert.clone(), failing_op.clone()];
+            assert!(matches!(
+                db.apply_batch(ops, None, None).unwrap(),
+                Err(Error::BatchPreconditionFailed(op)) if *op == failing_op
+            ));
+            assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+            assert!(matches!(
+                db.get([ANOTHER_TEST_LEAF], b"key", None).unwrap(),
+                Err(Error::PathKeyNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_batch_conditional_operation_with_write_on_same_key_is_rejected() {
+        let db = make_grovedb();
+        db.insert(
+            [TEST_LEAF],
+            b"key1",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful insert");
+        let root_hash = db.root_hash(None).unwrap().unwrap();
+
+        let guard = GroveDbOp::assert_equals(
+            vec![TEST_LEAF.to_vec()],
+            b"key1".to_vec(),
+            Element::new_item(b"ayy".to_vec()),
+        );
+        let write = GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"key1".to_vec(),
+            Element::new_item(b"ayy2".to_vec()),
+        );
+        // Neither the guard nor the write may replace the other, whatever
+        // their order is
+        for ops in [
+            vec![guard.clone(), write.clone()],
+            vec![write.clone(), guard.clone()],
+            vec![
+                write,
+                GroveDbOp::delete_if_exists(vec![TEST_LEAF.to_vec()], b"key1".to_vec()),
+            ],
+        ] {
+            assert!(matches!(
+                db.apply_batch(ops, None, None).unwrap(),
+                Err(Error::InvalidBatchOperation(_))
+            ));
+            assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+        }
+        assert_eq!(
+            db.get([TEST_LEAF], b"key1", None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"ayy".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_multi_batch_commits_batches_at_once() {
+        let db = make_grovedb();
+        let root_hash = db.root_hash(None).unwrap().unwrap();
+        let batches = vec![
+            vec![GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"tree".to_vec(),
+                Element::empty_tree(),
+            )],
+            vec![
+                GroveDbOp::insert_if_absent(
+                    vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                    b"key".to_vec(),
+                    Element::new_item(b"ayy".to_vec()),
+                ),
+                GroveDbOp::insert(
+                    vec![ANOTHER_TEST_LEAF.to_vec()],
+                    b"reference".to_vec(),
+                    Element::new_reference(vec![
+                        TEST_LEAF.to_vec(),
+                        b"tree".to_vec(),
+                        b"key".to_vec(),
+                    ]),
+                ),
+            ],
+            vec![GroveDbOp::replace_if_present(
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"ayy2".to_vec()),
+            )],
+        ];
 
-        db.get([], b"key1", None)
+        let mut multi_batch = db.start_multi_batch(None);
+        for ops in batches.iter() {
+            multi_batch
+                .apply_batch(ops.clone(), None)
+                .unwrap()
+                .expect("successful batch apply");
+        }
+        // A failed batch doesn't affect the ones applied before it
+        assert!(matches!(
+            multi_batch
+                .apply_batch(batches[1][..1].to_vec(), None)
+                .unwrap(),
+            Err(Error::BatchPreconditionFailed(_))
+        ));
+        multi_batch
+            .put_aux(b"block", b"height")
             .unwrap()
-            .expect_err("we should not get an element");
-        db.get([], b"key1", Some(&tx))
+            .expect("successful aux put");
+
+        // Nothing is written until commit
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+        assert!(matches!(
+            db.get([TEST_LEAF], b"tree", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert_eq!(db.get_aux(b"block", None).unwrap().unwrap(), None);
+        assert_eq!(
+            multi_batch.get_aux(b"block").unwrap().unwrap(),
+            Some(b"height".to_vec())
+        );
+        let pending_root_hash = multi_batch.root_hash().unwrap().unwrap();
+        multi_batch.commit().expect("successful commit");
+
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), pending_root_hash);
+        assert_eq!(
+            db.get_aux(b"block", None).unwrap().unwrap(),
+            Some(b"height".to_vec())
+        );
+        assert_eq!(
+            db.get([ANOTHER_TEST_LEAF], b"reference", None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"ayy2".to_vec())
+        );
+        let sequential = make_grovedb();
+        for ops in batches {
+            sequential
+                .apply_batch(ops, None, None)
+                .unwrap()
+                .expect("successful batch apply");
+        }
+        assert_eq!(
+            sequential.root_hash(None).unwrap().unwrap(),
+            pending_root_hash
+        );
+
+        // Dropped batches are discarded
+        let mut multi_batch = db.start_multi_batch(None);
+        multi_batch
+            .apply_batch(
+                vec![GroveDbOp::delete_tree(
+                    vec![TEST_LEAF.to_vec()],
+                    b"tree".to_vec(),
+                )],
+                None,
+            )
             .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref()], b"key2", Some(&tx))
+            .expect("successful batch apply");
+        drop(multi_batch);
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), pending_root_hash);
+    }
+
+    #[test]
+    fn test_multi_batch_in_transaction() {
+        let db = make_grovedb();
+        let root_hash = db.root_hash(None).unwrap().unwrap();
+        let transaction = db.start_transaction();
+        db.insert(
+            [TEST_LEAF],
+            b"tree",
+            Element::empty_tree(),
+            Some(&transaction),
+        )
+        .unwrap()
+        .expect("successful insert");
+
+        // Batches see writes of the transaction as well as of each other, a
+        // subtree inserted by one of them included
+        let mut multi_batch = db.start_multi_batch(Some(&transaction));
+        multi_batch
+            .apply_batch(
+                vec![
+                    GroveDbOp::insert(
+                        vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                        b"subtree".to_vec(),
+                        Element::empty_tree(),
+                    ),
+                    GroveDbOp::insert(
+                        vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                        b"key".to_vec(),
+                        Element::new_item(b"ayy".to_vec()),
+                    ),
+                ],
+                None,
+            )
             .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref(), b"key2"], b"key3", Some(&tx))
+            .expect("successful batch apply");
+        multi_batch
+            .apply_batch(
+                vec![GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec(), b"tree".to_vec(), b"subtree".to_vec()],
+                    b"key".to_vec(),
+                    Element::new_item(b"ayy".to_vec()),
+                )],
+                None,
+            )
             .unwrap()
-            .expect("cannot get element");
-        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", Some(&tx))
+            .expect("successful batch apply");
+        multi_batch
+            .apply_batch(
+                vec![GroveDbOp::delete_tree(
+                    vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                    b"subtree".to_vec(),
+                )],
+                None,
+            )
             .unwrap()
-            .expect("cannot get element");
+            .expect("successful batch apply");
+        let pending_root_hash = multi_batch.root_hash().unwrap().unwrap();
 
+        // Nothing is written into the transaction until commit
+        assert!(matches!(
+            db.get([TEST_LEAF, b"tree"], b"key", Some(&transaction))
+                .unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        multi_batch.commit().expect("successful commit");
         assert_eq!(
-            db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", Some(&tx))
-                .unwrap()
-                .expect("cannot get element"),
-            element
+            db.root_hash(Some(&transaction)).unwrap().unwrap(),
+            pending_root_hash
         );
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+
+        db.commit_transaction(transaction)
+            .expect("successful commit");
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), pending_root_hash);
         assert_eq!(
-            db.get([TEST_LEAF, b"key1"], b"key2", Some(&tx))
+            db.get([TEST_LEAF, b"tree"], b"key", None)
                 .unwrap()
-                .expect("cannot get element"),
-            element2
+                .expect("successful get"),
+            Element::new_item(b"ayy".to_vec())
         );
+        assert!(matches!(
+            db.get([TEST_LEAF, b"tree"], b"subtree", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        // Nested storage of the deleted subtree, written by an earlier batch,
+        // is cleared
+        let storage = db
+            .db
+            .get_storage_context([TEST_LEAF, b"tree".as_ref(), b"subtree"]);
+        let mut iter = storage.raw_iter();
+        iter.seek_to_first();
+        assert!(!iter.valid());
     }
 
     #[test]
-    fn test_batch_validation_broken_chain() {
+    fn test_batch_delete_of_tree_emptied_by_batch() {
         let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
+        db.insert([TEST_LEAF], b"tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"tree"],
+            b"item",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+
         let ops = vec![
-            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
-            ),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec()],
-                b"key2".to_vec(),
-                Element::empty_tree(),
-            ),
+            GroveDbOp::delete(vec![TEST_LEAF.to_vec()], b"tree".to_vec()),
+            GroveDbOp::delete(vec![TEST_LEAF.to_vec(), b"tree".to_vec()], b"item".to_vec()),
         ];
-        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
-        assert!(db.get([b"key1".as_ref()], b"key2", None).unwrap().is_err());
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("successful batch apply");
+        assert!(matches!(
+            db.get([TEST_LEAF], b"tree", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert_eq!(
+            db.root_hash(None).unwrap().unwrap(),
+            make_grovedb().root_hash(None).unwrap().unwrap()
+        );
     }
 
     #[test]
-    fn test_batch_validation_broken_chain_aborts_whole_batch() {
+    fn test_batch_delete_tree_clears_nested_subtrees() {
         let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
         let ops = vec![
             GroveDbOp::insert(
                 vec![TEST_LEAF.to_vec()],
-                b"key1".to_vec(),
+                b"tree".to_vec(),
                 Element::empty_tree(),
             ),
             GroveDbOp::insert(
-                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
-                b"key2".to_vec(),
-                element.clone(),
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                b"inner".to_vec(),
+                Element::empty_tree(),
             ),
-            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
             GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                b"item".to_vec(),
+                Element::new_item(b"ayy".to_vec()),
             ),
             GroveDbOp::insert(
-                vec![b"key1".to_vec()],
-                b"key2".to_vec(),
-                Element::empty_tree(),
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec(), b"inner".to_vec()],
+                b"item".to_vec(),
+                Element::new_item(b"ayy2".to_vec()),
             ),
         ];
-        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
-        assert!(db.get([b"key1".as_ref()], b"key2", None).unwrap().is_err());
-        assert!(db
-            .get([TEST_LEAF, b"key1"], b"key2", None)
-            .unwrap()
-            .is_err(),);
-    }
-
-    #[test]
-    fn test_batch_validation_deletion_brokes_chain() {
-        let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
-
-        db.insert([], b"key1", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert a subtree");
-        db.insert([b"key1".as_ref()], b"key2", Element::empty_tree(), None)
+        db.apply_batch(ops, None, None)
             .unwrap()
-            .expect("cannot insert a subtree");
+            .expect("successful batch apply");
+        // References from outside into the tree and from the tree outside
+        db.insert(
+            [ANOTHER_TEST_LEAF],
+            b"item",
+            Element::new_item(b"ayy4".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+        db.insert(
+            [ANOTHER_TEST_LEAF],
+            b"ref",
+            Element::new_reference(vec![
+                TEST_LEAF.to_vec(),
+                b"tree".to_vec(),
+                b"inner".to_vec(),
+                b"item".to_vec(),
+            ]),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+        db.insert(
+            [TEST_LEAF, b"tree", b"inner"],
+            b"ref",
+            Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"item".to_vec()]),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
 
+        // Modifications of the deleted tree are rejected
         let ops = vec![
+            GroveDbOp::delete_tree(vec![TEST_LEAF.to_vec()], b"tree".to_vec()),
             GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
-            ),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec()],
-                b"key3".to_vec(),
-                Element::empty_tree(),
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec(), b"inner".to_vec()],
+                b"item2".to_vec(),
+                Element::new_item(b"ayy3".to_vec()),
             ),
-            GroveDbOp::delete(vec![b"key1".to_vec()], b"key2".to_vec()),
         ];
-        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+        assert!(matches!(
+            db.apply_batch(ops, None, None).unwrap(),
+            Err(Error::InvalidBatchOperation(_))
+        ));
+        assert!(matches!(
+            db.apply_batch(
+                vec![GroveDbOp::delete_tree(
+                    vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                    b"item".to_vec()
+                )],
+                None,
+                None
+            )
+            .unwrap(),
+            Err(Error::InvalidBatchOperation(_))
+        ));
+        // A plain deletion of a non empty tree is rejected as well
+        assert!(matches!(
+            db.apply_batch(
+                vec![GroveDbOp::delete(
+                    vec![TEST_LEAF.to_vec()],
+                    b"tree".to_vec()
+                )],
+                None,
+                None
+            )
+            .unwrap(),
+            Err(Error::InvalidBatchOperation(_))
+        ));
+
+        db.apply_batch(
+            vec![GroveDbOp::delete_tree(
+                vec![TEST_LEAF.to_vec()],
+                b"tree".to_vec(),
+            )],
+            None,
+            None,
+        )
+        .unwrap()
+        .expect("successful tree deletion");
+        assert!(matches!(
+            db.get([TEST_LEAF], b"tree", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        let grove_db: &GroveDb = &db;
+        for path in [vec![TEST_LEAF, b"tree"], vec![TEST_LEAF, b"tree", b"inner"]] {
+            let storage = grove_db.db.get_storage_context(path);
+            let mut iter = storage.raw_iter();
+            iter.seek_to_first();
+            assert!(!iter.valid(), "subtree storage should be cleared");
+        }
+
+        // The reference into the deleted tree is deleted with it, while the
+        // element referenced from the tree doesn't keep a back-link to it
+        assert!(matches!(
+            db.get_raw([ANOTHER_TEST_LEAF], b"ref", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        assert!(db
+            .get_referrers(&[ANOTHER_TEST_LEAF.to_vec()], b"item", None)
+            .unwrap()
+            .expect("successful referrers get")
+            .is_empty());
+        db.delete([ANOTHER_TEST_LEAF], b"item", None)
+            .unwrap()
+            .expect("successful delete");
+
+        // The tree is created again as an empty one
+        db.insert([TEST_LEAF], b"tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful tree insert");
+        assert!(db
+            .is_empty_tree([TEST_LEAF, b"tree"], None)
+            .unwrap()
+            .expect("tree should exist"));
+        let fresh_db = make_grovedb();
+        fresh_db
+            .insert([TEST_LEAF], b"tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful tree insert");
+        assert_eq!(
+            db.root_hash(None).unwrap().unwrap(),
+            fresh_db.root_hash(None).unwrap().unwrap()
+        );
     }
 
     #[test]
-    fn test_batch_validation_insertion_under_deleted_tree() {
+    fn test_preview_batch_returns_root_hash_without_writing() {
         let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
+        let tx = db.start_transaction();
+        let root_hash = db.root_hash(None).unwrap().unwrap().unwrap();
         let ops = vec![
-            GroveDbOp::insert(vec![], b"key1".to_vec(), Element::empty_tree()),
-            GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
-            ),
             GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec()],
-                b"key3".to_vec(),
+                vec![TEST_LEAF.to_vec()],
+                b"key1".to_vec(),
                 Element::empty_tree(),
             ),
             GroveDbOp::insert(
-                vec![b"key1".to_vec()],
+                vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
                 b"key2".to_vec(),
-                Element::empty_tree(),
+                Element::new_item(b"ayy".to_vec()),
             ),
-            GroveDbOp::delete(vec![b"key1".to_vec()], b"key2".to_vec()),
         ];
-        db.apply_batch(ops, None, None)
+
+        // Dropped preview leaves no trace
+        let preview = db.preview_batch(ops.clone(), None, None);
+        assert!(preview.cost.seek_count > 0);
+        let (preview_root_hash, _) = preview.value.expect("successful preview");
+        assert_ne!(preview_root_hash, root_hash);
+        assert_eq!(db.root_hash(None).unwrap().unwrap().unwrap(), root_hash);
+        assert!(db.get([TEST_LEAF], b"key1", None).unwrap().is_err());
+
+        // Committed preview matches the applied batch
+        let (tx_root_hash, storage_batch) = db
+            .preview_batch(ops.clone(), None, Some(&tx))
             .unwrap()
-            .expect_err("insertion of element under a deleted tree should not be allowed");
-        db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
+            .expect("successful preview");
+        assert_eq!(tx_root_hash, preview_root_hash);
+        db.commit_batch(storage_batch, Some(&tx))
+            .expect("successful commit");
+        assert_eq!(
+            db.root_hash(Some(&tx)).unwrap().unwrap().unwrap(),
+            preview_root_hash
+        );
+        assert_eq!(db.root_hash(None).unwrap().unwrap().unwrap(), root_hash);
+        db.get([TEST_LEAF, b"key1"], b"key2", Some(&tx))
             .unwrap()
-            .expect_err("nothing should have been inserted");
+            .expect("element committed within the transaction");
+
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("successful batch apply");
+        assert_eq!(
+            db.root_hash(None).unwrap().unwrap().unwrap(),
+            preview_root_hash
+        );
     }
 
     #[test]
-    fn test_batch_validation_insert_into_existing_tree() {
-        let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
+    fn test_batch_on_many_sibling_subtrees_matches_sequential_application() {
+        let parallel_db = make_grovedb();
+        let sequential_db = make_grovedb();
 
-        db.insert([TEST_LEAF], b"invalid", element.clone(), None)
+        let subtree_key = |i: u8| format!("subtree{}", i).into_bytes();
+        let subtree_path = |i: u8| vec![TEST_LEAF.to_vec(), subtree_key(i)];
+        let mut ops = Vec::new();
+        for i in 0..32u8 {
+            ops.push(GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                subtree_key(i),
+                Element::empty_tree(),
+            ));
+            ops.push(GroveDbOp::insert(
+                subtree_path(i),
+                b"item".to_vec(),
+                Element::new_item(vec![i]),
+            ));
+            // References into sibling subtrees inserted by the same batch
+            let mut reference_path = subtree_path((i + 1) % 32);
+            reference_path.push(b"item".to_vec());
+            ops.push(GroveDbOp::insert(
+                subtree_path(i),
+                b"ref".to_vec(),
+                Element::new_reference(reference_path),
+            ));
+        }
+        parallel_db
+            .apply_batch(ops.clone(), None, None)
             .unwrap()
-            .expect("cannot insert value");
-        db.insert([TEST_LEAF], b"valid", Element::empty_tree(), None)
+            .expect("successful batch apply");
+        let transaction = sequential_db.start_transaction();
+        sequential_db
+            .apply_batch(ops, None, Some(&transaction))
             .unwrap()
-            .expect("cannot insert value");
-
-        // Insertion into scalar is invalid
-        let ops = vec![GroveDbOp::insert(
-            vec![TEST_LEAF.to_vec(), b"invalid".to_vec()],
-            b"key1".to_vec(),
-            element.clone(),
-        )];
-        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
+            .expect("successful batch apply");
+        sequential_db
+            .commit_transaction(transaction)
+            .expect("successful commit");
+        assert_eq!(
+            parallel_db.root_hash(None).unwrap().unwrap(),
+            sequential_db.root_hash(None).unwrap().unwrap()
+        );
 
-        // Insertion into a tree is correct
-        let ops = vec![GroveDbOp::insert(
-            vec![TEST_LEAF.to_vec(), b"valid".to_vec()],
-            b"key1".to_vec(),
-            element.clone(),
-        )];
-        db.apply_batch(ops, None, None)
+        // Subtrees which already exist
+        let mut ops = Vec::new();
+        for i in 0..32u8 {
+            ops.push(GroveDbOp::insert(
+                subtree_path(i),
+                b"item".to_vec(),
+                Element::new_item(vec![i, i]),
+            ));
+            if i % 2 == 0 {
+                ops.push(GroveDbOp::delete(subtree_path(i), b"ref".to_vec()));
+            }
+        }
+        parallel_db
+            .apply_batch(ops.clone(), None, None)
             .unwrap()
-            .expect("cannot apply batch");
+            .expect("successful batch apply");
+        let transaction = sequential_db.start_transaction();
+        sequential_db
+            .apply_batch(ops, None, Some(&transaction))
+            .unwrap()
+            .expect("successful batch apply");
+        sequential_db
+            .commit_transaction(transaction)
+            .expect("successful commit");
         assert_eq!(
-            db.get([TEST_LEAF, b"valid"], b"key1", None)
+            parallel_db.root_hash(None).unwrap().unwrap(),
+            sequential_db.root_hash(None).unwrap().unwrap()
+        );
+
+        let path = subtree_path(7);
+        assert_eq!(
+            parallel_db
+                .get(path.iter().map(|x| x.as_slice()), b"ref", None)
                 .unwrap()
-                .expect("cannot get element"),
-            element
+                .expect("reference resolved"),
+            Element::new_item(vec![8, 8])
         );
+        assert!(parallel_db
+            .get(subtree_path(8).iter().map(|x| x.as_slice()), b"ref", None)
+            .unwrap()
+            .is_err());
     }
 
     #[test]
-    fn test_batch_validation_nested_subtree_overwrite() {
+    fn test_batch_in_parallel_stops_subtrees_on_cost_limit() {
         let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
-        let element2 = Element::new_item(b"ayy2".to_vec());
-        db.insert([TEST_LEAF], b"key_subtree", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert a subtree");
-        db.insert([TEST_LEAF, b"key_subtree"], b"key2", element, None)
+        let subtree_key = |i: u16| format!("subtree{}", i).into_bytes();
+        let subtrees_ops = (0..256)
+            .map(|i| {
+                GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec()],
+                    subtree_key(i),
+                    Element::empty_tree(),
+                )
+            })
+            .collect::<Vec<_>>();
+        db.apply_batch(subtrees_ops, None, None)
             .unwrap()
-            .expect("cannot insert an item");
+            .expect("successful batch apply");
+        let root_hash = db.root_hash(None).unwrap().unwrap();
 
-        // TEST_LEAF can not be overwritten
-        let ops = vec![
-            GroveDbOp::insert(vec![], TEST_LEAF.to_vec(), element2.clone()),
-            GroveDbOp::insert(
-                vec![TEST_LEAF.to_vec(), b"key_subtree".to_vec()],
-                b"key1".to_vec(),
-                Element::empty_tree(),
-            ),
-        ];
-        assert!(db
+        let ops = (0..256)
+            .map(|i| {
+                GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec(), subtree_key(i)],
+                    b"item".to_vec(),
+                    Element::new_item(vec![0; 10]),
+                )
+            })
+            .collect::<Vec<_>>();
+        let unlimited_db = make_grovedb();
+        unlimited_db
             .apply_batch(
-                ops,
-                Some(BatchApplyOptions {
-                    validate_tree_insertion_does_not_override: true
-                }),
-                None
+                (0..256)
+                    .map(|i| {
+                        GroveDbOp::insert(
+                            vec![TEST_LEAF.to_vec()],
+                            subtree_key(i),
+                            Element::empty_tree(),
+                        )
+                    })
+                    .collect(),
+                None,
+                None,
+            )
+            .unwrap()
+            .expect("successful batch apply");
+        let unlimited_cost = unlimited_db.apply_batch(ops.clone(), None, None).cost;
+
+        let cost_limit = OperationCost {
+            seek_count: unlimited_cost.seek_count / 4,
+            ..unlimited_cost.clone()
+        };
+        let result = db.apply_batch_with_cost_limit(ops, None, Some(&cost_limit), None);
+        match result.value {
+            Err(Error::CostLimitExceeded(cost)) => {
+                assert!(cost.exceeds(&cost_limit));
+                assert_eq!(cost, result.cost);
+            }
+            _ => panic!("expected cost limit to be exceeded"),
+        }
+        // Subtrees left once the limit is exceeded are not processed
+        assert!(result.cost.seek_count < unlimited_cost.seek_count);
+        assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+    }
+
+    /// Asserts that each part of the `real` cost is within the `worst` case
+    fn assert_cost_bounded(worst: &OperationCost, real: &OperationCost) {
+        assert!(
+            worst.seek_count >= real.seek_count,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+        assert!(
+            worst.storage_written_bytes >= real.storage_written_bytes,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+        assert!(
+            worst.storage_loaded_bytes >= real.storage_loaded_bytes,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+        assert!(
+            worst.loaded_bytes >= real.loaded_bytes,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+        assert!(
+            worst.hash_byte_calls >= real.hash_byte_calls,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+        assert!(
+            worst.hash_node_calls >= real.hash_node_calls,
+            "{:?} {:?}",
+            worst,
+            real
+        );
+    }
+
+    #[test]
+    fn test_worst_case_cost_bounds_batch_cost() {
+        let db = make_grovedb();
+        for i in 0u32..200 {
+            db.insert(
+                [TEST_LEAF],
+                &i.to_be_bytes(),
+                Element::new_item(i.to_be_bytes().to_vec()),
+                None,
             )
             .unwrap()
-            .is_err());
+            .expect("successful item insert");
+        }
 
-        // TEST_LEAF will be deleted so you can not insert underneath it
         let ops = vec![
-            GroveDbOp::delete(vec![], TEST_LEAF.to_vec()),
             GroveDbOp::insert(
                 vec![TEST_LEAF.to_vec()],
-                b"key1".to_vec(),
-                Element::empty_tree(),
+                b"new item".to_vec(),
+                Element::new_item(vec![7; 100]),
             ),
-        ];
-        assert!(db.apply_batch(ops, None, None).unwrap().is_err());
-
-        // TEST_LEAF will be deleted so you can not insert underneath it
-        // We are testing with the batch apply option
-        // validate_tree_insertion_does_not_override set to true
-        let ops = vec![
-            GroveDbOp::delete(vec![], TEST_LEAF.to_vec()),
             GroveDbOp::insert(
                 vec![TEST_LEAF.to_vec()],
-                b"key1".to_vec(),
+                100u32.to_be_bytes().to_vec(),
+                Element::new_item(b"updated".to_vec()),
+            ),
+            GroveDbOp::delete(vec![TEST_LEAF.to_vec()], 50u32.to_be_bytes().to_vec()),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"new tree".to_vec(),
                 Element::empty_tree(),
             ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"value".to_vec()),
+            ),
+            GroveDbOp::insert(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                b"reference".to_vec(),
+                Element::new_reference(vec![TEST_LEAF.to_vec(), 10u32.to_be_bytes().to_vec()]),
+            ),
         ];
-        assert!(db
-            .apply_batch(
-                ops,
-                Some(BatchApplyOptions {
-                    validate_tree_insertion_does_not_override: true
-                }),
-                None
-            )
-            .unwrap()
-            .is_err());
-    }
 
-    #[test]
-    fn test_batch_validation_root_leaf_removal() {
-        let db = make_grovedb();
+        let worst_case_cost = db.worst_case_operations_for_batch(ops.clone(), None).cost;
+        let result = db.apply_batch(ops, None, None);
+        assert!(result.value.is_ok());
+        assert!(result.cost.seek_count > 0 && result.cost.storage_written_bytes > 0);
+        assert_cost_bounded(&worst_case_cost, &result.cost);
+
         let ops = vec![
-            GroveDbOp::insert(
-                vec![],
-                TEST_LEAF.to_vec(),
-                Element::new_item(b"ayy".to_vec()),
-            ),
             GroveDbOp::insert(
                 vec![TEST_LEAF.to_vec()],
-                b"key1".to_vec(),
-                Element::empty_tree(),
+                b"another item".to_vec(),
+                Element::new_item(vec![8; 100]),
+            ),
+            GroveDbOp::delete(
+                vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+                b"key".to_vec(),
             ),
         ];
-        assert!(db
-            .apply_batch(
-                ops,
+        let worst_case_cost = db
+            .worst_case_operations_for_batch(
+                ops.clone(),
                 Some(BatchApplyOptions {
-                    validate_tree_insertion_does_not_override: true
+                    validate_tree_insertion_does_not_override: true,
                 }),
-                None
             )
-            .unwrap()
-            .is_err());
+            .cost;
+        let result = db.apply_batch(
+            ops,
+            Some(BatchApplyOptions {
+                validate_tree_insertion_does_not_override: true,
+            }),
+            None,
+        );
+        assert!(result.value.is_ok());
+        assert_cost_bounded(&worst_case_cost, &result.cost);
     }
 
     #[test]
-    fn test_merk_data_is_deleted() {
+    fn test_worst_case_cost_accounts_for_propagation() {
         let db = make_grovedb();
-        let element = Element::new_item(b"ayy".to_vec());
+        let insert_at = |path: Vec<Vec<u8>>| {
+            db.worst_case_operations_for_batch(
+                vec![GroveDbOp::insert(
+                    path,
+                    b"key".to_vec(),
+                    Element::new_item(b"value".to_vec()),
+                )],
+                None,
+            )
+            .cost
+        };
 
-        db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert a subtree");
-        db.insert([TEST_LEAF, b"key1"], b"key2", element.clone(), None)
-            .unwrap()
-            .expect("cannot insert an item");
-        let ops = vec![GroveDbOp::insert(
-            vec![TEST_LEAF.to_vec()],
-            b"key1".to_vec(),
-            Element::new_item(b"ayy2".to_vec()),
-        )];
+        let shallow = insert_at(vec![TEST_LEAF.to_vec()]);
+        let deep = insert_at(vec![TEST_LEAF.to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        assert!(deep.seek_count > shallow.seek_count);
+        assert!(deep.storage_written_bytes > shallow.storage_written_bytes);
+        assert!(deep.hash_node_calls > shallow.hash_node_calls);
 
-        assert_eq!(
-            db.get([TEST_LEAF, b"key1"], b"key2", None)
-                .unwrap()
-                .expect("cannot get item"),
-            element
-        );
-        db.apply_batch(ops, None, None)
-            .unwrap()
-            .expect("cannot apply batch");
-        assert!(db
-            .get([TEST_LEAF, b"key1"], b"key2", None)
-            .unwrap()
-            .is_err());
+        // Larger values take more bytes to write
+        let large = db
+            .worst_case_operations_for_batch(
+                vec![GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec()],
+                    b"key".to_vec(),
+                    Element::new_item(vec![0; 1000]),
+                )],
+                None,
+            )
+            .cost;
+        assert!(large.storage_written_bytes > shallow.storage_written_bytes);
     }
 
     #[test]
-    fn test_multi_tree_insertion_deletion_with_propagation_no_tx() {
+    fn test_average_case_cost_for_batch() {
         let db = make_grovedb();
-        db.insert([], b"key1", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert root leaf");
-        db.insert([], b"key2", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert root leaf");
-        db.insert([ANOTHER_TEST_LEAF], b"key1", Element::empty_tree(), None)
-            .unwrap()
-            .expect("cannot insert root leaf");
-
-        let hash = db
-            .root_hash(None)
+        for i in 0u32..200 {
+            db.insert(
+                [TEST_LEAF],
+                &i.to_be_bytes(),
+                Element::new_item(i.to_be_bytes().to_vec()),
+                None,
+            )
             .unwrap()
-            .ok()
-            .flatten()
-            .expect("cannot get root hash");
-        let element = Element::new_item(b"ayy".to_vec());
-        let element2 = Element::new_item(b"ayy2".to_vec());
+            .expect("successful item insert");
+        }
 
         let ops = vec![
             GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()],
-                b"key4".to_vec(),
-                element.clone(),
+                vec![TEST_LEAF.to_vec()],
+                b"new item".to_vec(),
+                Element::new_item(vec![7; 10]),
             ),
             GroveDbOp::insert(
-                vec![b"key1".to_vec(), b"key2".to_vec()],
-                b"key3".to_vec(),
+                vec![TEST_LEAF.to_vec()],
+                b"new tree".to_vec(),
                 Element::empty_tree(),
             ),
             GroveDbOp::insert(
-                vec![b"key1".to_vec()],
-                b"key2".to_vec(),
-                Element::empty_tree(),
+                vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"value".to_vec()),
             ),
-            GroveDbOp::insert(vec![TEST_LEAF.to_vec()], b"key".to_vec(), element2.clone()),
-            GroveDbOp::delete(vec![ANOTHER_TEST_LEAF.to_vec()], b"key1".to_vec()),
         ];
-        db.apply_batch(ops, None, None)
-            .unwrap()
-            .expect("cannot apply batch");
-
-        assert!(db.get([ANOTHER_TEST_LEAF], b"key1", None).unwrap().is_err());
 
-        assert_eq!(
-            db.get([b"key1".as_ref(), b"key2", b"key3"], b"key4", None)
-                .unwrap()
-                .expect("cannot get element"),
-            element
-        );
-        assert_eq!(
-            db.get([TEST_LEAF], b"key", None)
-                .unwrap()
-                .expect("cannot get element"),
-            element2
+        // Layout of the test leaf is required, the new tree is assumed empty
+        let mut estimated_layers = EstimatedLayerInformationByPath::new();
+        estimated_layers.insert(
+            vec![],
+            EstimatedLayerInformation {
+                elements_count: 2,
+                average_key_size: 16,
+                average_value_size: 40,
+            },
         );
-        assert_ne!(
-            db.root_hash(None)
-                .unwrap()
-                .ok()
-                .flatten()
-                .expect("cannot get root hash"),
-            hash
+        assert!(matches!(
+            db.average_case_operations_for_batch(ops.clone(), None, &estimated_layers)
+                .value,
+            Err(Error::MissingParameter(_))
+        ));
+        estimated_layers.insert(
+            vec![TEST_LEAF.to_vec()],
+            EstimatedLayerInformation {
+                elements_count: 200,
+                average_key_size: 4,
+                average_value_size: 6,
+            },
         );
-        let mut root_leafs = BTreeMap::new();
-        root_leafs.insert(TEST_LEAF.to_vec(), 0);
-        root_leafs.insert(ANOTHER_TEST_LEAF.to_vec(), 1);
-        root_leafs.insert(b"key1".to_vec(), 2);
-        root_leafs.insert(b"key2".to_vec(), 3);
 
-        assert_eq!(
-            db.get_root_leaf_keys(None)
-                .unwrap()
-                .expect("cannot get root leafs"),
-            root_leafs
+        let average_case =
+            db.average_case_operations_for_batch(ops.clone(), None, &estimated_layers);
+        assert!(average_case.value.is_ok());
+        let average_case_cost = average_case.cost;
+        let worst_case_cost = db.worst_case_operations_for_batch(ops.clone(), None).cost;
+        assert_cost_bounded(&worst_case_cost, &average_case_cost);
+
+        let result = db.apply_batch(ops, None, None);
+        assert!(result.value.is_ok());
+        // Estimation is expected to be in the ballpark of the real cost
+        let real_cost = result.cost;
+        assert!(
+            average_case_cost.seek_count <= real_cost.seek_count * 2
+                && average_case_cost.seek_count * 2 >= real_cost.seek_count,
+            "{:?} {:?}",
+            average_case_cost,
+            real_cost
+        );
+        assert!(
+            average_case_cost.storage_written_bytes <= real_cost.storage_written_bytes * 2
+                && average_case_cost.storage_written_bytes * 2 >= real_cost.storage_written_bytes,
+            "{:?} {:?}",
+            average_case_cost,
+            real_cost
         );
     }
 
     #[test]
-    fn test_nested_batch_insertion_corrupts_state() {
+    fn test_average_case_cost_for_batch_requires_layers_read_by_preconditions() {
         let db = make_grovedb();
-        let full_path = vec![
-            b"leaf1".to_vec(),
-            b"sub1".to_vec(),
-            b"sub2".to_vec(),
-            b"sub3".to_vec(),
-            b"sub4".to_vec(),
-            b"sub5".to_vec(),
-        ];
-        let mut acc_path: Vec<Vec<u8>> = vec![];
-        for p in full_path.into_iter() {
-            db.insert(
-                acc_path.iter().map(|x| x.as_slice()),
-                &p,
+        let mut estimated_layers = EstimatedLayerInformationByPath::new();
+        for path in [vec![], vec![TEST_LEAF.to_vec()]] {
+            estimated_layers.insert(
+                path,
+                EstimatedLayerInformation {
+                    elements_count: 2,
+                    average_key_size: 16,
+                    average_value_size: 40,
+                },
+            );
+        }
+
+        // The new tree is assumed empty when applying the batch, but its
+        // layout is needed to check the precondition against it
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"new tree".to_vec(),
                 Element::empty_tree(),
+            ),
+            GroveDbOp::insert_if_absent(
+                vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"value".to_vec()),
+            ),
+        ];
+        assert!(matches!(
+            db.average_case_operations_for_batch(ops.clone(), None, &estimated_layers)
+                .value,
+            Err(Error::MissingParameter(_))
+        ));
+        estimated_layers.insert(
+            vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+            EstimatedLayerInformation {
+                elements_count: 0,
+                average_key_size: 0,
+                average_value_size: 0,
+            },
+        );
+        assert!(db
+            .average_case_operations_for_batch(ops, None, &estimated_layers)
+            .value
+            .is_ok());
+    }
+    #[test]
+    fn test_batch_looks_for_referrers_only_in_subtrees_having_them() {
+        let db = make_grovedb();
+        let has_referrers = |path: &[u8]| {
+            db.has_referrers_batched(&[path.to_vec()], &StorageBatch::new(), None)
+                .unwrap()
+                .expect("cannot read referrers mark")
+        };
+
+        db.apply_batch(
+            vec![GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"item".to_vec(),
+                Element::new_item(b"ayy".to_vec()),
+            )],
+            None,
+            None,
+        )
+        .unwrap()
+        .expect("successful batch apply");
+        assert!(!has_referrers(TEST_LEAF));
+
+        db.apply_batch(
+            vec![GroveDbOp::insert(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                b"ref".to_vec(),
+                Element::new_reference(vec![TEST_LEAF.to_vec(), b"item".to_vec()]),
+            )],
+            None,
+            None,
+        )
+        .unwrap()
+        .expect("successful batch apply");
+        assert!(has_referrers(TEST_LEAF));
+        assert!(!has_referrers(ANOTHER_TEST_LEAF));
+
+        // Referrers of an updated element are read only once its subtree may
+        // have them
+        let update = |key: &[u8], value: &[u8]| {
+            db.apply_batch(
+                vec![GroveDbOp::insert(
+                    vec![ANOTHER_TEST_LEAF.to_vec()],
+                    key.to_vec(),
+                    Element::new_item(value.to_vec()),
+                )],
+                None,
                 None,
             )
+        };
+        update(b"item", b"ayy")
             .unwrap()
-            .expect("expected to insert");
-            acc_path.push(p);
-        }
-
-        let element = Element::new_item(b"ayy".to_vec());
-        let batch = vec![GroveDbOp::insert(
-            acc_path.clone(),
-            b"key".to_vec(),
-            element.clone(),
-        )];
-        db.apply_batch(batch, None, None)
-            .unwrap()
-            .expect("cannot apply batch");
-
-        let batch = vec![GroveDbOp::insert(
-            acc_path,
-            b"key".to_vec(),
-            element.clone(),
-        )];
-        db.apply_batch(batch, None, None)
+            .expect("successful batch apply");
+        update(b"other", b"ayy")
             .unwrap()
-            .expect("cannot apply same batch twice");
+            .expect("successful batch apply");
+        let cost_without_referrers = update(b"item", b"ayy2").cost;
+        db.insert(
+            [TEST_LEAF],
+            b"ref",
+            Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"other".to_vec()]),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+        assert!(has_referrers(ANOTHER_TEST_LEAF));
+        let cost_with_referrers = update(b"item", b"ayy3").cost;
+        assert!(cost_without_referrers.seek_count < cost_with_referrers.seek_count);
     }
 
     #[test]
-    fn test_apply_sorted_pre_validated_batch_propagation() {
+    fn test_average_case_cost_for_batch_requires_layers_read_by_reference_links() {
         let db = make_grovedb();
-        let full_path = vec![b"leaf1".to_vec(), b"sub1".to_vec()];
-        let mut acc_path: Vec<Vec<u8>> = vec![];
-        for p in full_path.into_iter() {
-            db.insert(
-                acc_path.iter().map(|x| x.as_slice()),
-                &p,
-                Element::empty_tree(),
-                None,
-            )
-            .unwrap()
-            .expect("expected to insert");
-            acc_path.push(p);
+        let mut estimated_layers = EstimatedLayerInformationByPath::new();
+        for path in [vec![], vec![TEST_LEAF.to_vec()]] {
+            estimated_layers.insert(
+                path,
+                EstimatedLayerInformation {
+                    elements_count: 2,
+                    average_key_size: 16,
+                    average_value_size: 40,
+                },
+            );
         }
 
-        let root_hash = db.root_hash(None).unwrap().unwrap();
+        // Nothing is read from subtrees created by the batch
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"new tree".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"new tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"value".to_vec()),
+            ),
+        ];
+        assert!(db
+            .average_case_operations_for_batch(ops, None, &estimated_layers)
+            .value
+            .is_ok());
 
-        let element = Element::new_item(b"ayy".to_vec());
-        let batch = vec![GroveDbOp::insert(
-            acc_path.clone(),
-            b"key".to_vec(),
-            element.clone(),
+        // Referenced element is read from a subtree the batch doesn't create
+        let ops = vec![GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"reference".to_vec(),
+            Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"item".to_vec()]),
         )];
-        db.apply_batch(batch, None, None)
-            .unwrap()
-            .expect("cannot apply batch");
-
-        assert_ne!(db.root_hash(None).unwrap().unwrap(), root_hash);
+        assert!(matches!(
+            db.average_case_operations_for_batch(ops.clone(), None, &estimated_layers)
+                .value,
+            Err(Error::MissingParameter(_))
+        ));
+        estimated_layers.insert(
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            EstimatedLayerInformation {
+                elements_count: 1,
+                average_key_size: 4,
+                average_value_size: 40,
+            },
+        );
+        assert!(db
+            .average_case_operations_for_batch(ops, None, &estimated_layers)
+            .value
+            .is_ok());
     }
 }
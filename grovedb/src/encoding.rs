@@ -0,0 +1,477 @@
+//! Versioned binary encoding of batch operations and elements, meant for
+//! transmitting batches between nodes and recording them for replay.
+//!
+//! Every encoded value starts with a single byte of `ENCODING_VERSION`
+//! followed by the body. Lengths and counts are varints, elements are carried
+//! in the same form they are stored in Merk. Tags of operations follow the
+//! order of `Op` variants, so new operations may only be appended.
+
+use integer_encoding::VarInt;
+
+use crate::{
+    batch::{GroveDbOp, Op},
+    Element, ElementFlags, Error,
+};
+
+/// Version of the encoding written by this release
+pub const ENCODING_VERSION: u8 = 1;
+
+const OP_INSERT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_DELETE_TREE: u8 = 3;
+const OP_INSERT_IF_ABSENT: u8 = 4;
+const OP_REPLACE_IF_PRESENT: u8 = 5;
+const OP_DELETE_IF_EXISTS: u8 = 6;
+const OP_ASSERT_EQUALS: u8 = 7;
+
+/// Cursor over encoded bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading a value, which must be of a supported version
+    fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        let version = reader.read_u8()?;
+        if version != ENCODING_VERSION {
+            return Err(Error::CorruptedData(format!(
+                "unsupported encoding version {}",
+                version
+            )));
+        }
+        Ok(reader)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let (byte, rest) = self.bytes.split_first().ok_or_else(unexpected_end)?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        let (len, read) = u64::decode_var(self.bytes).ok_or_else(unexpected_end)?;
+        self.bytes = &self.bytes[read..];
+        usize::try_from(len).map_err(|_| Error::CorruptedData(String::from("length overflow")))
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.read_len()?;
+        if len > self.bytes.len() {
+            return Err(unexpected_end());
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes.to_vec())
+    }
+
+    /// Finishes reading a value, no bytes may be left
+    fn finish(self) -> Result<(), Error> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::CorruptedData(String::from(
+                "trailing bytes after encoded value",
+            )))
+        }
+    }
+}
+
+fn unexpected_end() -> Error {
+    Error::CorruptedData(String::from("unexpected end of encoded value"))
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend(bytes.len().encode_var_vec());
+    buf.extend_from_slice(bytes);
+}
+
+fn write_element(buf: &mut Vec<u8>, element: &Element) -> Result<(), Error> {
+    write_bytes(buf, &element.serialize()?);
+    Ok(())
+}
+
+fn read_element(reader: &mut Reader) -> Result<Element, Error> {
+    Element::deserialize(&reader.read_bytes()?)
+}
+
+/// Encodes element flags
+pub fn encode_element_flags(flags: &ElementFlags) -> Vec<u8> {
+    let mut buf = vec![ENCODING_VERSION];
+    match flags {
+        None => buf.push(0),
+        Some(flags) => {
+            buf.push(1);
+            write_bytes(&mut buf, flags);
+        }
+    }
+    buf
+}
+
+/// Decodes element flags encoded with `encode_element_flags`
+pub fn decode_element_flags(bytes: &[u8]) -> Result<ElementFlags, Error> {
+    let mut reader = Reader::new(bytes)?;
+    let flags = match reader.read_u8()? {
+        0 => None,
+        1 => Some(reader.read_bytes()?),
+        _ => {
+            return Err(Error::CorruptedData(String::from(
+                "unknown element flags marker",
+            )))
+        }
+    };
+    reader.finish()?;
+    Ok(flags)
+}
+
+impl Element {
+    /// Encodes the element for transport
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![ENCODING_VERSION];
+        write_element(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// Decodes an element encoded with `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes)?;
+        let element = read_element(&mut reader)?;
+        reader.finish()?;
+        Ok(element)
+    }
+}
+
+impl Op {
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Op::ReplaceTreeHash { .. } => {
+                return Err(Error::InvalidBatchOperation(
+                    "replace tree hash is an internal operation only",
+                ))
+            }
+            Op::Insert { element } => {
+                buf.push(OP_INSERT);
+                write_element(buf, element)?;
+            }
+            Op::Delete => buf.push(OP_DELETE),
+            Op::DeleteTree => buf.push(OP_DELETE_TREE),
+            Op::InsertIfAbsent { element } => {
+                buf.push(OP_INSERT_IF_ABSENT);
+                write_element(buf, element)?;
+            }
+            Op::ReplaceIfPresent { element } => {
+                buf.push(OP_REPLACE_IF_PRESENT);
+                write_element(buf, element)?;
+            }
+            Op::DeleteIfExists => buf.push(OP_DELETE_IF_EXISTS),
+            Op::AssertEquals { element } => {
+                buf.push(OP_ASSERT_EQUALS);
+                write_element(buf, element)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_from(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(match reader.read_u8()? {
+            OP_INSERT => Op::Insert {
+                element: read_element(reader)?,
+            },
+            OP_DELETE => Op::Delete,
+            OP_DELETE_TREE => Op::DeleteTree,
+            OP_INSERT_IF_ABSENT => Op::InsertIfAbsent {
+                element: read_element(reader)?,
+            },
+            OP_REPLACE_IF_PRESENT => Op::ReplaceIfPresent {
+                element: read_element(reader)?,
+            },
+            OP_DELETE_IF_EXISTS => Op::DeleteIfExists,
+            OP_ASSERT_EQUALS => Op::AssertEquals {
+                element: read_element(reader)?,
+            },
+            tag => {
+                return Err(Error::CorruptedData(format!(
+                    "unknown batch operation tag {}",
+                    tag
+                )))
+            }
+        })
+    }
+
+    /// Encodes the operation for transport, internal operations can't be
+    /// encoded
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![ENCODING_VERSION];
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decodes an operation encoded with `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes)?;
+        let op = Op::decode_from(&mut reader)?;
+        reader.finish()?;
+        Ok(op)
+    }
+}
+
+impl GroveDbOp {
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.extend(self.path.len().encode_var_vec());
+        for segment in self.path.iter() {
+            write_bytes(buf, segment);
+        }
+        write_bytes(buf, &self.key);
+        self.op.encode_into(buf)
+    }
+
+    fn decode_from(reader: &mut Reader) -> Result<Self, Error> {
+        let path_len = reader.read_len()?;
+        // Every segment takes at least a byte, so the length isn't trusted
+        // for preallocation
+        let mut path = Vec::new();
+        for _ in 0..path_len {
+            path.push(reader.read_bytes()?);
+        }
+        Ok(GroveDbOp {
+            path,
+            key: reader.read_bytes()?,
+            op: Op::decode_from(reader)?,
+        })
+    }
+
+    /// Encodes the operation for transport
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![ENCODING_VERSION];
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decodes an operation encoded with `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes)?;
+        let op = GroveDbOp::decode_from(&mut reader)?;
+        reader.finish()?;
+        Ok(op)
+    }
+
+    /// Encodes a batch of operations for transport
+    pub fn encode_batch(ops: &[GroveDbOp]) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![ENCODING_VERSION];
+        buf.extend(ops.len().encode_var_vec());
+        for op in ops {
+            op.encode_into(&mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    /// Decodes a batch encoded with `encode_batch`, the result may be passed
+    /// to `apply_batch` as is
+    pub fn decode_batch(bytes: &[u8]) -> Result<Vec<GroveDbOp>, Error> {
+        let mut reader = Reader::new(bytes)?;
+        let ops_len = reader.read_len()?;
+        let mut ops = Vec::new();
+        for _ in 0..ops_len {
+            ops.push(GroveDbOp::decode_from(&mut reader)?);
+        }
+        reader.finish()?;
+        Ok(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{make_grovedb, ANOTHER_TEST_LEAF, TEST_LEAF},
+        ReferencePathType,
+    };
+
+    fn all_ops() -> Vec<GroveDbOp> {
+        let path = vec![TEST_LEAF.to_vec(), b"tree".to_vec()];
+        vec![
+            GroveDbOp::insert(
+                path.clone(),
+                b"item".to_vec(),
+                Element::new_item_with_flags(b"ayy".to_vec(), Some(vec![1, 2])),
+            ),
+            GroveDbOp::delete(path.clone(), b"deleted".to_vec()),
+            GroveDbOp::delete_tree(vec![], b"tree".to_vec()),
+            GroveDbOp::insert_if_absent(
+                path.clone(),
+                b"reference".to_vec(),
+                Element::new_reference_with_max_hops(
+                    ReferencePathType::SiblingReference(b"item".to_vec()),
+                    Some(2),
+                ),
+            ),
+            GroveDbOp::replace_if_present(path.clone(), vec![], Element::empty_sum_tree()),
+            GroveDbOp::delete_if_exists(path.clone(), b"maybe".to_vec()),
+            GroveDbOp::assert_equals(path, b"sum".to_vec(), Element::new_sum_item(-7)),
+        ]
+    }
+
+    #[test]
+    fn test_element_flags_round_trip() {
+        for flags in [None, Some(vec![]), Some(vec![1, 2, 3])] {
+            let encoded = encode_element_flags(&flags);
+            assert_eq!(encoded[0], ENCODING_VERSION);
+            assert_eq!(
+                decode_element_flags(&encoded).expect("expected to decode flags"),
+                flags
+            );
+        }
+        assert!(decode_element_flags(&[ENCODING_VERSION, 2]).is_err());
+    }
+
+    #[test]
+    fn test_element_round_trip() {
+        let elements = [
+            Element::new_item(b"ayy".to_vec()),
+            Element::new_reference_with_flags(vec![TEST_LEAF.to_vec()], Some(vec![5])),
+            Element::new_tree_with_flags([7; 32], Some(vec![])),
+            Element::new_sum_tree_with_flags([1; 32], -3, None),
+            Element::new_count_tree_with_flags([2; 32], 3, None),
+        ];
+        for element in elements {
+            assert_eq!(
+                Element::decode(&element.encode().expect("expected to encode element"))
+                    .expect("expected to decode element"),
+                element
+            );
+        }
+    }
+
+    #[test]
+    fn test_ops_round_trip() {
+        let ops = all_ops();
+        for op in ops.iter() {
+            let encoded = op.encode().expect("expected to encode operation");
+            assert_eq!(
+                &GroveDbOp::decode(&encoded).expect("expected to decode operation"),
+                op
+            );
+            let encoded = op.op.encode().expect("expected to encode operation");
+            assert_eq!(
+                Op::decode(&encoded).expect("expected to decode operation"),
+                op.op
+            );
+        }
+
+        let encoded = GroveDbOp::encode_batch(&ops).expect("expected to encode batch");
+        assert_eq!(
+            GroveDbOp::decode_batch(&encoded).expect("expected to decode batch"),
+            ops
+        );
+        assert_eq!(
+            GroveDbOp::decode_batch(
+                &GroveDbOp::encode_batch(&[]).expect("expected to encode batch")
+            )
+            .expect("expected to decode batch"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_decoding_rejects_malformed_input() {
+        let encoded = GroveDbOp::encode_batch(&all_ops()).expect("expected to encode batch");
+
+        let mut unknown_version = encoded.clone();
+        unknown_version[0] = ENCODING_VERSION + 1;
+        assert!(matches!(
+            GroveDbOp::decode_batch(&unknown_version),
+            Err(Error::CorruptedData(_))
+        ));
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(matches!(
+            GroveDbOp::decode_batch(&trailing),
+            Err(Error::CorruptedData(_))
+        ));
+
+        for len in 0..encoded.len() {
+            assert!(matches!(
+                GroveDbOp::decode_batch(&encoded[..len]),
+                Err(Error::CorruptedData(_))
+            ));
+        }
+
+        assert!(matches!(
+            Op::decode(&[ENCODING_VERSION, 0]),
+            Err(Error::CorruptedData(_))
+        ));
+        assert!(matches!(
+            Op::ReplaceTreeHash {
+                hash: [0; 32],
+                aggregate_data: None,
+            }
+            .encode(),
+            Err(Error::InvalidBatchOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_decoded_batch() {
+        let ops = vec![
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"tree".to_vec(),
+                Element::empty_tree(),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec(), b"tree".to_vec()],
+                b"key".to_vec(),
+                Element::new_item(b"ayy".to_vec()),
+            ),
+            GroveDbOp::insert_if_absent(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                b"key".to_vec(),
+                Element::new_reference_with_flags(
+                    vec![TEST_LEAF.to_vec(), b"tree".to_vec(), b"key".to_vec()],
+                    Some(vec![1]),
+                ),
+            ),
+        ];
+        let encoded = GroveDbOp::encode_batch(&ops).expect("expected to encode batch");
+
+        let db = make_grovedb();
+        db.apply_batch(ops, None, None)
+            .unwrap()
+            .expect("successful batch apply");
+        let replica = make_grovedb();
+        replica
+            .apply_batch(
+                GroveDbOp::decode_batch(&encoded).expect("expected to decode batch"),
+                None,
+                None,
+            )
+            .unwrap()
+            .expect("successful batch apply");
+
+        assert_eq!(
+            replica.root_hash(None).unwrap().unwrap(),
+            db.root_hash(None).unwrap().unwrap()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let ops = all_ops();
+        let serialized = bincode::serialize(&ops).expect("expected to serialize batch");
+        assert_eq!(
+            bincode::deserialize::<Vec<GroveDbOp>>(&serialized)
+                .expect("expected to deserialize batch"),
+            ops
+        );
+
+        // Operations internal to batch application are rejected
+        assert!(bincode::serialize(&Op::ReplaceTreeHash {
+            hash: [1; 32],
+            aggregate_data: None,
+        })
+        .is_err());
+        assert!(bincode::deserialize::<Op>(&[0; 40]).is_err());
+    }
+}
@@ -1,49 +1,58 @@
 extern crate core;
 
+mod average_case;
 pub mod batch;
+mod encoding;
 mod operations;
 mod query;
+mod read_only;
+mod reference_path;
+pub mod replication;
 mod subtree;
 #[cfg(test)]
 mod tests;
 mod util;
 mod visualize;
 
-use std::{collections::BTreeMap, path::Path};
+#[cfg(feature = "rocksdb_storage")]
+use std::path::Path;
 
-use costs::{
-    cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
-};
+pub use average_case::{EstimatedLayerInformation, EstimatedLayerInformationByPath};
+use costs::{cost_return_on_error, CostResult, CostsExt, OperationCost};
+pub use encoding::{decode_element_flags, encode_element_flags, ENCODING_VERSION};
 pub use merk::proofs::{query::QueryItem, Query};
-use merk::{self, Merk};
+use merk::{self, AggregateData, Merk};
 pub use query::{PathQuery, SizedQuery};
-use rs_merkle::{algorithms::Sha256, MerkleTree};
+pub use read_only::ReadOnlyGroveDb;
+use reference_path::display_reference_chain;
+pub use reference_path::{MaxReferenceHop, ReferenceChain, ReferencePathType};
+#[cfg(feature = "rocksdb_storage")]
+pub use storage::rocksdb_storage::{self, RocksDbConfig, RocksDbStorage};
 pub use storage::{
-    rocksdb_storage::{self, RocksDbStorage},
-    Storage, StorageContext,
+    memory_storage::{self, MemoryStorage},
+    Storage, StorageBatch, StorageContext,
 };
-pub use subtree::{Element, ElementFlags};
-
-use crate::util::{merk_optional_tx, meta_storage_context_optional_tx};
+pub use subtree::{Element, ElementFlags, TreeType};
 
-/// A key to store serialized data about subtree prefixes to restore HADS
-/// structure
-/// A key to store serialized data about root tree leaves keys and order
-const ROOT_LEAFS_SERIALIZED_KEY: &[u8] = b"rootLeafsSerialized";
+use crate::util::merk_optional_tx;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     // Input data errors
-    #[error("cyclic reference path")]
-    CyclicReference,
-    #[error("reference hops limit exceeded")]
-    ReferenceLimit,
+    #[error("cyclic reference path: {}", display_reference_chain(.0))]
+    CyclicReference(ReferenceChain),
+    #[error("reference hops limit exceeded: {}", display_reference_chain(.0))]
+    ReferenceLimit(ReferenceChain),
     #[error("internal error: {0}")]
     InternalError(&'static str),
     #[error("invalid proof: {0}")]
     InvalidProof(&'static str),
     #[error("invalid input: {0}")]
     InvalidInput(&'static str),
+    // The operation was stopped as it went over the given budget, the cost
+    // accumulated by then is carried along
+    #[error("cost limit exceeded: {0:?}")]
+    CostLimitExceeded(OperationCost),
 
     // Path errors
 
@@ -67,7 +76,7 @@ pub enum Error {
     MissingParameter(&'static str),
     // Irrecoverable errors
     #[error("storage error: {0}")]
-    StorageError(#[from] rocksdb_storage::Error),
+    StorageError(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[error("data corruption error: {0}")]
     CorruptedData(String),
 
@@ -77,118 +86,106 @@ pub enum Error {
     #[error("invalid batch operation error: {0}")]
     InvalidBatchOperation(&'static str),
 
+    #[error("batch operation precondition failed: {0:?}")]
+    BatchPreconditionFailed(Box<batch::GroveDbOp>),
+
+    #[error("chunk restoring error: {0}")]
+    ChunkRestoringError(String),
+
     // Support errors
     #[error("not supported: {0}")]
     NotSupported(&'static str),
 }
 
-pub struct GroveDb {
-    db: RocksDbStorage,
+/// Storage used by GroveDB unless another one is specified: RocksDB if
+/// `rocksdb_storage` feature is enabled and in-memory one otherwise.
+#[cfg(feature = "rocksdb_storage")]
+pub type DefaultStorage = RocksDbStorage;
+#[cfg(not(feature = "rocksdb_storage"))]
+pub type DefaultStorage = MemoryStorage;
+
+/// GroveDB over any storage backend.
+pub struct GroveDb<S = DefaultStorage> {
+    db: S,
 }
 
-pub type Transaction<'db> = <RocksDbStorage as Storage<'db>>::Transaction;
-pub type TransactionArg<'db, 'a> = Option<&'a Transaction<'db>>;
+pub type Transaction<'db, S = DefaultStorage> = <S as Storage>::Transaction<'db>;
+pub type TransactionArg<'db, 'a, S = DefaultStorage> = Option<&'a Transaction<'db, S>>;
 
-impl GroveDb {
+#[cfg(feature = "rocksdb_storage")]
+impl GroveDb<RocksDbStorage> {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let db = RocksDbStorage::default_rocksdb_with_path(path)?;
+        let db = RocksDbStorage::default_rocksdb_with_path(path)
+            .map_err(|e| Error::StorageError(e.into()))?;
         Ok(GroveDb { db })
     }
 
-    // TODO: Checkpoints are currently not implemented for the transactional DB
-    // pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<GroveDb, Error> {
-    //     // let snapshot = self.db.transaction().snapshot();
-    //
-    //     storage::rocksdb_storage::Checkpoint::new(&self.db)
-    //         .and_then(|x| x.create_checkpoint(&path))
-    //         .map_err(PrefixedRocksDbStorageError::RocksDbError)?;
-    //     GroveDb::open(path)
-    // }
-
-    /// Returns root hash of GroveDb.
-    /// Will be `None` if GroveDb is empty.
-    pub fn root_hash(&self, transaction: TransactionArg) -> CostResult<Option<[u8; 32]>, Error> {
-        Self::get_root_tree_internal(&self.db, transaction).map_ok(|x| x.root())
+    /// Opens GroveDb at `path` with RocksDB tuned by `options`.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: RocksDbConfig,
+    ) -> Result<Self, Error> {
+        let db = RocksDbStorage::open_with_config(path, options)
+            .map_err(|e| Error::StorageError(e.into()))?;
+        Ok(GroveDb { db })
     }
 
-    fn get_root_leaf_keys_internal<'db, S>(
-        meta_storage: &S,
-    ) -> CostResult<BTreeMap<Vec<u8>, usize>, Error>
-    where
-        S: StorageContext<'db>,
-        Error: From<<S as StorageContext<'db>>::Error>,
-    {
-        let mut cost = OperationCost {
-            seek_count: 1,
-            ..Default::default()
-        };
-
-        let root_leaf_keys = if let Some(root_leaf_keys_serialized) = cost_return_on_error_no_add!(
-            &cost,
-            meta_storage
-                .get_meta(ROOT_LEAFS_SERIALIZED_KEY)
-                .map_err(|e| e.into())
-        ) {
-            cost.loaded_bytes += root_leaf_keys_serialized.len() as u32;
-            cost_return_on_error_no_add!(
-                &cost,
-                bincode::deserialize(&root_leaf_keys_serialized).map_err(|_| {
-                    Error::CorruptedData(String::from("unable to deserialize root leaves"))
-                })
-            )
-        } else {
-            BTreeMap::new()
-        };
-        Ok(root_leaf_keys).wrap_with_cost(cost)
+    /// Creates a checkpoint of GroveDb at `path` and opens it with the options
+    /// of the origin. The checkpoint is independent from the origin and shares
+    /// no further changes with it.
+    pub fn create_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<GroveDb, Error> {
+        self.db
+            .create_checkpoint(&path)
+            .map_err(|e| Error::StorageError(e.into()))?;
+        GroveDb::open_with_options(path, self.db.config().clone())
     }
 
-    fn get_root_leaf_keys(
+    /// Creates a checkpoint of GroveDb at `path` the way `create_checkpoint`
+    /// does, but opens it for reads only.
+    pub fn create_read_only_checkpoint<P: AsRef<Path>>(
         &self,
-        transaction: TransactionArg,
-    ) -> CostResult<BTreeMap<Vec<u8>, usize>, Error> {
-        meta_storage_context_optional_tx!(self.db, transaction, meta_storage, {
-            Self::get_root_leaf_keys_internal(&meta_storage)
-        })
+        path: P,
+    ) -> Result<ReadOnlyGroveDb, Error> {
+        self.create_checkpoint(path).map(ReadOnlyGroveDb::new)
     }
 
-    fn get_root_tree_internal(
-        db: &RocksDbStorage,
-        transaction: TransactionArg,
-    ) -> CostResult<MerkleTree<Sha256>, Error> {
-        let mut cost = OperationCost::default();
+    /// Opens a checkpoint created before at `path` with RocksDB tuned by
+    /// `options` for reads only, as a historical view of GroveDb.
+    pub fn open_checkpoint<P: AsRef<Path>>(
+        path: P,
+        options: RocksDbConfig,
+    ) -> Result<ReadOnlyGroveDb, Error> {
+        GroveDb::open_with_options(path, options).map(ReadOnlyGroveDb::new)
+    }
+}
 
-        let root_leaf_keys = meta_storage_context_optional_tx!(db, transaction, meta_storage, {
-            cost_return_on_error!(&mut cost, Self::get_root_leaf_keys_internal(&meta_storage))
-        });
-
-        let mut leaf_hashes: Vec<[u8; 32]> = vec![[0; 32]; root_leaf_keys.len()];
-        for (subtree_path, root_leaf_idx) in root_leaf_keys {
-            merk_optional_tx!(
-                &mut cost,
-                db,
-                [subtree_path.as_slice()],
-                transaction,
-                subtree,
-                {
-                    leaf_hashes[root_leaf_idx] = subtree.root_hash().unwrap_add_cost(&mut cost);
-                }
-            );
-        }
-        Ok(MerkleTree::<Sha256>::from_leaves(&leaf_hashes)).wrap_with_cost(cost)
+impl<S: Storage> GroveDb<S> {
+    /// Creates GroveDb on top of a storage, which could be an empty one or
+    /// could have been used by GroveDb before.
+    pub fn new(db: S) -> Self {
+        GroveDb { db }
     }
 
-    pub fn get_root_tree(
-        &self,
-        transaction: TransactionArg,
-    ) -> CostResult<MerkleTree<Sha256>, Error> {
-        Self::get_root_tree_internal(&self.db, transaction)
+    /// Returns root hash of GroveDb.
+    /// Will be `None` if GroveDb is empty.
+    pub fn root_hash(&self, transaction: TransactionArg<S>) -> CostResult<Option<[u8; 32]>, Error> {
+        let mut cost = OperationCost::default();
+
+        merk_optional_tx!(&mut cost, self.db, [], transaction, subtree, {
+            if subtree.is_empty_tree() {
+                Ok(None).wrap_with_cost(cost)
+            } else {
+                let root_hash = subtree.root_hash().unwrap_add_cost(&mut cost);
+                Ok(Some(root_hash)).wrap_with_cost(cost)
+            }
+        })
     }
 
     /// Method to propagate updated subtree root hashes up to GroveDB root
     fn propagate_changes<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -196,10 +193,10 @@ impl GroveDb {
     {
         let mut cost = OperationCost::default();
 
-        // Go up until only one element in path, which means a key of a root tree
+        // Go up until the path is empty, which means the root tree was updated
         let mut path_iter = path.into_iter();
 
-        while path_iter.len() > 1 {
+        while path_iter.len() > 0 {
             if let Some(tx) = transaction {
                 let subtree_storage = self
                     .db
@@ -210,6 +207,10 @@ impl GroveDb {
                         .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
                 );
                 let key = path_iter.next_back().expect("next element is `Some`");
+                let parent_tree_type = cost_return_on_error!(
+                    &mut cost,
+                    self.tree_type(path_iter.clone(), transaction)
+                );
                 let parent_storage = self
                     .db
                     .get_transactional_storage_context(path_iter.clone(), tx);
@@ -224,6 +225,8 @@ impl GroveDb {
                         &mut parent_tree,
                         key,
                         subtree.root_hash().unwrap_add_cost(&mut cost),
+                        subtree.aggregate_data(),
+                        parent_tree_type,
                     )
                 );
             } else {
@@ -234,6 +237,10 @@ impl GroveDb {
                         .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
                 );
                 let key = path_iter.next_back().expect("next element is `Some`");
+                let parent_tree_type = cost_return_on_error!(
+                    &mut cost,
+                    self.tree_type(path_iter.clone(), transaction)
+                );
                 let parent_storage = self.db.get_storage_context(path_iter.clone());
                 let mut parent_tree = cost_return_on_error!(
                     &mut cost,
@@ -246,6 +253,8 @@ impl GroveDb {
                         &mut parent_tree,
                         key,
                         subtree.root_hash().unwrap_add_cost(&mut cost),
+                        subtree.aggregate_data(),
+                        parent_tree_type,
                     )
                 );
             }
@@ -254,51 +263,8 @@ impl GroveDb {
         Ok(()).wrap_with_cost(cost)
     }
 
-    pub(crate) fn update_tree_item_preserve_flag<
-        'db,
-        K: AsRef<[u8]> + Copy,
-        S: StorageContext<'db>,
-    >(
-        parent_tree: &mut Merk<S>,
-        key: K,
-        root_hash: [u8; 32],
-    ) -> CostResult<(), Error> {
-        Self::get_element_from_subtree(parent_tree, key).flat_map_ok(|element| {
-            if let Element::Tree(_, flag) = element {
-                let tree = Element::new_tree_with_flags(root_hash, flag);
-                tree.insert(parent_tree, key.as_ref())
-            } else {
-                Err(Error::InvalidPath("can only propagate on tree items"))
-                    .wrap_with_cost(Default::default())
-            }
-        })
-    }
-
-    fn get_element_from_subtree<'db, K: AsRef<[u8]>, S: StorageContext<'db>>(
-        subtree: &Merk<S>,
-        key: K,
-    ) -> CostResult<Element, Error> {
-        subtree
-            .get(key.as_ref())
-            .map_err(|_| Error::InvalidPath("can't find subtree in parent during propagation"))
-            .map_ok(|subtree_opt| {
-                subtree_opt.ok_or(Error::InvalidPath(
-                    "can't find subtree in parent during propagation",
-                ))
-            })
-            .flatten()
-            .map_ok(|element_bytes| {
-                Element::deserialize(&element_bytes).map_err(|_| {
-                    Error::CorruptedData(
-                        "failed to deserialized parent during propagation".to_owned(),
-                    )
-                })
-            })
-            .flatten()
-    }
-
     pub fn flush(&self) -> Result<(), Error> {
-        Ok(self.db.flush()?)
+        self.db.flush().map_err(|e| Error::StorageError(e.into()))
     }
 
     /// Starts database transaction. Please note that you have to start
@@ -306,16 +272,13 @@ impl GroveDb {
     ///
     /// ## Examples:
     /// ```
-    /// # use grovedb::{Element, Error, GroveDb};
-    /// # use rs_merkle::{MerkleTree, MerkleProof, algorithms::Sha256, Hasher, utils};
+    /// # use grovedb::{Element, Error, GroveDb, MemoryStorage};
     /// # use std::convert::TryFrom;
-    /// # use tempfile::TempDir;
     /// #
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// const TEST_LEAF: &[u8] = b"test_leaf";
     ///
-    /// let tmp_dir = TempDir::new().unwrap();
-    /// let mut db = GroveDb::open(tmp_dir.path())?;
+    /// let mut db = GroveDb::new(MemoryStorage::new());
     /// db.insert([], TEST_LEAF, Element::empty_tree(), None)
     ///     .unwrap()?;
     ///
@@ -341,20 +304,73 @@ impl GroveDb {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn start_transaction(&self) -> Transaction {
+    pub fn start_transaction(&self) -> Transaction<'_, S> {
         self.db.start_transaction()
     }
 
     /// Commits previously started db transaction. For more details on the
     /// transaction usage, please check [`GroveDb::start_transaction`]
-    pub fn commit_transaction(&self, transaction: Transaction) -> Result<(), Error> {
-        Ok(self.db.commit_transaction(transaction)?)
+    pub fn commit_transaction(&self, transaction: Transaction<'_, S>) -> Result<(), Error> {
+        self.db
+            .commit_transaction(transaction)
+            .map_err(|e| Error::StorageError(e.into()))
     }
 
     /// Rollbacks previously started db transaction to initial state.
     /// For more details on the transaction usage, please check
     /// [`GroveDb::start_transaction`]
-    pub fn rollback_transaction(&self, transaction: &Transaction) -> Result<(), Error> {
-        Ok(self.db.rollback_transaction(transaction)?)
+    pub fn rollback_transaction(&self, transaction: &Transaction<'_, S>) -> Result<(), Error> {
+        self.db
+            .rollback_transaction(transaction)
+            .map_err(|e| Error::StorageError(e.into()))
+    }
+
+    /// Updates a tree element in `parent_tree` of `parent_tree_type` with the
+    /// root hash and the aggregate (`None` for regular and empty trees) of its
+    /// subtree
+    pub(crate) fn update_tree_item_preserve_flag<
+        'db,
+        K: AsRef<[u8]> + Copy,
+        C: StorageContext<'db>,
+    >(
+        parent_tree: &mut Merk<C>,
+        key: K,
+        root_hash: [u8; 32],
+        aggregate_data: Option<AggregateData>,
+        parent_tree_type: TreeType,
+    ) -> CostResult<(), Error> {
+        Self::get_element_from_subtree(parent_tree, key).flat_map_ok(|element| {
+            let tree = match element.with_subtree_root(root_hash, aggregate_data) {
+                Some(tree) => tree,
+                None => {
+                    return Err(Error::InvalidPath("can only propagate on tree items"))
+                        .wrap_with_cost(Default::default())
+                }
+            };
+            tree.insert(parent_tree, key.as_ref(), parent_tree_type)
+        })
+    }
+
+    fn get_element_from_subtree<'db, K: AsRef<[u8]>, C: StorageContext<'db>>(
+        subtree: &Merk<C>,
+        key: K,
+    ) -> CostResult<Element, Error> {
+        subtree
+            .get(key.as_ref())
+            .map_err(|_| Error::InvalidPath("can't find subtree in parent during propagation"))
+            .map_ok(|subtree_opt| {
+                subtree_opt.ok_or(Error::InvalidPath(
+                    "can't find subtree in parent during propagation",
+                ))
+            })
+            .flatten()
+            .map_ok(|element_bytes| {
+                Element::deserialize(&element_bytes).map_err(|_| {
+                    Error::CorruptedData(
+                        "failed to deserialized parent during propagation".to_owned(),
+                    )
+                })
+            })
+            .flatten()
     }
 }
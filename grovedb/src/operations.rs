@@ -4,3 +4,4 @@ pub(crate) mod get;
 pub(crate) mod insert;
 pub(crate) mod is_empty_tree;
 pub(crate) mod proof;
+pub(crate) mod references;
@@ -1,14 +1,14 @@
 use costs::{cost_return_on_error_no_add, CostResult, CostsExt, OperationCost};
-use storage::StorageContext;
+use storage::{Storage, StorageContext};
 
 use crate::{util::meta_storage_context_optional_tx, Error, GroveDb, TransactionArg};
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     pub fn put_aux<K: AsRef<[u8]>>(
         &self,
         key: K,
         value: &[u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
@@ -17,7 +17,7 @@ impl GroveDb {
                 &cost,
                 aux_storage
                     .put_aux(key.as_ref(), value)
-                    .map_err(|e| e.into())
+                    .map_err(|e| Error::StorageError(e.into()))
             );
         });
 
@@ -29,14 +29,16 @@ impl GroveDb {
     pub fn delete_aux<K: AsRef<[u8]>>(
         &self,
         key: K,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
         meta_storage_context_optional_tx!(self.db, transaction, aux_storage, {
             cost_return_on_error_no_add!(
                 &cost,
-                aux_storage.delete_aux(key.as_ref()).map_err(|e| e.into())
+                aux_storage
+                    .delete_aux(key.as_ref())
+                    .map_err(|e| Error::StorageError(e.into()))
             );
         });
 
@@ -48,13 +50,17 @@ impl GroveDb {
     pub fn get_aux<K: AsRef<[u8]>>(
         &self,
         key: K,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Option<Vec<u8>>, Error> {
         let mut cost = OperationCost::default();
 
         meta_storage_context_optional_tx!(self.db, transaction, aux_storage, {
-            let value =
-                cost_return_on_error_no_add!(&cost, aux_storage.get_aux(key).map_err(|e| e.into()));
+            let value = cost_return_on_error_no_add!(
+                &cost,
+                aux_storage
+                    .get_aux(key)
+                    .map_err(|e| Error::StorageError(e.into()))
+            );
 
             cost = OperationCost {
                 seek_count: 1,
@@ -1,21 +1,22 @@
 use std::collections::BTreeSet;
 
 use costs::{cost_return_on_error, CostResult, CostsExt, OperationCost};
-use storage::StorageContext;
+use storage::{Storage, StorageContext};
 
 use crate::{
     batch::{GroveDbOp, Op},
-    util::{merk_optional_tx, storage_context_optional_tx},
+    subtree::PathKeyElementTrio,
+    util::merk_optional_tx,
     Element, Error, GroveDb, TransactionArg,
 };
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     pub fn delete_up_tree_while_empty<'p, P>(
         &self,
         path: P,
         key: &'p [u8],
         stop_path_height: Option<u16>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<u16, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -57,7 +58,7 @@ impl GroveDb {
         stop_path_height: Option<u16>,
         validate: bool,
         mut current_batch_operations: Vec<GroveDbOp>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Option<Vec<GroveDbOp>>, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -80,7 +81,7 @@ impl GroveDb {
         stop_path_height: Option<u16>,
         validate: bool,
         current_batch_operations: &mut Vec<GroveDbOp>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Option<Vec<GroveDbOp>>, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -138,7 +139,7 @@ impl GroveDb {
         &self,
         path: P,
         key: &'p [u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -152,7 +153,7 @@ impl GroveDb {
         &self,
         path: P,
         key: &'p [u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<bool, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -168,7 +169,7 @@ impl GroveDb {
         only_delete_tree_if_empty: bool,
         validate: bool,
         current_batch_operations: &[GroveDbOp],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Option<GroveDbOp>, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -177,84 +178,76 @@ impl GroveDb {
         let mut cost = OperationCost::default();
 
         let path_iter = path.into_iter();
-        if path_iter.len() == 0 {
-            // Attempt to delete a root tree leaf
-            Err(Error::InvalidPath(
-                "root tree leaves currently cannot be deleted",
-            ))
-            .wrap_with_cost(cost)
-        } else {
-            if validate {
-                cost_return_on_error!(
-                    &mut cost,
-                    self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
-                );
-            }
-            let element = cost_return_on_error!(
+        if validate {
+            cost_return_on_error!(
                 &mut cost,
-                self.get_raw(path_iter.clone(), key.as_ref(), transaction)
+                self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
             );
+        }
+        let element = cost_return_on_error!(
+            &mut cost,
+            self.get_raw(path_iter.clone(), key.as_ref(), transaction)
+        );
 
-            if let Element::Tree(..) = element {
-                let subtree_merk_path = path_iter.clone().chain(std::iter::once(key));
-                let subtree_merk_path_vec = subtree_merk_path
-                    .clone()
-                    .map(|x| x.to_vec())
-                    .collect::<Vec<Vec<u8>>>();
-                // TODO: may be a bug
-                let _subtrees_paths = cost_return_on_error!(
-                    &mut cost,
-                    self.find_subtrees(subtree_merk_path.clone(), transaction)
-                );
-                let batch_deleted_keys = current_batch_operations
-                    .iter()
-                    .filter_map(|op| match op.op {
-                        Op::Delete => {
-                            if op.path == subtree_merk_path_vec {
-                                Some(op.key.as_slice())
-                            } else {
-                                None
-                            }
+        if element.is_tree() {
+            let subtree_merk_path = path_iter.clone().chain(std::iter::once(key));
+            let subtree_merk_path_vec = subtree_merk_path
+                .clone()
+                .map(|x| x.to_vec())
+                .collect::<Vec<Vec<u8>>>();
+            // TODO: may be a bug
+            let _subtrees_paths = cost_return_on_error!(
+                &mut cost,
+                self.find_subtrees(subtree_merk_path.clone(), transaction)
+            );
+            let batch_deleted_keys = current_batch_operations
+                .iter()
+                .filter_map(|op| match op.op {
+                    Op::Delete | Op::DeleteTree | Op::DeleteIfExists => {
+                        if op.path == subtree_merk_path_vec {
+                            Some(op.key.as_slice())
+                        } else {
+                            None
                         }
-                        _ => None,
-                    })
-                    .collect::<BTreeSet<&[u8]>>();
-                let mut is_empty = merk_optional_tx!(
-                    &mut cost,
-                    self.db,
-                    subtree_merk_path,
-                    transaction,
-                    subtree,
-                    { subtree.is_empty_tree_except(batch_deleted_keys) }
-                );
+                    }
+                    _ => None,
+                })
+                .collect::<BTreeSet<&[u8]>>();
+            let mut is_empty = merk_optional_tx!(
+                &mut cost,
+                self.db,
+                subtree_merk_path,
+                transaction,
+                subtree,
+                { subtree.is_empty_tree_except(batch_deleted_keys) }
+            );
 
-                // If there is any current batch operation that is inserting something in this
-                // tree then it is not empty either
-                is_empty &= !current_batch_operations.iter().any(|op| match op.op {
-                    Op::Delete => false,
-                    _ => op.path == subtree_merk_path_vec,
-                });
+            // If there is any current batch operation that is inserting something in this
+            // tree then it is not empty either
+            is_empty &= !current_batch_operations.iter().any(|op| match op.op {
+                Op::Delete | Op::DeleteTree | Op::DeleteIfExists | Op::AssertEquals { .. } => false,
+                _ => op.path == subtree_merk_path_vec,
+            });
 
-                let result = if only_delete_tree_if_empty && !is_empty {
-                    Ok(None)
-                } else if is_empty {
-                    Ok(Some(GroveDbOp::delete(
-                        path_iter.map(|x| x.to_vec()).collect(),
-                        key.to_vec(),
-                    )))
-                } else {
-                    Err(Error::NotSupported(
-                        "deletion operation for non empty tree not currently supported",
-                    ))
-                };
-                result.wrap_with_cost(cost)
-            } else {
+            let result = if only_delete_tree_if_empty && !is_empty {
+                Ok(None)
+            } else if is_empty {
                 Ok(Some(GroveDbOp::delete(
                     path_iter.map(|x| x.to_vec()).collect(),
                     key.to_vec(),
                 )))
-                .wrap_with_cost(cost)
-            }
+            } else {
+                Err(Error::NotSupported(
+                    "deletion operation for non empty tree not currently supported",
+                ))
+            };
+            result.wrap_with_cost(cost)
+        } else {
+            Ok(Some(GroveDbOp::delete(
+                path_iter.map(|x| x.to_vec()).collect(),
+                key.to_vec(),
+            )))
+            .wrap_with_cost(cost)
         }
     }
 
@@ -263,7 +256,7 @@ impl GroveDb {
         path: P,
         key: &'p [u8],
         only_delete_tree_if_empty: bool,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<bool, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -272,78 +265,62 @@ impl GroveDb {
         let mut cost = OperationCost::default();
 
         let path_iter = path.into_iter();
-        if path_iter.len() == 0 {
-            // Attempt to delete a root tree leaf
-            Err(Error::InvalidPath(
-                "root tree leaves currently cannot be deleted",
-            ))
-            .wrap_with_cost(cost)
-        } else {
-            cost_return_on_error!(
+        cost_return_on_error!(
+            &mut cost,
+            self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
+        );
+        let element = cost_return_on_error!(
+            &mut cost,
+            self.get_raw(path_iter.clone(), key.as_ref(), transaction)
+        );
+
+        // Elements of nested subtrees, which are gone along with the tree
+        let mut nested_elements = Vec::new();
+        if element.is_tree() {
+            let subtree_merk_path = path_iter.clone().chain(std::iter::once(key));
+            let subtrees_paths = cost_return_on_error!(
                 &mut cost,
-                self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
+                self.find_subtrees(subtree_merk_path.clone(), transaction)
             );
-            let element = cost_return_on_error!(
+            let is_empty = merk_optional_tx!(
                 &mut cost,
-                self.get_raw(path_iter.clone(), key.as_ref(), transaction)
+                self.db,
+                subtree_merk_path,
+                transaction,
+                subtree,
+                { subtree.is_empty_tree() }
             );
 
-            if let Element::Tree(..) = element {
-                let subtree_merk_path = path_iter.clone().chain(std::iter::once(key));
-                let subtrees_paths = cost_return_on_error!(
-                    &mut cost,
-                    self.find_subtrees(subtree_merk_path.clone(), transaction)
-                );
-                let is_empty = merk_optional_tx!(
-                    &mut cost,
-                    self.db,
-                    subtree_merk_path,
-                    transaction,
-                    subtree,
-                    { subtree.is_empty_tree() }
-                );
-
-                if only_delete_tree_if_empty && !is_empty {
-                    return Ok(false).wrap_with_cost(cost);
-                } else {
-                    if !is_empty {
-                        // TODO: dumb traversal should not be tolerated
-                        for subtree_path in subtrees_paths {
-                            merk_optional_tx!(
-                                &mut cost,
-                                self.db,
-                                subtree_path.iter().map(|x| x.as_slice()),
-                                transaction,
-                                mut subtree,
-                                {
-                                    cost_return_on_error!(
-                                        &mut cost,
-                                        subtree.clear().map_err(|e| {
-                                            Error::CorruptedData(format!(
-                                                "unable to cleanup tree from storage: {}",
-                                                e
-                                            ))
-                                        })
-                                    );
-                                }
-                            );
-                        }
-                    }
-                    merk_optional_tx!(
+            if only_delete_tree_if_empty && !is_empty {
+                return Ok(false).wrap_with_cost(cost);
+            } else {
+                if !is_empty {
+                    nested_elements = cost_return_on_error!(
                         &mut cost,
-                        self.db,
-                        path_iter.clone(),
-                        transaction,
-                        mut parent_merk,
-                        {
-                            cost_return_on_error!(
-                                &mut cost,
-                                Element::delete(&mut parent_merk, &key)
-                            );
-                        }
+                        self.find_subtrees_elements(&subtrees_paths, transaction)
                     );
+                    // TODO: dumb traversal should not be tolerated
+                    for subtree_path in subtrees_paths {
+                        merk_optional_tx!(
+                            &mut cost,
+                            self.db,
+                            subtree_path.iter().map(|x| x.as_slice()),
+                            transaction,
+                            mut subtree,
+                            {
+                                cost_return_on_error!(
+                                    &mut cost,
+                                    subtree.clear().map_err(|e| {
+                                        Error::CorruptedData(format!(
+                                            "unable to cleanup tree from storage: {}",
+                                            e
+                                        ))
+                                    })
+                                );
+                            }
+                        );
+                    }
                 }
-            } else {
                 merk_optional_tx!(
                     &mut cost,
                     self.db,
@@ -355,51 +332,145 @@ impl GroveDb {
                     }
                 );
             }
-            cost_return_on_error!(&mut cost, self.propagate_changes(path_iter, transaction));
-            Ok(true).wrap_with_cost(cost)
+        } else {
+            merk_optional_tx!(
+                &mut cost,
+                self.db,
+                path_iter.clone(),
+                transaction,
+                mut parent_merk,
+                {
+                    cost_return_on_error!(&mut cost, Element::delete(&mut parent_merk, &key));
+                }
+            );
+        }
+        cost_return_on_error!(
+            &mut cost,
+            self.propagate_changes(path_iter.clone(), transaction)
+        );
+        let path_vec: Vec<Vec<u8>> = path_iter.map(|x| x.to_vec()).collect();
+        cost_return_on_error!(
+            &mut cost,
+            self.delete_reference_links(&path_vec, key, &element, transaction)
+        );
+        for (nested_path, nested_key, nested_element) in nested_elements {
+            cost_return_on_error!(
+                &mut cost,
+                self.delete_reference_links(
+                    &nested_path,
+                    &nested_key,
+                    &nested_element,
+                    transaction
+                )
+            );
         }
+        Ok(true).wrap_with_cost(cost)
     }
 
-    // TODO: dumb traversal should not be tolerated
     /// Finds keys which are trees for a given subtree recursively.
     /// One element means a key of a `merk`, n > 1 elements mean relative path
     /// for a deeply nested subtree.
     pub(crate) fn find_subtrees<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<Vec<Vec<u8>>>, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
     {
-        let mut cost = OperationCost::default();
+        let path = path.into_iter().map(|x| x.to_vec()).collect();
+        if let Some(tx) = transaction {
+            find_subtrees_in_storage(path, |path: &[Vec<u8>]| {
+                self.db
+                    .get_transactional_storage_context(path.iter().map(|x| x.as_slice()), tx)
+            })
+        } else {
+            find_subtrees_in_storage(path, |path: &[Vec<u8>]| {
+                self.db
+                    .get_storage_context(path.iter().map(|x| x.as_slice()))
+            })
+        }
+    }
+
+    /// Finds elements of given subtrees along with paths of their subtrees
+    pub(crate) fn find_subtrees_elements(
+        &self,
+        subtrees_paths: &[Vec<Vec<u8>>],
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<PathKeyElementTrio>, Error> {
+        if let Some(tx) = transaction {
+            find_subtrees_elements_in_storage(subtrees_paths, |path: &[Vec<u8>]| {
+                self.db
+                    .get_transactional_storage_context(path.iter().map(|x| x.as_slice()), tx)
+            })
+        } else {
+            find_subtrees_elements_in_storage(subtrees_paths, |path: &[Vec<u8>]| {
+                self.db
+                    .get_storage_context(path.iter().map(|x| x.as_slice()))
+            })
+        }
+    }
+}
 
-        // TODO: remove conversion to vec;
-        // However, it's not easy for a reason:
-        // new keys to enqueue are taken from raw iterator which returns Vec<u8>;
-        // changing that to slice is hard as cursor should be moved for next iteration
-        // which requires exclusive (&mut) reference, also there is no guarantee that
-        // slice which points into storage internals will remain valid if raw iterator
-        // got altered so why that reference should be exclusive;
+// TODO: dumb traversal should not be tolerated
+/// Finds paths of subtrees nested in the subtree at `path`, the path itself
+/// included, with storage contexts opened by `get_storage_fn`
+pub(crate) fn find_subtrees_in_storage<'db, C, F>(
+    path: Vec<Vec<u8>>,
+    get_storage_fn: F,
+) -> CostResult<Vec<Vec<Vec<u8>>>, Error>
+where
+    C: StorageContext<'db>,
+    F: Fn(&[Vec<u8>]) -> C,
+{
+    let mut cost = OperationCost::default();
 
-        let mut queue: Vec<Vec<Vec<u8>>> = vec![path.into_iter().map(|x| x.to_vec()).collect()];
-        let mut result: Vec<Vec<Vec<u8>>> = queue.clone();
+    // TODO: remove conversion to vec;
+    // However, it's not easy for a reason:
+    // new keys to enqueue are taken from raw iterator which returns Vec<u8>;
+    // changing that to slice is hard as cursor should be moved for next iteration
+    // which requires exclusive (&mut) reference, also there is no guarantee that
+    // slice which points into storage internals will remain valid if raw iterator
+    // got altered so why that reference should be exclusive;
 
-        while let Some(q) = queue.pop() {
-            // Get the correct subtree with q_ref as path
-            let path_iter = q.iter().map(|x| x.as_slice());
-            storage_context_optional_tx!(self.db, path_iter.clone(), transaction, storage, {
-                let mut raw_iter = Element::iterator(storage.raw_iter()).unwrap_add_cost(&mut cost);
-                while let Some((key, value)) = cost_return_on_error!(&mut cost, raw_iter.next()) {
-                    if let Element::Tree(..) = value {
-                        let mut sub_path = q.clone();
-                        sub_path.push(key.to_vec());
-                        queue.push(sub_path.clone());
-                        result.push(sub_path);
-                    }
-                }
-            })
+    let mut queue: Vec<Vec<Vec<u8>>> = vec![path];
+    let mut result: Vec<Vec<Vec<u8>>> = queue.clone();
+
+    while let Some(q) = queue.pop() {
+        // Get the correct subtree with q_ref as path
+        let storage = get_storage_fn(&q);
+        let mut raw_iter = Element::iterator(storage.raw_iter()).unwrap_add_cost(&mut cost);
+        while let Some((key, value)) = cost_return_on_error!(&mut cost, raw_iter.next()) {
+            if value.is_tree() {
+                let mut sub_path = q.clone();
+                sub_path.push(key.to_vec());
+                queue.push(sub_path.clone());
+                result.push(sub_path);
+            }
+        }
+    }
+    Ok(result).wrap_with_cost(cost)
+}
+
+/// Finds elements of given subtrees along with paths of their subtrees, with
+/// storage contexts opened by `get_storage_fn`
+pub(crate) fn find_subtrees_elements_in_storage<'db, C, F>(
+    subtrees_paths: &[Vec<Vec<u8>>],
+    get_storage_fn: F,
+) -> CostResult<Vec<PathKeyElementTrio>, Error>
+where
+    C: StorageContext<'db>,
+    F: Fn(&[Vec<u8>]) -> C,
+{
+    let mut cost = OperationCost::default();
+
+    let mut result = Vec::new();
+    for subtree_path in subtrees_paths {
+        let storage = get_storage_fn(subtree_path);
+        let mut raw_iter = Element::iterator(storage.raw_iter()).unwrap_add_cost(&mut cost);
+        while let Some((key, element)) = cost_return_on_error!(&mut cost, raw_iter.next()) {
+            result.push((subtree_path.clone(), key, element));
         }
-        Ok(result).wrap_with_cost(cost)
     }
+    Ok(result).wrap_with_cost(cost)
 }
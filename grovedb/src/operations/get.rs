@@ -1,25 +1,27 @@
-use std::collections::HashSet;
-
 use costs::{
     cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
 };
-use storage::StorageContext;
+use storage::{Storage, StorageContext};
 
 use crate::{
     subtree::KeyElementPair,
-    util::{merk_optional_tx, meta_storage_context_optional_tx, storage_context_optional_tx},
-    Element, Error, GroveDb, PathQuery, TransactionArg,
+    util::{
+        check_cost_limit, merk_optional_tx, storage_context_optional_tx, with_total_cost_on_limit,
+    },
+    Element, Error, GroveDb, PathQuery, ReferenceChain, ReferencePathType, TransactionArg,
+    TreeType,
 };
 
-/// Limit of possible indirections
+/// Default limit of possible indirections, used for references without a
+/// limit of their own
 pub const MAX_REFERENCE_HOPS: usize = 10;
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     pub fn get<'p, P>(
         &self,
         path: P,
         key: &'p [u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Element, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -27,45 +29,107 @@ impl GroveDb {
     {
         let mut cost = OperationCost::default();
 
-        match cost_return_on_error!(&mut cost, self.get_raw(path, key, transaction)) {
-            Element::Reference(reference_path, _) => self
-                .follow_reference(reference_path, transaction)
-                .add_cost(cost),
-            other => Ok(other).wrap_with_cost(cost),
+        let path_iter = path.into_iter();
+        let element =
+            cost_return_on_error!(&mut cost, self.get_raw(path_iter.clone(), key, transaction));
+        if let Some(max_hops) = element.max_reference_hops() {
+            let mut full_path: Vec<Vec<u8>> = path_iter.map(|x| x.to_vec()).collect();
+            let reference_path = cost_return_on_error_no_add!(
+                &cost,
+                element.absolute_reference_path(&full_path, key)
+            )
+            .expect("element is a reference");
+            full_path.push(key.to_vec());
+            self.follow_reference_chain(
+                vec![full_path],
+                reference_path,
+                max_hops,
+                None,
+                transaction,
+            )
+            .add_cost(cost)
+        } else {
+            Ok(element).wrap_with_cost(cost)
         }
     }
 
+    /// Follows references starting from an element at `path` until an item
+    /// is reached, taking up to `MAX_REFERENCE_HOPS` hops
     pub fn follow_reference(
         &self,
+        path: Vec<Vec<u8>>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Element, Error> {
+        self.follow_reference_with_cost_limit(path, None, transaction)
+    }
+
+    /// Follows references the way `follow_reference` does, but stops with
+    /// `CostLimitExceeded` once the cost of the hops taken goes over
+    /// `cost_limit`, if any
+    pub fn follow_reference_with_cost_limit(
+        &self,
+        path: Vec<Vec<u8>>,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Element, Error> {
+        self.follow_reference_chain(
+            Vec::new(),
+            path,
+            MAX_REFERENCE_HOPS,
+            cost_limit,
+            transaction,
+        )
+    }
+
+    /// Follows references starting from an element at `path`, taking up to
+    /// `max_hops` hops or fewer if a reference met on the way has a lower
+    /// limit of its own. `chain` holds full paths of references which led to
+    /// `path`, so a cycle through any of them is detected even if the last
+    /// one is not written yet.
+    pub(crate) fn follow_reference_chain(
+        &self,
+        mut chain: ReferenceChain,
         mut path: Vec<Vec<u8>>,
-        transaction: TransactionArg,
+        mut max_hops: usize,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Element, Error> {
         let mut cost = OperationCost::default();
 
-        let mut hops_left = MAX_REFERENCE_HOPS;
-        let mut current_element;
-        let mut visited = HashSet::new();
-
-        while hops_left > 0 {
-            if visited.contains(&path) {
-                return Err(Error::CyclicReference).wrap_with_cost(cost);
+        let mut hops = 0;
+        loop {
+            if chain.contains(&path) {
+                chain.push(path);
+                return Err(Error::CyclicReference(chain)).wrap_with_cost(cost);
             }
-            if let Some((key, path_slice)) = path.split_last() {
-                current_element = cost_return_on_error!(
-                    &mut cost,
-                    self.get_raw(path_slice.iter().map(|x| x.as_slice()), key, transaction)
-                )
-            } else {
-                return Err(Error::CorruptedPath("empty path")).wrap_with_cost(cost);
+            if hops >= max_hops {
+                chain.push(path);
+                return Err(Error::ReferenceLimit(chain)).wrap_with_cost(cost);
             }
-            visited.insert(path);
-            match current_element {
-                Element::Reference(reference_path, _) => path = reference_path,
-                other => return Ok(other).wrap_with_cost(cost),
+            let (key, path_slice) = cost_return_on_error_no_add!(
+                &cost,
+                path.split_last().ok_or(Error::CorruptedPath("empty path"))
+            );
+            let element = cost_return_on_error!(
+                &mut cost,
+                self.get_raw(path_slice.iter().map(|x| x.as_slice()), key, transaction)
+            );
+            cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
+            hops += 1;
+            // Relative references are resolved against the path they're stored at
+            let next_path = cost_return_on_error_no_add!(
+                &cost,
+                element.absolute_reference_path(path_slice, key)
+            );
+            chain.push(path);
+            match (next_path, element.max_reference_hops()) {
+                (Some(next_path), Some(element_max_hops)) => {
+                    max_hops = max_hops.min(hops + element_max_hops);
+                    path = next_path;
+                }
+                _ => return Ok(element).wrap_with_cost(cost),
             }
-            hops_left -= 1;
         }
-        Err(Error::ReferenceLimit).wrap_with_cost(cost)
     }
 
     /// Get tree item without following references
@@ -73,7 +137,7 @@ impl GroveDb {
         &self,
         path: P,
         key: &'p [u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Element, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -82,27 +146,13 @@ impl GroveDb {
         let mut cost = OperationCost::default();
 
         let path_iter = path.into_iter();
-        if path_iter.len() == 0 {
-            cost_return_on_error!(
-                &mut cost,
-                self.check_subtree_exists_path_not_found([key], transaction)
-            );
-            merk_optional_tx!(&mut cost, self.db, [key], transaction, subtree, {
-                subtree
-                    .root_hash()
-                    .map(Element::new_tree)
-                    .map(Ok)
-                    .add_cost(cost)
-            })
-        } else {
-            cost_return_on_error!(
-                &mut cost,
-                self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
-            );
-            merk_optional_tx!(&mut cost, self.db, path_iter, transaction, subtree, {
-                Element::get(&subtree, key).add_cost(cost)
-            })
-        }
+        cost_return_on_error!(
+            &mut cost,
+            self.check_subtree_exists_path_not_found(path_iter.clone(), transaction)
+        );
+        merk_optional_tx!(&mut cost, self.db, path_iter, transaction, subtree, {
+            Element::get(&subtree, key).add_cost(cost)
+        })
     }
 
     /// Does tree element exist without following references
@@ -110,7 +160,7 @@ impl GroveDb {
         &self,
         path: P,
         key: &'p [u8],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<bool, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -118,61 +168,34 @@ impl GroveDb {
     {
         let path_iter = path.into_iter();
 
-        if path_iter.len() == 0 {
-            // Root tree's items are serialized into meta storage and cannot be checked
-            // easily; Knowing that root tree's leafs are subtrees only, we can
-            // check them using roots storage.
-            storage_context_optional_tx!(self.db, [key], transaction, storage, {
-                storage
-                    .get_root(merk::ROOT_KEY_KEY)
-                    .wrap_with_cost(Default::default())
-                    .map_err(|e| e.into())
-                    .flat_map_ok(|root| {
-                        root.map(|r| {
-                            Ok(true).wrap_with_cost(OperationCost {
-                                seek_count: 1,
-                                loaded_bytes: r.len() as u32,
-                                ..Default::default()
-                            })
-                        })
-                        .unwrap_or_else(|| {
-                            Ok(false).wrap_with_cost(OperationCost {
-                                seek_count: 1,
-                                ..Default::default()
-                            })
+        // Merk's items should be written into data storage and checked accordingly
+        storage_context_optional_tx!(self.db, path_iter, transaction, storage, {
+            storage
+                .get(key)
+                .wrap_with_cost(Default::default())
+                .map_err(|e| Error::StorageError(e.into()))
+                .flat_map_ok(|root| {
+                    root.map(|r| {
+                        Ok(true).wrap_with_cost(OperationCost {
+                            seek_count: 1,
+                            loaded_bytes: r.len() as u32,
+                            ..Default::default()
                         })
                     })
-            })
-        } else {
-            // Merk's items should be written into data storage and checked accordingly
-            storage_context_optional_tx!(self.db, path_iter, transaction, storage, {
-                storage
-                    .get(key)
-                    .wrap_with_cost(Default::default())
-                    .map_err(|e| e.into())
-                    .flat_map_ok(|root| {
-                        root.map(|r| {
-                            Ok(true).wrap_with_cost(OperationCost {
-                                seek_count: 1,
-                                loaded_bytes: r.len() as u32,
-                                ..Default::default()
-                            })
-                        })
-                        .unwrap_or_else(|| {
-                            Ok(false).wrap_with_cost(OperationCost {
-                                seek_count: 1,
-                                ..Default::default()
-                            })
+                    .unwrap_or_else(|| {
+                        Ok(false).wrap_with_cost(OperationCost {
+                            seek_count: 1,
+                            ..Default::default()
                         })
                     })
-            })
-        }
+                })
+        })
     }
 
     pub fn query_many(
         &self,
         path_queries: &[&PathQuery],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<Vec<u8>>, Error> {
         let mut cost = OperationCost::default();
 
@@ -181,9 +204,22 @@ impl GroveDb {
         let results_wrapped = elements
             .into_iter()
             .map(|(_, element)| match element {
-                Element::Reference(reference_path, _) => {
+                Element::Reference(..)
+                | Element::TypedReference(ReferencePathType::AbsolutePathReference(_), ..) => {
+                    let max_hops = element
+                        .max_reference_hops()
+                        .expect("element is a reference");
+                    let reference_path = element
+                        .absolute_reference_path::<&[u8]>(&[], &[])?
+                        .expect("element is a reference");
                     let maybe_item = self
-                        .follow_reference(reference_path, transaction)
+                        .follow_reference_chain(
+                            Vec::new(),
+                            reference_path,
+                            max_hops,
+                            None,
+                            transaction,
+                        )
                         .unwrap_add_cost(&mut cost)?;
                     if let Element::Item(item, _) = maybe_item {
                         Ok(item)
@@ -203,7 +239,7 @@ impl GroveDb {
     pub fn query_many_raw(
         &self,
         path_queries: &[&PathQuery],
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<KeyElementPair>, Error> {
         let mut cost = OperationCost::default();
 
@@ -215,22 +251,15 @@ impl GroveDb {
     pub fn get_proved_path_query(
         &self,
         path_query: &PathQuery,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<u8>, Error> {
-        if transaction.is_some() {
-            Err(Error::NotSupported(
-                "transactions are not currently supported",
-            ))
-            .wrap_with_cost(Default::default())
-        } else {
-            self.prove_query(path_query)
-        }
+        self.prove_query(path_query, transaction)
     }
 
     pub fn query(
         &self,
         path_query: &PathQuery,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(Vec<Vec<u8>>, u16), Error> {
         let mut cost = OperationCost::default();
 
@@ -240,12 +269,25 @@ impl GroveDb {
         let results_wrapped = elements
             .into_iter()
             .map(|(_, element)| match element {
-                Element::Reference(reference_path, _) => {
+                Element::Reference(..)
+                | Element::TypedReference(ReferencePathType::AbsolutePathReference(_), ..) => {
+                    let max_hops = element
+                        .max_reference_hops()
+                        .expect("element is a reference");
+                    let reference_path = element
+                        .absolute_reference_path::<&[u8]>(&[], &[])?
+                        .expect("element is a reference");
                     // While `map` on iterator is lazy, we should accumulate costs even if `collect`
                     // will end in `Err`, so we'll use external costs accumulator instead of
                     // returning costs from `map` call.
                     let maybe_item = self
-                        .follow_reference(reference_path, transaction)
+                        .follow_reference_chain(
+                            Vec::new(),
+                            reference_path,
+                            max_hops,
+                            None,
+                            transaction,
+                        )
                         .unwrap_add_cost(&mut cost)?;
 
                     if let Element::Item(item, _) = maybe_item {
@@ -255,7 +297,13 @@ impl GroveDb {
                     }
                 }
                 Element::Item(item, _) => Ok(item),
-                Element::Tree(..) => Err(Error::InvalidQuery(
+                Element::TypedReference(..) => Err(Error::CorruptedCodeExecution(
+                    "relative references must be made absolute by a raw query",
+                )),
+                Element::Tree(..)
+                | Element::SumItem(..)
+                | Element::SumTree(..)
+                | Element::CountTree(..) => Err(Error::InvalidQuery(
                     "path_queries can only refer to items and references",
                 )),
             })
@@ -268,20 +316,38 @@ impl GroveDb {
     pub fn query_raw(
         &self,
         path_query: &PathQuery,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
+        self.query_raw_with_cost_limit(path_query, None, transaction)
+    }
+
+    /// Queries elements without following references the way `query_raw`
+    /// does, but stops with `CostLimitExceeded` once the cost of the query
+    /// goes over `cost_limit`, if any
+    pub fn query_raw_with_cost_limit(
+        &self,
+        path_query: &PathQuery,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
         let path_slices = path_query
             .path
             .iter()
             .map(|x| x.as_slice())
             .collect::<Vec<_>>();
-        Element::get_path_query(&self.db, &path_slices, path_query, transaction)
+        with_total_cost_on_limit(Element::get_path_query(
+            &self.db,
+            &path_slices,
+            path_query,
+            cost_limit,
+            transaction,
+        ))
     }
 
     fn check_subtree_exists<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
         error: Error,
     ) -> CostResult<(), Error>
     where
@@ -290,40 +356,79 @@ impl GroveDb {
     {
         let mut cost = OperationCost::default();
 
-        let mut path_iter = path.into_iter();
+        let path_iter = path.into_iter();
         if path_iter.len() == 0 {
             return Ok(()).wrap_with_cost(cost);
         }
-        if path_iter.len() == 1 {
-            meta_storage_context_optional_tx!(self.db, transaction, meta_storage, {
-                let root_leaf_keys = cost_return_on_error!(
-                    &mut cost,
-                    Self::get_root_leaf_keys_internal(&meta_storage)
-                );
-                if !root_leaf_keys.contains_key(path_iter.next().expect("must contain an item")) {
-                    return Err(error).wrap_with_cost(cost);
-                }
-            });
-        } else {
-            let mut parent_iter = path_iter;
-            let parent_key = parent_iter.next_back().expect("path is not empty");
-            merk_optional_tx!(&mut cost, self.db, parent_iter, transaction, parent, {
-                match Element::get(&parent, parent_key).unwrap_add_cost(&mut cost) {
-                    Ok(Element::Tree(..)) => {}
-                    Ok(_) | Err(Error::PathKeyNotFound(_)) => {
-                        return Err(error).wrap_with_cost(cost)
-                    }
-                    Err(e) => return Err(e).wrap_with_cost(cost),
-                }
-            });
-        }
+
+        let mut parent_iter = path_iter;
+        let parent_key = parent_iter.next_back().expect("path is not empty");
+        merk_optional_tx!(&mut cost, self.db, parent_iter, transaction, parent, {
+            match Element::get(&parent, parent_key).unwrap_add_cost(&mut cost) {
+                Ok(element) if element.is_tree() => {}
+                Ok(_) | Err(Error::PathKeyNotFound(_)) => return Err(error).wrap_with_cost(cost),
+                Err(e) => return Err(e).wrap_with_cost(cost),
+            }
+        });
         Ok(()).wrap_with_cost(cost)
     }
 
+    /// Kind of the subtree at `path`, which is told by its element in the
+    /// parent tree. The root tree is always a regular tree.
+    pub(crate) fn tree_type<'p, P>(
+        &self,
+        path: P,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<TreeType, Error>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        let mut cost = OperationCost::default();
+
+        let mut parent_iter = path.into_iter();
+        let parent_key = match parent_iter.next_back() {
+            Some(key) => key,
+            None => return Ok(TreeType::NormalTree).wrap_with_cost(cost),
+        };
+        let element = merk_optional_tx!(&mut cost, self.db, parent_iter, transaction, parent, {
+            cost_return_on_error!(&mut cost, Element::get(&parent, parent_key))
+        });
+        Ok(element.tree_type().unwrap_or_default()).wrap_with_cost(cost)
+    }
+
+    /// Returns the number of elements in the count tree at `path`, which is
+    /// maintained by its Merk and kept in the tree element in its parent, so
+    /// no elements of the count tree are loaded
+    pub fn count<'p, P>(&self, path: P, transaction: TransactionArg<S>) -> CostResult<u64, Error>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        let mut cost = OperationCost::default();
+
+        let mut parent_iter = path.into_iter();
+        let parent_key = match parent_iter.next_back() {
+            Some(key) => key,
+            None => {
+                return Err(Error::InvalidPath("the root tree is not a count tree"))
+                    .wrap_with_cost(cost)
+            }
+        };
+        match cost_return_on_error!(
+            &mut cost,
+            self.get_raw(parent_iter, parent_key, transaction)
+        ) {
+            Element::CountTree(_, count, _) => Ok(count).wrap_with_cost(cost),
+            _ => Err(Error::InvalidPath("the path doesn't lead to a count tree"))
+                .wrap_with_cost(cost),
+        }
+    }
+
     pub fn check_subtree_exists_path_not_found<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -339,7 +444,7 @@ impl GroveDb {
     pub fn check_subtree_exists_invalid_path<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -1,21 +1,18 @@
 use costs::{
     cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
 };
-use merk::{Merk, ROOT_KEY_KEY};
+use merk::Merk;
 use storage::{Storage, StorageContext};
 
-use crate::{
-    util::{merk_optional_tx, meta_storage_context_optional_tx, storage_context_optional_tx},
-    Element, Error, GroveDb, TransactionArg, ROOT_LEAFS_SERIALIZED_KEY,
-};
+use crate::{util::merk_optional_tx, Element, Error, GroveDb, TransactionArg};
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     pub fn insert<'p, P>(
         &self,
         path: P,
         key: &'p [u8],
         element: Element,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -24,37 +21,64 @@ impl GroveDb {
         let mut cost = OperationCost::default();
 
         let path_iter = path.into_iter();
+        let path_vec: Vec<Vec<u8>> = path_iter.clone().map(|x| x.to_vec()).collect();
+        let previous_element = cost_return_on_error!(
+            &mut cost,
+            self.get_raw_optional(&path_vec, key, transaction)
+        );
+        let new_element = element.clone();
 
         match element {
-            Element::Tree(..) => {
-                if path_iter.len() == 0 {
-                    cost_return_on_error!(&mut cost, self.add_root_leaf(key, transaction));
-                } else {
-                    cost_return_on_error!(
-                        &mut cost,
-                        self.add_non_root_subtree(path_iter.clone(), key, element, transaction)
-                    );
-                    cost_return_on_error!(
-                        &mut cost,
-                        self.propagate_changes(path_iter, transaction)
-                    );
-                }
+            Element::Tree(..) | Element::SumTree(..) | Element::CountTree(..) => {
+                cost_return_on_error!(
+                    &mut cost,
+                    self.add_subtree(path_iter.clone(), key, element, transaction)
+                );
+                cost_return_on_error!(&mut cost, self.propagate_changes(path_iter, transaction));
             }
-            Element::Reference(ref reference_path, _) => {
+            Element::Reference(..) | Element::TypedReference(..) => {
                 if path_iter.len() == 0 {
                     return Err(Error::InvalidPath(
                         "only subtrees are allowed as root tree's leafs",
                     ))
                     .wrap_with_cost(cost);
                 }
+                let reference_path = cost_return_on_error_no_add!(
+                    &cost,
+                    element.absolute_reference_path(&path_vec, key)
+                )
+                .expect("element is a reference");
+                let max_hops = element
+                    .max_reference_hops()
+                    .expect("element is a reference");
+                if max_hops == 0 {
+                    return Err(Error::InvalidInput(
+                        "reference must be allowed to take at least one hop",
+                    ))
+                    .wrap_with_cost(cost);
+                }
 
                 cost_return_on_error!(
                     &mut cost,
                     self.check_subtree_exists_invalid_path(path_iter.clone(), transaction)
                 );
+                // The reference itself starts the chain, so the reference is not written
+                // if it would close a cycle
+                let mut full_path = path_vec.clone();
+                full_path.push(key.to_vec());
                 let referenced_element = cost_return_on_error!(
                     &mut cost,
-                    self.follow_reference(reference_path.to_owned(), transaction)
+                    self.follow_reference_chain(
+                        vec![full_path],
+                        reference_path,
+                        max_hops,
+                        None,
+                        transaction
+                    )
+                );
+                let tree_type = cost_return_on_error!(
+                    &mut cost,
+                    self.tree_type(path_iter.clone(), transaction)
                 );
 
                 merk_optional_tx!(
@@ -68,7 +92,7 @@ impl GroveDb {
                             cost_return_on_error_no_add!(&cost, referenced_element.serialize());
                         cost_return_on_error!(
                             &mut cost,
-                            element.insert_reference(&mut subtree, key, serialized)
+                            element.insert_reference(&mut subtree, key, serialized, tree_type)
                         );
                     }
                 );
@@ -88,6 +112,10 @@ impl GroveDb {
                     &mut cost,
                     self.check_subtree_exists_invalid_path(path_iter.clone(), transaction)
                 );
+                let tree_type = cost_return_on_error!(
+                    &mut cost,
+                    self.tree_type(path_iter.clone(), transaction)
+                );
                 merk_optional_tx!(
                     &mut cost,
                     self.db,
@@ -95,65 +123,41 @@ impl GroveDb {
                     transaction,
                     mut subtree,
                     {
-                        cost_return_on_error!(&mut cost, element.insert(&mut subtree, key));
+                        cost_return_on_error!(
+                            &mut cost,
+                            element.insert(&mut subtree, key, tree_type)
+                        );
                     }
                 );
                 cost_return_on_error!(&mut cost, self.propagate_changes(path_iter, transaction));
             }
         }
+        cost_return_on_error!(
+            &mut cost,
+            self.update_reference_links(
+                &path_vec,
+                key,
+                previous_element.as_ref(),
+                &new_element,
+                transaction
+            )
+        );
 
         Ok(()).wrap_with_cost(cost)
     }
 
-    /// Add subtree to the root tree
-    fn add_root_leaf(&self, key: &[u8], transaction: TransactionArg) -> CostResult<(), Error> {
-        let mut cost = OperationCost::default();
-        meta_storage_context_optional_tx!(self.db, transaction, meta_storage, {
-            let mut root_leaf_keys =
-                cost_return_on_error!(&mut cost, Self::get_root_leaf_keys_internal(&meta_storage));
-            if root_leaf_keys.get(&key.to_vec()).is_none() {
-                root_leaf_keys.insert(key.to_vec(), root_leaf_keys.len());
-            }
-            let value = cost_return_on_error_no_add!(
-                &cost,
-                bincode::serialize(&root_leaf_keys).map_err(|_| {
-                    Error::CorruptedData(String::from("unable to serialize root leaves data"))
-                })
-            );
-
-            cost_return_on_error_no_add!(
-                &cost,
-                meta_storage
-                    .put_meta(ROOT_LEAFS_SERIALIZED_KEY, &value)
-                    .map_err(|e| e.into())
-            );
-            cost.storage_written_bytes +=
-                ROOT_LEAFS_SERIALIZED_KEY.len() as u32 + value.len() as u32;
-        });
-
-        // Persist root leaf as a regular subtree
-        storage_context_optional_tx!(self.db, [key], transaction, storage, {
-            cost_return_on_error_no_add!(
-                &cost,
-                storage.put_root(ROOT_KEY_KEY, key).map_err(|e| e.into())
-            );
-            cost.storage_written_bytes += ROOT_KEY_KEY.len() as u32 + key.len() as u32
-        });
-
-        Ok(()).wrap_with_cost(cost)
-    }
-
-    /// Add subtree to another subtree.
+    /// Add subtree to another subtree (the root tree is a subtree with an
+    /// empty path).
     /// We want to add a new empty merk to another merk at a key
     /// first make sure other merk exist
     /// if it exists, then create merk to be inserted, and get root hash
     /// we only care about root hash of merk to be inserted
-    fn add_non_root_subtree<'p, P>(
+    fn add_subtree<'p, P>(
         &self,
         path: P,
         key: &'p [u8],
         element: Element,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
@@ -161,11 +165,12 @@ impl GroveDb {
     {
         let mut cost = OperationCost::default();
 
-        let element_flag = cost_return_on_error_no_add!(
+        cost_return_on_error_no_add!(
             &cost,
-            match element {
-                Element::Tree(_, flag) => Ok(flag),
-                _ => Err(Error::CorruptedData("element should be a tree".to_owned())),
+            if element.is_tree() {
+                Ok(())
+            } else {
+                Err(Error::CorruptedData("element should be a tree".to_owned()))
             }
         );
         let path_iter = path.into_iter();
@@ -173,6 +178,8 @@ impl GroveDb {
             &mut cost,
             self.check_subtree_exists_invalid_path(path_iter.clone(), transaction)
         );
+        let tree_type =
+            cost_return_on_error!(&mut cost, self.tree_type(path_iter.clone(), transaction));
 
         if let Some(tx) = transaction {
             let parent_storage = self
@@ -191,11 +198,11 @@ impl GroveDb {
                 Merk::open(child_storage)
                     .map_err(|_| crate::Error::CorruptedData("cannot open a subtree".to_owned()))
             );
-            let element = Element::new_tree_with_flags(
-                child_subtree.root_hash().unwrap_add_cost(&mut cost),
-                element_flag,
+            let element = Self::tree_element_for_subtree(element, &child_subtree, &mut cost);
+            cost_return_on_error!(
+                &mut cost,
+                element.insert(&mut parent_subtree, key, tree_type)
             );
-            cost_return_on_error!(&mut cost, element.insert(&mut parent_subtree, key));
         } else {
             let parent_storage = self.db.get_storage_context(path_iter.clone());
             let mut parent_subtree = cost_return_on_error!(
@@ -211,21 +218,34 @@ impl GroveDb {
                 Merk::open(child_storage)
                     .map_err(|_| crate::Error::CorruptedData("cannot open a subtree".to_owned()))
             );
-            let element = Element::new_tree_with_flags(
-                child_subtree.root_hash().unwrap_add_cost(&mut cost),
-                element_flag,
+            let element = Self::tree_element_for_subtree(element, &child_subtree, &mut cost);
+            cost_return_on_error!(
+                &mut cost,
+                element.insert(&mut parent_subtree, key, tree_type)
             );
-            cost_return_on_error!(&mut cost, element.insert(&mut parent_subtree, key));
         }
         Ok(()).wrap_with_cost(cost)
     }
 
+    /// Builds a tree element of the same kind and flags as `element` to
+    /// represent `subtree` in its parent
+    fn tree_element_for_subtree<'db, C: StorageContext<'db>>(
+        element: Element,
+        subtree: &Merk<C>,
+        cost: &mut OperationCost,
+    ) -> Element {
+        let root_hash = subtree.root_hash().unwrap_add_cost(cost);
+        element
+            .with_subtree_root(root_hash, subtree.aggregate_data())
+            .unwrap_or_else(|| Element::new_tree_with_flags(root_hash, element.get_flags().clone()))
+    }
+
     pub fn insert_if_not_exists<'p, P>(
         &self,
         path: P,
         key: &'p [u8],
         element: Element,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<bool, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
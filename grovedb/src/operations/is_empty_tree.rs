@@ -1,12 +1,13 @@
 use costs::{cost_return_on_error, CostResult, CostsExt, OperationCost};
+use storage::Storage;
 
 use crate::{util::merk_optional_tx, Error, GroveDb, TransactionArg};
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     pub fn is_empty_tree<'p, P>(
         &self,
         path: P,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<bool, Error>
     where
         P: IntoIterator<Item = &'p [u8]>,
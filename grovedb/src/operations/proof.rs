@@ -1,3 +1,3 @@
 mod generate;
-mod util;
+pub(crate) mod util;
 mod verify;
@@ -5,26 +5,69 @@ use merk::{
     proofs::{encode_into, Node, Op},
     KVIterator, Merk, ProofWithoutEncodingResult,
 };
-use storage::{rocksdb_storage::PrefixedRocksDbStorageContext, Storage, StorageContext};
+use storage::{Storage, StorageContext};
 
 use crate::{
     operations::proof::util::{write_to_vec, ProofType, EMPTY_TREE_HASH},
     subtree::raw_decode,
-    Element, Error, GroveDb, PathQuery, Query,
+    util::{check_cost_limit, merk_optional_tx, with_total_cost_on_limit},
+    Element, Error, GroveDb, PathQuery, Query, TransactionArg,
 };
 
-impl GroveDb {
-    pub fn prove_query_many(&self, query: Vec<&PathQuery>) -> CostResult<Vec<u8>, Error> {
+impl<S: Storage> GroveDb<S> {
+    pub fn prove_query_many(
+        &self,
+        query: Vec<&PathQuery>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<u8>, Error> {
         let mut cost = OperationCost::default();
         if query.len() > 1 {
             let query = cost_return_on_error!(&mut cost, PathQuery::merge(query));
-            self.prove_query(&query)
+            self.prove_query(&query, transaction)
         } else {
-            self.prove_query(query[0])
+            self.prove_query(query[0], transaction)
         }
     }
 
-    pub fn prove_query(&self, query: &PathQuery) -> CostResult<Vec<u8>, Error> {
+    /// Proves the number of elements in the count tree at `path` by proving
+    /// its tree element in the parent tree, so no elements of the count tree
+    /// are included, see [GroveDb::verify_count]
+    pub fn prove_count(
+        &self,
+        path: Vec<Vec<u8>>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<u8>, Error> {
+        let cost = OperationCost::default();
+        let query = cost_return_on_error_no_add!(&cost, PathQuery::new_tree_element_query(path));
+        self.prove_query(&query, transaction)
+    }
+
+    pub fn prove_query(
+        &self,
+        query: &PathQuery,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<u8>, Error> {
+        self.prove_query_with_cost_limit(query, None, transaction)
+    }
+
+    /// Generates a proof for a path query the way `prove_query` does, but
+    /// stops with `CostLimitExceeded` once the cost of generating it goes over
+    /// `cost_limit`, if any
+    pub fn prove_query_with_cost_limit(
+        &self,
+        query: &PathQuery,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<u8>, Error> {
+        with_total_cost_on_limit(self.prove_query_internal(query, cost_limit, transaction))
+    }
+
+    fn prove_query_internal(
+        &self,
+        query: &PathQuery,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Vec<u8>, Error> {
         let mut cost = OperationCost::default();
 
         // TODO: should it be possible to generate proofs for tree items (currently yes)
@@ -33,17 +76,18 @@ impl GroveDb {
         let mut offset: Option<u16> = query.query.offset;
 
         let path_slices = query.path.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
-        // TODO: get rid of this error once root tree is also of type merk
-        if path_slices.is_empty() {
-            return Err(Error::InvalidPath("can't generate proof for empty path"))
-                .wrap_with_cost(cost);
-        }
 
-        // TODO: should prove that path does not exist, rather than returning an error
-        cost_return_on_error!(
+        // If the path doesn't lead to a subtree then prove its absence instead
+        if let Some(absent_depth) = cost_return_on_error!(
             &mut cost,
-            self.check_subtree_exists_path_not_found(path_slices.clone(), None)
-        );
+            self.find_absent_path_depth(&path_slices, transaction)
+        ) {
+            cost_return_on_error!(
+                &mut cost,
+                self.prove_absent_path(&mut proof_result, path_slices, absent_depth, transaction)
+            );
+            return Ok(proof_result).wrap_with_cost(cost);
+        }
 
         cost_return_on_error!(
             &mut cost,
@@ -53,15 +97,22 @@ impl GroveDb {
                 query,
                 &mut limit,
                 &mut offset,
+                cost_limit.map(|limit| limit.saturating_sub(&cost)).as_ref(),
+                transaction,
             )
         );
-        cost_return_on_error!(&mut cost, self.prove_path(&mut proof_result, path_slices));
+        cost_return_on_error!(
+            &mut cost,
+            self.prove_path(&mut proof_result, path_slices, transaction)
+        );
+        cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
 
         Ok(proof_result).wrap_with_cost(cost)
     }
 
     /// Perform a pre-order traversal of the tree based on the provided
     /// subqueries
+    #[allow(clippy::too_many_arguments)]
     fn prove_subqueries(
         &self,
         proofs: &mut Vec<u8>,
@@ -69,6 +120,8 @@ impl GroveDb {
         query: &PathQuery,
         current_limit: &mut Option<u16>,
         current_offset: &mut Option<u16>,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
@@ -77,137 +130,167 @@ impl GroveDb {
             return Ok(()).wrap_with_cost(cost);
         }
 
-        let subtree = cost_return_on_error!(&mut cost, self.open_subtree(path.iter().copied()));
-        if subtree.root_hash().unwrap_add_cost(&mut cost) == EMPTY_TREE_HASH {
-            write_to_vec(proofs, &[ProofType::EmptyTree.into()]);
-            return Ok(()).wrap_with_cost(cost);
-        }
-
-        let mut is_leaf_tree = true;
+        merk_optional_tx!(
+            &mut cost,
+            self.db,
+            path.iter().copied(),
+            transaction,
+            subtree,
+            {
+                if subtree.root_hash().unwrap_add_cost(&mut cost) == EMPTY_TREE_HASH {
+                    write_to_vec(proofs, &[ProofType::EmptyTree.into()]);
+                    return Ok(()).wrap_with_cost(cost);
+                }
 
-        let kv_iterator = KVIterator::new(subtree.storage.raw_iter(), &query.query.query);
-        for (key, value_bytes) in kv_iterator {
-            let (subquery_key, subquery_value) =
-                Element::subquery_paths_for_sized_query(&query.query, &key);
+                let mut is_leaf_tree = true;
 
-            if subquery_value.is_none() && subquery_key.is_none() {
-                continue;
-            }
+                let kv_iterator = KVIterator::new(subtree.storage.raw_iter(), &query.query.query);
+                for (key, value_bytes) in kv_iterator {
+                    let (subquery_key, subquery_value) =
+                        Element::subquery_paths_for_sized_query(&query.query, &key);
 
-            let element = cost_return_on_error_no_add!(&cost, raw_decode(&value_bytes));
-            match element {
-                Element::Tree(tree_hash, _) => {
-                    if tree_hash == EMPTY_TREE_HASH {
+                    if subquery_value.is_none() && subquery_key.is_none() {
                         continue;
                     }
 
-                    // if the element is a non empty tree then current tree is not a leaf tree
-                    if is_leaf_tree {
-                        is_leaf_tree = false;
-                        cost_return_on_error!(
-                            &mut cost,
-                            self.generate_and_store_merk_proof(
-                                &subtree,
-                                &query.query.query,
-                                None,
-                                None,
-                                ProofType::Merk,
-                                proofs,
-                            )
-                        );
-                    }
-
-                    let mut new_path = path.clone();
-                    new_path.push(key.as_ref());
-
-                    let mut query = subquery_value;
-
-                    if query.is_some() {
-                        if subquery_key.is_some() {
-                            // prove the subquery key first
-                            let inner_subtree = cost_return_on_error!(
-                                &mut cost,
-                                self.open_subtree(new_path.iter().copied())
-                            );
-
-                            let mut key_as_query = Query::new();
-                            key_as_query.insert_key(subquery_key.clone().unwrap());
+                    let element = cost_return_on_error_no_add!(&cost, raw_decode(&value_bytes));
+                    match element {
+                        Element::Tree(tree_hash, _)
+                        | Element::SumTree(tree_hash, ..)
+                        | Element::CountTree(tree_hash, ..) => {
+                            if tree_hash == EMPTY_TREE_HASH {
+                                continue;
+                            }
+
+                            // if the element is a non empty tree then current tree is not a leaf
+                            // tree
+                            if is_leaf_tree {
+                                is_leaf_tree = false;
+                                cost_return_on_error!(
+                                    &mut cost,
+                                    self.generate_and_store_merk_proof(
+                                        &subtree,
+                                        &path,
+                                        &query.query.query,
+                                        None,
+                                        None,
+                                        ProofType::Merk,
+                                        proofs,
+                                        transaction,
+                                    )
+                                );
+                            }
+
+                            let mut new_path = path.clone();
+                            new_path.push(key.as_ref());
+
+                            let mut query = subquery_value;
+
+                            if query.is_some() {
+                                if subquery_key.is_some() {
+                                    // prove the subquery key first
+                                    let mut key_as_query = Query::new();
+                                    key_as_query.insert_key(subquery_key.clone().unwrap());
+
+                                    merk_optional_tx!(
+                                        &mut cost,
+                                        self.db,
+                                        new_path.iter().copied(),
+                                        transaction,
+                                        inner_subtree,
+                                        {
+                                            cost_return_on_error!(
+                                                &mut cost,
+                                                self.generate_and_store_merk_proof(
+                                                    &inner_subtree,
+                                                    &new_path,
+                                                    &key_as_query,
+                                                    None,
+                                                    None,
+                                                    ProofType::Merk,
+                                                    proofs,
+                                                    transaction,
+                                                )
+                                            );
+                                        }
+                                    );
+
+                                    new_path.push(subquery_key.as_ref().unwrap());
+                                }
+                            } else {
+                                let mut key_as_query = Query::new();
+                                key_as_query.insert_key(subquery_key.unwrap());
+                                query = Some(key_as_query);
+                            }
+
+                            let new_path_owned = new_path.iter().map(|x| x.to_vec()).collect();
+                            let new_path_query =
+                                PathQuery::new_unsized(new_path_owned, query.unwrap());
+
+                            if self
+                                .check_subtree_exists_path_not_found(new_path.clone(), transaction)
+                                .unwrap_add_cost(&mut cost)
+                                .is_err()
+                            {
+                                continue;
+                            }
 
                             cost_return_on_error!(
                                 &mut cost,
-                                self.generate_and_store_merk_proof(
-                                    &inner_subtree,
-                                    &key_as_query,
-                                    None,
-                                    None,
-                                    ProofType::Merk,
+                                self.prove_subqueries(
                                     proofs,
+                                    new_path,
+                                    &new_path_query,
+                                    current_limit,
+                                    current_offset,
+                                    cost_limit.map(|limit| limit.saturating_sub(&cost)).as_ref(),
+                                    transaction,
                                 )
                             );
+                            cost_return_on_error_no_add!(
+                                &cost,
+                                check_cost_limit(&cost, cost_limit)
+                            );
 
-                            new_path.push(subquery_key.as_ref().unwrap());
+                            if *current_limit == Some(0) {
+                                break;
+                            }
+                        }
+                        _ => {
+                            // currently not handling trees with mixed types
+                            // if a tree has been seen, we should see nothing but tree
+                            if !is_leaf_tree {
+                                return Err(Error::InvalidQuery("mixed tree types"))
+                                    .wrap_with_cost(cost);
+                            }
                         }
-                    } else {
-                        let mut key_as_query = Query::new();
-                        key_as_query.insert_key(subquery_key.unwrap());
-                        query = Some(key_as_query);
-                    }
-
-                    let new_path_owned = new_path.iter().map(|x| x.to_vec()).collect();
-                    let new_path_query = PathQuery::new_unsized(new_path_owned, query.unwrap());
-
-                    if self
-                        .check_subtree_exists_path_not_found(new_path.clone(), None)
-                        .unwrap_add_cost(&mut cost)
-                        .is_err()
-                    {
-                        continue;
                     }
+                }
 
-                    cost_return_on_error!(
+                if is_leaf_tree {
+                    // if no useful subtree, then we care about the result set of this subtree.
+                    // apply the sized query
+                    let limit_offset = cost_return_on_error!(
                         &mut cost,
-                        self.prove_subqueries(
+                        self.generate_and_store_merk_proof(
+                            &subtree,
+                            &path,
+                            &query.query.query,
+                            *current_limit,
+                            *current_offset,
+                            ProofType::SizedMerk,
                             proofs,
-                            new_path,
-                            &new_path_query,
-                            current_limit,
-                            current_offset,
+                            transaction,
                         )
                     );
 
-                    if *current_limit == Some(0) {
-                        break;
-                    }
-                }
-                _ => {
-                    // currently not handling trees with mixed types
-                    // if a tree has been seen, we should see nothing but tree
-                    if !is_leaf_tree {
-                        return Err(Error::InvalidQuery("mixed tree types")).wrap_with_cost(cost);
-                    }
+                    // update limit and offset values
+                    *current_limit = limit_offset.0;
+                    *current_offset = limit_offset.1;
                 }
+                cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
             }
-        }
-
-        if is_leaf_tree {
-            // if no useful subtree, then we care about the result set of this subtree.
-            // apply the sized query
-            let limit_offset = cost_return_on_error!(
-                &mut cost,
-                self.generate_and_store_merk_proof(
-                    &subtree,
-                    &query.query.query,
-                    *current_limit,
-                    *current_offset,
-                    ProofType::SizedMerk,
-                    proofs,
-                )
-            );
-
-            // update limit and offset values
-            *current_limit = limit_offset.0;
-            *current_offset = limit_offset.1;
-        }
+        );
 
         Ok(()).wrap_with_cost(cost)
     }
@@ -216,96 +299,150 @@ impl GroveDb {
     /// a valid path from the root of the db to that point.
     fn prove_path(
         &self,
-        mut proof_result: &mut Vec<u8>,
+        proof_result: &mut Vec<u8>,
         path_slices: Vec<&[u8]>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
         // generate proof to show that the path leads up to the root
         let mut split_path = path_slices.split_last();
         while let Some((key, path_slice)) = split_path {
-            if path_slice.is_empty() {
-                // generate root proof
-                let meta_storage = self.db.get_storage_context(std::iter::empty());
-                let root_leaf_keys = cost_return_on_error!(
-                    &mut cost,
-                    Self::get_root_leaf_keys_internal(&meta_storage)
-                );
-                let mut index_to_prove: Vec<usize> = vec![];
-                match root_leaf_keys.get(&key.to_vec()) {
-                    Some(index) => index_to_prove.push(*index),
-                    None => {
-                        return Err(Error::InvalidPath("invalid root key")).wrap_with_cost(cost)
-                    }
+            let mut query = Query::new();
+            query.insert_key(key.to_vec());
+
+            merk_optional_tx!(
+                &mut cost,
+                self.db,
+                path_slice.iter().copied(),
+                transaction,
+                subtree,
+                {
+                    cost_return_on_error!(
+                        &mut cost,
+                        self.generate_and_store_merk_proof(
+                            &subtree,
+                            path_slice,
+                            &query,
+                            None,
+                            None,
+                            ProofType::Merk,
+                            proof_result,
+                            transaction,
+                        )
+                    );
+                }
+            );
+            split_path = path_slice.split_last();
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Returns the depth of the first path segment which doesn't point to a
+    /// subtree, `None` if the whole path exists.
+    fn find_absent_path_depth(
+        &self,
+        path_slices: &[&[u8]],
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Option<usize>, Error> {
+        let mut cost = OperationCost::default();
+
+        for (depth, key) in path_slices.iter().enumerate() {
+            let element = merk_optional_tx!(
+                &mut cost,
+                self.db,
+                path_slices[..depth].iter().copied(),
+                transaction,
+                parent_tree,
+                { Element::get(&parent_tree, key).unwrap_add_cost(&mut cost) }
+            );
+            match element {
+                Ok(element) if element.is_tree() => continue,
+                Ok(_) | Err(Error::PathKeyNotFound(_)) => {
+                    return Ok(Some(depth)).wrap_with_cost(cost)
                 }
-                let root_tree = cost_return_on_error!(&mut cost, self.get_root_tree(None));
-                let root_proof = root_tree.proof(&index_to_prove).to_bytes();
-
-                write_to_vec(&mut proof_result, &[ProofType::Root.into()]);
-                write_to_vec(&mut proof_result, &root_proof.len().to_be_bytes());
-                write_to_vec(&mut proof_result, &root_proof);
-
-                // write the number of root leafs
-                // this makes the assumption that 1 byte is enough to represent the number of
-                // root leafs i.e max of 255 root leaf keys
-                debug_assert!(root_leaf_keys.len() < 256);
-                write_to_vec(&mut proof_result, &[root_leaf_keys.len() as u8]);
-
-                // add the index values required to prove the root
-                let index_to_prove_as_bytes = index_to_prove
-                    .into_iter()
-                    .map(|index| index as u8)
-                    .collect::<Vec<u8>>();
-
-                write_to_vec(&mut proof_result, &index_to_prove_as_bytes);
-            } else {
-                // generate proofs for the intermediate paths
-                let subtree =
-                    cost_return_on_error!(&mut cost, self.open_subtree(path_slice.iter().copied()));
-                let mut query = Query::new();
-                query.insert_key(key.to_vec());
+                Err(e) => return Err(e).wrap_with_cost(cost),
+            }
+        }
+        Ok(None).wrap_with_cost(cost)
+    }
 
+    /// Given a path which doesn't exist, construct and append a proof that
+    /// the path segment at `absent_depth` is not a subtree, followed by the
+    /// proofs connecting its parent to the root of the db.
+    fn prove_absent_path(
+        &self,
+        proof_result: &mut Vec<u8>,
+        path_slices: Vec<&[u8]>,
+        absent_depth: usize,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        write_to_vec(proof_result, &[ProofType::AbsentPath.into()]);
+        write_to_vec(proof_result, &(absent_depth as u16).to_be_bytes());
+
+        let parent_path = &path_slices[..absent_depth];
+        let mut query = Query::new();
+        query.insert_key(path_slices[absent_depth].to_vec());
+
+        merk_optional_tx!(
+            &mut cost,
+            self.db,
+            parent_path.iter().copied(),
+            transaction,
+            parent_tree,
+            {
                 cost_return_on_error!(
                     &mut cost,
                     self.generate_and_store_merk_proof(
-                        &subtree,
+                        &parent_tree,
+                        parent_path,
                         &query,
                         None,
                         None,
                         ProofType::Merk,
                         proof_result,
+                        transaction,
                     )
                 );
             }
-            split_path = path_slice.split_last();
-        }
-        Ok(()).wrap_with_cost(cost)
+        );
+        self.prove_path(proof_result, parent_path.to_vec(), transaction)
+            .add_cost(cost)
     }
 
     /// Generates query proof given a subtree and appends the result to a proof
     /// list
-    fn generate_and_store_merk_proof<'a, S: 'a>(
+    #[allow(clippy::too_many_arguments)]
+    fn generate_and_store_merk_proof<'a, 'ctx, C: 'a>(
         &self,
-        subtree: &'a Merk<S>,
+        subtree: &'a Merk<C>,
+        path: &[&[u8]],
         query: &Query,
         limit: Option<u16>,
         offset: Option<u16>,
         proof_type: ProofType,
         proofs: &mut Vec<u8>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(Option<u16>, Option<u16>), Error>
     where
-        S: StorageContext<'a>,
+        C: StorageContext<'ctx>,
     {
         let mut cost = OperationCost::default();
 
         // TODO: How do you handle mixed tree types?
-        // TODO implement costs
-        let mut proof_result = subtree
-            .prove_without_encoding(query.clone(), limit, offset)
-            .unwrap()
-            .expect("should generate proof");
+        let mut proof_result = cost_return_on_error!(
+            &mut cost,
+            subtree
+                .prove_without_encoding(query.clone(), limit, offset)
+                .map_err(|e| Error::CorruptedData(format!("unable to generate proof: {}", e)))
+        );
 
-        cost_return_on_error!(&mut cost, self.replace_references(&mut proof_result));
+        cost_return_on_error!(
+            &mut cost,
+            self.replace_references(&mut proof_result, path, transaction)
+        );
 
         let mut proof_bytes = Vec::with_capacity(128);
         encode_into(proof_result.proof.iter(), &mut proof_bytes);
@@ -322,18 +459,37 @@ impl GroveDb {
     fn replace_references(
         &self,
         proof_result: &mut ProofWithoutEncodingResult,
+        path: &[&[u8]],
+        transaction: TransactionArg<S>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
         for op in proof_result.proof.iter_mut() {
             match op {
                 Op::Push(node) | Op::PushInverted(node) => match node {
-                    Node::KV(_, value) => {
-                        let elem = Element::deserialize(value);
-                        if let Ok(Element::Reference(reference_path, _)) = elem {
+                    Node::KV(key, value)
+                    | Node::KVWithSum(key, value, _)
+                    | Node::KVWithCount(key, value, _) => {
+                        let elem = match Element::deserialize(value) {
+                            Ok(elem) => elem,
+                            Err(_) => continue,
+                        };
+                        if let (Ok(Some(reference_path)), Some(max_hops)) = (
+                            elem.absolute_reference_path(path, key),
+                            elem.max_reference_hops(),
+                        ) {
+                            let mut full_path: Vec<Vec<u8>> =
+                                path.iter().map(|x| x.to_vec()).collect();
+                            full_path.push(key.clone());
                             let referenced_elem = cost_return_on_error!(
                                 &mut cost,
-                                self.follow_reference(reference_path, None)
+                                self.follow_reference_chain(
+                                    vec![full_path],
+                                    reference_path,
+                                    max_hops,
+                                    None,
+                                    transaction
+                                )
                             );
                             *value = referenced_elem.serialize().unwrap();
                         }
@@ -345,14 +501,4 @@ impl GroveDb {
         }
         Ok(()).wrap_with_cost(cost)
     }
-
-    /// Opens merk at a given path without transaction
-    fn open_subtree<'p, P>(&self, path: P) -> CostResult<Merk<PrefixedRocksDbStorageContext>, Error>
-    where
-        P: IntoIterator<Item = &'p [u8]>,
-        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
-    {
-        let storage = self.db.get_storage_context(path);
-        Merk::open(storage).map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
-    }
 }
@@ -8,7 +8,7 @@ pub const EMPTY_TREE_HASH: [u8; 32] = [0; 32];
 pub enum ProofType {
     Merk,
     SizedMerk,
-    Root,
+    AbsentPath,
     EmptyTree,
     Invalid,
 }
@@ -18,7 +18,7 @@ impl From<ProofType> for u8 {
         match proof_type {
             ProofType::Merk => 0x01,
             ProofType::SizedMerk => 0x02,
-            ProofType::Root => 0x03,
+            ProofType::AbsentPath => 0x03,
             ProofType::EmptyTree => 0x04,
             ProofType::Invalid => 0x10,
         }
@@ -30,7 +30,7 @@ impl From<u8> for ProofType {
         match val {
             0x01 => ProofType::Merk,
             0x02 => ProofType::SizedMerk,
-            0x03 => ProofType::Root,
+            0x03 => ProofType::AbsentPath,
             0x04 => ProofType::EmptyTree,
             _ => ProofType::Invalid,
         }
@@ -47,12 +47,11 @@ impl<'a> ProofReader<'a> {
         Self { proof_data }
     }
 
-    pub fn read_byte(&mut self) -> Result<[u8; 1], Error> {
-        let mut data = [0; 1];
+    pub fn peek_proof_type(&self) -> Result<ProofType, Error> {
         self.proof_data
-            .read(&mut data)
-            .map_err(|_| Error::CorruptedData(String::from("failed to read proof data")))?;
-        Ok(data)
+            .first()
+            .map(|data_type| (*data_type).into())
+            .ok_or_else(|| Error::CorruptedData(String::from("failed to read proof data")))
     }
 
     pub fn read_proof(&mut self) -> Result<(ProofType, Vec<u8>), Error> {
@@ -87,6 +86,15 @@ impl<'a> ProofReader<'a> {
             return Ok((proof_type, vec![]));
         }
 
+        if proof_type == ProofType::AbsentPath {
+            // absent path proof only carries the depth of the missing path segment
+            let mut absent_depth = [0; 2];
+            self.proof_data
+                .read(&mut absent_depth)
+                .map_err(|_| Error::CorruptedData(String::from("failed to read proof data")))?;
+            return Ok((proof_type, absent_depth.to_vec()));
+        }
+
         let mut proof_length = [0; 8_usize];
         self.proof_data
             .read(&mut proof_length)
@@ -100,14 +108,6 @@ impl<'a> ProofReader<'a> {
 
         Ok((proof_type, proof))
     }
-
-    pub fn read_to_end(&mut self) -> Result<Vec<u8>, Error> {
-        let mut data = vec![];
-        self.proof_data
-            .read_to_end(&mut data)
-            .map_err(|_| Error::CorruptedData(String::from("failed to read proof data")))?;
-        Ok(data)
-    }
 }
 
 pub fn write_to_vec<W: Write>(dest: &mut W, value: &[u8]) {
@@ -1,5 +1,4 @@
-use merk::{proofs::Query, Hash};
-use rs_merkle::{algorithms::Sha256, MerkleProof};
+use merk::{proofs::Query, AggregateData, Hash};
 
 use crate::{
     operations::proof::util::{ProofReader, ProofType, EMPTY_TREE_HASH},
@@ -28,6 +27,21 @@ impl GroveDb {
 
         Ok((hash, verifier.result_set))
     }
+
+    /// Verifies a proof made by [GroveDb::prove_count] and returns the root
+    /// hash of GroveDB with the number of elements in the count tree at
+    /// `path`
+    pub fn verify_count(proof: &[u8], path: Vec<Vec<u8>>) -> Result<([u8; 32], u64), Error> {
+        let query = PathQuery::new_tree_element_query(path)?;
+        let (root_hash, result_set) = GroveDb::verify_query(proof, &query)?;
+        match result_set.first() {
+            Some((_, value_bytes)) => match Element::deserialize(value_bytes)? {
+                Element::CountTree(_, count, _) => Ok((root_hash, count)),
+                _ => Err(Error::InvalidProof("proven element is not a count tree")),
+            },
+            None => Err(Error::InvalidProof("proof doesn't contain a count tree")),
+        }
+    }
 }
 
 struct ProofVerifier {
@@ -49,34 +63,81 @@ impl ProofVerifier {
         let mut proof_reader = ProofReader::new(proof);
 
         let path_slices = query.path.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
-        // TODO: get rid of this error once root tree is also of type merk
-        if path_slices.is_empty() {
-            return Err(Error::InvalidPath("can't verify proof for empty path"));
+
+        if proof_reader.peek_proof_type()? == ProofType::AbsentPath {
+            return self.execute_absent_path_proof(query, path_slices, &mut proof_reader);
         }
 
-        let mut last_subtree_root_hash =
+        let (mut root_hash, aggregate_data) =
             self.execute_subquery_proof(&mut proof_reader, query.clone())?;
 
-        // validate the path elements are connected
+        // validate the path elements are connected up to the root tree
         self.verify_path_to_root(
             query,
             path_slices,
             &mut proof_reader,
-            &mut last_subtree_root_hash,
+            &mut root_hash,
+            aggregate_data,
         )?;
 
-        // execute the root proof
-        let root_hash = Self::execute_root_proof(&mut proof_reader, last_subtree_root_hash)?;
+        Ok(root_hash)
+    }
+
+    /// Verifies a proof that the query path doesn't exist, the result set
+    /// stays empty
+    fn execute_absent_path_proof(
+        &mut self,
+        query: &PathQuery,
+        path_slices: Vec<&[u8]>,
+        proof_reader: &mut ProofReader,
+    ) -> Result<[u8; 32], Error> {
+        let absent_depth_bytes = proof_reader.read_proof_of_type(ProofType::AbsentPath.into())?;
+        let absent_depth = u16::from_be_bytes(
+            absent_depth_bytes
+                .try_into()
+                .map_err(|_| Error::InvalidProof("invalid absent path depth"))?,
+        ) as usize;
+        if absent_depth >= path_slices.len() {
+            return Err(Error::InvalidProof("absent path depth is out of bounds"));
+        }
+
+        let parent_merk_proof = proof_reader.read_proof_of_type(ProofType::Merk.into())?;
+        let mut key_query = Query::new();
+        key_query.insert_key(path_slices[absent_depth].to_vec());
+
+        let (mut root_hash, aggregate_data, result_set) = self.execute_merk_proof(
+            ProofType::Merk,
+            &parent_merk_proof,
+            &key_query,
+            key_query.left_to_right,
+        )?;
+        let result_set = result_set.expect("MERK_PROOF always returns a result set");
+        if let Some((_, value_bytes)) = result_set.first() {
+            if Element::deserialize(value_bytes.as_slice())?.is_tree() {
+                return Err(Error::InvalidProof("proof invalid: path exists"));
+            }
+        }
+
+        self.verify_path_to_root(
+            query,
+            path_slices[..absent_depth].to_vec(),
+            proof_reader,
+            &mut root_hash,
+            aggregate_data,
+        )?;
 
         Ok(root_hash)
     }
 
+    /// Returns the root hash of the proven subtree together with its aggregate
+    /// if it is a non-empty sum or count tree
     fn execute_subquery_proof(
         &mut self,
         proof_reader: &mut ProofReader,
         query: PathQuery,
-    ) -> Result<[u8; 32], Error> {
+    ) -> Result<([u8; 32], Option<AggregateData>), Error> {
         let last_root_hash: [u8; 32];
+        let last_aggregate_data: Option<AggregateData>;
         let (proof_type, proof) = proof_reader.read_proof()?;
 
         match proof_type {
@@ -90,6 +151,7 @@ impl ProofVerifier {
                 )?;
 
                 last_root_hash = verification_result.0;
+                last_aggregate_data = verification_result.1;
             }
             ProofType::Merk => {
                 // for non leaf subtrees, we want to prove that all the queried keys
@@ -103,14 +165,17 @@ impl ProofVerifier {
                 )?;
 
                 last_root_hash = verification_result.0;
+                last_aggregate_data = verification_result.1;
                 let children = verification_result
-                    .1
+                    .2
                     .expect("MERK_PROOF always returns a result set");
 
                 for (key, value_bytes) in children {
-                    let child_element = Element::deserialize(value_bytes.as_slice())?;
+                    let mut child_element = Element::deserialize(value_bytes.as_slice())?;
                     match child_element {
-                        Element::Tree(mut expected_root_hash, _) => {
+                        Element::Tree(expected_root_hash, _)
+                        | Element::SumTree(expected_root_hash, ..)
+                        | Element::CountTree(expected_root_hash, ..) => {
                             if expected_root_hash == EMPTY_TREE_HASH {
                                 // child node is empty, move on to next
                                 continue;
@@ -145,7 +210,7 @@ impl ProofVerifier {
                                         ProofType::Merk,
                                         subquery_key,
                                     )?;
-                                    let subquery_key_result_set = verification_result.1;
+                                    let subquery_key_result_set = verification_result.2;
                                     if subquery_key_result_set.is_none() {
                                         // this means a sized proof was generated for the subquery
                                         // key
@@ -165,24 +230,22 @@ impl ProofVerifier {
                                         continue;
                                     }
 
-                                    Self::update_root_hash_from_subquery_key_element(
-                                        &mut expected_root_hash,
-                                        &subquery_key_result_set,
-                                    )?;
+                                    child_element =
+                                        Self::subquery_key_tree_element(&subquery_key_result_set)?;
                                 }
                             }
 
                             let new_path_query =
                                 PathQuery::new_unsized(vec![], subquery_value.unwrap());
 
-                            let child_hash =
+                            let (child_hash, child_aggregate_data) =
                                 self.execute_subquery_proof(proof_reader, new_path_query)?;
 
-                            if child_hash != expected_root_hash {
-                                return Err(Error::InvalidProof(
-                                    "child hash doesn't match the expected hash",
-                                ));
-                            }
+                            Self::verify_child_tree(
+                                &child_element,
+                                child_hash,
+                                child_aggregate_data,
+                            )?;
                         }
                         _ => {
                             // MerkProof type signifies there are more subtrees to explore
@@ -195,36 +258,74 @@ impl ProofVerifier {
             }
             ProofType::EmptyTree => {
                 last_root_hash = EMPTY_TREE_HASH;
+                last_aggregate_data = None;
             }
             _ => {
                 // execute_subquery_proof only expects proofs for merk trees
-                // root proof is handled separately
                 return Err(Error::InvalidProof("wrong proof type"));
             }
         }
-        Ok(last_root_hash)
+        Ok((last_root_hash, last_aggregate_data))
     }
 
-    /// Deserialize subkey_element and update expected root hash
-    fn update_root_hash_from_subquery_key_element(
-        expected_root_hash: &mut [u8; 32],
+    /// Deserialize subkey_element which is expected to be a tree
+    fn subquery_key_tree_element(
         subquery_key_result_set: &[ProofKeyValue],
-    ) -> Result<(), Error> {
+    ) -> Result<Element, Error> {
         let elem_value = &subquery_key_result_set[0].1;
         let subquery_key_element = Element::deserialize(elem_value)
             .map_err(|_| Error::CorruptedData("failed to deserialize element".to_string()))?;
-        match subquery_key_element {
-            Element::Tree(new_exptected_hash, _) => {
-                *expected_root_hash = new_exptected_hash;
+        if !subquery_key_element.is_tree() {
+            // the means that the subquery key pointed to a non tree
+            // element, this is not valid as you cannot apply the
+            // the subquery value to non tree items
+            return Err(Error::InvalidProof(
+                "subquery key cannot point to non tree element",
+            ));
+        }
+        Ok(subquery_key_element)
+    }
+
+    /// Checks that a tree element matches the proven root hash of its subtree,
+    /// and the proven sum or count of it in case of a sum or a count tree
+    fn verify_child_tree(
+        tree_element: &Element,
+        child_hash: [u8; 32],
+        child_aggregate_data: Option<AggregateData>,
+    ) -> Result<(), Error> {
+        let expected_root_hash = match tree_element {
+            Element::Tree(hash, _) | Element::SumTree(hash, ..) | Element::CountTree(hash, ..) => {
+                hash
             }
             _ => {
-                // the means that the subquery key pointed to a non tree
-                // element, this is not valid as you cannot apply the
-                // the subquery value to non tree items
                 return Err(Error::InvalidProof(
-                    "subquery key cannot point to non tree element",
-                ));
+                    "intermediate proofs should be for trees",
+                ))
             }
+        };
+        if child_hash != *expected_root_hash {
+            return Err(Error::InvalidProof(
+                "child hash doesn't match the expected hash",
+            ));
+        }
+        match tree_element {
+            Element::SumTree(_, sum, _) => {
+                let child_sum = child_aggregate_data.and_then(|data| data.sum());
+                if *sum != child_sum.unwrap_or_default() {
+                    return Err(Error::InvalidProof(
+                        "sum tree sum doesn't match the proven sum",
+                    ));
+                }
+            }
+            Element::CountTree(_, count, _) => {
+                let child_count = child_aggregate_data.and_then(|data| data.count());
+                if *count != child_count.unwrap_or_default() {
+                    return Err(Error::InvalidProof(
+                        "count tree count doesn't match the proven count",
+                    ));
+                }
+            }
+            _ => {}
         }
         Ok(())
     }
@@ -236,7 +337,7 @@ impl ProofVerifier {
         proof_reader: &mut ProofReader,
         expected_proof_type: ProofType,
         subquery_key: Option<Vec<u8>>,
-    ) -> Result<(Hash, Option<Proof>), Error> {
+    ) -> Result<(Hash, Option<AggregateData>, Option<Proof>), Error> {
         let (proof_type, subkey_proof) = proof_reader.read_proof()?;
 
         if proof_type != expected_proof_type {
@@ -271,87 +372,42 @@ impl ProofVerifier {
         path_slices: Vec<&[u8]>,
         proof_reader: &mut ProofReader,
         expected_root_hash: &mut [u8; 32],
+        mut expected_aggregate_data: Option<AggregateData>,
     ) -> Result<(), Error> {
         let mut split_path = path_slices.split_last();
         while let Some((key, path_slice)) = split_path {
-            if !path_slice.is_empty() {
-                // for every subtree, there should be a corresponding proof for the parent
-                // which should prove that this subtree is a child of the parent tree
-                let parent_merk_proof = proof_reader.read_proof_of_type(ProofType::Merk.into())?;
-
-                let mut parent_query = Query::new();
-                parent_query.insert_key(key.to_vec());
-
-                let proof_result = self.execute_merk_proof(
-                    ProofType::Merk,
-                    &parent_merk_proof,
-                    &parent_query,
-                    query.query.query.left_to_right,
-                )?;
-
-                let result_set = proof_result
-                    .1
-                    .expect("MERK_PROOF always returns a result set");
-                if result_set.is_empty() || &result_set[0].0 != key {
-                    return Err(Error::InvalidProof("proof invalid: invalid parent"));
-                }
-
-                let elem = Element::deserialize(result_set[0].1.as_slice())?;
-                let child_hash = match elem {
-                    Element::Tree(hash, _) => Ok(hash),
-                    _ => Err(Error::InvalidProof(
-                        "intermediate proofs should be for trees",
-                    )),
-                }?;
+            // for every subtree, there should be a corresponding proof for the parent
+            // which should prove that this subtree is a child of the parent tree
+            let parent_merk_proof = proof_reader.read_proof_of_type(ProofType::Merk.into())?;
+
+            let mut parent_query = Query::new();
+            parent_query.insert_key(key.to_vec());
+
+            let proof_result = self.execute_merk_proof(
+                ProofType::Merk,
+                &parent_merk_proof,
+                &parent_query,
+                query.query.query.left_to_right,
+            )?;
+
+            let result_set = proof_result
+                .2
+                .expect("MERK_PROOF always returns a result set");
+            if result_set.is_empty() || &result_set[0].0 != key {
+                return Err(Error::InvalidProof("proof invalid: invalid parent"));
+            }
 
-                if child_hash != *expected_root_hash {
-                    return Err(Error::InvalidProof("Bad path"));
-                }
+            let elem = Element::deserialize(result_set[0].1.as_slice())?;
+            Self::verify_child_tree(&elem, *expected_root_hash, expected_aggregate_data)?;
 
-                *expected_root_hash = proof_result.0;
-            } else {
-                break;
-            }
+            *expected_root_hash = proof_result.0;
+            expected_aggregate_data = proof_result.1;
             split_path = path_slice.split_last();
         }
 
         Ok(())
     }
 
-    /// Generate expected root hash based on root proof and leaf hashes
-    fn execute_root_proof(
-        proof_reader: &mut ProofReader,
-        leaf_hash: [u8; 32],
-    ) -> Result<[u8; 32], Error> {
-        let root_proof_bytes = proof_reader.read_proof_of_type(ProofType::Root.into())?;
-
-        // makes the assumption that 1 byte is enough to represent the root leaf count
-        // hence max of 255 root leaf keys
-        let root_leaf_count = proof_reader.read_byte()?;
-
-        let index_to_prove_as_bytes = proof_reader.read_to_end()?;
-        let index_to_prove_as_usize = index_to_prove_as_bytes
-            .into_iter()
-            .map(|index| index as usize)
-            .collect::<Vec<usize>>();
-
-        let root_proof = match MerkleProof::<Sha256>::try_from(root_proof_bytes) {
-            Ok(proof) => Ok(proof),
-            Err(_) => Err(Error::InvalidProof("invalid proof element")),
-        }?;
-
-        let root_hash = match root_proof.root(
-            &index_to_prove_as_usize,
-            &[leaf_hash],
-            root_leaf_count[0] as usize,
-        ) {
-            Ok(hash) => Ok(hash),
-            Err(_) => Err(Error::InvalidProof("Invalid proof element")),
-        }?;
-
-        Ok(root_hash)
-    }
-
     /// Execute a merk proof, update the state when a sized proof is
     /// encountered i.e. update the limit, offset and result set values
     fn execute_merk_proof(
@@ -360,7 +416,7 @@ impl ProofVerifier {
         proof: &[u8],
         query: &Query,
         left_to_right: bool,
-    ) -> Result<(Hash, Option<Proof>), Error> {
+    ) -> Result<(Hash, Option<AggregateData>, Option<Proof>), Error> {
         let is_sized_proof = proof_type == ProofType::SizedMerk;
         let mut limit = None;
         let mut offset = None;
@@ -378,13 +434,17 @@ impl ProofVerifier {
                 Error::InvalidProof("invalid proof verification parameters")
             })?;
 
+        let aggregate_data = result
+            .sum
+            .map(AggregateData::Sum)
+            .or(result.count.map(AggregateData::Count));
         if is_sized_proof {
             self.limit = result.limit;
             self.offset = result.offset;
             self.result_set.extend(result.result_set);
-            Ok((hash, None))
+            Ok((hash, aggregate_data, None))
         } else {
-            Ok((hash, Some(result.result_set)))
+            Ok((hash, aggregate_data, Some(result.result_set)))
         }
     }
 }
@@ -0,0 +1,590 @@
+//! Back-links from elements to references pointing to them.
+//!
+//! A reference is hashed together with the value it points to, so once the
+//! value changes the reference has to be re-hashed and once the value is gone
+//! the reference goes as well. To find references of an element their full
+//! paths are kept in the meta storage of the subtree holding the element, which
+//! is not exposed to users the way aux storage is.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use bincode::Options;
+use costs::{
+    cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
+    WORST_CASE_KEY_SIZE,
+};
+use storage::{Storage, StorageContext};
+
+use crate::{
+    average_case::{add_average_case_get_raw, EstimatedLayerInformationByPath},
+    batch::Op,
+    util::{merk_optional_tx, storage_context_optional_tx},
+    Element, Error, GroveDb, TransactionArg,
+};
+
+/// Prefix of meta keys holding referrers of an element, followed by the
+/// element's key
+const REFERRERS_META_PREFIX: &[u8] = b"referrers";
+
+/// Full paths (subtree path followed by a key) of references to an element
+pub(crate) type Referrers = BTreeSet<Vec<Vec<u8>>>;
+
+/// Meta key marking a subtree which had referrers of its elements written at
+/// least once, so subtrees without it can be skipped when looking for them
+const HAS_REFERRERS_META_KEY: &[u8] = b"has_referrers";
+
+fn referrers_meta_key(key: &[u8]) -> Vec<u8> {
+    [REFERRERS_META_PREFIX, key].concat()
+}
+
+/// Tells whether elements of a subtree may have referrers, `false` means none
+/// of them has any
+pub(crate) fn read_has_referrers<'db, C: StorageContext<'db>>(
+    storage: &C,
+) -> CostResult<bool, Error> {
+    let cost = OperationCost {
+        seek_count: 1,
+        ..Default::default()
+    };
+    storage
+        .get_meta(HAS_REFERRERS_META_KEY)
+        .map(|value| value.is_some())
+        .map_err(|e| Error::StorageError(e.into()))
+        .wrap_with_cost(cost)
+}
+
+/// Reads referrers of an element under `key` from the meta storage of its
+/// subtree
+pub(crate) fn read_referrers<'db, C: StorageContext<'db>>(
+    storage: &C,
+    key: &[u8],
+) -> CostResult<Referrers, Error> {
+    let mut cost = OperationCost {
+        seek_count: 1,
+        ..Default::default()
+    };
+    let value = cost_return_on_error_no_add!(
+        &cost,
+        storage
+            .get_meta(referrers_meta_key(key))
+            .map_err(|e| Error::StorageError(e.into()))
+    );
+    match value {
+        Some(bytes) => {
+            cost.loaded_bytes = bytes.len() as u32;
+            bincode::DefaultOptions::default()
+                .with_varint_encoding()
+                .reject_trailing_bytes()
+                .deserialize(&bytes)
+                .map_err(|_| Error::CorruptedData(String::from("unable to deserialize referrers")))
+                .wrap_with_cost(cost)
+        }
+        None => Ok(Referrers::new()).wrap_with_cost(cost),
+    }
+}
+
+/// Writes referrers of an element under `key` into the meta storage of its
+/// subtree, the record is removed if there are no referrers left. The subtree
+/// is marked as having referrers, the mark is kept once set.
+pub(crate) fn write_referrers<'db, C: StorageContext<'db>>(
+    storage: &C,
+    key: &[u8],
+    referrers: &Referrers,
+) -> CostResult<(), Error> {
+    let meta_key = referrers_meta_key(key);
+    let mut cost = OperationCost {
+        seek_count: 1,
+        storage_written_bytes: meta_key.len() as u32,
+        ..Default::default()
+    };
+    let result = if referrers.is_empty() {
+        storage.delete_meta(&meta_key)
+    } else {
+        let bytes = cost_return_on_error_no_add!(
+            &cost,
+            bincode::DefaultOptions::default()
+                .with_varint_encoding()
+                .reject_trailing_bytes()
+                .serialize(referrers)
+                .map_err(|_| Error::CorruptedData(String::from("unable to serialize referrers")))
+        );
+        cost.seek_count += 1;
+        cost.storage_written_bytes += (bytes.len() + HAS_REFERRERS_META_KEY.len()) as u32;
+        storage
+            .put_meta(HAS_REFERRERS_META_KEY, &[])
+            .and_then(|_| storage.put_meta(&meta_key, &bytes))
+    };
+    result
+        .map_err(|e| Error::StorageError(e.into()))
+        .wrap_with_cost(cost)
+}
+
+/// Adds worst case of getting an element without following references
+pub(crate) fn add_worst_case_get_raw(cost: &mut OperationCost, path: &[Vec<u8>], key: &[u8]) {
+    // The subtree is checked to exist in its parent first
+    if let Some((parent_key, parent_path)) = path.split_last() {
+        cost.add_worst_case_get_merk(parent_path.iter().map(|x| x.as_slice()));
+        cost.add_worst_case_merk_has_element(parent_key);
+    }
+    cost.add_worst_case_get_merk(path.iter().map(|x| x.as_slice()));
+    cost.add_worst_case_merk_has_element(key);
+}
+
+/// Size of serialized referrers holding only the element at `path` and `key`
+fn single_referrer_size(path: &[Vec<u8>], key: &[u8]) -> u32 {
+    let mut full_path = path.to_vec();
+    full_path.push(key.to_vec());
+    bincode::DefaultOptions::default()
+        .with_varint_encoding()
+        .serialized_size(&Referrers::from([full_path]))
+        .unwrap_or_default() as u32
+}
+
+/// Adds cost of reading and writing back referrers of an element under
+/// a key of `key_len` bytes, which take `referrers_size` bytes once written
+fn add_referrers_update_cost(cost: &mut OperationCost, key_len: u32, referrers_size: u32) {
+    cost.seek_count += 2;
+    cost.storage_written_bytes += REFERRERS_META_PREFIX.len() as u32 + key_len + referrers_size;
+    if referrers_size > 0 {
+        cost.seek_count += 1;
+        cost.storage_written_bytes += HAS_REFERRERS_META_KEY.len() as u32;
+    }
+}
+
+/// Adds worst case of keeping back-links in sync for a batch operation on an
+/// element at `path` and `key`. Back-links of stored references aren't known in
+/// advance, so neither they nor the references to re-hash are accounted for.
+pub(crate) fn add_worst_case_reference_links(
+    cost: &mut OperationCost,
+    path: &[Vec<u8>],
+    key: &[u8],
+    op: &Op,
+) {
+    // Referrers of the element are read along with the mark of its subtree and
+    // the previous element is read to unregister it at its target in case it
+    // was a reference
+    cost.seek_count += 2;
+    add_worst_case_get_raw(cost, path, key);
+    add_referrers_update_cost(cost, WORST_CASE_KEY_SIZE, 0);
+    if op.is_delete() {
+        // Back-links of the deleted element are removed
+        cost.seek_count += 1;
+        cost.storage_written_bytes += referrers_meta_key(key).len() as u32;
+    } else if let Some(element) = op.inserted_element() {
+        let target = element.absolute_reference_path(path, key).ok().flatten();
+        if let (Some(target), Some(max_hops)) = (target, element.max_reference_hops()) {
+            let (target_key, target_path) = match target.split_last() {
+                Some(split) => split,
+                None => return,
+            };
+            // The referenced element is resolved hop by hop
+            for _ in 0..max_hops {
+                add_worst_case_get_raw(cost, target_path, target_key);
+            }
+            add_referrers_update_cost(
+                cost,
+                target_key.len() as u32,
+                single_referrer_size(path, key),
+            );
+        }
+    }
+}
+
+/// Adds average case of keeping back-links in sync for a batch operation on an
+/// element at `path` and `key`. Elements are assumed to have no references to
+/// them and previous elements are assumed to be no references. Subtrees at
+/// `inserted_paths` are created by the batch and have nothing to read, all
+/// others need estimated layer information.
+pub(crate) fn add_average_case_reference_links(
+    cost: &mut OperationCost,
+    path: &[Vec<u8>],
+    key: &[u8],
+    op: &Op,
+    estimated_layers: &EstimatedLayerInformationByPath,
+    inserted_paths: &HashSet<Vec<Vec<u8>>>,
+) -> Result<(), Error> {
+    // Subtree of the element is checked to have referrers and the previous
+    // element is read
+    cost.seek_count += 1;
+    if !inserted_paths.contains(path) {
+        add_average_case_get_raw(cost, path, estimated_layers)?;
+    }
+    if op.is_delete() {
+        cost.seek_count += 1;
+        cost.storage_written_bytes += referrers_meta_key(key).len() as u32;
+    } else if let Some(element) = op.inserted_element() {
+        let target = element.absolute_reference_path(path, key).ok().flatten();
+        if let Some(target) = target {
+            let (target_key, target_path) = match target.split_last() {
+                Some(split) => split,
+                None => return Ok(()),
+            };
+            // References are expected to point to items directly
+            if !inserted_paths.contains(target_path) {
+                add_average_case_get_raw(cost, target_path, estimated_layers)?;
+            }
+            add_referrers_update_cost(
+                cost,
+                target_key.len() as u32,
+                single_referrer_size(path, key),
+            );
+        }
+    }
+    Ok(())
+}
+
+impl<S: Storage> GroveDb<S> {
+    /// Get an element without following references, `None` if either the
+    /// element or its subtree doesn't exist
+    pub(crate) fn get_raw_optional(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Option<Element>, Error> {
+        self.get_raw(path.iter().map(|x| x.as_slice()), key, transaction)
+            .map(|result| match result {
+                Ok(element) => Ok(Some(element)),
+                Err(Error::PathKeyNotFound(_) | Error::PathNotFound(_)) => Ok(None),
+                Err(e) => Err(e),
+            })
+    }
+
+    /// Get full paths of references pointing to an element
+    pub(crate) fn get_referrers(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        transaction: TransactionArg<S>,
+    ) -> CostResult<Referrers, Error> {
+        storage_context_optional_tx!(
+            self.db,
+            path.iter().map(|x| x.as_slice()),
+            transaction,
+            storage,
+            { read_referrers(&storage, key) }
+        )
+    }
+
+    fn put_referrers(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        referrers: &Referrers,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        storage_context_optional_tx!(
+            self.db,
+            path.iter().map(|x| x.as_slice()),
+            transaction,
+            storage,
+            { write_referrers(&storage, key, referrers) }
+        )
+    }
+
+    /// Adds or removes `referrer` to referrers of an element at
+    /// `target` full path
+    fn update_referrer(
+        &self,
+        target: &[Vec<u8>],
+        referrer: &[Vec<u8>],
+        add: bool,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let (key, path) = cost_return_on_error_no_add!(
+            &cost,
+            target
+                .split_last()
+                .ok_or(Error::CorruptedPath("empty reference path"))
+        );
+        let mut referrers =
+            cost_return_on_error!(&mut cost, self.get_referrers(path, key, transaction));
+        let changed = if add {
+            referrers.insert(referrer.to_vec())
+        } else {
+            referrers.remove(referrer)
+        };
+        if changed {
+            cost_return_on_error!(
+                &mut cost,
+                self.put_referrers(path, key, &referrers, transaction)
+            );
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Keeps back-links and referrers in sync after `new` element was written
+    /// in place of `previous` one: a reference is registered at the element it
+    /// points to and references to the updated element are re-hashed.
+    pub(crate) fn update_reference_links(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        previous: Option<&Element>,
+        new: &Element,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut full_path = path.to_vec();
+        full_path.push(key.to_vec());
+
+        let previous_target = match previous {
+            Some(element) => {
+                cost_return_on_error_no_add!(&cost, element.absolute_reference_path(path, key))
+            }
+            None => None,
+        };
+        let new_target =
+            cost_return_on_error_no_add!(&cost, new.absolute_reference_path(path, key));
+        if previous_target != new_target {
+            if let Some(target) = previous_target {
+                cost_return_on_error!(
+                    &mut cost,
+                    self.update_referrer(&target, &full_path, false, transaction)
+                );
+            }
+            if let Some(target) = new_target {
+                cost_return_on_error!(
+                    &mut cost,
+                    self.update_referrer(&target, &full_path, true, transaction)
+                );
+            }
+        }
+        if previous.is_some() {
+            cost_return_on_error!(&mut cost, self.refresh_referrers(path, key, transaction));
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Re-hashes references to an element with its current value, following
+    /// up to references of these references. Changes of all the updated
+    /// subtrees are propagated at once in the end.
+    fn refresh_referrers(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut updated_subtrees = BTreeSet::new();
+        cost_return_on_error!(
+            &mut cost,
+            self.rehash_referrers(path, key, &mut updated_subtrees, transaction)
+        );
+        self.propagate_changes_of_subtrees(updated_subtrees, transaction)
+            .add_cost(cost)
+    }
+
+    /// Re-hashes references to an element and references to them, collecting
+    /// paths of subtrees holding these references into `updated_subtrees`
+    fn rehash_referrers(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        updated_subtrees: &mut BTreeSet<Vec<Vec<u8>>>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut full_path = path.to_vec();
+        full_path.push(key.to_vec());
+
+        let referrers =
+            cost_return_on_error!(&mut cost, self.get_referrers(path, key, transaction));
+        for referrer in referrers {
+            let (referrer_key, referrer_path) = cost_return_on_error_no_add!(
+                &cost,
+                referrer
+                    .split_last()
+                    .ok_or(Error::CorruptedData("empty referrer path".to_owned()))
+            );
+            let element = match cost_return_on_error!(
+                &mut cost,
+                self.get_raw_optional(referrer_path, referrer_key, transaction)
+            ) {
+                Some(element) => element,
+                None => continue,
+            };
+            if cost_return_on_error_no_add!(
+                &cost,
+                element.absolute_reference_path(referrer_path, referrer_key)
+            ) != Some(full_path.clone())
+            {
+                continue;
+            }
+            let referenced_element = cost_return_on_error!(
+                &mut cost,
+                self.follow_reference_chain(
+                    vec![referrer.clone()],
+                    full_path.clone(),
+                    element
+                        .max_reference_hops()
+                        .expect("element is a reference"),
+                    None,
+                    transaction
+                )
+            );
+            let serialized = cost_return_on_error_no_add!(&cost, referenced_element.serialize());
+            let referrer_path_iter = referrer_path.iter().map(|x| x.as_slice());
+            let tree_type = cost_return_on_error!(
+                &mut cost,
+                self.tree_type(referrer_path_iter.clone(), transaction)
+            );
+            merk_optional_tx!(
+                &mut cost,
+                self.db,
+                referrer_path_iter,
+                transaction,
+                mut subtree,
+                {
+                    cost_return_on_error!(
+                        &mut cost,
+                        element.insert_reference(&mut subtree, referrer_key, serialized, tree_type)
+                    );
+                }
+            );
+            updated_subtrees.insert(referrer_path.to_vec());
+            cost_return_on_error!(
+                &mut cost,
+                self.rehash_referrers(referrer_path, referrer_key, updated_subtrees, transaction)
+            );
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Propagates changes of several subtrees up to the root tree, deeper
+    /// subtrees first, so that each of their ancestors is updated only once
+    fn propagate_changes_of_subtrees(
+        &self,
+        subtrees: BTreeSet<Vec<Vec<u8>>>,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut subtrees_by_depth: BTreeMap<usize, BTreeSet<Vec<Vec<u8>>>> = BTreeMap::new();
+        for path in subtrees {
+            subtrees_by_depth
+                .entry(path.len())
+                .or_default()
+                .insert(path);
+        }
+        while let Some(depth) = subtrees_by_depth.keys().next_back().copied() {
+            let paths = subtrees_by_depth.remove(&depth).unwrap_or_default();
+            // The root tree has no parent to update
+            if depth == 0 {
+                break;
+            }
+            for path in paths {
+                let (key, parent_path) = path.split_last().expect("path is not empty");
+                let parent_tree_type = cost_return_on_error!(
+                    &mut cost,
+                    self.tree_type(parent_path.iter().map(|x| x.as_slice()), transaction)
+                );
+                merk_optional_tx!(
+                    &mut cost,
+                    self.db,
+                    path.iter().map(|x| x.as_slice()),
+                    transaction,
+                    subtree,
+                    {
+                        let root_hash = subtree.root_hash().unwrap_add_cost(&mut cost);
+                        let aggregate_data = subtree.aggregate_data();
+                        merk_optional_tx!(
+                            &mut cost,
+                            self.db,
+                            parent_path.iter().map(|x| x.as_slice()),
+                            transaction,
+                            mut parent_tree,
+                            {
+                                cost_return_on_error!(
+                                    &mut cost,
+                                    Self::update_tree_item_preserve_flag(
+                                        &mut parent_tree,
+                                        key,
+                                        root_hash,
+                                        aggregate_data,
+                                        parent_tree_type,
+                                    )
+                                );
+                            }
+                        );
+                    }
+                );
+                subtrees_by_depth
+                    .entry(depth - 1)
+                    .or_default()
+                    .insert(parent_path.to_vec());
+            }
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+
+    /// Drops back-links of a deleted element: the element is removed from
+    /// referrers of the element it pointed to, if it was a reference, and
+    /// references to the deleted element are deleted as well.
+    pub(crate) fn delete_reference_links(
+        &self,
+        path: &[Vec<u8>],
+        key: &[u8],
+        deleted: &Element,
+        transaction: TransactionArg<S>,
+    ) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let mut full_path = path.to_vec();
+        full_path.push(key.to_vec());
+
+        if let Some(target) =
+            cost_return_on_error_no_add!(&cost, deleted.absolute_reference_path(path, key))
+        {
+            cost_return_on_error!(
+                &mut cost,
+                self.update_referrer(&target, &full_path, false, transaction)
+            );
+        }
+
+        let referrers =
+            cost_return_on_error!(&mut cost, self.get_referrers(path, key, transaction));
+        if referrers.is_empty() {
+            return Ok(()).wrap_with_cost(cost);
+        }
+        cost_return_on_error!(
+            &mut cost,
+            self.put_referrers(path, key, &Referrers::new(), transaction)
+        );
+        for referrer in referrers {
+            let (referrer_key, referrer_path) = cost_return_on_error_no_add!(
+                &cost,
+                referrer
+                    .split_last()
+                    .ok_or(Error::CorruptedData("empty referrer path".to_owned()))
+            );
+            let element = cost_return_on_error!(
+                &mut cost,
+                self.get_raw_optional(referrer_path, referrer_key, transaction)
+            );
+            let referrer_target = match element {
+                Some(element) => cost_return_on_error_no_add!(
+                    &cost,
+                    element.absolute_reference_path(referrer_path, referrer_key)
+                ),
+                None => None,
+            };
+            if referrer_target == Some(full_path.clone()) {
+                cost_return_on_error!(
+                    &mut cost,
+                    self.delete(
+                        referrer_path.iter().map(|x| x.as_slice()),
+                        referrer_key,
+                        transaction
+                    )
+                );
+            }
+        }
+        Ok(()).wrap_with_cost(cost)
+    }
+}
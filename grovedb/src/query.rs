@@ -39,6 +39,17 @@ impl PathQuery {
         Self { path, query }
     }
 
+    /// Query for the element of the subtree at `tree_path` in its parent tree,
+    /// which carries the root hash and the aggregate of the subtree
+    pub fn new_tree_element_query(mut tree_path: Vec<Vec<u8>>) -> Result<Self, Error> {
+        let key = tree_path
+            .pop()
+            .ok_or(Error::InvalidPath("the root tree has no tree element"))?;
+        let mut query = Query::new();
+        query.insert_key(key);
+        Ok(Self::new_unsized(tree_path, query))
+    }
+
     pub fn merge(path_queries: Vec<&PathQuery>) -> CostContext<Result<Self, Error>> {
         let cost = OperationCost::default();
 
@@ -161,7 +172,7 @@ mod tests {
         let path_query_one =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query_one);
 
-        let proof = temp_db.prove_query(&path_query_one).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_one, None).unwrap().unwrap();
         let (_, result_set_one) =
             GroveDb::verify_query(proof.as_slice(), &path_query_one).expect("should execute proof");
         assert_eq!(result_set_one.len(), 1);
@@ -171,7 +182,7 @@ mod tests {
         let path_query_two =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query_two);
 
-        let proof = temp_db.prove_query(&path_query_two).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_two, None).unwrap().unwrap();
         let (_, result_set_two) =
             GroveDb::verify_query(proof.as_slice(), &path_query_two).expect("should execute proof");
         assert_eq!(result_set_two.len(), 1);
@@ -185,7 +196,10 @@ mod tests {
         );
         assert_eq!(merged_path_query.query.query.items.len(), 2);
 
-        let proof = temp_db.prove_query(&merged_path_query).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&merged_path_query, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_merged) = GroveDb::verify_query(proof.as_slice(), &merged_path_query)
             .expect("should execute proof");
         assert_eq!(result_set_merged.len(), 2);
@@ -208,7 +222,7 @@ mod tests {
         let path_query_one =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query_one);
 
-        let proof = temp_db.prove_query(&path_query_one).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_one, None).unwrap().unwrap();
         let (_, result_set_one) =
             GroveDb::verify_query(proof.as_slice(), &path_query_one).expect("should execute proof");
         assert_eq!(result_set_one.len(), 1);
@@ -218,7 +232,7 @@ mod tests {
         let path_query_two =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree4".to_vec()], query_two);
 
-        let proof = temp_db.prove_query(&path_query_two).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_two, None).unwrap().unwrap();
         let (_, result_set_two) =
             GroveDb::verify_query(proof.as_slice(), &path_query_two).expect("should execute proof");
         assert_eq!(result_set_two.len(), 1);
@@ -229,7 +243,10 @@ mod tests {
         assert_eq!(merged_path_query.path, vec![TEST_LEAF.to_vec()]);
         assert_eq!(merged_path_query.query.query.items.len(), 2);
 
-        let proof = temp_db.prove_query(&merged_path_query).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&merged_path_query, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_merged) = GroveDb::verify_query(proof.as_slice(), &merged_path_query)
             .expect("should execute proof");
         assert_eq!(result_set_merged.len(), 2);
@@ -252,7 +269,7 @@ mod tests {
             query_one,
         );
 
-        let proof = temp_db.prove_query(&path_query_one).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_one, None).unwrap().unwrap();
         let (_, result_set_one) =
             GroveDb::verify_query(proof.as_slice(), &path_query_one).expect("should execute proof");
         assert_eq!(result_set_one.len(), 3);
@@ -269,7 +286,7 @@ mod tests {
             query_two,
         );
 
-        let proof = temp_db.prove_query(&path_query_two).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_two, None).unwrap().unwrap();
         let (_, result_set_two) =
             GroveDb::verify_query(proof.as_slice(), &path_query_two).expect("should execute proof");
         assert_eq!(result_set_two.len(), 2);
@@ -286,7 +303,10 @@ mod tests {
             query_three,
         );
 
-        let proof = temp_db.prove_query(&path_query_three).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&path_query_three, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_two) = GroveDb::verify_query(proof.as_slice(), &path_query_three)
             .expect("should execute proof");
         assert_eq!(result_set_two.len(), 2);
@@ -298,7 +318,10 @@ mod tests {
         assert_eq!(merged_path_query.path, vec![b"deep_leaf".to_vec()]);
         assert_eq!(merged_path_query.query.query.items.len(), 2);
 
-        let proof = temp_db.prove_query(&merged_path_query).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&merged_path_query, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_merged) = GroveDb::verify_query(proof.as_slice(), &merged_path_query)
             .expect("should execute proof");
         assert_eq!(result_set_merged.len(), 7);
@@ -342,7 +365,7 @@ mod tests {
             query_one,
         );
 
-        let proof = temp_db.prove_query(&path_query_one).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_one, None).unwrap().unwrap();
         let (_, result_set_one) =
             GroveDb::verify_query(proof.as_slice(), &path_query_one).expect("should execute proof");
         assert_eq!(result_set_one.len(), 6);
@@ -359,7 +382,7 @@ mod tests {
             query_two,
         );
 
-        let proof = temp_db.prove_query(&path_query_two).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_two, None).unwrap().unwrap();
         let (_, result_set_two) =
             GroveDb::verify_query(proof.as_slice(), &path_query_two).expect("should execute proof");
         assert_eq!(result_set_two.len(), 2);
@@ -369,7 +392,10 @@ mod tests {
             .expect("expect to merge path queries");
         assert_eq!(merged_path_query.path, vec![b"deep_leaf".to_vec()]);
 
-        let proof = temp_db.prove_query(&merged_path_query).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&merged_path_query, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_merged) = GroveDb::verify_query(proof.as_slice(), &merged_path_query)
             .expect("should execute proof");
         assert_eq!(result_set_merged.len(), 8);
@@ -408,7 +434,7 @@ mod tests {
         let path_query_one =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query_one);
 
-        let proof = temp_db.prove_query(&path_query_one).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_one, None).unwrap().unwrap();
         let (_, result_set_one) =
             GroveDb::verify_query(proof.as_slice(), &path_query_one).expect("should execute proof");
         assert_eq!(result_set_one.len(), 1);
@@ -418,7 +444,7 @@ mod tests {
         let path_query_two =
             PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query_two);
 
-        let proof = temp_db.prove_query(&path_query_two).unwrap().unwrap();
+        let proof = temp_db.prove_query(&path_query_two, None).unwrap().unwrap();
         let (_, result_set_two) =
             GroveDb::verify_query(proof.as_slice(), &path_query_two).expect("should execute proof");
         assert_eq!(result_set_two.len(), 1);
@@ -430,7 +456,10 @@ mod tests {
             query_three,
         );
 
-        let proof = temp_db.prove_query(&path_query_three).unwrap().unwrap();
+        let proof = temp_db
+            .prove_query(&path_query_three, None)
+            .unwrap()
+            .unwrap();
         let (_, result_set_two) = GroveDb::verify_query(proof.as_slice(), &path_query_three)
             .expect("should execute proof");
         assert_eq!(result_set_two.len(), 2);
@@ -447,7 +476,10 @@ mod tests {
         // &merged_path_query)     .expect("should execute proof");
         // assert_eq!(result_set_merged.len(), 4);
         let proof = temp_db
-            .prove_query_many(vec![&path_query_one, &path_query_two, &path_query_three])
+            .prove_query_many(
+                vec![&path_query_one, &path_query_two, &path_query_three],
+                None,
+            )
             .unwrap()
             .unwrap();
         let (_, result_set_merged) = GroveDb::verify_query(proof.as_slice(), &merged_path_query)
@@ -0,0 +1,67 @@
+//! Read-only access to GroveDB, meant for historical views opened from
+//! checkpoints: such a view exposes reads only, so the state it was opened at
+//! can't be changed through it.
+
+use costs::CostResult;
+use storage::Storage;
+
+use crate::{subtree::KeyElementPair, DefaultStorage, Element, Error, GroveDb, PathQuery};
+
+/// GroveDB which can only be read from, reads are made outside of any
+/// transaction
+pub struct ReadOnlyGroveDb<S = DefaultStorage> {
+    db: GroveDb<S>,
+}
+
+impl<S: Storage> ReadOnlyGroveDb<S> {
+    /// Wraps GroveDb to be used for reads only
+    pub fn new(db: GroveDb<S>) -> Self {
+        ReadOnlyGroveDb { db }
+    }
+
+    /// Returns root hash of GroveDb, `None` if GroveDb is empty
+    pub fn root_hash(&self) -> CostResult<Option<[u8; 32]>, Error> {
+        self.db.root_hash(None)
+    }
+
+    /// Gets an element following references
+    pub fn get<'p, P>(&self, path: P, key: &'p [u8]) -> CostResult<Element, Error>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        self.db.get(path, key, None)
+    }
+
+    /// Gets an element without following references
+    pub fn get_raw<'p, P>(&self, path: P, key: &'p [u8]) -> CostResult<Element, Error>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        self.db.get_raw(path, key, None)
+    }
+
+    /// Gets auxiliary data under `key`
+    pub fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> CostResult<Option<Vec<u8>>, Error> {
+        self.db.get_aux(key, None)
+    }
+
+    /// Queries values following references
+    pub fn query(&self, path_query: &PathQuery) -> CostResult<(Vec<Vec<u8>>, u16), Error> {
+        self.db.query(path_query, None)
+    }
+
+    /// Queries elements without following references
+    pub fn query_raw(
+        &self,
+        path_query: &PathQuery,
+    ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
+        self.db.query_raw(path_query, None)
+    }
+
+    /// Proves a query against the root hash of the view
+    pub fn prove_query(&self, path_query: &PathQuery) -> CostResult<Vec<u8>, Error> {
+        self.db.prove_query(path_query, None)
+    }
+}
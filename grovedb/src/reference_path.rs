@@ -0,0 +1,168 @@
+//! Reference path types.
+
+use integer_encoding::VarInt;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Limit of hops a reference may take to reach an item, `None` stands for
+/// the default limit of `MAX_REFERENCE_HOPS`
+pub type MaxReferenceHop = Option<u8>;
+
+/// Chain of full paths (subtree path followed by a key) passed while
+/// following a reference
+pub type ReferenceChain = Vec<Vec<Vec<u8>>>;
+
+/// Formats a reference chain for error messages with hex-encoded path
+/// segments
+pub(crate) fn display_reference_chain(chain: &ReferenceChain) -> String {
+    chain
+        .iter()
+        .map(|path| {
+            path.iter()
+                .map(hex::encode)
+                .collect::<Vec<String>>()
+                .join("/")
+        })
+        .collect::<Vec<String>>()
+        .join(" -> ")
+}
+
+/// Path of an element a reference points to. Relative paths are resolved
+/// against the path of the reference itself, so subtrees holding such
+/// references may be copied or moved under another parent without breaking
+/// them.
+/// ONLY APPEND TO THIS LIST!!! Because
+/// of how serialization works.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ReferencePathType {
+    /// A path from the root of GroveDB
+    AbsolutePathReference(Vec<Vec<u8>>),
+    /// Goes up from the subtree holding the reference by the given number of
+    /// levels, then appends the path
+    UpstreamFromElementHeightReference(u8, Vec<Vec<u8>>),
+    /// An element under the given key in the subtree holding the reference
+    SiblingReference(Vec<u8>),
+    /// An element under the same key as the reference, but in the subtree
+    /// which is a sibling of the reference's subtree and has the given key
+    CousinReference(Vec<u8>),
+}
+
+impl ReferencePathType {
+    /// Absolute path of the referenced element given `current_path` of the
+    /// subtree holding the reference and `current_key` of the reference.
+    pub fn absolute_path<P: AsRef<[u8]>>(
+        &self,
+        current_path: &[P],
+        current_key: &[u8],
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        match self {
+            ReferencePathType::AbsolutePathReference(path) => Ok(path.clone()),
+            ReferencePathType::UpstreamFromElementHeightReference(levels_up, append_path) => {
+                let levels_up = *levels_up as usize;
+                if levels_up > current_path.len() {
+                    return Err(Error::InvalidPath("reference goes up above the root tree"));
+                }
+                let mut path: Vec<Vec<u8>> = current_path[..current_path.len() - levels_up]
+                    .iter()
+                    .map(|x| x.as_ref().to_vec())
+                    .collect();
+                path.extend(append_path.iter().cloned());
+                Ok(path)
+            }
+            ReferencePathType::SiblingReference(key) => {
+                let mut path: Vec<Vec<u8>> =
+                    current_path.iter().map(|x| x.as_ref().to_vec()).collect();
+                path.push(key.clone());
+                Ok(path)
+            }
+            ReferencePathType::CousinReference(parent_key) => {
+                let (_, grandparent_path) = current_path.split_last().ok_or(Error::InvalidPath(
+                    "cousin reference can't be placed into the root tree",
+                ))?;
+                let mut path: Vec<Vec<u8>> = grandparent_path
+                    .iter()
+                    .map(|x| x.as_ref().to_vec())
+                    .collect();
+                path.push(parent_key.clone());
+                path.push(current_key.to_vec());
+                Ok(path)
+            }
+        }
+    }
+
+    /// Get the size of the serialization of a reference path in bytes
+    pub fn serialized_byte_size(&self) -> usize {
+        let path_size = |path: &[Vec<u8>]| {
+            path.iter()
+                .map(|inner| inner.len() + inner.len().required_space())
+                .sum::<usize>()
+                + path.len().required_space()
+        };
+        1 + match self {
+            ReferencePathType::AbsolutePathReference(path) => path_size(path),
+            ReferencePathType::UpstreamFromElementHeightReference(_, path) => 1 + path_size(path),
+            ReferencePathType::SiblingReference(key) | ReferencePathType::CousinReference(key) => {
+                key.len() + key.len().required_space()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_absolute_paths() {
+        let path: [&[u8]; 3] = [b"a", b"b", b"c"];
+
+        assert_eq!(
+            ReferencePathType::AbsolutePathReference(vec![b"x".to_vec()])
+                .absolute_path(&path, b"key")
+                .unwrap(),
+            vec![b"x".to_vec()]
+        );
+        assert_eq!(
+            ReferencePathType::UpstreamFromElementHeightReference(
+                2,
+                vec![b"d".to_vec(), b"e".to_vec()]
+            )
+            .absolute_path(&path, b"key")
+            .unwrap(),
+            vec![b"a".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        assert_eq!(
+            ReferencePathType::SiblingReference(b"other".to_vec())
+                .absolute_path(&path, b"key")
+                .unwrap(),
+            vec![
+                b"a".to_vec(),
+                b"b".to_vec(),
+                b"c".to_vec(),
+                b"other".to_vec()
+            ]
+        );
+        assert_eq!(
+            ReferencePathType::CousinReference(b"d".to_vec())
+                .absolute_path(&path, b"key")
+                .unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec(), b"key".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_invalid_relative_paths() {
+        let path: [&[u8]; 1] = [b"a"];
+
+        assert!(matches!(
+            ReferencePathType::UpstreamFromElementHeightReference(2, vec![])
+                .absolute_path(&path, b"key"),
+            Err(Error::InvalidPath(_))
+        ));
+        assert!(matches!(
+            ReferencePathType::CousinReference(b"b".to_vec()).absolute_path::<&[u8]>(&[], b"key"),
+            Err(Error::InvalidPath(_))
+        ));
+    }
+}
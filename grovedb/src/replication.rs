@@ -0,0 +1,428 @@
+//! State sync for the whole GroveDB: every subtree is replicated by its Merk
+//! chunks, starting from the root tree and going down through
+//! `Element::Tree` elements, so a new node can bootstrap from an untrusted
+//! peer knowing only the root hash.
+//!
+//! Note: subtrees containing references can't be restored yet since a
+//! reference node is hashed with the referenced value, which chunks don't
+//! carry, so such chunks fail verification.
+
+use std::collections::VecDeque;
+
+use costs::{
+    cost_return_on_error, cost_return_on_error_no_add, CostResult, CostsExt, OperationCost,
+};
+use merk::{ChunkProducer, Merk, Restorer as MerkRestorer};
+use storage::{Storage, StorageContext};
+
+use crate::{operations::proof::util::EMPTY_TREE_HASH, Element, Error, GroveDb};
+
+/// Chunk of a subtree's Merk identified by the subtree path and the chunk
+/// index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubtreeChunk {
+    pub path: Vec<Vec<u8>>,
+    pub index: usize,
+    /// Total number of chunks of the subtree as stated by the producer, it is
+    /// verified by the restorer
+    pub chunks_count: usize,
+    pub data: Vec<u8>,
+}
+
+type SubtreeMerkChunkProducer<'db, S> = ChunkProducer<'db, <S as Storage>::StorageContext<'db>>;
+
+/// Produces chunks of any GroveDB subtree on request.
+pub struct SubtreeChunkProducer<'db, S: Storage> {
+    grove_db: &'db GroveDb<S>,
+    cache: Option<(Vec<Vec<u8>>, SubtreeMerkChunkProducer<'db, S>)>,
+}
+
+impl<'db, S: Storage> SubtreeChunkProducer<'db, S> {
+    pub fn new(grove_db: &'db GroveDb<S>) -> Self {
+        SubtreeChunkProducer {
+            grove_db,
+            cache: None,
+        }
+    }
+
+    /// Returns the number of chunks of a subtree at `path`
+    pub fn chunks_in_subtree(&mut self, path: &[Vec<u8>]) -> CostResult<usize, Error> {
+        self.producer(path).map_ok(|producer| producer.len())
+    }
+
+    /// Returns chunk of a subtree at `path` by its index
+    pub fn get_chunk(&mut self, path: &[Vec<u8>], index: usize) -> CostResult<SubtreeChunk, Error> {
+        let mut cost = OperationCost::default();
+
+        let producer = cost_return_on_error!(&mut cost, self.producer(path));
+        let chunks_count = producer.len();
+        let data = cost_return_on_error!(
+            &mut cost,
+            producer
+                .chunk(index)
+                .map_err(|e| Error::CorruptedData(e.to_string()))
+        );
+
+        Ok(SubtreeChunk {
+            path: path.to_vec(),
+            index,
+            chunks_count,
+            data,
+        })
+        .wrap_with_cost(cost)
+    }
+
+    /// Returns Merk chunk producer of a subtree reusing the previous one if
+    /// the path is the same
+    fn producer(
+        &mut self,
+        path: &[Vec<u8>],
+    ) -> CostResult<&mut SubtreeMerkChunkProducer<'db, S>, Error> {
+        let mut cost = OperationCost::default();
+
+        if !matches!(&self.cache, Some((cached_path, _)) if cached_path == path) {
+            let storage = self
+                .grove_db
+                .db
+                .get_storage_context(path.iter().map(|x| x.as_slice()));
+            let merk = cost_return_on_error!(
+                &mut cost,
+                Merk::open(storage)
+                    .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
+            );
+            let producer = cost_return_on_error!(
+                &mut cost,
+                merk.chunks()
+                    .map_err(|e| Error::CorruptedData(e.to_string()))
+            );
+            self.cache = Some((path.to_vec(), producer));
+        }
+
+        Ok(&mut self.cache.as_mut().expect("producer is cached above").1).wrap_with_cost(cost)
+    }
+}
+
+/// Restores GroveDB subtree by subtree from verified chunks. The root tree
+/// is checked against the trusted root hash and each child subtree against
+/// the `Element::Tree` hash of its parent, so nothing is accepted unless it
+/// is proven by the root hash.
+pub struct Restorer<'db, S: Storage> {
+    grove_db: &'db GroveDb<S>,
+    merk_restorer: Option<MerkRestorer<S::StorageContext<'db>>>,
+    processed_chunks: usize,
+    /// Subtrees left to restore with their expected root hashes, the first one
+    /// is being restored at the moment
+    pending_subtrees: VecDeque<(Vec<Vec<u8>>, [u8; 32])>,
+}
+
+impl<'db, S: Storage> Restorer<'db, S> {
+    /// Creates a restorer of an empty GroveDB to the state with
+    /// `expected_root_hash`
+    pub fn new(grove_db: &'db GroveDb<S>, expected_root_hash: [u8; 32]) -> CostResult<Self, Error> {
+        let mut cost = OperationCost::default();
+
+        let root_hash = cost_return_on_error!(&mut cost, grove_db.root_hash(None));
+        if root_hash.is_some() {
+            return Err(Error::InvalidInput("only an empty GroveDb can be restored"))
+                .wrap_with_cost(cost);
+        }
+
+        let mut pending_subtrees = VecDeque::new();
+        if expected_root_hash != EMPTY_TREE_HASH {
+            pending_subtrees.push_back((vec![], expected_root_hash));
+        }
+
+        Ok(Restorer {
+            grove_db,
+            merk_restorer: None,
+            processed_chunks: 0,
+            pending_subtrees,
+        })
+        .wrap_with_cost(cost)
+    }
+
+    /// Returns subtree path and chunk index which should be processed next,
+    /// `None` if the restoration is finished
+    pub fn next_chunk_request(&self) -> Option<(&[Vec<u8>], usize)> {
+        self.pending_subtrees
+            .front()
+            .map(|(path, _)| (path.as_slice(), self.processed_chunks))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending_subtrees.is_empty()
+    }
+
+    /// Verifies and writes the requested chunk. In case of an error the same
+    /// chunk should be requested again (possibly from another peer).
+    pub fn process_chunk(&mut self, chunk: SubtreeChunk) -> CostResult<(), Error> {
+        let mut cost = OperationCost::default();
+
+        let (path, expected_hash) = match self.pending_subtrees.front() {
+            Some(subtree) => subtree.clone(),
+            None => {
+                return Err(Error::ChunkRestoringError(
+                    "no more chunks are expected".to_owned(),
+                ))
+                .wrap_with_cost(cost)
+            }
+        };
+        if chunk.path != path || chunk.index != self.processed_chunks {
+            return Err(Error::ChunkRestoringError(
+                "received chunk is not the requested one".to_owned(),
+            ))
+            .wrap_with_cost(cost);
+        }
+
+        // A trunk may be retried with another stated chunks count, so a Merk
+        // restorer is created for each trunk
+        if self.processed_chunks == 0 {
+            let storage = self
+                .grove_db
+                .db
+                .get_storage_context(path.iter().map(|x| x.as_slice()));
+            let merk = cost_return_on_error!(
+                &mut cost,
+                Merk::open(storage)
+                    .map_err(|_| Error::CorruptedData("cannot open a subtree".to_owned()))
+            );
+            self.merk_restorer = Some(cost_return_on_error_no_add!(
+                &cost,
+                merk.restore(expected_hash, chunk.chunks_count)
+                    .map_err(|e| Error::ChunkRestoringError(e.to_string()))
+            ));
+        }
+
+        let merk_restorer = self
+            .merk_restorer
+            .as_mut()
+            .expect("merk restorer is created on trunk");
+        let remaining_chunks = cost_return_on_error!(
+            &mut cost,
+            merk_restorer
+                .process_chunk(&chunk.data)
+                .map_err(|e| Error::ChunkRestoringError(e.to_string()))
+        );
+        self.processed_chunks += 1;
+
+        if remaining_chunks == 0 {
+            let merk_restorer = self.merk_restorer.take().expect("checked above");
+            let merk = cost_return_on_error!(
+                &mut cost,
+                merk_restorer
+                    .finalize()
+                    .map_err(|e| Error::ChunkRestoringError(e.to_string()))
+            );
+            self.pending_subtrees.pop_front();
+            self.processed_chunks = 0;
+
+            // Child subtrees are restored next, their hashes are already
+            // verified as a part of this subtree
+            let mut elements =
+                Element::iterator(merk.storage.raw_iter()).unwrap_add_cost(&mut cost);
+            while let Some((key, element)) = cost_return_on_error!(&mut cost, elements.next()) {
+                if let Element::Tree(hash, _)
+                | Element::SumTree(hash, ..)
+                | Element::CountTree(hash, ..) = element
+                {
+                    if hash != EMPTY_TREE_HASH {
+                        let mut child_path = path.clone();
+                        child_path.push(key);
+                        self.pending_subtrees.push_back((child_path, hash));
+                    }
+                }
+            }
+        }
+
+        Ok(()).wrap_with_cost(cost)
+    }
+}
+
+impl<S: Storage> GroveDb<S> {
+    /// Creates a producer of chunks for every subtree of this GroveDB
+    pub fn chunks(&self) -> SubtreeChunkProducer<'_, S> {
+        SubtreeChunkProducer::new(self)
+    }
+
+    /// Creates a restorer which replicates a GroveDB with
+    /// `expected_root_hash` into this empty one
+    pub fn restore(&self, expected_root_hash: [u8; 32]) -> CostResult<Restorer<'_, S>, Error> {
+        Restorer::new(self, expected_root_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{make_empty_grovedb, make_grovedb, TEST_LEAF};
+
+    fn replicate(source: &GroveDb, target: &GroveDb) {
+        let mut producer = source.chunks();
+        let mut restorer = target
+            .restore(source.root_hash(None).unwrap().unwrap().unwrap())
+            .unwrap()
+            .unwrap();
+        while let Some((path, index)) = restorer.next_chunk_request() {
+            let path = path.to_vec();
+            let chunk = producer.get_chunk(&path, index).unwrap().unwrap();
+            restorer.process_chunk(chunk).unwrap().unwrap();
+        }
+        assert!(restorer.is_finished());
+    }
+
+    fn make_source_grovedb() -> crate::tests::TempGroveDb {
+        let db = make_grovedb();
+        db.insert([TEST_LEAF], b"tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+        db.insert([TEST_LEAF], b"empty_tree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF],
+            b"item",
+            Element::new_item(b"value".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+        // big enough for the subtree to be split into several chunks
+        for i in 0u32..2000 {
+            db.insert(
+                [TEST_LEAF, b"tree"],
+                &i.to_be_bytes(),
+                Element::new_item(i.to_le_bytes().to_vec()),
+                None,
+            )
+            .unwrap()
+            .expect("successful item insert");
+        }
+        db
+    }
+
+    #[test]
+    fn test_replicate_grovedb() {
+        let source = make_source_grovedb();
+        assert!(
+            source
+                .chunks()
+                .chunks_in_subtree(&[TEST_LEAF.to_vec(), b"tree".to_vec()])
+                .unwrap()
+                .unwrap()
+                > 1
+        );
+
+        let target = make_empty_grovedb();
+        replicate(&source, &target);
+
+        assert_eq!(
+            source.root_hash(None).unwrap().unwrap(),
+            target.root_hash(None).unwrap().unwrap()
+        );
+        assert_eq!(
+            target
+                .get([TEST_LEAF, b"tree"], &1999u32.to_be_bytes(), None)
+                .unwrap()
+                .expect("replicated item"),
+            Element::new_item(1999u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            target.get([TEST_LEAF], b"item", None).unwrap().unwrap(),
+            Element::new_item(b"value".to_vec())
+        );
+        assert!(target
+            .is_empty_tree([TEST_LEAF, b"empty_tree"], None)
+            .unwrap()
+            .unwrap());
+    }
+
+    #[test]
+    fn test_replicate_empty_grovedb() {
+        let target = make_empty_grovedb();
+        let restorer = target.restore(EMPTY_TREE_HASH).unwrap().unwrap();
+        assert!(restorer.is_finished());
+        assert!(restorer.next_chunk_request().is_none());
+    }
+
+    #[test]
+    fn test_restore_rejects_wrong_root_hash() {
+        let source = make_source_grovedb();
+        let target = make_empty_grovedb();
+        let mut producer = source.chunks();
+        let mut restorer = target.restore([1; 32]).unwrap().unwrap();
+
+        let chunk = producer.get_chunk(&[], 0).unwrap().unwrap();
+        assert!(matches!(
+            restorer.process_chunk(chunk).unwrap(),
+            Err(Error::ChunkRestoringError(_))
+        ));
+        assert_eq!(restorer.next_chunk_request(), Some((&[][..], 0)));
+    }
+
+    #[test]
+    fn test_restore_rejects_subtree_not_matching_parent() {
+        let source = make_source_grovedb();
+        let other = make_source_grovedb();
+        other
+            .insert(
+                [TEST_LEAF, b"tree"],
+                b"other",
+                Element::new_item(b"value".to_vec()),
+                None,
+            )
+            .unwrap()
+            .expect("successful item insert");
+
+        let target = make_empty_grovedb();
+        let mut source_producer = source.chunks();
+        let mut other_producer = other.chunks();
+        let mut restorer = target
+            .restore(source.root_hash(None).unwrap().unwrap().unwrap())
+            .unwrap()
+            .unwrap();
+
+        let tree_path = vec![TEST_LEAF.to_vec(), b"tree".to_vec()];
+        while let Some((path, index)) = restorer.next_chunk_request() {
+            let path = path.to_vec();
+            if path == tree_path {
+                // the peer sends a subtree which is not committed in its parent
+                let chunk = other_producer.get_chunk(&path, index).unwrap().unwrap();
+                assert!(restorer.process_chunk(chunk).unwrap().is_err());
+                break;
+            }
+            let chunk = source_producer.get_chunk(&path, index).unwrap().unwrap();
+            restorer.process_chunk(chunk).unwrap().unwrap();
+        }
+        assert!(!restorer.is_finished());
+    }
+
+    #[test]
+    fn test_restore_rejects_unexpected_chunk() {
+        let source = make_source_grovedb();
+        let target = make_empty_grovedb();
+        let mut producer = source.chunks();
+        let mut restorer = target
+            .restore(source.root_hash(None).unwrap().unwrap().unwrap())
+            .unwrap()
+            .unwrap();
+
+        let chunk = producer
+            .get_chunk(&[TEST_LEAF.to_vec()], 0)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            restorer.process_chunk(chunk).unwrap(),
+            Err(Error::ChunkRestoringError(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_into_non_empty_grovedb_fails() {
+        let source = make_source_grovedb();
+        let target = make_grovedb();
+        assert!(target
+            .restore(source.root_hash(None).unwrap().unwrap().unwrap())
+            .unwrap()
+            .is_err());
+    }
+}
@@ -13,20 +13,24 @@ use integer_encoding::VarInt;
 use merk::{
     proofs::{query::QueryItem, Query},
     tree::Tree,
-    Op, HASH_LENGTH,
+    AggregateData, Op, TreeFeatureType, HASH_LENGTH,
 };
 use serde::{Deserialize, Serialize};
-use storage::{rocksdb_storage::RocksDbStorage, RawIterator, StorageContext};
+use storage::{RawIterator, Storage, StorageContext};
 use visualize::visualize_to_vec;
 
 use crate::{
-    util::{merk_optional_tx, storage_context_optional_tx},
-    Error, Merk, PathQuery, SizedQuery, TransactionArg,
+    operations::get::MAX_REFERENCE_HOPS,
+    util::{check_cost_limit, merk_optional_tx, storage_context_optional_tx},
+    Error, MaxReferenceHop, Merk, PathQuery, ReferencePathType, SizedQuery, TransactionArg,
 };
 
 /// Type alias for key-element common pattern.
 pub(crate) type KeyElementPair = (Vec<u8>, Element);
 
+/// Type alias for an element along with its key and the path of its subtree
+pub(crate) type PathKeyElementTrio = (Vec<Vec<u8>>, Vec<u8>, Element);
+
 /// Optional single byte meta-data to be stored per element
 pub type ElementFlags = Option<Vec<u8>>;
 
@@ -43,6 +47,32 @@ pub enum Element {
     /// Hash is stored to make Merk become different when its subtrees have
     /// changed, otherwise changes won't be reflected in parent trees.
     Tree([u8; 32], ElementFlags),
+    /// A signed integer value which is added up into the sum of a sum tree
+    /// it is placed into
+    SumItem(i64, ElementFlags),
+    /// A subtree which maintains a sum of its elements in Merk nodes, contains
+    /// a root hash of the underlying Merk and the sum of all its elements.
+    SumTree([u8; 32], i64, ElementFlags),
+    /// A subtree which maintains a count of its elements in Merk nodes,
+    /// contains a root hash of the underlying Merk and the number of elements
+    /// in it.
+    CountTree([u8; 32], u64, ElementFlags),
+    /// A reference to an object by a path of any type with an optional limit
+    /// of hops to reach the object, references by absolute paths without a
+    /// limit are kept in `Reference` by constructors
+    TypedReference(ReferencePathType, MaxReferenceHop, ElementFlags),
+}
+
+/// Kind of a subtree, which tells what Merk nodes of the subtree aggregate
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TreeType {
+    /// A regular subtree
+    #[default]
+    NormalTree,
+    /// A subtree maintaining a sum of its elements
+    SumTree,
+    /// A subtree maintaining a count of its elements
+    CountTree,
 }
 
 impl fmt::Debug for Element {
@@ -54,12 +84,12 @@ impl fmt::Debug for Element {
     }
 }
 
-pub struct PathQueryPushArgs<'db, 'ctx, 'a>
+pub struct PathQueryPushArgs<'db, 'ctx, 'a, S: Storage + 'db>
 where
     'db: 'ctx,
 {
-    pub storage: &'db RocksDbStorage,
-    pub transaction: TransactionArg<'db, 'ctx>,
+    pub storage: &'a S,
+    pub transaction: TransactionArg<'db, 'ctx, S>,
     pub key: Option<&'a [u8]>,
     pub element: Element,
     pub path: Option<&'a [&'a [u8]]>,
@@ -69,6 +99,7 @@ where
     pub results: &'a mut Vec<KeyElementPair>,
     pub limit: &'a mut Option<u16>,
     pub offset: &'a mut Option<u16>,
+    pub cost_limit: Option<&'a OperationCost>,
 }
 
 impl Element {
@@ -81,6 +112,22 @@ impl Element {
         Element::new_tree_with_flags(Default::default(), flags)
     }
 
+    pub fn empty_sum_tree() -> Self {
+        Element::new_sum_tree(Default::default(), 0)
+    }
+
+    pub fn empty_sum_tree_with_flags(flags: ElementFlags) -> Self {
+        Element::new_sum_tree_with_flags(Default::default(), 0, flags)
+    }
+
+    pub fn empty_count_tree() -> Self {
+        Element::new_count_tree(Default::default(), 0)
+    }
+
+    pub fn empty_count_tree_with_flags(flags: ElementFlags) -> Self {
+        Element::new_count_tree_with_flags(Default::default(), 0, flags)
+    }
+
     pub fn new_item(item_value: Vec<u8>) -> Self {
         Element::Item(item_value, None)
     }
@@ -97,6 +144,39 @@ impl Element {
         Element::Reference(reference_path, flags)
     }
 
+    pub fn new_reference_with_path_type(reference_path_type: ReferencePathType) -> Self {
+        Element::new_reference_with_path_type_and_flags(reference_path_type, None)
+    }
+
+    pub fn new_reference_with_path_type_and_flags(
+        reference_path_type: ReferencePathType,
+        flags: ElementFlags,
+    ) -> Self {
+        Element::new_reference_with_max_hops_and_flags(reference_path_type, None, flags)
+    }
+
+    pub fn new_reference_with_max_hops(
+        reference_path_type: ReferencePathType,
+        max_hop: MaxReferenceHop,
+    ) -> Self {
+        Element::new_reference_with_max_hops_and_flags(reference_path_type, max_hop, None)
+    }
+
+    pub fn new_reference_with_max_hops_and_flags(
+        reference_path_type: ReferencePathType,
+        max_hop: MaxReferenceHop,
+        flags: ElementFlags,
+    ) -> Self {
+        match (reference_path_type, max_hop) {
+            (ReferencePathType::AbsolutePathReference(reference_path), None) => {
+                Element::Reference(reference_path, flags)
+            }
+            (reference_path_type, max_hop) => {
+                Element::TypedReference(reference_path_type, max_hop, flags)
+            }
+        }
+    }
+
     pub fn new_tree(tree_hash: [u8; 32]) -> Self {
         Element::Tree(tree_hash, None)
     }
@@ -105,12 +185,188 @@ impl Element {
         Element::Tree(tree_hash, flags)
     }
 
+    pub fn new_sum_item(value: i64) -> Self {
+        Element::SumItem(value, None)
+    }
+
+    pub fn new_sum_item_with_flags(value: i64, flags: ElementFlags) -> Self {
+        Element::SumItem(value, flags)
+    }
+
+    pub fn new_sum_tree(tree_hash: [u8; 32], sum: i64) -> Self {
+        Element::SumTree(tree_hash, sum, None)
+    }
+
+    pub fn new_sum_tree_with_flags(tree_hash: [u8; 32], sum: i64, flags: ElementFlags) -> Self {
+        Element::SumTree(tree_hash, sum, flags)
+    }
+
+    pub fn new_count_tree(tree_hash: [u8; 32], count: u64) -> Self {
+        Element::CountTree(tree_hash, count, None)
+    }
+
+    pub fn new_count_tree_with_flags(tree_hash: [u8; 32], count: u64, flags: ElementFlags) -> Self {
+        Element::CountTree(tree_hash, count, flags)
+    }
+
+    /// Whether the element is a subtree of any kind
+    pub fn is_tree(&self) -> bool {
+        self.tree_type().is_some()
+    }
+
+    /// Whether the element is a sum tree
+    pub fn is_sum_tree(&self) -> bool {
+        matches!(self, Element::SumTree(..))
+    }
+
+    /// Whether the element is a count tree
+    pub fn is_count_tree(&self) -> bool {
+        matches!(self, Element::CountTree(..))
+    }
+
+    /// Kind of the subtree if the element is a tree
+    pub fn tree_type(&self) -> Option<TreeType> {
+        match self {
+            Element::Tree(..) => Some(TreeType::NormalTree),
+            Element::SumTree(..) => Some(TreeType::SumTree),
+            Element::CountTree(..) => Some(TreeType::CountTree),
+            Element::Item(..)
+            | Element::Reference(..)
+            | Element::SumItem(..)
+            | Element::TypedReference(..) => None,
+        }
+    }
+
+    /// Tree element of the same kind and flags for a subtree with the given
+    /// root hash and aggregate (`None` for empty and regular subtrees),
+    /// `None` if the element is not a tree
+    pub(crate) fn with_subtree_root(
+        &self,
+        root_hash: [u8; 32],
+        aggregate_data: Option<AggregateData>,
+    ) -> Option<Element> {
+        let flags = self.get_flags().clone();
+        match self {
+            Element::Tree(..) => Some(Element::new_tree_with_flags(root_hash, flags)),
+            Element::SumTree(..) => Some(Element::new_sum_tree_with_flags(
+                root_hash,
+                aggregate_data
+                    .and_then(|data| data.sum())
+                    .unwrap_or_default(),
+                flags,
+            )),
+            Element::CountTree(..) => Some(Element::new_count_tree_with_flags(
+                root_hash,
+                aggregate_data
+                    .and_then(|data| data.count())
+                    .unwrap_or_default(),
+                flags,
+            )),
+            Element::Item(..)
+            | Element::Reference(..)
+            | Element::SumItem(..)
+            | Element::TypedReference(..) => None,
+        }
+    }
+
+    /// The value the element adds to the sum of a sum tree: the value of a
+    /// sum item, the aggregate of a sum tree and zero for anything else
+    pub fn sum_value(&self) -> i64 {
+        match self {
+            Element::SumItem(value, _) => *value,
+            Element::SumTree(_, sum, _) => *sum,
+            _ => 0,
+        }
+    }
+
+    /// Feature type of a Merk node holding the element in a subtree of
+    /// `parent_tree_type`, sums are maintained by nodes of sum trees only and
+    /// every element counts as one in a count tree
+    pub fn get_feature_type(&self, parent_tree_type: TreeType) -> TreeFeatureType {
+        match parent_tree_type {
+            TreeType::NormalTree => TreeFeatureType::BasicMerk,
+            TreeType::SumTree => TreeFeatureType::SummedMerk(self.sum_value()),
+            TreeType::CountTree => TreeFeatureType::CountedMerk(1),
+        }
+    }
+
+    /// Whether the element is a reference of any path type
+    pub fn is_reference(&self) -> bool {
+        matches!(self, Element::Reference(..) | Element::TypedReference(..))
+    }
+
+    /// Path type of a reference, `None` for anything else
+    pub fn reference_path_type(&self) -> Option<ReferencePathType> {
+        match self {
+            Element::Reference(reference_path, _) => Some(
+                ReferencePathType::AbsolutePathReference(reference_path.clone()),
+            ),
+            Element::TypedReference(reference_path_type, ..) => Some(reference_path_type.clone()),
+            _ => None,
+        }
+    }
+
+    /// Number of hops a reference may take to reach an item, `None` for
+    /// anything but references
+    pub fn max_reference_hops(&self) -> Option<usize> {
+        match self {
+            Element::Reference(..) | Element::TypedReference(_, None, _) => {
+                Some(MAX_REFERENCE_HOPS)
+            }
+            Element::TypedReference(_, Some(max_hop), _) => Some(*max_hop as usize),
+            _ => None,
+        }
+    }
+
+    /// Absolute path of the element a reference points to, given `path` of
+    /// the subtree holding the reference and its `key`; `None` for anything
+    /// but references
+    pub fn absolute_reference_path<P: AsRef<[u8]>>(
+        &self,
+        path: &[P],
+        key: &[u8],
+    ) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        match self {
+            Element::Reference(reference_path, _) => Ok(Some(reference_path.clone())),
+            Element::TypedReference(reference_path_type, ..) => {
+                reference_path_type.absolute_path(path, key).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Converts a reference with a relative path into one with an absolute
+    /// path given `path` of the subtree holding it and its `key`, other
+    /// elements are returned as is
+    pub(crate) fn with_absolute_reference_path<P: AsRef<[u8]>>(
+        self,
+        path: &[P],
+        key: &[u8],
+    ) -> Result<Element, Error> {
+        match self {
+            Element::TypedReference(reference_path_type, max_hop, flags) => {
+                Ok(Element::new_reference_with_max_hops_and_flags(
+                    ReferencePathType::AbsolutePathReference(
+                        reference_path_type.absolute_path(path, key)?,
+                    ),
+                    max_hop,
+                    flags,
+                ))
+            }
+            other => Ok(other),
+        }
+    }
+
     /// Grab the optional flag stored in an element
     pub fn get_flags(&self) -> &ElementFlags {
         match self {
-            Element::Tree(_, flags) | Element::Item(_, flags) | Element::Reference(_, flags) => {
-                flags
-            }
+            Element::Tree(_, flags)
+            | Element::Item(_, flags)
+            | Element::Reference(_, flags)
+            | Element::SumItem(_, flags)
+            | Element::SumTree(.., flags)
+            | Element::CountTree(.., flags)
+            | Element::TypedReference(.., flags) => flags,
         }
     }
 
@@ -144,6 +400,29 @@ impl Element {
                     32
                 }
             }
+            Element::SumItem(_, element_flag) => {
+                if let Some(flag) = element_flag {
+                    flag.len() + 8
+                } else {
+                    8
+                }
+            }
+            Element::SumTree(.., element_flag) | Element::CountTree(.., element_flag) => {
+                if let Some(flag) = element_flag {
+                    flag.len() + 32 + 8
+                } else {
+                    32 + 8
+                }
+            }
+            Element::TypedReference(reference_path_type, _, element_flag) => {
+                let path_length = reference_path_type.serialized_byte_size() + 1;
+
+                if let Some(flag) = element_flag {
+                    flag.len() + path_length
+                } else {
+                    path_length
+                }
+            }
         }
     }
 
@@ -190,6 +469,46 @@ impl Element {
                 };
                 32 + flag_len + flag_len.required_space() + 1 // + 1 for enum
             }
+            Element::SumItem(value, element_flag) => {
+                let flag_len = if let Some(flag) = element_flag {
+                    flag.len() + 1
+                } else {
+                    0
+                };
+                value.required_space() + flag_len + flag_len.required_space() + 1
+                // + 1 for enum
+            }
+            Element::SumTree(_, sum, element_flag) => {
+                let flag_len = if let Some(flag) = element_flag {
+                    flag.len() + 1
+                } else {
+                    0
+                };
+                32 + sum.required_space() + flag_len + flag_len.required_space() + 1
+                // + 1 for enum
+            }
+            Element::CountTree(_, count, element_flag) => {
+                let flag_len = if let Some(flag) = element_flag {
+                    flag.len() + 1
+                } else {
+                    0
+                };
+                32 + count.required_space() + flag_len + flag_len.required_space() + 1
+                // + 1 for enum
+            }
+            Element::TypedReference(reference_path_type, max_hop, element_flag) => {
+                let flag_len = if let Some(flag) = element_flag {
+                    flag.len() + 1
+                } else {
+                    0
+                };
+                let max_hop_len = if max_hop.is_some() { 2 } else { 1 };
+                reference_path_type.serialized_byte_size()
+                    + max_hop_len
+                    + flag_len
+                    + flag_len.required_space()
+                    + 1 // + 1 for enum
+            }
         }
     }
 
@@ -212,12 +531,12 @@ impl Element {
         node_size + parent_additions + child_sizes
     }
 
-    /// Delete an element from Merk under a key
+    /// Delete an element from Merk under a key; references to the element are
+    /// tracked and deleted by GroveDB operations, not by this method
     pub fn delete<'db, K: AsRef<[u8]>, S: StorageContext<'db>>(
         merk: &mut Merk<S>,
         key: K,
     ) -> CostResult<(), Error> {
-        // TODO: delete references on this element
         let batch = [(key, Op::Delete)];
         merk.apply::<_, Vec<u8>>(&batch, &[])
             .map_err(|e| Error::CorruptedData(e.to_string()))
@@ -250,29 +569,29 @@ impl Element {
         Ok(element).wrap_with_cost(cost)
     }
 
-    pub fn get_query(
-        storage: &RocksDbStorage,
+    pub fn get_query<S: Storage>(
+        storage: &S,
         merk_path: &[&[u8]],
         query: &Query,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<KeyElementPair>, Error> {
         let sized_query = SizedQuery::new(query.clone(), None, None);
         Element::get_sized_query(storage, merk_path, &sized_query, transaction)
             .map_ok(|(elements, _)| elements)
     }
 
-    pub fn get_query_values(
-        storage: &RocksDbStorage,
+    pub fn get_query_values<S: Storage>(
+        storage: &S,
         merk_path: &[&[u8]],
         query: &Query,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<Vec<Element>, Error> {
         let sized_query = SizedQuery::new(query.clone(), None, None);
         Element::get_sized_query(storage, merk_path, &sized_query, transaction)
             .map_ok(|(elements, _)| elements.into_iter().map(|(_, v)| v).collect())
     }
 
-    fn basic_push(args: PathQueryPushArgs) -> Result<(), Error> {
+    fn basic_push<S: Storage>(args: PathQueryPushArgs<S>) -> Result<(), Error> {
         let PathQueryPushArgs {
             key,
             element,
@@ -293,7 +612,7 @@ impl Element {
         Ok(())
     }
 
-    fn path_query_push(args: PathQueryPushArgs) -> CostResult<(), Error> {
+    fn path_query_push<S: Storage>(args: PathQueryPushArgs<S>) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
         let PathQueryPushArgs {
@@ -308,9 +627,10 @@ impl Element {
             results,
             limit,
             offset,
+            cost_limit,
         } = args;
         match element {
-            Element::Tree(..) => {
+            Element::Tree(..) | Element::SumTree(..) | Element::CountTree(..) => {
                 let mut path_vec = cost_return_on_error_no_add!(
                     &cost,
                     path.ok_or(Error::MissingParameter(
@@ -337,7 +657,13 @@ impl Element {
 
                     let (mut sub_elements, skipped) = cost_return_on_error!(
                         &mut cost,
-                        Element::get_path_query(storage, &path_vec, &inner_path_query, transaction,)
+                        Element::get_path_query(
+                            storage,
+                            &path_vec,
+                            &inner_path_query,
+                            cost_limit,
+                            transaction,
+                        )
                     );
 
                     if let Some(limit) = limit {
@@ -356,11 +682,18 @@ impl Element {
                             transaction,
                             subtree,
                             {
+                                let element = cost_return_on_error!(
+                                    &mut cost,
+                                    Element::get(&subtree, subquery_key.as_slice())
+                                );
                                 results.push((
                                     subquery_key.clone(),
-                                    cost_return_on_error!(
-                                        &mut cost,
-                                        Element::get(&subtree, subquery_key.as_slice())
+                                    cost_return_on_error_no_add!(
+                                        &cost,
+                                        element.with_absolute_reference_path(
+                                            &path_vec,
+                                            subquery_key.as_slice()
+                                        )
                                     ),
                                 ));
                             }
@@ -394,6 +727,7 @@ impl Element {
                         results,
                         limit,
                         offset,
+                        cost_limit,
                     })
                 );
             }
@@ -431,17 +765,18 @@ impl Element {
 
     // TODO: refactor
     #[allow(clippy::too_many_arguments)]
-    fn query_item(
-        storage: &RocksDbStorage,
+    fn query_item<S: Storage>(
+        storage: &S,
         item: &QueryItem,
         results: &mut Vec<(Vec<u8>, Element)>,
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
         path: Option<&[&[u8]]>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
         limit: &mut Option<u16>,
         offset: &mut Option<u16>,
-        add_element_function: fn(PathQueryPushArgs) -> CostResult<(), Error>,
+        cost_limit: Option<&OperationCost>,
+        add_element_function: fn(PathQueryPushArgs<S>) -> CostResult<(), Error>,
     ) -> CostResult<(), Error> {
         let mut cost = OperationCost::default();
 
@@ -456,10 +791,15 @@ impl Element {
                     subtree,
                     { Element::get(&subtree, key).unwrap_add_cost(&mut cost) }
                 );
-                match element_res {
+                // Relative references are made absolute while the path is known
+                match element_res
+                    .and_then(|element| element.with_absolute_reference_path(merk_path, key))
+                {
                     Ok(element) => {
                         let (subquery_key, subquery) =
                             Self::subquery_paths_for_sized_query(sized_query, key);
+                        let remaining_cost_limit =
+                            cost_limit.map(|limit| limit.saturating_sub(&cost));
                         add_element_function(PathQueryPushArgs {
                             storage,
                             transaction,
@@ -472,6 +812,7 @@ impl Element {
                             results,
                             limit,
                             offset,
+                            cost_limit: remaining_cost_limit.as_ref(),
                         })
                         .unwrap_add_cost(&mut cost)
                     }
@@ -492,14 +833,18 @@ impl Element {
                 cost.seek_count += 1;
 
                 while item.iter_is_valid_for_type(&iter, *limit, sized_query.query.left_to_right) {
+                    let key = iter.key().expect("key should exist");
                     let element = cost_return_on_error_no_add!(
                         &cost,
                         raw_decode(iter.value().expect("if key exists then value should too"))
+                            .and_then(|element| {
+                                element.with_absolute_reference_path(merk_path, key)
+                            })
                     );
-                    let key = iter.key().expect("key should exist");
                     cost.loaded_bytes += key.len() as u32;
                     let (subquery_key, subquery) =
                         Self::subquery_paths_for_sized_query(sized_query, key);
+                    let remaining_cost_limit = cost_limit.map(|limit| limit.saturating_sub(&cost));
                     cost_return_on_error!(
                         &mut cost,
                         add_element_function(PathQueryPushArgs {
@@ -514,6 +859,7 @@ impl Element {
                             results,
                             limit,
                             offset,
+                            cost_limit: remaining_cost_limit.as_ref(),
                         })
                     );
                     if sized_query.query.left_to_right {
@@ -522,6 +868,7 @@ impl Element {
                         iter.prev();
                     }
                     cost.seek_count += 1;
+                    cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
                 }
                 Ok(())
             })
@@ -529,13 +876,14 @@ impl Element {
         .wrap_with_cost(cost)
     }
 
-    pub fn get_query_apply_function(
-        storage: &RocksDbStorage,
+    pub fn get_query_apply_function<S: Storage>(
+        storage: &S,
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
         path: Option<&[&[u8]]>,
-        transaction: TransactionArg,
-        add_element_function: fn(PathQueryPushArgs) -> CostResult<(), Error>,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
+        add_element_function: fn(PathQueryPushArgs<S>) -> CostResult<(), Error>,
     ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
         let mut cost = OperationCost::default();
 
@@ -559,9 +907,11 @@ impl Element {
                         transaction,
                         &mut limit,
                         &mut offset,
+                        cost_limit.map(|limit| limit.saturating_sub(&cost)).as_ref(),
                         add_element_function,
                     )
                 );
+                cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
                 if limit == Some(0) {
                     break;
                 }
@@ -580,9 +930,11 @@ impl Element {
                         transaction,
                         &mut limit,
                         &mut offset,
+                        cost_limit.map(|limit| limit.saturating_sub(&cost)).as_ref(),
                         add_element_function,
                     )
                 );
+                cost_return_on_error_no_add!(&cost, check_cost_limit(&cost, cost_limit));
                 if limit == Some(0) {
                     break;
                 }
@@ -598,11 +950,12 @@ impl Element {
     }
 
     // Returns a vector of elements, and the number of skipped elements
-    pub fn get_path_query(
-        storage: &RocksDbStorage,
+    pub fn get_path_query<S: Storage>(
+        storage: &S,
         merk_path: &[&[u8]],
         path_query: &PathQuery,
-        transaction: TransactionArg,
+        cost_limit: Option<&OperationCost>,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
         let path_slices = path_query
             .path
@@ -614,23 +967,25 @@ impl Element {
             merk_path,
             &path_query.query,
             Some(path_slices.as_slice()),
+            cost_limit,
             transaction,
             Element::path_query_push,
         )
     }
 
     /// Returns a vector of elements, and the number of skipped elements
-    pub fn get_sized_query(
-        storage: &RocksDbStorage,
+    pub fn get_sized_query<S: Storage>(
+        storage: &S,
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> CostResult<(Vec<KeyElementPair>, u16), Error> {
         Element::get_query_apply_function(
             storage,
             merk_path,
             sized_query,
             None,
+            None,
             transaction,
             Element::path_query_push,
         )
@@ -652,17 +1007,23 @@ impl Element {
     /// If transaction is not passed, the batch will be written immediately.
     /// If transaction is passed, the operation will be committed on the
     /// transaction commit.
+    /// `tree_type` is the kind of `merk`, which tells what the element's node
+    /// contributes to the aggregate of a sum or a count tree.
     pub fn insert<'db, K: AsRef<[u8]>, S: StorageContext<'db>>(
         &self,
         merk: &mut Merk<S>,
         key: K,
+        tree_type: TreeType,
     ) -> CostResult<(), Error> {
         let serialized = match self.serialize() {
             Ok(s) => s,
             Err(e) => return Err(e).wrap_with_cost(Default::default()),
         };
 
-        let batch_operations = [(key, Op::Put(serialized))];
+        let batch_operations = [(
+            key,
+            Op::PutWithFeatureType(serialized, self.get_feature_type(tree_type)),
+        )];
         merk.apply::<_, Vec<u8>>(&batch_operations, &[])
             .map_err(|e| Error::CorruptedData(e.to_string()))
     }
@@ -676,6 +1037,7 @@ impl Element {
         &self,
         merk: &mut Merk<S>,
         key: &[u8],
+        tree_type: TreeType,
     ) -> CostResult<bool, Error> {
         let mut cost = OperationCost::default();
         let exists =
@@ -683,7 +1045,7 @@ impl Element {
         if exists {
             Ok(false).wrap_with_cost(cost)
         } else {
-            cost_return_on_error!(&mut cost, self.insert(merk, key));
+            cost_return_on_error!(&mut cost, self.insert(merk, key, tree_type));
             Ok(true).wrap_with_cost(cost)
         }
     }
@@ -698,13 +1060,21 @@ impl Element {
         merk: &mut Merk<S>,
         key: K,
         referenced_value: Vec<u8>,
+        tree_type: TreeType,
     ) -> CostResult<(), Error> {
         let serialized = match self.serialize() {
             Ok(s) => s,
             Err(e) => return Err(e).wrap_with_cost(Default::default()),
         };
 
-        let batch_operations = [(key, Op::PutReference(serialized, referenced_value))];
+        let batch_operations = [(
+            key,
+            Op::PutReferenceWithFeatureType(
+                serialized,
+                referenced_value,
+                self.get_feature_type(tree_type),
+            ),
+        )];
         merk.apply::<_, Vec<u8>>(&batch_operations, &[])
             .map_err(|e| Error::CorruptedData(e.to_string()))
     }
@@ -772,21 +1142,23 @@ impl<I: RawIterator> ElementsIterator<I> {
 
 #[cfg(test)]
 mod tests {
-    use merk::test_utils::TempMerk;
-    use storage::Storage;
+    use storage::{memory_storage::MemoryStorage, Storage};
 
     use super::*;
     use crate::tests::{make_grovedb, TEST_LEAF};
 
     #[test]
     fn test_success_insert() {
-        let mut merk = TempMerk::new();
+        let storage = MemoryStorage::new();
+        let mut merk = Merk::open(storage.get_storage_context(std::iter::empty()))
+            .unwrap()
+            .expect("cannot open merk");
         Element::empty_tree()
-            .insert(&mut merk, b"mykey")
+            .insert(&mut merk, b"mykey", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"value".to_vec())
-            .insert(&mut merk, b"another-key")
+            .insert(&mut merk, b"another-key", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion 2");
 
@@ -871,19 +1243,19 @@ mod tests {
             .expect("cannot open Merk"); // TODO implement costs
 
         Element::new_item(b"ayyd".to_vec())
-            .insert(&mut merk, b"d")
+            .insert(&mut merk, b"d", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyc".to_vec())
-            .insert(&mut merk, b"c")
+            .insert(&mut merk, b"c", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayya".to_vec())
-            .insert(&mut merk, b"a")
+            .insert(&mut merk, b"a", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyb".to_vec())
-            .insert(&mut merk, b"b")
+            .insert(&mut merk, b"b", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
 
@@ -892,7 +1264,7 @@ mod tests {
         query.insert_key(b"c".to_vec());
         query.insert_key(b"a".to_vec());
         assert_eq!(
-            Element::get_query_values(&storage, &[TEST_LEAF], &query, None)
+            Element::get_query_values(storage, &[TEST_LEAF], &query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             vec![
@@ -906,7 +1278,7 @@ mod tests {
         query.insert_range(b"b".to_vec()..b"d".to_vec());
         query.insert_range(b"a".to_vec()..b"c".to_vec());
         assert_eq!(
-            Element::get_query_values(&storage, &[TEST_LEAF], &query, None)
+            Element::get_query_values(storage, &[TEST_LEAF], &query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             vec![
@@ -921,7 +1293,7 @@ mod tests {
         query.insert_range_inclusive(b"b".to_vec()..=b"d".to_vec());
         query.insert_range(b"b".to_vec()..b"c".to_vec());
         assert_eq!(
-            Element::get_query_values(&storage, &[TEST_LEAF], &query, None)
+            Element::get_query_values(storage, &[TEST_LEAF], &query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             vec![
@@ -937,7 +1309,7 @@ mod tests {
         query.insert_range(b"b".to_vec()..b"d".to_vec());
         query.insert_range(b"a".to_vec()..b"c".to_vec());
         assert_eq!(
-            Element::get_query_values(&storage, &[TEST_LEAF], &query, None)
+            Element::get_query_values(storage, &[TEST_LEAF], &query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             vec![
@@ -959,19 +1331,19 @@ mod tests {
             .expect("cannot open Merk"); // TODO implement costs
 
         Element::new_item(b"ayyd".to_vec())
-            .insert(&mut merk, b"d")
+            .insert(&mut merk, b"d", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyc".to_vec())
-            .insert(&mut merk, b"c")
+            .insert(&mut merk, b"c", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayya".to_vec())
-            .insert(&mut merk, b"a")
+            .insert(&mut merk, b"a", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyb".to_vec())
-            .insert(&mut merk, b"b")
+            .insert(&mut merk, b"b", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
 
@@ -981,7 +1353,7 @@ mod tests {
 
         let ascending_query = SizedQuery::new(query.clone(), None, None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &ascending_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &ascending_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -998,7 +1370,7 @@ mod tests {
 
         let backwards_query = SizedQuery::new(query.clone(), None, None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1023,19 +1395,19 @@ mod tests {
             .expect("cannot open Merk");
 
         Element::new_item(b"ayyd".to_vec())
-            .insert(&mut merk, b"d")
+            .insert(&mut merk, b"d", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyc".to_vec())
-            .insert(&mut merk, b"c")
+            .insert(&mut merk, b"c", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayya".to_vec())
-            .insert(&mut merk, b"a")
+            .insert(&mut merk, b"a", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyb".to_vec())
-            .insert(&mut merk, b"b")
+            .insert(&mut merk, b"b", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
 
@@ -1062,7 +1434,7 @@ mod tests {
         }
 
         check_elements_no_skipped(
-            Element::get_sized_query(&storage, &[TEST_LEAF], &ascending_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &ascending_query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             false,
@@ -1072,7 +1444,7 @@ mod tests {
 
         let backwards_query = SizedQuery::new(query.clone(), None, None);
         check_elements_no_skipped(
-            Element::get_sized_query(&storage, &[TEST_LEAF], &backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             true,
@@ -1085,7 +1457,7 @@ mod tests {
 
         let backwards_query = SizedQuery::new(query.clone(), None, None);
         check_elements_no_skipped(
-            Element::get_sized_query(&storage, &[TEST_LEAF], &backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query"),
             true,
@@ -1103,19 +1475,19 @@ mod tests {
             .expect("cannot open Merk");
 
         Element::new_item(b"ayyd".to_vec())
-            .insert(&mut merk, b"d")
+            .insert(&mut merk, b"d", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyc".to_vec())
-            .insert(&mut merk, b"c")
+            .insert(&mut merk, b"c", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayya".to_vec())
-            .insert(&mut merk, b"a")
+            .insert(&mut merk, b"a", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
         Element::new_item(b"ayyb".to_vec())
-            .insert(&mut merk, b"b")
+            .insert(&mut merk, b"b", TreeType::NormalTree)
             .unwrap()
             .expect("expected successful insertion");
 
@@ -1127,7 +1499,7 @@ mod tests {
         // since these are just keys a backwards query will keep same order
         let backwards_query = SizedQuery::new(query.clone(), None, None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1147,7 +1519,7 @@ mod tests {
         // since these are just keys a backwards query will keep same order
         let backwards_query = SizedQuery::new(query.clone(), None, None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1162,7 +1534,7 @@ mod tests {
         // The limit will mean we will only get back 1 item
         let limit_query = SizedQuery::new(query.clone(), Some(1), None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1177,7 +1549,7 @@ mod tests {
         query.insert_range(b"a".to_vec()..b"c".to_vec());
         let limit_query = SizedQuery::new(query.clone(), Some(2), None);
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1191,7 +1563,7 @@ mod tests {
 
         let limit_offset_query = SizedQuery::new(query.clone(), Some(2), Some(1));
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_offset_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_offset_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1210,7 +1582,7 @@ mod tests {
 
         let limit_offset_backwards_query = SizedQuery::new(query.clone(), Some(2), Some(1));
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_offset_backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_offset_backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1228,7 +1600,7 @@ mod tests {
         query.insert_range(b"b".to_vec()..b"c".to_vec());
         let limit_full_query = SizedQuery::new(query.clone(), Some(5), Some(0));
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_full_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_full_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1247,7 +1619,7 @@ mod tests {
 
         let limit_offset_backwards_query = SizedQuery::new(query.clone(), Some(2), Some(1));
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_offset_backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_offset_backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
@@ -1266,7 +1638,7 @@ mod tests {
         query.insert_range(b"b".to_vec()..b"c".to_vec());
         let limit_backwards_query = SizedQuery::new(query.clone(), Some(2), Some(1));
         let (elements, skipped) =
-            Element::get_sized_query(&storage, &[TEST_LEAF], &limit_backwards_query, None)
+            Element::get_sized_query(storage, &[TEST_LEAF], &limit_backwards_query, None)
                 .unwrap()
                 .expect("expected successful get_query");
         assert_eq!(
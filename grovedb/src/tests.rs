@@ -5,6 +5,7 @@ use rand::Rng;
 use tempfile::TempDir;
 
 use super::*;
+use crate::batch::{GroveDbOp, Op};
 
 pub const TEST_LEAF: &[u8] = b"test_leaf";
 pub const ANOTHER_TEST_LEAF: &[u8] = b"test_leaf2";
@@ -12,7 +13,7 @@ const DEEP_LEAF: &[u8] = b"deep_leaf";
 
 /// GroveDB wrapper to keep temp directory alive
 pub struct TempGroveDb {
-    _tmp_dir: TempDir,
+    _tmp_dir: Option<TempDir>,
     db: GroveDb,
 }
 
@@ -36,18 +37,36 @@ impl Visualize for TempGroveDb {
     }
 }
 
-/// A helper method to create GroveDB with one leaf for a root tree
-pub fn make_grovedb() -> TempGroveDb {
+/// A helper method to create an empty GroveDB over the default storage,
+/// RocksDB ones live in a temp directory
+#[cfg(feature = "rocksdb_storage")]
+pub fn make_empty_grovedb() -> TempGroveDb {
     let tmp_dir = TempDir::new().unwrap();
-    let mut db = GroveDb::open(tmp_dir.path()).unwrap();
-    add_test_leaves(&mut db);
+    let db = GroveDb::open(tmp_dir.path()).unwrap();
     TempGroveDb {
-        _tmp_dir: tmp_dir,
+        _tmp_dir: Some(tmp_dir),
         db,
     }
 }
 
-fn add_test_leaves(db: &mut GroveDb) {
+/// A helper method to create an empty GroveDB over the default storage, which
+/// is kept in memory
+#[cfg(not(feature = "rocksdb_storage"))]
+pub fn make_empty_grovedb() -> TempGroveDb {
+    TempGroveDb {
+        _tmp_dir: None,
+        db: GroveDb::new(MemoryStorage::new()),
+    }
+}
+
+/// A helper method to create GroveDB with one leaf for a root tree
+pub fn make_grovedb() -> TempGroveDb {
+    let mut db = make_empty_grovedb();
+    add_test_leaves(&mut db.db);
+    db
+}
+
+fn add_test_leaves<S: Storage>(db: &mut GroveDb<S>) {
     db.insert([], TEST_LEAF, Element::empty_tree(), None)
         .unwrap()
         .expect("successful root tree leaf insert");
@@ -339,6 +358,7 @@ pub fn make_deep_tree() -> TempGroveDb {
     temp_db
 }
 
+#[cfg(feature = "rocksdb_storage")]
 #[test]
 fn test_init() {
     let tmp_dir = TempDir::new().unwrap();
@@ -485,7 +505,7 @@ fn test_element_with_flags() {
         SizedQuery::new(query, None, None),
     );
     let proof = db
-        .prove_query(&path_query)
+        .prove_query(&path_query, None)
         .unwrap()
         .expect("should successfully create proof");
     let (root_hash, result_set) =
@@ -654,35 +674,1132 @@ fn test_too_many_indirections() {
     db.insert(
         [TEST_LEAF],
         b"key0",
-        Element::new_item(b"oops".to_vec()),
+        Element::new_item(b"oops".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+
+    for i in 1..=(MAX_REFERENCE_HOPS) {
+        db.insert(
+            [TEST_LEAF],
+            &keygen(i),
+            Element::new_reference(vec![TEST_LEAF.to_vec(), keygen(i - 1)]),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+    }
+
+    assert!(matches!(
+        db.insert(
+            [TEST_LEAF],
+            &keygen(MAX_REFERENCE_HOPS + 1),
+            Element::new_reference(vec![TEST_LEAF.to_vec(), keygen(MAX_REFERENCE_HOPS)]),
+            None,
+        )
+        .unwrap(),
+        Err(Error::ReferenceLimit(_))
+    ))
+}
+
+#[test]
+fn test_references_are_rehashed_on_target_update() {
+    let db = make_grovedb();
+    let fresh_db = make_grovedb();
+    let reference_path = vec![TEST_LEAF.to_vec(), b"key1".to_vec()];
+
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_item(b"old".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference1",
+        Element::new_reference(reference_path.clone()),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference2",
+        Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"reference1".to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_item(b"new".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item update");
+
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"reference2", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"new".to_vec())
+    );
+
+    // Hashes are the same as if references were inserted after the update
+    fresh_db
+        .insert(
+            [TEST_LEAF],
+            b"key1",
+            Element::new_item(b"new".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    fresh_db
+        .insert(
+            [ANOTHER_TEST_LEAF],
+            b"reference1",
+            Element::new_reference(reference_path),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+    fresh_db
+        .insert(
+            [ANOTHER_TEST_LEAF],
+            b"reference2",
+            Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"reference1".to_vec()]),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        fresh_db.root_hash(None).unwrap().unwrap()
+    );
+}
+
+#[test]
+fn test_references_are_deleted_with_target() {
+    let db = make_grovedb();
+
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [TEST_LEAF],
+        b"reference1",
+        Element::new_reference(vec![TEST_LEAF.to_vec(), b"key1".to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference2",
+        Element::new_reference(vec![TEST_LEAF.to_vec(), b"reference1".to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+
+    db.delete([TEST_LEAF], b"key1", None)
+        .unwrap()
+        .expect("successful delete");
+
+    assert!(matches!(
+        db.get_raw([TEST_LEAF], b"reference1", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert!(matches!(
+        db.get_raw([ANOTHER_TEST_LEAF], b"reference2", None)
+            .unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+}
+
+#[test]
+fn test_references_into_deleted_tree_are_deleted() {
+    let db = make_grovedb();
+
+    db.insert([TEST_LEAF], b"tree", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful subtree insert");
+    db.insert([TEST_LEAF, b"tree"], b"inner", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"tree", b"inner"],
+        b"key1",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"key2",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference",
+        Element::new_reference(vec![
+            TEST_LEAF.to_vec(),
+            b"tree".to_vec(),
+            b"inner".to_vec(),
+            b"key1".to_vec(),
+        ]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    db.insert(
+        [TEST_LEAF, b"tree", b"inner"],
+        b"reference",
+        Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"key2".to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+
+    db.delete([TEST_LEAF], b"tree", None)
+        .unwrap()
+        .expect("successful delete");
+
+    assert!(matches!(
+        db.get_raw([ANOTHER_TEST_LEAF], b"reference", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert!(db
+        .get_referrers(&[ANOTHER_TEST_LEAF.to_vec()], b"key2", None)
+        .unwrap()
+        .expect("successful referrers get")
+        .is_empty());
+}
+
+#[test]
+fn test_back_links_are_not_shared_with_aux_data() {
+    let db = make_grovedb();
+
+    db.insert(
+        [TEST_LEAF],
+        b"reference",
+        Element::new_reference(vec![ANOTHER_TEST_LEAF.to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    // Aux data of users is kept under the root tree's prefix, just like
+    // back-links of root leaves used to be
+    let aux_key = [b"\0referrers".as_slice(), ANOTHER_TEST_LEAF].concat();
+    db.put_aux(&aux_key, b"not referrers", None)
+        .unwrap()
+        .expect("successful aux insert");
+
+    db.delete([], ANOTHER_TEST_LEAF, None)
+        .unwrap()
+        .expect("successful delete");
+    assert!(matches!(
+        db.get_raw([TEST_LEAF], b"reference", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert_eq!(
+        db.get_aux(&aux_key, None)
+            .unwrap()
+            .expect("successful aux get"),
+        Some(b"not referrers".to_vec())
+    );
+}
+
+#[test]
+fn test_overwritten_reference_is_not_tracked() {
+    let db = make_grovedb();
+
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [TEST_LEAF],
+        b"reference",
+        Element::new_reference(vec![TEST_LEAF.to_vec(), b"key1".to_vec()]),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    db.insert(
+        [TEST_LEAF],
+        b"reference",
+        Element::new_item(b"not a reference".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+
+    db.delete([TEST_LEAF], b"key1", None)
+        .unwrap()
+        .expect("successful delete");
+    assert_eq!(
+        db.get([TEST_LEAF], b"reference", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"not a reference".to_vec())
+    );
+}
+
+#[test]
+fn test_batch_keeps_references_in_sync() {
+    let db = make_grovedb();
+    let fresh_db = make_grovedb();
+    let reference_path = vec![TEST_LEAF.to_vec(), b"key1".to_vec()];
+
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_item(b"old".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference",
+        Element::new_reference(reference_path.clone()),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+
+    let tx = db.start_transaction();
+    db.apply_batch(
+        vec![GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"key1".to_vec(),
+            Element::new_item(b"new".to_vec()),
+        )],
+        None,
+        Some(&tx),
+    )
+    .unwrap()
+    .expect("successful batch apply");
+    db.commit_transaction(tx).expect("successful commit");
+
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"reference", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"new".to_vec())
+    );
+    fresh_db
+        .apply_batch(
+            vec![
+                GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec()],
+                    b"key1".to_vec(),
+                    Element::new_item(b"new".to_vec()),
+                ),
+                GroveDbOp::insert(
+                    vec![ANOTHER_TEST_LEAF.to_vec()],
+                    b"reference".to_vec(),
+                    Element::new_reference(reference_path),
+                ),
+            ],
+            None,
+            None,
+        )
+        .unwrap()
+        .expect("successful batch apply");
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        fresh_db.root_hash(None).unwrap().unwrap()
+    );
+
+    db.apply_batch(
+        vec![GroveDbOp::delete(
+            vec![TEST_LEAF.to_vec()],
+            b"key1".to_vec(),
+        )],
+        None,
+        None,
+    )
+    .unwrap()
+    .expect("successful batch apply");
+    assert!(matches!(
+        db.get_raw([ANOTHER_TEST_LEAF], b"reference", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+}
+
+#[test]
+fn test_batch_references_to_elements_of_the_same_batch() {
+    let db = make_grovedb();
+    let sequential_db = make_grovedb();
+    let document_path = vec![TEST_LEAF.to_vec(), b"document".to_vec()];
+
+    // References come first to make sure they don't depend on the order of
+    // operations in the batch
+    let ops = vec![
+        GroveDbOp::insert(
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            b"alias".to_vec(),
+            Element::new_reference_with_path_type(ReferencePathType::SiblingReference(
+                b"by_name".to_vec(),
+            )),
+        ),
+        GroveDbOp::insert(
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            b"by_name".to_vec(),
+            Element::new_reference(document_path.clone()),
+        ),
+        GroveDbOp::insert(
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            b"by_date".to_vec(),
+            Element::new_reference(document_path.clone()),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"document".to_vec(),
+            Element::new_item(b"value".to_vec()),
+        ),
+    ];
+    db.apply_batch(ops.clone(), None, None)
+        .unwrap()
+        .expect("successful batch apply");
+
+    for op in ops.into_iter().rev() {
+        let element = match op.op {
+            Op::Insert { element } => element,
+            _ => unreachable!(),
+        };
+        sequential_db
+            .insert(op.path.iter().map(|x| x.as_slice()), &op.key, element, None)
+            .unwrap()
+            .expect("successful insert");
+    }
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        sequential_db.root_hash(None).unwrap().unwrap()
+    );
+    for key in [b"alias".as_slice(), b"by_name", b"by_date"] {
+        assert_eq!(
+            db.get([ANOTHER_TEST_LEAF], key, None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"value".to_vec())
+        );
+    }
+
+    // The document is updated along with an index reference to it
+    db.apply_batch(
+        vec![
+            GroveDbOp::insert(
+                vec![ANOTHER_TEST_LEAF.to_vec()],
+                b"by_date".to_vec(),
+                Element::new_reference(document_path),
+            ),
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                b"document".to_vec(),
+                Element::new_item(b"new value".to_vec()),
+            ),
+        ],
+        None,
+        None,
+    )
+    .unwrap()
+    .expect("successful batch apply");
+    for key in [b"alias".as_slice(), b"by_name", b"by_date"] {
+        assert_eq!(
+            db.get([ANOTHER_TEST_LEAF], key, None)
+                .unwrap()
+                .expect("successful get"),
+            Element::new_item(b"new value".to_vec())
+        );
+    }
+}
+
+#[test]
+fn test_batch_rejects_cyclic_references() {
+    let db = make_grovedb();
+    let full_path = |key: &[u8]| vec![TEST_LEAF.to_vec(), key.to_vec()];
+    let root_hash = db.root_hash(None).unwrap().unwrap();
+
+    let ops = vec![
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"a".to_vec(),
+            Element::new_reference(full_path(b"b")),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"b".to_vec(),
+            Element::new_reference(full_path(b"c")),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"c".to_vec(),
+            Element::new_reference(full_path(b"a")),
+        ),
+    ];
+    match db.apply_batch(ops, None, None).unwrap() {
+        Err(Error::CyclicReference(chain)) => assert_eq!(
+            chain,
+            vec![
+                full_path(b"a"),
+                full_path(b"b"),
+                full_path(b"c"),
+                full_path(b"a")
+            ]
+        ),
+        result => panic!("expected cyclic reference error, got {:?}", result),
+    }
+    assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+}
+
+#[test]
+fn test_relative_references() {
+    let db = make_grovedb();
+
+    for parent in [TEST_LEAF, ANOTHER_TEST_LEAF] {
+        db.insert([parent], b"index1", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+        db.insert([parent], b"index2", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+    }
+    let items: [(&[u8], &[u8], &[u8]); 4] = [
+        (TEST_LEAF, b"index1", b"value1"),
+        (TEST_LEAF, b"index2", b"value2"),
+        (ANOTHER_TEST_LEAF, b"index1", b"another1"),
+        (ANOTHER_TEST_LEAF, b"index2", b"another2"),
+    ];
+    for (parent, index, value) in items {
+        db.insert(
+            [parent, index],
+            b"key1",
+            Element::new_item(value.to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    }
+    // The same references are placed under both parents and resolve within them
+    for parent in [TEST_LEAF, ANOTHER_TEST_LEAF] {
+        db.insert(
+            [parent, b"index1"],
+            b"sibling",
+            Element::new_reference_with_path_type(ReferencePathType::SiblingReference(
+                b"key1".to_vec(),
+            )),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+        db.insert(
+            [parent, b"index1"],
+            b"upstream",
+            Element::new_reference_with_path_type(
+                ReferencePathType::UpstreamFromElementHeightReference(
+                    1,
+                    vec![b"index2".to_vec(), b"key1".to_vec()],
+                ),
+            ),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+        db.insert(
+            [parent, b"index2"],
+            b"key1_cousin",
+            Element::new_item(b"cousin".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+        db.insert(
+            [parent, b"index1"],
+            b"key1_cousin",
+            Element::new_reference_with_path_type(ReferencePathType::CousinReference(
+                b"index2".to_vec(),
+            )),
+            None,
+        )
+        .unwrap()
+        .expect("successful reference insert");
+    }
+
+    let get_item =
+        |path: [&[u8]; 2], key: &[u8]| db.get(path, key, None).unwrap().expect("successful get");
+    assert_eq!(
+        get_item([TEST_LEAF, b"index1"], b"sibling"),
+        Element::new_item(b"value1".to_vec())
+    );
+    assert_eq!(
+        get_item([TEST_LEAF, b"index1"], b"upstream"),
+        Element::new_item(b"value2".to_vec())
+    );
+    assert_eq!(
+        get_item([TEST_LEAF, b"index1"], b"key1_cousin"),
+        Element::new_item(b"cousin".to_vec())
+    );
+    assert_eq!(
+        get_item([ANOTHER_TEST_LEAF, b"index1"], b"sibling"),
+        Element::new_item(b"another1".to_vec())
+    );
+    assert_eq!(
+        get_item([ANOTHER_TEST_LEAF, b"index1"], b"upstream"),
+        Element::new_item(b"another2".to_vec())
+    );
+
+    // Queries resolve references against the subtree they are found in
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new(
+        vec![ANOTHER_TEST_LEAF.to_vec(), b"index1".to_vec()],
+        SizedQuery::new(query, None, None),
+    );
+    let (values, _) = db
+        .query(&path_query, None)
+        .unwrap()
+        .expect("successful query");
+    assert_eq!(
+        values,
+        vec![
+            b"another1".to_vec(),
+            b"cousin".to_vec(),
+            b"another1".to_vec(),
+            b"another2".to_vec()
+        ]
+    );
+    let (raw_elements, _) = db
+        .query_raw(&path_query, None)
+        .unwrap()
+        .expect("successful query");
+    assert_eq!(
+        raw_elements[2].1,
+        Element::new_reference(vec![
+            ANOTHER_TEST_LEAF.to_vec(),
+            b"index1".to_vec(),
+            b"key1".to_vec()
+        ])
+    );
+
+    let proof = db
+        .prove_query(&path_query, None)
+        .unwrap()
+        .expect("should successfully create proof");
+    let (root_hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(root_hash, db.db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(
+        Element::deserialize(&result_set[3].1).expect("should deserialize element"),
+        Element::new_item(b"another2".to_vec())
+    );
+
+    assert!(matches!(
+        db.insert(
+            [TEST_LEAF],
+            b"too_high",
+            Element::new_reference_with_path_type(
+                ReferencePathType::UpstreamFromElementHeightReference(2, vec![b"key".to_vec()])
+            ),
+            None,
+        )
+        .unwrap(),
+        Err(Error::InvalidPath(_))
+    ));
+}
+
+#[test]
+fn test_reference_serialization_is_append_compatible() {
+    let path = vec![TEST_LEAF.to_vec(), b"key1".to_vec()];
+    let absolute = Element::new_reference_with_path_type(ReferencePathType::AbsolutePathReference(
+        path.clone(),
+    ));
+    assert_eq!(absolute, Element::new_reference(path));
+    assert_eq!(absolute.serialize().expect("serializable")[0], 1);
+
+    let sibling = Element::new_reference_with_path_type_and_flags(
+        ReferencePathType::SiblingReference(b"key1".to_vec()),
+        Some(vec![1]),
+    );
+    let serialized = sibling.serialize().expect("serializable");
+    assert_eq!(serialized[0], 6);
+    assert_eq!(serialized.len(), sibling.serialized_byte_size());
+    assert_eq!(
+        Element::deserialize(&serialized).expect("deserializable"),
+        sibling
+    );
+}
+
+#[test]
+fn test_reference_max_hops() {
+    let db = make_grovedb();
+    let full_path = |key: &[u8]| vec![TEST_LEAF.to_vec(), key.to_vec()];
+
+    db.insert(
+        [TEST_LEAF],
+        b"key0",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::new_reference(full_path(b"key0")),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+
+    // Two hops are needed to reach the item while only one is allowed
+    let result = db
+        .insert(
+            [TEST_LEAF],
+            b"key2",
+            Element::new_reference_with_max_hops(
+                ReferencePathType::SiblingReference(b"key1".to_vec()),
+                Some(1),
+            ),
+            None,
+        )
+        .unwrap();
+    match result {
+        Err(Error::ReferenceLimit(chain)) => assert_eq!(
+            chain,
+            vec![full_path(b"key2"), full_path(b"key1"), full_path(b"key0")]
+        ),
+        _ => panic!("expected reference limit error, got {:?}", result),
+    }
+    assert!(matches!(
+        db.get([TEST_LEAF], b"key2", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+
+    assert!(matches!(
+        db.insert(
+            [TEST_LEAF],
+            b"key2",
+            Element::new_reference_with_max_hops(
+                ReferencePathType::SiblingReference(b"key0".to_vec()),
+                Some(0),
+            ),
+            None,
+        )
+        .unwrap(),
+        Err(Error::InvalidInput(_))
+    ));
+
+    let reference = Element::new_reference_with_max_hops(
+        ReferencePathType::SiblingReference(b"key1".to_vec()),
+        Some(2),
+    );
+    db.insert([TEST_LEAF], b"key2", reference.clone(), None)
+        .unwrap()
+        .expect("successful reference insert");
+    assert_eq!(reference.max_reference_hops(), Some(2));
+    assert_eq!(
+        db.get_raw([TEST_LEAF], b"key2", None)
+            .unwrap()
+            .expect("successful get"),
+        reference
+    );
+    assert_eq!(
+        db.get([TEST_LEAF], b"key2", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"value".to_vec())
+    );
+
+    // Each reference on the chain keeps its own limit
+    db.insert(
+        [TEST_LEAF],
+        b"key3",
+        Element::new_reference(full_path(b"key2")),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+
+    // The same limits hold for references inserted by a batch
+    let ops = vec![GroveDbOp::insert(
+        vec![TEST_LEAF.to_vec()],
+        b"key4".to_vec(),
+        Element::new_reference_with_max_hops(
+            ReferencePathType::SiblingReference(b"key1".to_vec()),
+            Some(1),
+        ),
+    )];
+    assert!(matches!(
+        db.apply_batch(ops, None, None).unwrap(),
+        Err(Error::ReferenceLimit(_))
+    ));
+}
+
+#[test]
+fn test_cyclic_reference_is_rejected_before_write() {
+    let db = make_grovedb();
+    let full_path = |key: &[u8]| vec![TEST_LEAF.to_vec(), key.to_vec()];
+
+    db.insert(
+        [TEST_LEAF],
+        b"b",
+        Element::new_item(b"value".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    db.insert(
+        [TEST_LEAF],
+        b"a",
+        Element::new_reference(full_path(b"b")),
+        None,
+    )
+    .unwrap()
+    .expect("successful reference insert");
+    let root_hash = db.root_hash(None).unwrap().unwrap();
+
+    let result = db
+        .insert(
+            [TEST_LEAF],
+            b"b",
+            Element::new_reference(full_path(b"a")),
+            None,
+        )
+        .unwrap();
+    match result {
+        Err(Error::CyclicReference(chain)) => {
+            assert_eq!(
+                chain,
+                vec![full_path(b"b"), full_path(b"a"), full_path(b"b")]
+            )
+        }
+        _ => panic!("expected cyclic reference error, got {:?}", result),
+    }
+    assert_eq!(
+        db.get_raw([TEST_LEAF], b"b", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"value".to_vec())
+    );
+    assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+
+    let ops = vec![GroveDbOp::insert(
+        vec![TEST_LEAF.to_vec()],
+        b"b".to_vec(),
+        Element::new_reference(full_path(b"a")),
+    )];
+    assert!(matches!(
+        db.apply_batch(ops, None, None).unwrap(),
+        Err(Error::CyclicReference(_))
+    ));
+    assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+}
+
+#[test]
+fn test_average_case_costs_grow_with_subtree_size() {
+    let db = make_grovedb();
+    let layers_with = |elements_count: u64| {
+        let mut estimated_layers = EstimatedLayerInformationByPath::new();
+        estimated_layers.insert(
+            vec![],
+            EstimatedLayerInformation {
+                elements_count: 2,
+                average_key_size: 16,
+                average_value_size: 40,
+            },
+        );
+        estimated_layers.insert(
+            vec![TEST_LEAF.to_vec()],
+            EstimatedLayerInformation {
+                elements_count,
+                average_key_size: 8,
+                average_value_size: 32,
+            },
+        );
+        estimated_layers
+    };
+    let small = layers_with(10);
+    let large = layers_with(100_000);
+    let path = [TEST_LEAF.to_vec()];
+    let element = Element::new_item(b"value".to_vec());
+
+    let small_get = db.average_case_for_get(&path, &small).cost;
+    let large_get = db.average_case_for_get(&path, &large).cost;
+    // Elements are fetched by key directly, whatever the height of the tree
+    assert_eq!(large_get.seek_count, small_get.seek_count);
+    assert!(large_get.seek_count > 0);
+
+    let small_insert = db
+        .average_case_for_insert(&path, b"key", &element, &small)
+        .cost;
+    let large_insert = db
+        .average_case_for_insert(&path, b"key", &element, &large)
+        .cost;
+    assert!(large_insert.storage_written_bytes > small_insert.storage_written_bytes);
+    assert!(large_insert.hash_node_calls > small_insert.hash_node_calls);
+
+    let small_delete = db.average_case_for_delete(&path, b"key", &small).cost;
+    let large_delete = db.average_case_for_delete(&path, b"key", &large).cost;
+    assert!(large_delete.seek_count > small_delete.seek_count);
+
+    // Ranges are bounded by the limit of the query
+    let mut query = Query::new();
+    query.insert_all();
+    let unlimited = PathQuery::new_unsized(path.to_vec(), query.clone());
+    let limited = PathQuery::new(path.to_vec(), SizedQuery::new(query, Some(10), None));
+    let unlimited_cost = db.average_case_for_query(&unlimited, &large).cost;
+    let limited_cost = db.average_case_for_query(&limited, &large).cost;
+    assert!(unlimited_cost.seek_count > limited_cost.seek_count);
+
+    // The subtree is opened once whatever the number of keys queried
+    let keys_query_cost = |keys: &[&[u8]]| {
+        let mut query = Query::new();
+        for key in keys {
+            query.insert_key(key.to_vec());
+        }
+        db.average_case_for_query(&PathQuery::new_unsized(path.to_vec(), query), &large)
+            .cost
+            .seek_count
+    };
+    let one_key_cost = keys_query_cost(&[b"key1"]);
+    let two_keys_cost = keys_query_cost(&[b"key1", b"key2"]);
+    assert!(two_keys_cost - one_key_cost < one_key_cost);
+
+    // Hints are required for every subtree on the way
+    assert!(matches!(
+        db.average_case_for_insert(
+            &[TEST_LEAF.to_vec(), b"missing".to_vec()],
+            b"key",
+            &element,
+            &large
+        )
+        .value,
+        Err(Error::MissingParameter(_))
+    ));
+}
+
+#[test]
+fn test_cost_limit_stops_batch_before_write() {
+    let db = make_grovedb();
+    let root_hash = db.root_hash(None).unwrap().unwrap();
+    let ops = (0u8..20)
+        .map(|i| {
+            GroveDbOp::insert(
+                vec![TEST_LEAF.to_vec()],
+                vec![i],
+                Element::new_item(vec![i; 10]),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let cost_limit = OperationCost {
+        seek_count: 5,
+        storage_written_bytes: u32::MAX,
+        storage_loaded_bytes: u32::MAX,
+        loaded_bytes: u32::MAX,
+        hash_byte_calls: u32::MAX,
+        hash_node_calls: u16::MAX,
+    };
+    let result = db.apply_batch_with_cost_limit(ops.clone(), None, Some(&cost_limit), None);
+    match result.value {
+        Err(Error::CostLimitExceeded(cost)) => {
+            assert!(cost.exceeds(&cost_limit));
+            assert_eq!(cost, result.cost);
+        }
+        _ => panic!("expected cost limit to be exceeded"),
+    }
+    assert_eq!(db.root_hash(None).unwrap().unwrap(), root_hash);
+    assert!(matches!(
+        db.get([TEST_LEAF], &[0], None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+
+    let unlimited_cost = make_grovedb().apply_batch(ops.clone(), None, None).cost;
+    assert!(result.cost.seek_count < unlimited_cost.seek_count);
+
+    // The batch fits into its own cost
+    db.apply_batch_with_cost_limit(ops, None, Some(&unlimited_cost), None)
+        .unwrap()
+        .expect("batch within the limit");
+}
+
+#[test]
+fn test_cost_limit_stops_queries() {
+    let db = make_grovedb();
+    for i in 0u8..50 {
+        db.insert([TEST_LEAF], &[i], Element::new_item(vec![i; 10]), None)
+            .unwrap()
+            .expect("successful item insert");
+    }
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
+    let cost_limit = OperationCost {
+        seek_count: 10,
+        storage_written_bytes: u32::MAX,
+        storage_loaded_bytes: u32::MAX,
+        loaded_bytes: u32::MAX,
+        hash_byte_calls: u32::MAX,
+        hash_node_calls: u16::MAX,
+    };
+
+    let unlimited_cost = db.query_raw(&path_query, None).cost;
+    let result = db.query_raw_with_cost_limit(&path_query, Some(&cost_limit), None);
+    assert!(matches!(
+        result.value,
+        Err(Error::CostLimitExceeded(ref cost)) if cost == &result.cost
+    ));
+    assert!(result.cost.seek_count < unlimited_cost.seek_count);
+    assert!(db
+        .query_raw_with_cost_limit(&path_query, Some(&unlimited_cost), None)
+        .unwrap()
+        .is_ok());
+
+    // Subqueries are limited by the same budget
+    let mut query = Query::new();
+    query.insert_all();
+    let mut subquery = Query::new();
+    subquery.insert_all();
+    query.set_subquery(subquery);
+    let path_query = PathQuery::new_unsized(vec![], query);
+    let result = db.query_raw_with_cost_limit(&path_query, Some(&cost_limit), None);
+    assert!(matches!(
+        result.value,
+        Err(Error::CostLimitExceeded(ref cost)) if cost == &result.cost
+    ));
+
+    let cost_limit = OperationCost {
+        hash_node_calls: 1,
+        ..cost_limit
+    };
+    let result = db.prove_query_with_cost_limit(&path_query, Some(&cost_limit), None);
+    assert!(matches!(
+        result.value,
+        Err(Error::CostLimitExceeded(ref cost)) if cost == &result.cost
+    ));
+
+    // Proofs of subtrees count towards the limit as well
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
+    let proof_cost = db.prove_query(&path_query, None).cost;
+    assert!(proof_cost.seek_count >= db.query_raw(&path_query, None).cost.seek_count);
+    let result = db.prove_query_with_cost_limit(&path_query, Some(&cost_limit), None);
+    assert!(matches!(
+        result.value,
+        Err(Error::CostLimitExceeded(ref cost)) if cost == &result.cost
+    ));
+}
+
+#[test]
+fn test_cost_limit_stops_following_references() {
+    let db = make_grovedb();
+    db.insert(
+        [TEST_LEAF],
+        b"key0",
+        Element::new_item(b"value".to_vec()),
         None,
     )
     .unwrap()
     .expect("successful item insert");
-
-    for i in 1..=(MAX_REFERENCE_HOPS) {
+    for i in 1u8..5 {
         db.insert(
             [TEST_LEAF],
-            &keygen(i),
-            Element::new_reference(vec![TEST_LEAF.to_vec(), keygen(i - 1)]),
+            &[b'k', b'e', b'y', b'0' + i],
+            Element::new_reference(vec![
+                TEST_LEAF.to_vec(),
+                vec![b'k', b'e', b'y', b'0' + i - 1],
+            ]),
             None,
         )
         .unwrap()
         .expect("successful reference insert");
     }
 
+    let path = vec![TEST_LEAF.to_vec(), b"key4".to_vec()];
+    let result = db.follow_reference(path.clone(), None);
+    assert_eq!(
+        result.value.expect("reference should be followed"),
+        Element::new_item(b"value".to_vec())
+    );
+
+    let cost_limit = OperationCost {
+        seek_count: result.cost.seek_count / 2,
+        ..result.cost
+    };
+    let limited = db.follow_reference_with_cost_limit(path, Some(&cost_limit), None);
     assert!(matches!(
+        limited.value,
+        Err(Error::CostLimitExceeded(ref cost)) if cost == &limited.cost
+    ));
+    assert!(limited.cost.seek_count < result.cost.seek_count);
+}
+
+#[cfg(feature = "rocksdb_storage")]
+#[test]
+fn test_open_with_options() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = RocksDbConfig {
+        block_cache_size: Some(8 * 1024 * 1024),
+        compression: Some(rocksdb_storage::Compression::Lz4),
+        write_buffer_size: Some(4 * 1024 * 1024),
+        parallelism: None,
+        allow_mmap_reads: false,
+        allow_mmap_writes: false,
+    };
+    {
+        let mut db = GroveDb::open_with_options(tmp_dir.path(), options.clone()).unwrap();
+        add_test_leaves(&mut db);
         db.insert(
             [TEST_LEAF],
-            &keygen(MAX_REFERENCE_HOPS + 1),
-            Element::new_reference(vec![TEST_LEAF.to_vec(), keygen(MAX_REFERENCE_HOPS)]),
+            b"key",
+            Element::new_item(b"ayy".to_vec()),
             None,
         )
-        .unwrap(),
-        Err(Error::ReferenceLimit)
-    ))
+        .unwrap()
+        .expect("successful item insert");
+        db.put_aux(b"aux_key", b"aux_value", None)
+            .unwrap()
+            .expect("successful aux insert");
+    }
+
+    let db = GroveDb::open_with_options(tmp_dir.path(), options).unwrap();
+    assert_eq!(
+        db.get([TEST_LEAF], b"key", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"ayy".to_vec())
+    );
+    assert_eq!(
+        db.get_aux(b"aux_key", None)
+            .unwrap()
+            .expect("successful get"),
+        Some(b"aux_value".to_vec())
+    );
 }
 
+#[cfg(feature = "rocksdb_storage")]
 #[test]
 fn test_tree_structure_is_persistent() {
     let tmp_dir = TempDir::new().unwrap();
@@ -734,11 +1851,16 @@ fn test_tree_structure_is_persistent() {
 #[test]
 fn test_root_tree_leaves_are_noted() {
     let db = make_grovedb();
-    let mut hm = BTreeMap::new();
-    hm.insert(TEST_LEAF.to_vec(), 0);
-    hm.insert(ANOTHER_TEST_LEAF.to_vec(), 1);
-    assert_eq!(db.get_root_leaf_keys(None).unwrap().unwrap(), hm);
-    assert_eq!(db.get_root_tree(None).unwrap().unwrap().leaves_len(), 2);
+    for leaf in [TEST_LEAF, ANOTHER_TEST_LEAF] {
+        assert!(matches!(
+            db.get([], leaf, None).unwrap(),
+            Ok(Element::Tree(..))
+        ));
+    }
+    assert!(!db
+        .has_raw([], b"unknown_leaf", None)
+        .unwrap()
+        .expect("cannot check root leaf"));
 }
 
 #[test]
@@ -751,14 +1873,142 @@ fn test_proof_for_non_existent_data() {
     // path to empty subtree
     let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
+
+    assert_eq!(hash, temp_db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(result_set.len(), 0);
+}
+
+#[test]
+fn test_proof_for_non_existent_path() {
+    let temp_db = make_grovedb();
+    temp_db
+        .insert([TEST_LEAF], b"innertree", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"key1",
+            Element::new_item(b"value1".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+
+    let mut query = Query::new();
+    query.insert_all();
+
+    // missing root leaf, missing intermediate subtree and a path through an item
+    for path in [
+        vec![b"unknown_leaf".to_vec()],
+        vec![
+            TEST_LEAF.to_vec(),
+            b"unknown_tree".to_vec(),
+            b"innertree".to_vec(),
+        ],
+        vec![TEST_LEAF.to_vec(), b"innertree".to_vec(), b"key1".to_vec()],
+    ] {
+        let path_query = PathQuery::new_unsized(path, query.clone());
+
+        let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
+        let (hash, result_set) =
+            GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
+
+        assert_eq!(hash, temp_db.root_hash(None).unwrap().unwrap().unwrap());
+        assert_eq!(result_set.len(), 0);
+    }
+}
+
+#[test]
+fn test_absent_path_proof_cannot_be_used_for_existing_path() {
+    let temp_db = make_grovedb();
+    temp_db
+        .insert([TEST_LEAF], b"innertree", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful subtree insert");
+
+    let mut query = Query::new();
+    query.insert_all();
+
+    let absent_path_query = PathQuery::new_unsized(
+        vec![TEST_LEAF.to_vec(), b"unknown_tree".to_vec()],
+        query.clone(),
+    );
+    let proof = temp_db
+        .prove_query(&absent_path_query, None)
+        .unwrap()
+        .unwrap();
+
+    let existing_path_query =
+        PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query);
+    assert!(GroveDb::verify_query(proof.as_slice(), &existing_path_query).is_err());
+}
+
+#[test]
+fn test_proof_with_transaction() {
+    let temp_db = make_grovedb();
+    let transaction = temp_db.start_transaction();
+    temp_db
+        .insert(
+            [TEST_LEAF],
+            b"key1",
+            Element::new_item(b"value1".to_vec()),
+            Some(&transaction),
+        )
+        .unwrap()
+        .expect("successful item insert");
+
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
+
+    // proof of the transaction's state contains the uncommitted item
+    let proof = temp_db
+        .get_proved_path_query(&path_query, Some(&transaction))
+        .unwrap()
+        .unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
+    assert_eq!(
+        hash,
+        temp_db
+            .root_hash(Some(&transaction))
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    );
+    assert_eq!(result_set.len(), 1);
 
+    // proof of the committed state doesn't see it
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
     assert_eq!(hash, temp_db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 0);
 }
 
+#[test]
+fn test_proof_for_root_tree_leaves() {
+    let temp_db = make_grovedb();
+
+    let mut query = Query::new();
+    query.insert_all();
+
+    let path_query = PathQuery::new_unsized(vec![], query);
+
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
+
+    assert_eq!(hash, temp_db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(result_set.len(), 2);
+    assert_eq!(result_set[0].0, TEST_LEAF.to_vec());
+    assert_eq!(result_set[1].0, ANOTHER_TEST_LEAF.to_vec());
+}
+
 #[test]
 fn test_path_query_proofs_without_subquery_with_reference() {
     // Tree Structure
@@ -883,7 +2133,7 @@ fn test_path_query_proofs_without_subquery_with_reference() {
         query,
     );
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -990,7 +2240,7 @@ fn test_path_query_proofs_without_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1006,7 +2256,7 @@ fn test_path_query_proofs_without_subquery() {
         SizedQuery::new(query, Some(1), None),
     );
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1022,7 +2272,7 @@ fn test_path_query_proofs_without_subquery() {
         SizedQuery::new(query, Some(1), Some(1)),
     );
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1038,7 +2288,7 @@ fn test_path_query_proofs_without_subquery() {
         SizedQuery::new(query, Some(2), None),
     );
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1064,7 +2314,7 @@ fn test_path_query_proofs_with_default_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1098,7 +2348,7 @@ fn test_path_query_proofs_with_default_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1121,7 +2371,7 @@ fn test_path_query_proofs_with_default_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(proof.as_slice(), &path_query).expect(
         "should
     execute proof",
@@ -1151,7 +2401,7 @@ fn test_path_query_proofs_with_default_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1204,7 +2454,7 @@ fn test_path_query_proofs_with_subquery_key() {
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1233,7 +2483,7 @@ fn test_path_query_proofs_with_key_and_subquery() {
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1269,7 +2519,7 @@ fn test_path_query_proofs_with_conditional_subquery() {
     query.set_subquery(subquery);
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1311,7 +2561,7 @@ fn test_path_query_proofs_with_conditional_subquery() {
     query.set_subquery(subquery);
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1369,7 +2619,7 @@ fn test_path_query_proofs_with_sized_query() {
         vec![DEEP_LEAF.to_vec()],
         SizedQuery::new(query, Some(3), Some(1)),
     );
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1412,7 +2662,7 @@ fn test_path_query_proofs_with_direction() {
         vec![DEEP_LEAF.to_vec()],
         SizedQuery::new(query, Some(3), Some(1)),
     );
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1440,7 +2690,7 @@ fn test_path_query_proofs_with_direction() {
 
     let path_query = PathQuery::new_unsized(vec![DEEP_LEAF.to_vec()], query);
 
-    let proof = temp_db.prove_query(&path_query).unwrap().unwrap();
+    let proof = temp_db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) =
         GroveDb::verify_query(proof.as_slice(), &path_query).expect("should execute proof");
 
@@ -1478,81 +2728,163 @@ fn test_path_query_proofs_with_direction() {
     assert_eq!(result_set, expected_result_set);
 }
 
-// #[test]
-// fn test_checkpoint() {
-//     let mut db = make_grovedb();
-//     let element1 = Element::new_item(b"ayy".to_vec());
-//
-//     db.insert([], b"key1", Element::empty_tree()).unwrap()
-//         .expect("cannot insert a subtree 1 into GroveDB");
-//     db.insert([b"key1"], b"key2", Element::empty_tree()).unwrap()
-//         .expect("cannot insert a subtree 2 into GroveDB");
-//     db.insert([b"key1", b"key2"], b"key3", element1.clone()).unwrap()
-//         .expect("cannot insert an item into GroveDB");
-//
-//     assert_eq!(
-//         db.get([b"key1", b"key2"], b"key3").unwrap()
-//             .expect("cannot get from grovedb"),
-//         element1
-//     );
-//
-//     let checkpoint_tempdir = TempDir::new("checkpoint").expect("cannot open
-// tempdir");     let mut checkpoint = db
-//         .checkpoint(checkpoint_tempdir.path().join("checkpoint"))
-//         .expect("cannot create a checkpoint");
-//
-//     assert_eq!(
-//         db.get([b"key1", b"key2"], b"key3").unwrap()
-//             .expect("cannot get from grovedb"),
-//         element1
-//     );
-//     assert_eq!(
-//         checkpoint
-//             .get(&[b"key1", b"key2"], b"key3")
-//             .expect("cannot get from checkpoint"),
-//         element1
-//     );
-//
-//     let element2 = Element::new_item(b"ayy2".to_vec());
-//     let element3 = Element::new_item(b"ayy3".to_vec());
-//
-//     checkpoint
-//         .insert([b"key1"], b"key4", element2.clone())
-//         .expect("cannot insert into checkpoint");
-//
-//     db.insert([b"key1"], b"key4", element3.clone()).unwrap()
-//         .expect("cannot insert into GroveDB");
-//
-//     assert_eq!(
-//         checkpoint
-//             .get(&[b"key1"], b"key4")
-//             .expect("cannot get from checkpoint"),
-//         element2,
-//     );
-//
-//     assert_eq!(
-//         db.get([b"key1"], b"key4").unwrap()
-//             .expect("cannot get from GroveDB"),
-//         element3
-//     );
-//
-//     checkpoint
-//         .insert([b"key1"], b"key5", element3.clone())
-//         .expect("cannot insert into checkpoint");
-//
-//     db.insert([b"key1"], b"key6", element3.clone()).unwrap()
-//         .expect("cannot insert into GroveDB");
-//
-//     assert!(matches!(
-//         checkpoint.get(&[b"key1"], b"key6"),
-//         Err(Error::InvalidPath(_))
-//     ));
-//
-//     assert!(matches!(
-//         db.get([b"key1"], b"key5").unwrap(),
-//         Err(Error::InvalidPath(_))
-//     ));
-// }
+#[cfg(feature = "rocksdb_storage")]
+#[test]
+fn test_checkpoint() {
+    let db = make_grovedb();
+    let element1 = Element::new_item(b"ayy".to_vec());
+
+    db.insert([], b"key1", Element::empty_tree(), None)
+        .unwrap()
+        .expect("cannot insert a subtree 1 into GroveDB");
+    db.insert([b"key1".as_ref()], b"key2", Element::empty_tree(), None)
+        .unwrap()
+        .expect("cannot insert a subtree 2 into GroveDB");
+    db.insert([b"key1".as_ref(), b"key2"], b"key3", element1.clone(), None)
+        .unwrap()
+        .expect("cannot insert an item into GroveDB");
+
+    assert_eq!(
+        db.get([b"key1".as_ref(), b"key2"], b"key3", None)
+            .unwrap()
+            .expect("cannot get from grovedb"),
+        element1
+    );
+
+    let checkpoint_tempdir = TempDir::new().expect("cannot open tempdir");
+    let checkpoint = db
+        .create_checkpoint(checkpoint_tempdir.path().join("checkpoint"))
+        .expect("cannot create a checkpoint");
+
+    assert_eq!(
+        db.get([b"key1".as_ref(), b"key2"], b"key3", None)
+            .unwrap()
+            .expect("cannot get from grovedb"),
+        element1
+    );
+    assert_eq!(
+        checkpoint
+            .get([b"key1".as_ref(), b"key2"], b"key3", None)
+            .unwrap()
+            .expect("cannot get from checkpoint"),
+        element1
+    );
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        checkpoint.root_hash(None).unwrap().unwrap()
+    );
+
+    let element2 = Element::new_item(b"ayy2".to_vec());
+    let element3 = Element::new_item(b"ayy3".to_vec());
+
+    checkpoint
+        .insert([b"key1".as_ref()], b"key4", element2.clone(), None)
+        .unwrap()
+        .expect("cannot insert into checkpoint");
+
+    db.insert([b"key1".as_ref()], b"key4", element3.clone(), None)
+        .unwrap()
+        .expect("cannot insert into GroveDB");
+
+    assert_eq!(
+        checkpoint
+            .get([b"key1".as_ref()], b"key4", None)
+            .unwrap()
+            .expect("cannot get from checkpoint"),
+        element2,
+    );
+
+    assert_eq!(
+        db.get([b"key1".as_ref()], b"key4", None)
+            .unwrap()
+            .expect("cannot get from GroveDB"),
+        element3
+    );
+
+    checkpoint
+        .insert([b"key1".as_ref()], b"key5", element3.clone(), None)
+        .unwrap()
+        .expect("cannot insert into checkpoint");
+
+    db.insert([b"key1".as_ref()], b"key6", element3, None)
+        .unwrap()
+        .expect("cannot insert into GroveDB");
+
+    assert!(matches!(
+        checkpoint.get([b"key1".as_ref()], b"key6", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+
+    assert!(matches!(
+        db.get([b"key1".as_ref()], b"key5", None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+}
+
+#[cfg(feature = "rocksdb_storage")]
+#[test]
+fn test_read_only_checkpoint() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = RocksDbConfig {
+        compression: Some(rocksdb_storage::Compression::Lz4),
+        allow_mmap_reads: false,
+        allow_mmap_writes: false,
+        ..Default::default()
+    };
+    let mut db = GroveDb::open_with_options(tmp_dir.path(), options.clone()).unwrap();
+    add_test_leaves(&mut db);
+    db.insert(
+        [TEST_LEAF],
+        b"key",
+        Element::new_item(b"ayy".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+
+    // Checkpoints are opened the way the origin was
+    let checkpoint_tempdir = TempDir::new().expect("cannot open tempdir");
+    let checkpoint = db
+        .create_checkpoint(checkpoint_tempdir.path().join("checkpoint"))
+        .expect("cannot create a checkpoint");
+    assert_eq!(checkpoint.db.config(), &options);
+    drop(checkpoint);
+
+    let read_only = db
+        .create_read_only_checkpoint(checkpoint_tempdir.path().join("read_only"))
+        .expect("cannot create a read-only checkpoint");
+    db.insert(
+        [TEST_LEAF],
+        b"key",
+        Element::new_item(b"ayy2".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    assert_eq!(
+        read_only
+            .get([TEST_LEAF], b"key")
+            .unwrap()
+            .expect("cannot get from checkpoint"),
+        Element::new_item(b"ayy".to_vec())
+    );
+    let read_only_root_hash = read_only.root_hash().unwrap().unwrap();
+    assert_ne!(db.root_hash(None).unwrap().unwrap(), read_only_root_hash);
+    drop(read_only);
+
+    // A historical view is opened again from the checkpoint
+    let reopened = GroveDb::open_checkpoint(checkpoint_tempdir.path().join("read_only"), options)
+        .expect("cannot open a checkpoint");
+    assert_eq!(reopened.root_hash().unwrap().unwrap(), read_only_root_hash);
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
+    let proof = reopened.prove_query(&path_query).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(Some(hash), read_only_root_hash);
+    assert_eq!(result_set.len(), 1);
+}
 
 #[test]
 fn test_insert_if_not_exists() {
@@ -1960,21 +3292,81 @@ fn test_subtree_deletion() {
         .unwrap()
         .expect("successful subtree 3 insert");
 
-    let root_hash = db.root_hash(None).unwrap().unwrap();
-    db.delete([TEST_LEAF], b"key1", None)
+    let root_hash = db.root_hash(None).unwrap().unwrap();
+    db.delete([TEST_LEAF], b"key1", None)
+        .unwrap()
+        .expect("unable to delete subtree");
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1", b"key2"], b"key3", None)
+            .unwrap(),
+        Err(Error::PathNotFound(_))
+    ));
+    // assert_eq!(db.subtrees.len().unwrap(), 3); // TEST_LEAF, ANOTHER_TEST_LEAF
+    // TEST_LEAF.key4 stay
+    assert!(db.get([], TEST_LEAF, None).unwrap().is_ok());
+    assert!(db.get([], ANOTHER_TEST_LEAF, None).unwrap().is_ok());
+    assert!(db.get([TEST_LEAF], b"key4", None).unwrap().is_ok());
+    assert_ne!(root_hash, db.root_hash(None).unwrap().unwrap());
+}
+
+#[test]
+fn test_root_leaf_deletion() {
+    let element = Element::new_item(b"ayy".to_vec());
+    let db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful subtree insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", element, None)
+        .unwrap()
+        .expect("successful value insert");
+
+    let root_hash = db.root_hash(None).unwrap().unwrap();
+    db.delete([], TEST_LEAF, None)
+        .unwrap()
+        .expect("unable to delete root leaf");
+    assert!(matches!(
+        db.get([], TEST_LEAF, None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key2", None).unwrap(),
+        Err(Error::PathNotFound(_))
+    ));
+    assert!(db.get([], ANOTHER_TEST_LEAF, None).unwrap().is_ok());
+    assert_ne!(root_hash, db.root_hash(None).unwrap().unwrap());
+
+    // Nested subtree's storage was cleared too, so reinserting the root leaf gives
+    // an empty tree
+    db.insert([], TEST_LEAF, Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful root leaf insert");
+    let subtree_storage = db.db.db.get_storage_context([TEST_LEAF, b"key1"]);
+    let subtree = Merk::open(subtree_storage)
+        .unwrap()
+        .expect("cannot open merk");
+    assert!(subtree.is_empty_tree());
+}
+
+#[test]
+fn test_root_leaf_deletion_with_transaction() {
+    let db = make_grovedb();
+    let transaction = db.start_transaction();
+
+    db.delete([], TEST_LEAF, Some(&transaction))
         .unwrap()
-        .expect("unable to delete subtree");
+        .expect("unable to delete root leaf");
     assert!(matches!(
-        db.get([TEST_LEAF, b"key1", b"key2"], b"key3", None)
-            .unwrap(),
-        Err(Error::PathNotFound(_))
+        db.get([], TEST_LEAF, Some(&transaction)).unwrap(),
+        Err(Error::PathKeyNotFound(_))
     ));
-    // assert_eq!(db.subtrees.len().unwrap(), 3); // TEST_LEAF, ANOTHER_TEST_LEAF
-    // TEST_LEAF.key4 stay
     assert!(db.get([], TEST_LEAF, None).unwrap().is_ok());
-    assert!(db.get([], ANOTHER_TEST_LEAF, None).unwrap().is_ok());
-    assert!(db.get([TEST_LEAF], b"key4", None).unwrap().is_ok());
-    assert_ne!(root_hash, db.root_hash(None).unwrap().unwrap());
+
+    db.commit_transaction(transaction)
+        .expect("cannot commit changes");
+    assert!(matches!(
+        db.get([], TEST_LEAF, None).unwrap(),
+        Err(Error::PathKeyNotFound(_))
+    ));
 }
 
 #[test]
@@ -2606,7 +3998,7 @@ fn test_get_range_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 200);
@@ -2641,7 +4033,7 @@ fn test_get_range_query_with_unique_subquery() {
     let last_value = 1991_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 4);
@@ -2676,7 +4068,7 @@ fn test_get_range_query_with_unique_subquery_on_references() {
     let last_value = 1991_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 4);
@@ -2720,7 +4112,7 @@ fn test_get_range_query_with_unique_subquery_with_non_unique_null_values() {
     let last_value = 1999_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 115);
@@ -2763,7 +4155,7 @@ fn test_get_range_query_with_unique_subquery_ignore_non_unique_null_values() {
     let last_value = 1999_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 15);
@@ -2803,7 +4195,7 @@ fn test_get_range_inclusive_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 400);
@@ -2846,7 +4238,7 @@ fn test_get_range_inclusive_query_with_non_unique_subquery_on_references() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert!(elements.contains(&last_value));
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 400);
@@ -2881,7 +4273,7 @@ fn test_get_range_inclusive_query_with_unique_subquery() {
     let last_value = 1995_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 8);
@@ -2921,7 +4313,7 @@ fn test_get_range_from_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 250);
@@ -2956,7 +4348,7 @@ fn test_get_range_from_query_with_unique_subquery() {
     let last_value = 1999_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 5);
@@ -2996,7 +4388,7 @@ fn test_get_range_to_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 500);
@@ -3031,7 +4423,7 @@ fn test_get_range_to_query_with_unique_subquery() {
     let last_value = 1994_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 10);
@@ -3071,7 +4463,7 @@ fn test_get_range_to_inclusive_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 550);
@@ -3111,7 +4503,7 @@ fn test_get_range_to_inclusive_query_with_non_unique_subquery_and_key_out_of_bou
     last_value.append(&mut 100_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 750);
@@ -3146,7 +4538,7 @@ fn test_get_range_to_inclusive_query_with_unique_subquery() {
     let last_value = 1995_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 11);
@@ -3186,7 +4578,7 @@ fn test_get_range_after_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 200);
@@ -3226,7 +4618,7 @@ fn test_get_range_after_to_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 50);
@@ -3268,7 +4660,7 @@ fn test_get_range_after_to_inclusive_query_with_non_unique_subquery() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 100);
@@ -3310,7 +4702,7 @@ fn test_get_range_after_to_inclusive_query_with_non_unique_subquery_and_key_out_
     last_value.append(&mut 100_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 200);
@@ -3356,7 +4748,7 @@ fn test_get_range_inclusive_query_with_double_non_unique_subquery() {
     let last_value = 109_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 60);
@@ -3397,7 +4789,7 @@ fn test_get_range_query_with_limit_and_offset() {
     last_value.append(&mut 149_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 250);
@@ -3428,7 +4820,7 @@ fn test_get_range_query_with_limit_and_offset() {
     last_value.append(&mut 100_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 250);
@@ -3460,7 +4852,7 @@ fn test_get_range_query_with_limit_and_offset() {
     last_value.append(&mut 104_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 55);
@@ -3495,7 +4887,7 @@ fn test_get_range_query_with_limit_and_offset() {
     last_value.append(&mut 123_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 60);
@@ -3531,7 +4923,7 @@ fn test_get_range_query_with_limit_and_offset() {
     last_value.append(&mut 119_u32.to_be_bytes().to_vec());
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 60);
@@ -3555,7 +4947,7 @@ fn test_get_range_query_with_limit_and_offset() {
 
     assert_eq!(elements.len(), 0);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 0);
@@ -3576,7 +4968,7 @@ fn test_get_range_query_with_limit_and_offset() {
 
     assert_eq!(elements.len(), 250);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 250);
@@ -3605,7 +4997,7 @@ fn test_get_range_query_with_limit_and_offset() {
     let last_value = 1996_u32.to_be_bytes().to_vec();
     assert_eq!(elements[elements.len() - 1], last_value);
 
-    let proof = db.prove_query(&path_query).unwrap().unwrap();
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
     let (hash, result_set) = GroveDb::verify_query(&proof, &path_query).unwrap();
     assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
     assert_eq!(result_set.len(), 5);
@@ -3700,7 +5092,8 @@ fn test_subtree_deletion_with_transaction() {
             .unwrap(),
         Err(Error::PathNotFound(_))
     ));
-    transaction.commit().expect("cannot commit transaction");
+    db.commit_transaction(transaction)
+        .expect("cannot commit transaction");
     assert!(matches!(
         db.get([TEST_LEAF], b"key1", None).unwrap(),
         Err(Error::PathKeyNotFound(_))
@@ -3808,3 +5201,505 @@ fn test_tree_value_exists_method_tx() {
     assert!(db.has_raw([TEST_LEAF], b"key", None).unwrap().unwrap());
     assert!(db.has_raw([], b"leaf", None).unwrap().unwrap());
 }
+
+#[test]
+fn test_memory_storage() {
+    fn fill<S: Storage>(db: &GroveDb<S>) {
+        db.insert([TEST_LEAF], b"innertree", Element::empty_tree(), None)
+            .unwrap()
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"innertree"],
+            b"key1",
+            Element::new_item(b"value1".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+
+        let tx = db.start_transaction();
+        db.insert(
+            [TEST_LEAF, b"innertree"],
+            b"key2",
+            Element::new_item(b"value2".to_vec()),
+            Some(&tx),
+        )
+        .unwrap()
+        .expect("successful item insert");
+        assert!(matches!(
+            db.get([TEST_LEAF, b"innertree"], b"key2", None).unwrap(),
+            Err(Error::PathKeyNotFound(_))
+        ));
+        db.commit_transaction(tx)
+            .expect("cannot commit transaction");
+
+        db.apply_batch(
+            vec![
+                GroveDbOp::insert(
+                    vec![TEST_LEAF.to_vec(), b"innertree".to_vec()],
+                    b"key3".to_vec(),
+                    Element::new_item(b"value3".to_vec()),
+                ),
+                GroveDbOp::insert(
+                    vec![ANOTHER_TEST_LEAF.to_vec()],
+                    b"ref".to_vec(),
+                    Element::new_reference(vec![
+                        TEST_LEAF.to_vec(),
+                        b"innertree".to_vec(),
+                        b"key1".to_vec(),
+                    ]),
+                ),
+            ],
+            None,
+            None,
+        )
+        .unwrap()
+        .expect("cannot apply batch");
+    }
+
+    let mut db = GroveDb::new(MemoryStorage::new());
+    add_test_leaves(&mut db);
+    fill(&db);
+
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"ref", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::new_item(b"value1".to_vec())
+    );
+
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"innertree".to_vec()], query);
+    let (elements, _) = db
+        .query(&path_query, None)
+        .unwrap()
+        .expect("successful query");
+    assert_eq!(
+        elements,
+        vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()]
+    );
+
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(result_set.len(), 3);
+
+    // The same data results in the same root hash regardless of the backend
+    let rocksdb = make_grovedb();
+    fill(&rocksdb);
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        rocksdb.root_hash(None).unwrap().unwrap()
+    );
+}
+
+#[test]
+fn test_sum_tree_propagates_sums() {
+    let db = make_grovedb();
+    db.insert([TEST_LEAF], b"sums", Element::empty_sum_tree(), None)
+        .unwrap()
+        .expect("successful sum tree insert");
+    assert_eq!(
+        db.get([TEST_LEAF], b"sums", None)
+            .unwrap()
+            .expect("successful get"),
+        Element::empty_sum_tree()
+    );
+
+    db.insert([TEST_LEAF, b"sums"], b"a", Element::new_sum_item(5), None)
+        .unwrap()
+        .expect("successful sum item insert");
+    db.insert([TEST_LEAF, b"sums"], b"b", Element::new_sum_item(10), None)
+        .unwrap()
+        .expect("successful sum item insert");
+    db.insert([TEST_LEAF, b"sums"], b"c", Element::new_sum_item(-3), None)
+        .unwrap()
+        .expect("successful sum item insert");
+    // Items which are not sum items don't count
+    db.insert(
+        [TEST_LEAF, b"sums"],
+        b"d",
+        Element::new_item(b"ayy".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    let sum_of = |db: &TempGroveDb, path: &[&[u8]], key: &[u8]| {
+        db.get(path.iter().copied(), key, None)
+            .unwrap()
+            .expect("successful get")
+            .sum_value()
+    };
+    assert_eq!(sum_of(&db, &[TEST_LEAF], b"sums"), 12);
+
+    // Nested sum trees add up into the parent sum tree
+    db.insert([TEST_LEAF, b"sums"], b"e", Element::empty_sum_tree(), None)
+        .unwrap()
+        .expect("successful sum tree insert");
+    db.insert(
+        [TEST_LEAF, b"sums", b"e"],
+        b"a",
+        Element::new_sum_item(7),
+        None,
+    )
+    .unwrap()
+    .expect("successful sum item insert");
+    assert_eq!(sum_of(&db, &[TEST_LEAF, b"sums"], b"e"), 7);
+    assert_eq!(sum_of(&db, &[TEST_LEAF], b"sums"), 19);
+
+    // Updates and deletions are reflected as well
+    db.insert([TEST_LEAF, b"sums"], b"b", Element::new_sum_item(1), None)
+        .unwrap()
+        .expect("successful sum item update");
+    db.delete([TEST_LEAF, b"sums"], b"c", None)
+        .unwrap()
+        .expect("successful delete");
+    assert_eq!(sum_of(&db, &[TEST_LEAF], b"sums"), 13);
+
+    // The same data in a regular tree results in another hash
+    db.insert([ANOTHER_TEST_LEAF], b"sums", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful tree insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF, b"sums"],
+        b"a",
+        Element::new_sum_item(5),
+        None,
+    )
+    .unwrap()
+    .expect("successful sum item insert");
+    let sum_tree = db
+        .get([TEST_LEAF], b"sums", None)
+        .unwrap()
+        .expect("successful get");
+    let tree = db
+        .get([ANOTHER_TEST_LEAF], b"sums", None)
+        .unwrap()
+        .expect("successful get");
+    assert!(matches!(tree, Element::Tree(..)));
+    assert!(matches!(sum_tree, Element::SumTree(..)));
+}
+
+#[test]
+fn test_sum_tree_batch() {
+    let db = make_grovedb();
+    let ops = vec![
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"sums".to_vec(),
+            Element::empty_sum_tree(),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"sums".to_vec()],
+            b"a".to_vec(),
+            Element::new_sum_item(5),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"sums".to_vec()],
+            b"b".to_vec(),
+            Element::empty_sum_tree(),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"sums".to_vec(), b"b".to_vec()],
+            b"a".to_vec(),
+            Element::new_sum_item(-2),
+        ),
+    ];
+    db.apply_batch(ops, None, None)
+        .unwrap()
+        .expect("cannot apply batch");
+    assert_eq!(
+        db.get([TEST_LEAF], b"sums", None)
+            .unwrap()
+            .expect("successful get")
+            .sum_value(),
+        3
+    );
+
+    // Operations on an existing sum tree
+    let ops = vec![
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"sums".to_vec()],
+            b"c".to_vec(),
+            Element::new_sum_item(10),
+        ),
+        GroveDbOp::delete(
+            vec![TEST_LEAF.to_vec(), b"sums".to_vec(), b"b".to_vec()],
+            b"a".to_vec(),
+        ),
+    ];
+    db.apply_batch(ops, None, None)
+        .unwrap()
+        .expect("cannot apply batch");
+    assert_eq!(
+        db.get([TEST_LEAF], b"sums", None)
+            .unwrap()
+            .expect("successful get")
+            .sum_value(),
+        15
+    );
+
+    // Batch and direct operations lead to the same state
+    let direct_db = make_grovedb();
+    direct_db
+        .insert([TEST_LEAF], b"sums", Element::empty_sum_tree(), None)
+        .unwrap()
+        .expect("successful sum tree insert");
+    direct_db
+        .insert([TEST_LEAF, b"sums"], b"a", Element::new_sum_item(5), None)
+        .unwrap()
+        .expect("successful sum item insert");
+    direct_db
+        .insert([TEST_LEAF, b"sums"], b"b", Element::empty_sum_tree(), None)
+        .unwrap()
+        .expect("successful sum tree insert");
+    direct_db
+        .insert([TEST_LEAF, b"sums"], b"c", Element::new_sum_item(10), None)
+        .unwrap()
+        .expect("successful sum item insert");
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        direct_db.root_hash(None).unwrap().unwrap()
+    );
+}
+
+#[test]
+fn test_sum_tree_proofs() {
+    let db = make_grovedb();
+    db.insert([TEST_LEAF], b"sums", Element::empty_sum_tree(), None)
+        .unwrap()
+        .expect("successful sum tree insert");
+    for (key, value) in [(b"a", 3), (b"b", 4), (b"c", -1), (b"d", 8)] {
+        db.insert(
+            [TEST_LEAF, b"sums"],
+            key,
+            Element::new_sum_item(value),
+            None,
+        )
+        .unwrap()
+        .expect("successful sum item insert");
+    }
+
+    // A query into a sum tree is checked against the sum of its element
+    let mut query = Query::new();
+    query.insert_key(b"b".to_vec());
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec(), b"sums".to_vec()], query);
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(
+        result_set,
+        vec![(b"b".to_vec(), Element::new_sum_item(4).serialize().unwrap())]
+    );
+
+    // The aggregate is proven by the sum tree element itself
+    let mut query = Query::new();
+    query.insert_key(b"sums".to_vec());
+    let path_query = PathQuery::new_unsized(vec![TEST_LEAF.to_vec()], query);
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(hash, db.root_hash(None).unwrap().unwrap().unwrap());
+    assert_eq!(result_set.len(), 1);
+    assert_eq!(
+        Element::deserialize(&result_set[0].1)
+            .expect("should deserialize element")
+            .sum_value(),
+        14
+    );
+}
+
+#[test]
+fn test_count_tree_counts_elements() {
+    let db = make_grovedb();
+    db.insert([TEST_LEAF], b"counts", Element::empty_count_tree(), None)
+        .unwrap()
+        .expect("successful count tree insert");
+    assert_eq!(
+        db.count([TEST_LEAF, b"counts"], None)
+            .unwrap()
+            .expect("successful count"),
+        0
+    );
+
+    for key in [b"a", b"b", b"c"] {
+        db.insert(
+            [TEST_LEAF, b"counts"],
+            key,
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    }
+    // A nested subtree is a single element regardless of its contents
+    db.insert([TEST_LEAF, b"counts"], b"d", Element::empty_tree(), None)
+        .unwrap()
+        .expect("successful tree insert");
+    db.insert(
+        [TEST_LEAF, b"counts", b"d"],
+        b"a",
+        Element::new_item(b"ayy".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item insert");
+    assert_eq!(
+        db.count([TEST_LEAF, b"counts"], None)
+            .unwrap()
+            .expect("successful count"),
+        4
+    );
+
+    // Updates don't change the count, deletions do
+    db.insert(
+        [TEST_LEAF, b"counts"],
+        b"a",
+        Element::new_item(b"lmao".to_vec()),
+        None,
+    )
+    .unwrap()
+    .expect("successful item update");
+    db.delete([TEST_LEAF, b"counts"], b"b", None)
+        .unwrap()
+        .expect("successful delete");
+    assert_eq!(
+        db.count([TEST_LEAF, b"counts"], None)
+            .unwrap()
+            .expect("successful count"),
+        3
+    );
+
+    assert!(matches!(
+        db.count([TEST_LEAF], None).unwrap(),
+        Err(Error::InvalidPath(_))
+    ));
+    assert!(matches!(
+        db.count([TEST_LEAF, b"counts", b"d"], None).unwrap(),
+        Err(Error::InvalidPath(_))
+    ));
+}
+
+#[test]
+fn test_count_tree_batch() {
+    let db = make_grovedb();
+    let ops = vec![
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec()],
+            b"counts".to_vec(),
+            Element::empty_count_tree(),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"counts".to_vec()],
+            b"a".to_vec(),
+            Element::new_item(b"ayy".to_vec()),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"counts".to_vec()],
+            b"b".to_vec(),
+            Element::empty_count_tree(),
+        ),
+        GroveDbOp::insert(
+            vec![TEST_LEAF.to_vec(), b"counts".to_vec(), b"b".to_vec()],
+            b"a".to_vec(),
+            Element::new_item(b"ayy".to_vec()),
+        ),
+    ];
+    db.apply_batch(ops, None, None)
+        .unwrap()
+        .expect("cannot apply batch");
+    assert_eq!(db.count([TEST_LEAF, b"counts"], None).unwrap().unwrap(), 2);
+    assert_eq!(
+        db.count([TEST_LEAF, b"counts", b"b"], None)
+            .unwrap()
+            .unwrap(),
+        1
+    );
+
+    // Batch and direct operations lead to the same state
+    let direct_db = make_grovedb();
+    direct_db
+        .insert([TEST_LEAF], b"counts", Element::empty_count_tree(), None)
+        .unwrap()
+        .expect("successful count tree insert");
+    direct_db
+        .insert(
+            [TEST_LEAF, b"counts"],
+            b"a",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    direct_db
+        .insert(
+            [TEST_LEAF, b"counts"],
+            b"b",
+            Element::empty_count_tree(),
+            None,
+        )
+        .unwrap()
+        .expect("successful count tree insert");
+    direct_db
+        .insert(
+            [TEST_LEAF, b"counts", b"b"],
+            b"a",
+            Element::new_item(b"ayy".to_vec()),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    assert_eq!(
+        db.root_hash(None).unwrap().unwrap(),
+        direct_db.root_hash(None).unwrap().unwrap()
+    );
+}
+
+#[test]
+fn test_count_tree_proofs() {
+    let db = make_grovedb();
+    db.insert([TEST_LEAF], b"counts", Element::empty_count_tree(), None)
+        .unwrap()
+        .expect("successful count tree insert");
+    for i in 0u8..20 {
+        db.insert(
+            [TEST_LEAF, b"counts"],
+            &[i],
+            Element::new_item(vec![i]),
+            None,
+        )
+        .unwrap()
+        .expect("successful item insert");
+    }
+    let root_hash = db.root_hash(None).unwrap().unwrap().unwrap();
+
+    // The count is proven without any elements of the count tree
+    let path = vec![TEST_LEAF.to_vec(), b"counts".to_vec()];
+    let proof = db.prove_count(path.clone(), None).unwrap().unwrap();
+    let (hash, count) = GroveDb::verify_count(&proof, path.clone()).expect("should verify proof");
+    assert_eq!(hash, root_hash);
+    assert_eq!(count, 20);
+
+    // A query into a count tree is checked against the count of its element
+    let mut query = Query::new();
+    query.insert_key(vec![5]);
+    let path_query = PathQuery::new_unsized(path, query);
+    let proof = db.prove_query(&path_query, None).unwrap().unwrap();
+    let (hash, result_set) =
+        GroveDb::verify_query(&proof, &path_query).expect("should verify proof");
+    assert_eq!(hash, root_hash);
+    assert_eq!(
+        result_set,
+        vec![(vec![5], Element::new_item(vec![5]).serialize().unwrap())]
+    );
+
+    // A proof for a regular tree is not a count proof
+    let proof = db
+        .prove_count(vec![TEST_LEAF.to_vec()], None)
+        .unwrap()
+        .unwrap();
+    assert!(GroveDb::verify_count(&proof, vec![TEST_LEAF.to_vec()]).is_err());
+}
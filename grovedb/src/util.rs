@@ -1,9 +1,12 @@
+use costs::{CostResult, OperationCost};
+
+use crate::Error;
+
 /// Macro to execute same piece of code on different storage contexts
 /// (transactional or not) using path argument.
 macro_rules! storage_context_optional_tx {
     ($db:expr, $path:expr, $transaction:ident, $storage:ident, { $($body:tt)* }) => {
         {
-            use ::storage::Storage;
             if let Some(tx) = $transaction {
                 let $storage = $db
                     .get_transactional_storage_context($path, tx);
@@ -17,12 +20,30 @@ macro_rules! storage_context_optional_tx {
     };
 }
 
+/// Macro to execute same piece of code on different batched storage contexts
+/// (transactional or not) using path argument. Writes pending in the batch are
+/// seen by reads made through them.
+macro_rules! batch_storage_context_optional_tx {
+    ($db:expr, $path:expr, $batch:expr, $transaction:ident, $storage:ident, { $($body:tt)* }) => {
+        {
+            if let Some(tx) = $transaction {
+                let $storage = $db
+                    .get_batch_transactional_storage_context($path, $batch, tx);
+                $($body)*
+            } else {
+                let $storage = $db
+                    .get_batch_storage_context($path, $batch);
+                $($body)*
+            }
+        }
+    };
+}
+
 /// Macro to execute same piece of code on different storage contexts with empty
 /// prefix.
 macro_rules! meta_storage_context_optional_tx {
     ($db:expr, $transaction:ident, $storage:ident, { $($body:tt)* }) => {
         {
-            use ::storage::Storage;
             if let Some(tx) = $transaction {
                 let $storage = $db
                     .get_transactional_storage_context(::std::iter::empty(), tx);
@@ -91,6 +112,30 @@ macro_rules! merk_optional_tx {
     };
 }
 
+pub(crate) use batch_storage_context_optional_tx;
 pub(crate) use merk_optional_tx;
 pub(crate) use meta_storage_context_optional_tx;
 pub(crate) use storage_context_optional_tx;
+
+/// Fails with `CostLimitExceeded` if `cost` spent so far is over `cost_limit`
+pub(crate) fn check_cost_limit(
+    cost: &OperationCost,
+    cost_limit: Option<&OperationCost>,
+) -> Result<(), Error> {
+    match cost_limit {
+        Some(limit) if cost.exceeds(limit) => Err(Error::CostLimitExceeded(cost.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Nested calls are limited by what is left of the budget and report only
+/// their own cost on exceeding it, so the error is made to carry the whole
+/// cost of the operation instead
+pub(crate) fn with_total_cost_on_limit<T>(
+    mut result: CostResult<T, Error>,
+) -> CostResult<T, Error> {
+    if let Err(Error::CostLimitExceeded(cost)) = &mut result.value {
+        *cost = result.cost.clone();
+    }
+    result
+}
@@ -1,6 +1,6 @@
 use std::io::{Result, Write};
 
-use storage::StorageContext;
+use storage::{Storage, StorageContext};
 use visualize::{Drawer, Visualize};
 
 use crate::{subtree::Element, util::storage_context_optional_tx, GroveDb, TransactionArg};
@@ -12,7 +12,7 @@ impl Visualize for Element {
                 drawer.write(b"item: ")?;
                 drawer = value.visualize(drawer)?;
             }
-            Element::Reference(_ref, _) => {
+            Element::Reference(..) | Element::TypedReference(..) => {
                 drawer.write(b"ref")?;
                 // drawer.write(b"ref: [path: ")?;
                 // let mut path_iter = path.iter();
@@ -29,17 +29,28 @@ impl Visualize for Element {
                 drawer.write(b"tree: ")?;
                 drawer = hash.visualize(drawer)?;
             }
+            Element::SumItem(value, _) => {
+                drawer.write(format!("sum item: {}", value).as_bytes())?;
+            }
+            Element::SumTree(hash, sum, _) => {
+                drawer.write(format!("sum tree: {} ", sum).as_bytes())?;
+                drawer = hash.visualize(drawer)?;
+            }
+            Element::CountTree(hash, count, _) => {
+                drawer.write(format!("count tree: {} ", count).as_bytes())?;
+                drawer = hash.visualize(drawer)?;
+            }
         }
         Ok(drawer)
     }
 }
 
-impl GroveDb {
+impl<S: Storage> GroveDb<S> {
     fn draw_subtree<W: Write>(
         &self,
         mut drawer: Drawer<W>,
         path: Vec<Vec<u8>>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> Result<Drawer<W>> {
         drawer.down();
 
@@ -57,7 +68,7 @@ impl GroveDb {
                     drawer = key.visualize(drawer)?;
                     drawer.write(b" ")?;
                     match element {
-                        Element::Tree(..) => {
+                        Element::Tree(..) | Element::SumTree(..) | Element::CountTree(..) => {
                             drawer.write(b"tree:")?;
                             drawer.down();
                             let mut inner_path = path.clone();
@@ -77,41 +88,13 @@ impl GroveDb {
         Ok(drawer)
     }
 
-    fn draw_root_tree<W: Write>(
-        &self,
-        mut drawer: Drawer<W>,
-        transaction: TransactionArg,
-    ) -> Result<Drawer<W>> {
-        drawer.down();
-        let root_leaf_keys = self
-            .get_root_leaf_keys(transaction)
-            .unwrap()
-            .expect("cannot get root leaf keys");
-        let keys = root_leaf_keys.iter().fold(
-            vec![Vec::new(); root_leaf_keys.len()],
-            |mut acc, (key, idx)| {
-                acc[*idx] = key.clone();
-                acc
-            },
-        );
-
-        for k in keys {
-            drawer.write(b"\n")?;
-            drawer = k.visualize(drawer)?;
-            drawer.write(b" tree:")?;
-            drawer = self.draw_subtree(drawer, vec![k], transaction)?
-        }
-        drawer.up();
-        Ok(drawer)
-    }
-
     fn visualize_start<W: Write>(
         &self,
         mut drawer: Drawer<W>,
-        transaction: TransactionArg,
+        transaction: TransactionArg<S>,
     ) -> Result<Drawer<W>> {
         drawer.write(b"root")?;
-        drawer = self.draw_root_tree(drawer, transaction)?;
+        drawer = self.draw_subtree(drawer, vec![], transaction)?;
         drawer.flush()?;
         Ok(drawer)
     }
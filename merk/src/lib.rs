@@ -17,10 +17,12 @@ pub mod tree;
 #[allow(deprecated)]
 pub use proofs::query::verify_query;
 pub use proofs::query::{execute_proof, verify};
-pub use tree::{BatchEntry, Hash, MerkBatch, Op, PanicSource, HASH_LENGTH};
+pub use tree::{
+    AggregateData, BatchEntry, Hash, MerkBatch, Op, PanicSource, TreeFeatureType, HASH_LENGTH,
+};
 
-// #[cfg(feature = "full")]
-// // pub use crate::merk::{chunks, restore, Merk};
 pub use crate::merk::{
+    chunks::{ChunkIter, ChunkProducer},
+    restore::Restorer,
     KVIterator, Merk, ProofConstructionResult, ProofWithoutEncodingResult, ROOT_KEY_KEY,
 };
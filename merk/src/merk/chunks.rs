@@ -45,7 +45,9 @@ where
             trunk
                 .iter()
                 .filter_map(|op| match op {
-                    Op::Push(Node::KV(key, _)) => Some(key.clone()),
+                    Op::Push(
+                        Node::KV(key, _) | Node::KVWithSum(key, ..) | Node::KVWithCount(key, ..),
+                    ) => Some(key.clone()),
                     _ => None,
                 })
                 .collect()
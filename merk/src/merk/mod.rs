@@ -1,6 +1,5 @@
 pub mod chunks;
-// TODO
-// pub mod restore;
+pub mod restore;
 use std::{
     cell::Cell,
     cmp::Ordering,
@@ -16,7 +15,9 @@ use storage::{self, Batch, RawIterator, StorageContext};
 
 use crate::{
     proofs::{encode_into, query::QueryItem, Op as ProofOp, Query},
-    tree::{Commit, Fetch, Hash, Link, MerkBatch, Op, RefWalker, Tree, Walker, NULL_HASH},
+    tree::{
+        AggregateData, Commit, Fetch, Hash, Link, MerkBatch, Op, RefWalker, Tree, Walker, NULL_HASH,
+    },
 };
 
 pub const ROOT_KEY_KEY: &[u8] = b"root";
@@ -176,6 +177,12 @@ where
             iter.next();
             cost.seek_count += 1;
         }
+        // The pointer to the root node is removed too, so the tree could be
+        // opened again as an empty one
+        cost_return_on_error_no_add!(
+            &cost,
+            to_delete.delete_root(ROOT_KEY_KEY).map_err(|e| e.into())
+        );
         cost_return_on_error_no_add!(
             &cost,
             self.storage.commit_batch(to_delete).map_err(|e| e.into())
@@ -316,6 +323,24 @@ where
         })
     }
 
+    /// Returns the sum of the tree if it's a sum tree, `None` for an empty
+    /// tree or a regular one.
+    pub fn sum(&self) -> Option<i64> {
+        self.use_tree(|tree| tree.and_then(|tree| tree.sum()))
+    }
+
+    /// Returns the count of the tree if it's a count tree, `None` for an empty
+    /// tree or another kind of tree.
+    pub fn count(&self) -> Option<u64> {
+        self.use_tree(|tree| tree.and_then(|tree| tree.count()))
+    }
+
+    /// Returns the aggregate of the tree if it's a sum or a count tree, `None`
+    /// for an empty tree or a regular one.
+    pub fn aggregate_data(&self) -> Option<AggregateData> {
+        self.use_tree(|tree| tree.and_then(|tree| tree.aggregate_data()))
+    }
+
     /// Applies a batch of operations (puts and deletes) to the tree.
     ///
     /// This will fail if the keys in `batch` are not sorted and unique. This
@@ -591,17 +616,7 @@ where
 
         for (key, value) in aux {
             match value {
-                Op::Put(value) => cost_return_on_error!(
-                    &mut cost,
-                    batch
-                        .put_aux(key, value)
-                        .map_err(|e| e.into())
-                        .wrap_with_cost(OperationCost {
-                            storage_written_bytes: value.len() as u32,
-                            ..Default::default()
-                        })
-                ),
-                Op::PutReference(value, _) => cost_return_on_error!(
+                Op::Put(value) | Op::PutWithFeatureType(value, _) => cost_return_on_error!(
                     &mut cost,
                     batch
                         .put_aux(key, value)
@@ -611,6 +626,18 @@ where
                             ..Default::default()
                         })
                 ),
+                Op::PutReference(value, _) | Op::PutReferenceWithFeatureType(value, ..) => {
+                    cost_return_on_error!(
+                        &mut cost,
+                        batch
+                            .put_aux(key, value)
+                            .map_err(|e| e.into())
+                            .wrap_with_cost(OperationCost {
+                                storage_written_bytes: value.len() as u32,
+                                ..Default::default()
+                            })
+                    )
+                }
                 Op::Delete => {
                     let value_wrapped = self
                         .storage
@@ -811,7 +838,7 @@ mod test {
     use tempfile::TempDir;
 
     use super::{Merk, MerkSource, RefWalker};
-    use crate::{test_utils::*, Op};
+    use crate::{test_utils::*, Op, TreeFeatureType};
 
     // TODO: Close and then reopen test
 
@@ -1053,6 +1080,130 @@ mod test {
         assert_eq!(reopen_nodes, original_nodes);
     }
 
+    #[test]
+    fn sum_tree() {
+        let tmp_dir = TempDir::new().expect("cannot open tempdir");
+        let storage = RocksDbStorage::default_rocksdb_with_path(tmp_dir.path())
+            .expect("cannot open rocksdb storage");
+        let mut merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .expect("cannot open merk");
+        assert_eq!(merk.sum(), None);
+
+        let batch: Vec<_> = (0..100u64)
+            .map(|i| {
+                (
+                    i.to_be_bytes().to_vec(),
+                    Op::PutWithFeatureType(vec![1], TreeFeatureType::SummedMerk(i as i64)),
+                )
+            })
+            .collect();
+        merk.apply::<_, Vec<_>>(&batch, &[])
+            .unwrap()
+            .expect("apply failed");
+        assert_eq!(merk.sum(), Some(4950));
+        let root_hash = merk.root_hash().unwrap();
+
+        // Sums of pruned nodes are read from their parents
+        drop(merk);
+        let mut merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .expect("cannot open merk");
+        assert_eq!(merk.sum(), Some(4950));
+        assert_eq!(merk.root_hash().unwrap(), root_hash);
+
+        merk.apply::<_, Vec<_>>(
+            &[
+                (10u64.to_be_bytes().to_vec(), Op::Delete),
+                (
+                    20u64.to_be_bytes().to_vec(),
+                    Op::PutWithFeatureType(vec![1], TreeFeatureType::SummedMerk(-20)),
+                ),
+            ],
+            &[],
+        )
+        .unwrap()
+        .expect("apply failed");
+        assert_eq!(merk.sum(), Some(4950 - 10 - 40));
+
+        // The sum is a part of the root hash
+        let mut basic_merk = TempMerk::new();
+        let batch: Vec<_> = (0..100u64)
+            .map(|i| (i.to_be_bytes().to_vec(), Op::Put(vec![1])))
+            .collect();
+        basic_merk
+            .apply::<_, Vec<_>>(&batch, &[])
+            .unwrap()
+            .expect("apply failed");
+        assert_eq!(basic_merk.sum(), None);
+        assert_ne!(basic_merk.root_hash().unwrap(), root_hash);
+    }
+
+    #[test]
+    fn count_tree() {
+        let tmp_dir = TempDir::new().expect("cannot open tempdir");
+        let storage = RocksDbStorage::default_rocksdb_with_path(tmp_dir.path())
+            .expect("cannot open rocksdb storage");
+        let mut merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .expect("cannot open merk");
+        assert_eq!(merk.count(), None);
+
+        let batch: Vec<_> = (0..100u64)
+            .map(|i| {
+                (
+                    i.to_be_bytes().to_vec(),
+                    Op::PutWithFeatureType(vec![1], TreeFeatureType::CountedMerk(1)),
+                )
+            })
+            .collect();
+        merk.apply::<_, Vec<_>>(&batch, &[])
+            .unwrap()
+            .expect("apply failed");
+        assert_eq!(merk.count(), Some(100));
+        assert_eq!(merk.sum(), None);
+        let root_hash = merk.root_hash().unwrap();
+
+        // Counts of pruned nodes are read from their parents
+        drop(merk);
+        let mut merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .expect("cannot open merk");
+        assert_eq!(merk.count(), Some(100));
+        assert_eq!(merk.root_hash().unwrap(), root_hash);
+
+        merk.apply::<_, Vec<_>>(
+            &[
+                (10u64.to_be_bytes().to_vec(), Op::Delete),
+                (
+                    20u64.to_be_bytes().to_vec(),
+                    Op::PutWithFeatureType(vec![1], TreeFeatureType::CountedMerk(5)),
+                ),
+            ],
+            &[],
+        )
+        .unwrap()
+        .expect("apply failed");
+        assert_eq!(merk.count(), Some(100 - 1 + 4));
+
+        // The same values summed up give a different root hash
+        let mut sum_merk = TempMerk::new();
+        let batch: Vec<_> = (0..100u64)
+            .map(|i| {
+                (
+                    i.to_be_bytes().to_vec(),
+                    Op::PutWithFeatureType(vec![1], TreeFeatureType::SummedMerk(1)),
+                )
+            })
+            .collect();
+        sum_merk
+            .apply::<_, Vec<_>>(&batch, &[])
+            .unwrap()
+            .expect("apply failed");
+        assert_eq!(sum_merk.sum(), Some(100));
+        assert_ne!(sum_merk.root_hash().unwrap(), root_hash);
+    }
+
     type PrefixedStorageIter<'db, 'ctx> =
         &'ctx mut <PrefixedRocksDbStorageContext<'db> as StorageContext<'db>>::RawIterator;
 
@@ -1,37 +1,50 @@
 //! Provides `Restorer`, which can create a replica of a Merk instance by
 //! receiving chunk proofs.
 
-use super::Merk;
+use std::{error::Error, iter::Peekable, vec};
+
+use anyhow::{anyhow, bail, Result};
+use costs::{
+    cost_return_on_error, cost_return_on_error_no_add, CostContext, CostsExt, OperationCost,
+};
+use storage::{Batch, StorageContext};
+
+use super::{Merk, ROOT_KEY_KEY};
 use crate::{
-    merk::MerkSource,
     proofs::{
         chunk::{verify_leaf, verify_trunk, MIN_TRUNK_HEIGHT},
-        tree::{Child, Tree as ProofTree},
+        tree::Tree as ProofTree,
         Decoder, Node,
     },
-    tree::{Link, RefWalker, Tree},
-    Hash, Result,
+    tree::{Link, Tree, TreeFeatureType},
+    Hash,
 };
-use failure::bail;
-use rocksdb::WriteBatch;
-use std::iter::Peekable;
-use std::{path::Path, u8};
 
 /// A `Restorer` handles decoding, verifying, and storing chunk proofs to
 /// replicate an entire Merk tree. It expects the chunks to be processed in
 /// order, retrying the last chunk if verification fails.
-pub struct Restorer<'a> {
-    leaf_hashes: Option<Peekable<std::vec::IntoIter<Hash>>>,
-    parent_keys: Option<Peekable<std::vec::IntoIter<Vec<u8>>>>,
+///
+/// Leaf chunks are written to the storage context as soon as they are
+/// verified, while the trunk is kept in memory and written on `finalize`
+/// once the heights of all its leaves are known. Since the restorer never
+/// reads back what it has written it works with batch storage contexts too.
+pub struct Restorer<S> {
+    leaf_hashes: Option<Peekable<vec::IntoIter<Hash>>>,
+    trunk: Option<ProofTree>,
     trunk_height: Option<usize>,
-    merk: Merk<'a>,
+    leaf_links: Vec<Link>,
+    merk: Merk<S>,
     expected_root_hash: Hash,
     stated_length: usize,
 }
 
-impl<'a> Restorer<'a> {
-    /// Creates a new `Restorer`, which will initialize a new Merk at the given
-    /// file path. The first chunk (the "trunk") will be compared against
+impl<'db, S> Restorer<S>
+where
+    S: StorageContext<'db>,
+    <S as StorageContext<'db>>::Error: Error + Sync + Send + 'static,
+{
+    /// Creates a new `Restorer`, which will populate the given empty Merk.
+    /// The first chunk (the "trunk") will be compared against
     /// `expected_root_hash`, then each subsequent chunk will be compared
     /// against the hashes stored in the trunk, so that the restore process will
     /// never allow malicious peers to send more than a single invalid chunk.
@@ -40,33 +53,28 @@ impl<'a> Restorer<'a> {
     /// which will be verified after processing a valid first chunk to make it
     /// easier to download chunks from peers without needing to trust this
     /// length.
-    pub fn new<P: AsRef<Path>>(
-        db_path: P,
-        prefix: &[u8],
-        expected_root_hash: Hash,
-        stated_length: usize,
-    ) -> Result<Self> {
-        if db_path.as_ref().exists() {
-            bail!("The given path already exists");
+    pub fn new(merk: Merk<S>, expected_root_hash: Hash, stated_length: usize) -> Result<Self> {
+        if !merk.is_empty_tree() {
+            bail!("The given merk is not empty");
         }
 
         Ok(Self {
             expected_root_hash,
             stated_length,
+            trunk: None,
             trunk_height: None,
-            merk: Merk::open(db_path, prefix)?,
+            leaf_links: vec![],
+            merk,
             leaf_hashes: None,
-            parent_keys: None,
         })
     }
 
-    /// Verifies a chunk and writes it to the working RocksDB instance. Expects
-    /// to be called for each chunk in order. Returns the number of remaining
-    /// chunks.
+    /// Verifies a chunk and writes it to the storage. Expects to be called for
+    /// each chunk in order. Returns the number of remaining chunks.
     ///
     /// Once there are no remaining chunks to be processed, `finalize` should
     /// be called.
-    pub fn process_chunk(&mut self, chunk_bytes: &[u8]) -> Result<usize> {
+    pub fn process_chunk(&mut self, chunk_bytes: &[u8]) -> CostContext<Result<usize>> {
         let ops = Decoder::new(chunk_bytes);
 
         match self.leaf_hashes {
@@ -79,19 +87,46 @@ impl<'a> Restorer<'a> {
     /// Merk instance. This method will return an error if called before
     /// processing all chunks (e.g. `restorer.remaining_chunks()` is not equal
     /// to 0).
-    pub fn finalize(mut self) -> Result<Merk<'a>> {
-        if self.remaining_chunks().is_none() || self.remaining_chunks().unwrap() != 0 {
-            bail!("Called finalize before all chunks were processed");
+    ///
+    /// In case of a batch storage context the returned Merk won't see the
+    /// restored data until the batch is committed, so it should be reopened
+    /// after that.
+    pub fn finalize(mut self) -> CostContext<Result<Merk<S>>> {
+        let mut cost = OperationCost::default();
+
+        if self.remaining_chunks() != Some(0) {
+            return Err(anyhow!("Called finalize before all chunks were processed"))
+                .wrap_with_cost(cost);
         }
 
-        if self.trunk_height.unwrap() >= MIN_TRUNK_HEIGHT {
-            self.rewrite_trunk_child_heights()?;
-        }
+        let trunk = self.trunk.take().expect("trunk is set once processed");
+        let trunk_height = self.trunk_height.expect("trunk is set once processed");
+        let depth_limit = if trunk_height >= MIN_TRUNK_HEIGHT {
+            trunk_height
+        } else {
+            usize::MAX
+        };
 
-        self.merk.flush()?;
-        self.merk.load_root()?;
+        let mut batch = self.merk.storage.new_batch();
+        let mut leaf_links = std::mem::take(&mut self.leaf_links).into_iter();
+        cost_return_on_error!(
+            &mut cost,
+            write_trunk_node(&trunk, depth_limit, &mut leaf_links, &mut batch)
+        );
+        cost_return_on_error_no_add!(
+            &cost,
+            batch
+                .put_root(ROOT_KEY_KEY, trunk.key())
+                .map_err(|e| e.into())
+        );
+        cost_return_on_error_no_add!(
+            &cost,
+            self.merk.storage.commit_batch(batch).map_err(|e| e.into())
+        );
 
-        Ok(self.merk)
+        cost_return_on_error!(&mut cost, self.merk.load_root());
+
+        Ok(self.merk).wrap_with_cost(cost)
     }
 
     /// Returns the number of remaining chunks to be processed. If called before
@@ -101,210 +136,162 @@ impl<'a> Restorer<'a> {
         self.leaf_hashes.as_ref().map(|lh| lh.len())
     }
 
-    /// Writes the data contained in `tree` (extracted from a verified chunk
-    /// proof) to the RocksDB.
-    fn write_chunk(&mut self, tree: ProofTree) -> Result<()> {
-        let mut batch = WriteBatch::default();
-
-        tree.visit_refs(&mut |proof_node| {
-            let (key, value) = match &proof_node.node {
-                Node::KV(key, value) => (key, value),
-                _ => return,
-            };
-
-            // TODO: encode tree node without cloning key/value
-            let mut node = Tree::new(key.clone(), value.clone());
-            *node.slot_mut(true) = proof_node.left.as_ref().map(Child::as_link);
-            *node.slot_mut(false) = proof_node.right.as_ref().map(Child::as_link);
-
-            let bytes = node.encode();
-            batch.put(key, bytes);
-        });
-
-        self.merk.write(batch)
-    }
-
-    /// Verifies the trunk then writes its data to the RocksDB.
+    /// Verifies the trunk and keeps it until all leaves are processed.
     ///
     /// The trunk contains a height proof which lets us verify the total number
     /// of expected chunks is the same as `stated_length` as passed into
     /// `Restorer::new()`. We also verify the expected root hash at this step.
-    fn process_trunk(&mut self, ops: Decoder) -> Result<usize> {
-        let (trunk, height) = verify_trunk(ops)?;
+    fn process_trunk(&mut self, ops: Decoder) -> CostContext<Result<usize>> {
+        let mut cost = OperationCost::default();
+
+        let (trunk, height) = cost_return_on_error!(&mut cost, verify_trunk(ops));
+        let trunk_hash = trunk.hash().unwrap_add_cost(&mut cost);
 
-        if trunk.hash() != self.expected_root_hash {
-            bail!(
+        if trunk_hash != self.expected_root_hash {
+            return Err(anyhow!(
                 "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
                 self.expected_root_hash,
-                trunk.hash()
-            );
+                trunk_hash
+            ))
+            .wrap_with_cost(cost);
         }
 
-        let root_key = trunk.key().to_vec();
-
         let trunk_height = height / 2;
-        self.trunk_height = Some(trunk_height);
 
-        let chunks_remaining = if trunk_height >= MIN_TRUNK_HEIGHT {
-            let leaf_hashes = trunk
+        let leaf_hashes = if trunk_height >= MIN_TRUNK_HEIGHT {
+            trunk
                 .layer(trunk_height)
-                .map(|node| node.hash())
+                .map(|node| node.hash().unwrap_add_cost(&mut cost))
                 .collect::<Vec<Hash>>()
-                .into_iter()
-                .peekable();
-            self.leaf_hashes = Some(leaf_hashes);
-
-            let parent_keys = trunk
-                .layer(trunk_height - 1)
-                .map(|node| node.key().to_vec())
-                .collect::<Vec<Vec<u8>>>()
-                .into_iter()
-                .peekable();
-            self.parent_keys = Some(parent_keys);
-            assert_eq!(
-                self.parent_keys.as_ref().unwrap().len(),
-                self.leaf_hashes.as_ref().unwrap().len() / 2
-            );
-
-            let chunks_remaining = (2_usize).pow(trunk_height as u32);
-            assert_eq!(self.remaining_chunks_unchecked(), chunks_remaining);
-            chunks_remaining
         } else {
-            self.leaf_hashes = Some(vec![].into_iter().peekable());
-            self.parent_keys = Some(vec![].into_iter().peekable());
-            0
+            vec![]
         };
+        let chunks_remaining = leaf_hashes.len();
+
+        if self.stated_length != chunks_remaining + 1 {
+            return Err(anyhow!(
+                "Stated length doesn't match the trunk\n\tStated: {}\n\tActual: {}",
+                self.stated_length,
+                chunks_remaining + 1
+            ))
+            .wrap_with_cost(cost);
+        }
 
-        // FIXME: this one shouldn't be an assert because it comes from a peer
-        assert_eq!(self.stated_length, chunks_remaining + 1);
-
-        // note that these writes don't happen atomically, which is fine here
-        // because if anything fails during the restore process we will just
-        // scrap the whole restore and start over
-        self.write_chunk(trunk)?;
-        self.merk.set_root_key(root_key)?;
+        self.leaf_hashes = Some(leaf_hashes.into_iter().peekable());
+        self.trunk_height = Some(trunk_height);
+        self.trunk = Some(trunk);
 
-        Ok(chunks_remaining)
+        Ok(chunks_remaining).wrap_with_cost(cost)
     }
 
-    /// Verifies a leaf chunk then writes it to the RocksDB. This needs to be
+    /// Verifies a leaf chunk then writes it to the storage. This needs to be
     /// called in order, retrying the last chunk for any failed verifications.
-    fn process_leaf(&mut self, ops: Decoder) -> Result<usize> {
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        let leaf_hash = leaf_hashes
-            .peek()
-            .expect("Received more chunks than expected");
-
-        let leaf = verify_leaf(ops, *leaf_hash)?;
-        self.rewrite_parent_link(&leaf)?;
-        self.write_chunk(leaf)?;
+    fn process_leaf(&mut self, ops: Decoder) -> CostContext<Result<usize>> {
+        let mut cost = OperationCost::default();
 
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        leaf_hashes.next();
-
-        Ok(self.remaining_chunks_unchecked())
-    }
-
-    /// The parent of the root node of the leaf does not know the key of its
-    /// children when it is first written. Now that we have verified this leaf,
-    /// we can write the key into the parent node's entry. Note that this does
-    /// not need to recalcuate hashes since it already had the child hash.
-    fn rewrite_parent_link(&mut self, leaf: &ProofTree) -> Result<()> {
-        let parent_keys = self.parent_keys.as_mut().unwrap();
-        let parent_key = parent_keys.peek().unwrap().clone();
-        let mut parent = self
-            .merk
-            .fetch_node(parent_key.as_slice())?
-            .expect("Could not find parent of leaf chunk");
-
-        let is_left_child = self.remaining_chunks_unchecked() % 2 == 0;
-        if let Some(Link::Reference { ref mut key, .. }) = parent.link_mut(is_left_child) {
-            *key = leaf.key().to_vec();
-        } else {
-            panic!("Expected parent links to be type Link::Reference");
+        let leaf_hashes = self.leaf_hashes.as_mut().expect("trunk is processed");
+        let leaf_hash = match leaf_hashes.peek() {
+            Some(leaf_hash) => *leaf_hash,
+            None => return Err(anyhow!("Received more chunks than expected")).wrap_with_cost(cost),
         };
 
-        let parent_bytes = parent.encode();
-        self.merk.db.put(parent_key, parent_bytes)?;
-
-        if !is_left_child {
-            let parent_keys = self.parent_keys.as_mut().unwrap();
-            parent_keys.next();
-        }
-
-        Ok(())
-    }
+        let leaf = cost_return_on_error!(&mut cost, verify_leaf(ops, leaf_hash));
 
-    fn rewrite_trunk_child_heights(&mut self) -> Result<()> {
-        fn recurse(
-            mut node: RefWalker<MerkSource>,
-            remaining_depth: usize,
-            batch: &mut WriteBatch,
-        ) -> Result<(u8, u8)> {
-            if remaining_depth == 0 {
-                return Ok(node.tree().child_heights());
-            }
+        let mut batch = self.merk.storage.new_batch();
+        cost_return_on_error!(
+            &mut cost,
+            write_trunk_node(&leaf, usize::MAX, &mut vec![].into_iter(), &mut batch)
+        );
+        cost_return_on_error_no_add!(
+            &cost,
+            self.merk.storage.commit_batch(batch).map_err(|e| e.into())
+        );
 
-            let mut cloned_node =
-                Tree::decode(node.tree().key().to_vec(), node.tree().encode().as_slice());
+        // the trunk's parent nodes will reference the leaf by its key
+        self.leaf_links.push(Link::Reference {
+            hash: leaf_hash,
+            aggregate_data: leaf.aggregate_data(),
+            child_heights: leaf.child_heights(),
+            key: leaf.key().to_vec(),
+        });
 
-            let left_child = node.walk(true)?.unwrap();
-            let left_child_heights = recurse(left_child, remaining_depth - 1, batch)?;
-            let left_height = left_child_heights.0.max(left_child_heights.1) + 1;
-            *cloned_node.link_mut(true).unwrap().child_heights_mut() = left_child_heights;
+        let leaf_hashes = self.leaf_hashes.as_mut().expect("trunk is processed");
+        leaf_hashes.next();
 
-            let right_child = node.walk(false)?.unwrap();
-            let right_child_heights = recurse(right_child, remaining_depth - 1, batch)?;
-            let right_height = right_child_heights.0.max(right_child_heights.1) + 1;
-            *cloned_node.link_mut(false).unwrap().child_heights_mut() = right_child_heights;
+        Ok(leaf_hashes.len()).wrap_with_cost(cost)
+    }
+}
 
-            let bytes = cloned_node.encode();
-            batch.put(node.tree().key(), bytes);
+/// Writes nodes of a verified proof tree into the batch down to
+/// `remaining_depth`, the nodes below are taken from `leaf_links` in order.
+/// Returns a link to the written node.
+fn write_trunk_node<B: Batch>(
+    proof_tree: &ProofTree,
+    remaining_depth: usize,
+    leaf_links: &mut impl Iterator<Item = Link>,
+    batch: &mut B,
+) -> CostContext<Result<Link>> {
+    let mut cost = OperationCost::default();
+
+    if remaining_depth == 0 {
+        return leaf_links
+            .next()
+            .ok_or_else(|| anyhow!("Missing leaf chunk for the trunk"))
+            .wrap_with_cost(cost);
+    }
 
-            Ok((left_height, right_height))
+    let (key, value, feature_type) = match &proof_tree.node {
+        Node::KV(key, value) => (key, value, TreeFeatureType::BasicMerk),
+        Node::KVWithSum(key, value, sum) => (key, value, TreeFeatureType::SummedMerk(*sum)),
+        Node::KVWithCount(key, value, count) => (key, value, TreeFeatureType::CountedMerk(*count)),
+        _ => {
+            return Err(anyhow!("Expected chunk node to contain a key and value"))
+                .wrap_with_cost(cost)
+        }
+    };
+
+    // TODO: encode tree node without cloning key/value
+    let mut node = Tree::new(key.clone(), value.clone())
+        .unwrap_add_cost(&mut cost)
+        .with_feature_type(feature_type);
+    for left in [true, false] {
+        if let Some(child) = proof_tree.child(left) {
+            let link = cost_return_on_error!(
+                &mut cost,
+                write_trunk_node(&child.tree, remaining_depth - 1, leaf_links, batch)
+            );
+            *node.slot_mut(left) = Some(link);
         }
-
-        self.merk.flush()?;
-        self.merk.load_root()?;
-
-        let mut batch = WriteBatch::default();
-
-        let depth = self.trunk_height.unwrap();
-        self.merk.use_tree_mut(|maybe_tree| {
-            let tree = maybe_tree.unwrap();
-            let walker = RefWalker::new(tree, self.merk.source());
-            recurse(walker, depth, &mut batch)
-        })?;
-
-        self.merk.write(batch)?;
-
-        Ok(())
     }
 
-    /// Returns the number of remaining chunks to be processed. This method will
-    /// panic if called before processing the first chunk (since that chunk
-    /// gives us the information to know how many chunks to expect).
-    pub fn remaining_chunks_unchecked(&self) -> usize {
-        self.leaf_hashes.as_ref().unwrap().len()
-    }
+    let bytes = node.encode();
+    cost.storage_written_bytes += bytes.len() as u32;
+    cost_return_on_error_no_add!(&cost, batch.put(key, &bytes).map_err(|e| e.into()));
+
+    let hash = node.hash().unwrap_add_cost(&mut cost);
+    Ok(Link::Reference {
+        hash,
+        aggregate_data: node.aggregate_data(),
+        child_heights: node.child_heights(),
+        key: key.clone(),
+    })
+    .wrap_with_cost(cost)
 }
 
-impl Merk {
+impl<'db, S> Merk<S>
+where
+    S: StorageContext<'db>,
+    <S as StorageContext<'db>>::Error: Error + Sync + Send + 'static,
+{
     /// Creates a new `Restorer`, which can be used to verify chunk proofs to
-    /// replicate an entire Merk tree. A new Merk instance will be initialized
-    /// by creating a RocksDB at `path`.
+    /// replicate an entire Merk tree into this (empty) Merk.
     ///
     /// The restoration process will verify integrity by checking that the
     /// incoming chunk proofs match `expected_root_hash`. The `stated_length`
     /// should be the number of chunks as stated by peers, which will also be
     /// verified during the restoration process.
-    pub fn restore<P: AsRef<Path>>(
-        path: P,
-        prefix: &[u8],
-        expected_root_hash: Hash,
-        stated_length: usize,
-    ) -> Result<Restorer> {
-        Restorer::new(path, prefix, expected_root_hash, stated_length)
+    pub fn restore(self, expected_root_hash: Hash, stated_length: usize) -> Result<Restorer<S>> {
+        Restorer::new(self, expected_root_hash, stated_length)
     }
 }
 
@@ -315,55 +302,43 @@ impl ProofTree {
             self.right.as_ref().map_or(0, |c| c.tree.height as u8),
         )
     }
-}
-
-impl Child {
-    fn as_link(&self) -> Link {
-        let key = match &self.tree.node {
-            Node::KV(key, _) => key.as_slice(),
-            // for the connection between the trunk and leaf chunks, we don't
-            // have the child key so we must first write in an empty one. once
-            // the leaf gets verified, we can write in this key to its parent
-            _ => &[],
-        };
 
-        Link::Reference {
-            hash: self.hash,
-            child_heights: self.tree.child_heights(),
-            key: key.to_vec(),
-        }
+    fn key(&self) -> &[u8] {
+        self.node.key().unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::iter::empty;
+
+    use storage::{rocksdb_storage::test_utils::TempStorage, RawIterator, Storage, StorageBatch};
+
     use super::*;
-    use crate::test_utils::*;
-    use crate::tree::{Batch, Op};
-    use std::path::PathBuf;
+    use crate::{test_utils::*, BatchEntry, Op};
 
-    fn restore_test(batches: &[&Batch], expected_nodes: usize) {
-        let mut original = TempMerk::new().unwrap();
+    fn restore_test(batches: &[&[BatchEntry<Vec<u8>>]], expected_nodes: usize) {
+        let mut original = TempMerk::new();
         for batch in batches {
-            original.apply(batch, &[]).unwrap();
+            original.apply::<_, Vec<_>>(batch, &[]).unwrap().unwrap();
         }
-        original.flush().unwrap();
 
-        let chunks = original.chunks().unwrap();
-
-        let path: PathBuf = std::thread::current().name().unwrap().into();
-        if path.exists() {
-            std::fs::remove_dir_all(&path).unwrap();
-        }
+        let chunks = original.chunks().unwrap().unwrap();
 
-        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        let storage = TempStorage::new();
+        let merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .unwrap();
+        let mut restorer = merk
+            .restore(original.root_hash().unwrap(), chunks.len())
+            .unwrap();
 
         assert_eq!(restorer.remaining_chunks(), None);
 
         let mut expected_remaining = chunks.len();
         for chunk in chunks {
-            let chunk = chunk.unwrap();
-            let remaining = restorer.process_chunk(chunk.as_slice()).unwrap();
+            let chunk = chunk.unwrap().unwrap();
+            let remaining = restorer.process_chunk(chunk.as_slice()).unwrap().unwrap();
 
             expected_remaining -= 1;
             assert_eq!(remaining, expected_remaining);
@@ -371,11 +346,9 @@ mod tests {
         }
         assert_eq!(expected_remaining, 0);
 
-        let restored = restorer.finalize().unwrap();
-        assert_eq!(restored.root_hash(), original.root_hash());
+        let restored = restorer.finalize().unwrap().unwrap();
+        assert_eq!(restored.root_hash().unwrap(), original.root_hash().unwrap());
         assert_raw_db_entries_eq(&restored, &original, expected_nodes);
-
-        std::fs::remove_dir_all(&path).unwrap();
     }
 
     #[test]
@@ -409,9 +382,105 @@ mod tests {
         restore_test(&[&make_batch_seq(0..1)], 1);
     }
 
-    fn assert_raw_db_entries_eq(restored: &Merk, original: &Merk, length: usize) {
-        let mut original_entries = original.raw_iter();
-        let mut restored_entries = restored.raw_iter();
+    #[test]
+    fn restore_into_batch() {
+        let mut original = TempMerk::new();
+        original
+            .apply::<_, Vec<_>>(&make_batch_seq(0..5_000), &[])
+            .unwrap()
+            .unwrap();
+        let chunks = original.chunks().unwrap().unwrap();
+        let chunks_len = chunks.len();
+
+        let storage = TempStorage::new();
+        let batch = StorageBatch::new();
+        let merk = Merk::open(storage.get_batch_storage_context(empty(), &batch))
+            .unwrap()
+            .unwrap();
+        let mut restorer = merk
+            .restore(original.root_hash().unwrap(), chunks_len)
+            .unwrap();
+        for chunk in chunks {
+            restorer
+                .process_chunk(chunk.unwrap().unwrap().as_slice())
+                .unwrap()
+                .unwrap();
+        }
+        restorer.finalize().unwrap().unwrap();
+
+        storage
+            .commit_multi_context_batch(batch, None)
+            .expect("cannot commit batch");
+        let restored = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.root_hash().unwrap(), original.root_hash().unwrap());
+        assert_raw_db_entries_eq(&restored, &original, 5_000);
+    }
+
+    #[test]
+    fn restore_rejects_wrong_root_hash() {
+        let mut original = TempMerk::new();
+        original
+            .apply::<_, Vec<_>>(&make_batch_seq(0..1_000), &[])
+            .unwrap()
+            .unwrap();
+        let chunks = original.chunks().unwrap().unwrap();
+        let chunks_len = chunks.len();
+        let mut chunks = chunks.into_iter();
+
+        let storage = TempStorage::new();
+        let merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .unwrap();
+        let mut restorer = merk.restore([1; 32], chunks_len).unwrap();
+        let trunk = chunks.next().unwrap().unwrap().unwrap();
+        assert!(restorer.process_chunk(trunk.as_slice()).unwrap().is_err());
+        assert_eq!(restorer.remaining_chunks(), None);
+    }
+
+    #[test]
+    fn restore_rejects_wrong_leaf() {
+        let mut original = TempMerk::new();
+        original
+            .apply::<_, Vec<_>>(&make_batch_seq(0..1_000), &[])
+            .unwrap()
+            .unwrap();
+        let chunks = original
+            .chunks()
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|chunk| chunk.unwrap().unwrap())
+            .collect::<Vec<_>>();
+
+        let storage = TempStorage::new();
+        let merk = Merk::open(storage.get_storage_context(empty()))
+            .unwrap()
+            .unwrap();
+        let mut restorer = merk
+            .restore(original.root_hash().unwrap(), chunks.len())
+            .unwrap();
+        restorer
+            .process_chunk(chunks[0].as_slice())
+            .unwrap()
+            .unwrap();
+        // leaf chunks out of order don't match the trunk's hashes
+        assert!(restorer
+            .process_chunk(chunks[2].as_slice())
+            .unwrap()
+            .is_err());
+        assert_eq!(restorer.remaining_chunks(), Some(chunks.len() - 1));
+        assert!(restorer.finalize().unwrap().is_err());
+    }
+
+    fn assert_raw_db_entries_eq<'db, S: StorageContext<'db>>(
+        restored: &Merk<S>,
+        original: &Merk<impl StorageContext<'db>>,
+        length: usize,
+    ) {
+        let mut original_entries = original.storage.raw_iter();
+        let mut restored_entries = restored.storage.raw_iter();
         original_entries.seek_to_first();
         restored_entries.seek_to_first();
 
@@ -8,6 +8,7 @@ use {
     super::tree::{execute, Tree as ProofTree},
     crate::tree::Hash,
     crate::tree::Tree,
+    crate::tree::TreeFeatureType,
 };
 
 use super::{Node, Op};
@@ -83,7 +84,7 @@ where
             }
 
             if let Some(right) = self.tree().link(false) {
-                proof.push(Op::Push(Node::Hash(*right.hash())));
+                proof.push(Op::Push(right.to_hash_node()));
                 proof.push(Op::Child);
             }
         }
@@ -173,7 +174,15 @@ pub(crate) fn get_next_chunk(
         let encoded_node = iter.value().unwrap();
         Tree::decode_into(&mut node, vec![], encoded_node);
 
-        let kv = Node::KV(key.to_vec(), node.value().to_vec());
+        let kv = match node.feature_type() {
+            TreeFeatureType::BasicMerk => Node::KV(key.to_vec(), node.value().to_vec()),
+            TreeFeatureType::SummedMerk(sum) => {
+                Node::KVWithSum(key.to_vec(), node.value().to_vec(), sum)
+            }
+            TreeFeatureType::CountedMerk(count) => {
+                Node::KVWithCount(key.to_vec(), node.value().to_vec(), count)
+            }
+        };
         chunk.push(Op::Push(kv));
 
         if node.link(true).is_some() {
@@ -206,13 +215,12 @@ pub(crate) fn get_next_chunk(
 /// were no abridged nodes (Hash or KVHash) and the proof hashes to
 /// `expected_hash`.
 #[cfg(feature = "full")]
-#[allow(dead_code)] // TODO: remove when proofs will be enabled
 pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
     ops: I,
     expected_hash: Hash,
 ) -> CostContext<Result<ProofTree>> {
     execute(ops, false, |node| match node {
-        Node::KV(..) => Ok(()),
+        Node::KV(..) | Node::KVWithSum(..) | Node::KVWithCount(..) => Ok(()),
         _ => bail!("Leaf chunks must contain full subtree"),
     })
     .flat_map_ok(|tree| {
@@ -235,7 +243,6 @@ pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
 /// height, and all of its inner nodes are not abridged. Returns the tree and
 /// the height given by the height proof.
 #[cfg(feature = "full")]
-#[allow(dead_code)] // TODO: remove when proofs will be enabled
 pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(
     ops: I,
 ) -> CostContext<Result<(ProofTree, usize)>> {
@@ -244,7 +251,9 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(
     fn verify_height_proof(tree: &ProofTree) -> Result<usize> {
         Ok(match tree.child(true) {
             Some(child) => {
-                if let Node::Hash(_) = child.tree.node {
+                if let Node::Hash(_) | Node::HashWithSum(..) | Node::HashWithCount(..) =
+                    child.tree.node
+                {
                     bail!("Expected height proof to only contain KV and KVHash nodes")
                 }
                 verify_height_proof(&child.tree)? + 1
@@ -263,19 +272,19 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(
 
         if remaining_depth > 0 {
             match tree.node {
-                Node::KV(..) => {}
+                Node::KV(..) | Node::KVWithSum(..) | Node::KVWithCount(..) => {}
                 _ => bail!("Expected trunk inner nodes to contain keys and values"),
             }
             recurse(true, leftmost)?;
             recurse(false, false)
         } else if !leftmost {
             match tree.node {
-                Node::Hash(_) => Ok(()),
+                Node::Hash(_) | Node::HashWithSum(..) | Node::HashWithCount(..) => Ok(()),
                 _ => bail!("Expected trunk leaves to contain Hash nodes"),
             }
         } else {
             match &tree.node {
-                Node::KVHash(_) => Ok(()),
+                Node::KVHash(_) | Node::KVHashWithSum(..) | Node::KVHashWithCount(..) => Ok(()),
                 _ => bail!("Expected leftmost trunk leaf to contain KVHash node"),
             }
         }
@@ -285,7 +294,10 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(
     let tree = cost_return_on_error!(
         &mut cost,
         execute(ops, false, |node| {
-            kv_only &= matches!(node, Node::KV(_, _));
+            kv_only &= matches!(
+                node,
+                Node::KV(..) | Node::KVWithSum(..) | Node::KVWithCount(..)
+            );
             Ok(())
         })
     );
@@ -329,10 +341,14 @@ mod tests {
 
         tree.visit_nodes(&mut |node| {
             match node {
-                Node::Hash(_) => counts.hash += 1,
-                Node::KVHash(_) => counts.kvhash += 1,
-                Node::KV(..) => counts.kv += 1,
-                Node::KVDigest(..) => counts.kvdigest += 1,
+                Node::Hash(_) | Node::HashWithSum(..) | Node::HashWithCount(..) => counts.hash += 1,
+                Node::KVHash(_) | Node::KVHashWithSum(..) | Node::KVHashWithCount(..) => {
+                    counts.kvhash += 1
+                }
+                Node::KV(..) | Node::KVWithSum(..) | Node::KVWithCount(..) => counts.kv += 1,
+                Node::KVDigest(..) | Node::KVDigestWithSum(..) | Node::KVDigestWithCount(..) => {
+                    counts.kvdigest += 1
+                }
             };
         });
 
@@ -4,7 +4,7 @@ use anyhow::{anyhow, Result};
 use ed::{Decode, Encode, Error, Terminated};
 
 use super::{Node, Op};
-use crate::tree::HASH_LENGTH;
+use crate::tree::{AggregateData, HASH_LENGTH};
 
 impl Encode for Op {
     fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
@@ -59,6 +59,9 @@ impl Encode for Op {
                 dest.write_all(value_hash)?;
             }
 
+            Op::Push(node) => encode_aggregate_node(node, false, dest)?,
+            Op::PushInverted(node) => encode_aggregate_node(node, true, dest)?,
+
             Op::Parent => dest.write_all(&[0x10])?,
             Op::Child => dest.write_all(&[0x11])?,
             Op::ParentInverted => dest.write_all(&[0x12])?,
@@ -77,6 +80,7 @@ impl Encode for Op {
             Op::PushInverted(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::PushInverted(Node::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
             Op::PushInverted(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(node) | Op::PushInverted(node) => aggregate_node_encoding_length(node),
             Op::Parent => 1,
             Op::Child => 1,
             Op::ParentInverted => 1,
@@ -152,6 +156,10 @@ impl Decode for Op {
 
                 Self::PushInverted(Node::KVDigest(key, value_hash))
             }
+            0x20..=0x2f => match decode_aggregate_node(variant, input)? {
+                (node, false) => Self::Push(node),
+                (node, true) => Self::PushInverted(node),
+            },
             0x10 => Self::Parent,
             0x11 => Self::Child,
             0x12 => Self::ParentInverted,
@@ -164,6 +172,145 @@ impl Decode for Op {
 
 impl Terminated for Op {}
 
+/// Encodes a node of a sum or a count tree. Variants of sum tree nodes start
+/// at `0x20` for push operations and at `0x24` for inverted ones, count tree
+/// nodes follow them starting at `0x28`, in the same order.
+fn encode_aggregate_node<W: Write>(node: &Node, inverted: bool, dest: &mut W) -> ed::Result<()> {
+    let (offset, aggregate_data) = match node {
+        Node::HashWithSum(_, sum) | Node::KVHashWithSum(_, sum) => (0, AggregateData::Sum(*sum)),
+        Node::KVWithSum(_, _, sum) | Node::KVDigestWithSum(_, _, sum) => {
+            (0, AggregateData::Sum(*sum))
+        }
+        Node::HashWithCount(_, count) | Node::KVHashWithCount(_, count) => {
+            (8, AggregateData::Count(*count))
+        }
+        Node::KVWithCount(_, _, count) | Node::KVDigestWithCount(_, _, count) => {
+            (8, AggregateData::Count(*count))
+        }
+        Node::Hash(_) | Node::KVHash(_) | Node::KV(..) | Node::KVDigest(..) => {
+            unreachable!("nodes without aggregate data are encoded separately")
+        }
+    };
+    let base_variant = 0x20 + offset + if inverted { 4 } else { 0 };
+
+    match node {
+        Node::HashWithSum(hash, _) | Node::HashWithCount(hash, _) => {
+            dest.write_all(&[base_variant])?;
+            dest.write_all(hash)?;
+        }
+        Node::KVHashWithSum(kv_hash, _) | Node::KVHashWithCount(kv_hash, _) => {
+            dest.write_all(&[base_variant + 1])?;
+            dest.write_all(kv_hash)?;
+        }
+        Node::KVWithSum(key, value, _) | Node::KVWithCount(key, value, _) => {
+            debug_assert!(key.len() < 256);
+            debug_assert!(value.len() < 65536);
+
+            dest.write_all(&[base_variant + 2, key.len() as u8])?;
+            dest.write_all(key)?;
+            (value.len() as u16).encode_into(dest)?;
+            dest.write_all(value)?;
+        }
+        Node::KVDigestWithSum(key, value_hash, _) | Node::KVDigestWithCount(key, value_hash, _) => {
+            debug_assert!(key.len() < 256);
+
+            dest.write_all(&[base_variant + 3, key.len() as u8])?;
+            dest.write_all(key)?;
+            dest.write_all(value_hash)?;
+        }
+        Node::Hash(_) | Node::KVHash(_) | Node::KV(..) | Node::KVDigest(..) => unreachable!(),
+    }
+    dest.write_all(&aggregate_data.to_be_bytes())?;
+    Ok(())
+}
+
+fn aggregate_node_encoding_length(node: &Node) -> usize {
+    match node {
+        Node::HashWithSum(..)
+        | Node::KVHashWithSum(..)
+        | Node::HashWithCount(..)
+        | Node::KVHashWithCount(..) => 1 + HASH_LENGTH + 8,
+        Node::KVWithSum(key, value, _) | Node::KVWithCount(key, value, _) => {
+            4 + key.len() + value.len() + 8
+        }
+        Node::KVDigestWithSum(key, ..) | Node::KVDigestWithCount(key, ..) => {
+            2 + key.len() + HASH_LENGTH + 8
+        }
+        Node::Hash(_) | Node::KVHash(_) | Node::KV(..) | Node::KVDigest(..) => {
+            unreachable!("nodes without aggregate data are encoded separately")
+        }
+    }
+}
+
+/// Decodes a node of a sum or a count tree, `variant` is in `0x20..=0x2f`
+/// range, see [encode_aggregate_node] for the layout. Returns the node and
+/// whether it was pushed inverted.
+fn decode_aggregate_node<R: Read>(variant: u8, mut input: R) -> ed::Result<(Node, bool)> {
+    let read_hash = |input: &mut R| -> ed::Result<[u8; HASH_LENGTH]> {
+        let mut hash = [0; HASH_LENGTH];
+        input.read_exact(&mut hash)?;
+        Ok(hash)
+    };
+    let read_bytes = |input: &mut R, len: usize| -> ed::Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        input.read_exact(bytes.as_mut_slice())?;
+        Ok(bytes)
+    };
+    let read_aggregate = |input: &mut R| -> ed::Result<[u8; 8]> {
+        let mut aggregate = [0; 8];
+        input.read_exact(&mut aggregate)?;
+        Ok(aggregate)
+    };
+
+    let is_count = variant >= 0x28;
+    let inverted = (variant - 0x20) % 8 >= 4;
+
+    let node = match (variant - 0x20) % 4 {
+        0 => {
+            let hash = read_hash(&mut input)?;
+            let aggregate = read_aggregate(&mut input)?;
+            if is_count {
+                Node::HashWithCount(hash, u64::from_be_bytes(aggregate))
+            } else {
+                Node::HashWithSum(hash, i64::from_be_bytes(aggregate))
+            }
+        }
+        1 => {
+            let kv_hash = read_hash(&mut input)?;
+            let aggregate = read_aggregate(&mut input)?;
+            if is_count {
+                Node::KVHashWithCount(kv_hash, u64::from_be_bytes(aggregate))
+            } else {
+                Node::KVHashWithSum(kv_hash, i64::from_be_bytes(aggregate))
+            }
+        }
+        2 => {
+            let key_len: u8 = Decode::decode(&mut input)?;
+            let key = read_bytes(&mut input, key_len as usize)?;
+            let value_len: u16 = Decode::decode(&mut input)?;
+            let value = read_bytes(&mut input, value_len as usize)?;
+            let aggregate = read_aggregate(&mut input)?;
+            if is_count {
+                Node::KVWithCount(key, value, u64::from_be_bytes(aggregate))
+            } else {
+                Node::KVWithSum(key, value, i64::from_be_bytes(aggregate))
+            }
+        }
+        _ => {
+            let key_len: u8 = Decode::decode(&mut input)?;
+            let key = read_bytes(&mut input, key_len as usize)?;
+            let value_hash = read_hash(&mut input)?;
+            let aggregate = read_aggregate(&mut input)?;
+            if is_count {
+                Node::KVDigestWithCount(key, value_hash, u64::from_be_bytes(aggregate))
+            } else {
+                Node::KVDigestWithSum(key, value_hash, i64::from_be_bytes(aggregate))
+            }
+        }
+    };
+    Ok((node, inverted))
+}
+
 impl Op {
     fn encode_into<W: Write>(&self, dest: &mut W) -> Result<()> {
         Encode::encode_into(self, dest).map_err(|e| match e {
@@ -234,7 +381,7 @@ impl<'a> Iterator for Decoder<'a> {
 #[cfg(test)]
 mod test {
     use super::super::{Node, Op};
-    use crate::tree::HASH_LENGTH;
+    use crate::tree::{AggregateData, HASH_LENGTH};
 
     #[test]
     fn encode_push_hash() {
@@ -516,6 +663,73 @@ mod test {
         assert_eq!(op, Op::ChildInverted);
     }
 
+    #[test]
+    fn encode_push_kv_with_sum() {
+        let op = Op::Push(Node::KVWithSum(vec![1, 2, 3], vec![4, 5, 6], -2));
+        assert_eq!(op.encoding_length(), 18);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            vec![0x22, 3, 1, 2, 3, 0, 3, 4, 5, 6, 255, 255, 255, 255, 255, 255, 255, 254]
+        );
+    }
+
+    #[test]
+    fn sum_nodes_roundtrip() {
+        let ops = [
+            Op::Push(Node::HashWithSum([123; HASH_LENGTH], 1)),
+            Op::Push(Node::KVHashWithSum([123; HASH_LENGTH], 2)),
+            Op::Push(Node::KVWithSum(vec![1, 2, 3], vec![4, 5, 6], 3)),
+            Op::Push(Node::KVDigestWithSum(vec![1, 2, 3], [123; HASH_LENGTH], 4)),
+            Op::PushInverted(Node::HashWithSum([123; HASH_LENGTH], -1)),
+            Op::PushInverted(Node::KVHashWithSum([123; HASH_LENGTH], -2)),
+            Op::PushInverted(Node::KVWithSum(vec![1, 2, 3], vec![4, 5, 6], -3)),
+            Op::PushInverted(Node::KVDigestWithSum(vec![1, 2, 3], [123; HASH_LENGTH], -4)),
+        ];
+        for op in ops {
+            let mut bytes = vec![];
+            op.encode_into(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), op.encoding_length());
+            assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+        }
+    }
+
+    #[test]
+    fn count_nodes_roundtrip() {
+        let ops = [
+            Op::Push(Node::HashWithCount([123; HASH_LENGTH], 1)),
+            Op::Push(Node::KVHashWithCount([123; HASH_LENGTH], 2)),
+            Op::Push(Node::KVWithCount(vec![1, 2, 3], vec![4, 5, 6], 3)),
+            Op::Push(Node::KVDigestWithCount(
+                vec![1, 2, 3],
+                [123; HASH_LENGTH],
+                4,
+            )),
+            Op::PushInverted(Node::HashWithCount([123; HASH_LENGTH], 5)),
+            Op::PushInverted(Node::KVHashWithCount([123; HASH_LENGTH], 6)),
+            Op::PushInverted(Node::KVWithCount(vec![1, 2, 3], vec![4, 5, 6], 7)),
+            Op::PushInverted(Node::KVDigestWithCount(
+                vec![1, 2, 3],
+                [123; HASH_LENGTH],
+                8,
+            )),
+        ];
+        for op in ops {
+            let mut bytes = vec![];
+            op.encode_into(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), op.encoding_length());
+            assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+        }
+
+        let mut bytes = vec![];
+        Op::PushInverted(Node::KVHashWithCount([123; HASH_LENGTH], 6))
+            .encode_into(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes[0], 0x2d);
+    }
+
     #[test]
     fn decode_unknown() {
         let bytes = [0x88];
@@ -56,4 +56,54 @@ pub enum Node {
 
     /// Represents the key and value of a tree node.
     KV(Vec<u8>, Vec<u8>),
+
+    /// Represents the hash of a sum tree node and the sum of its subtree.
+    HashWithSum(Hash, i64),
+
+    /// Represents the hash of the key/value pair of a sum tree node and the
+    /// node's own value contributed to the sum.
+    KVHashWithSum(Hash, i64),
+
+    /// Represents the key/value_hash pair of a sum tree node and the node's
+    /// own value contributed to the sum.
+    KVDigestWithSum(Vec<u8>, Hash, i64),
+
+    /// Represents the key and value of a sum tree node and the node's own
+    /// value contributed to the sum.
+    KVWithSum(Vec<u8>, Vec<u8>, i64),
+
+    /// Represents the hash of a count tree node and the count of its subtree.
+    HashWithCount(Hash, u64),
+
+    /// Represents the hash of the key/value pair of a count tree node and the
+    /// node's own value contributed to the count.
+    KVHashWithCount(Hash, u64),
+
+    /// Represents the key/value_hash pair of a count tree node and the node's
+    /// own value contributed to the count.
+    KVDigestWithCount(Vec<u8>, Hash, u64),
+
+    /// Represents the key and value of a count tree node and the node's own
+    /// value contributed to the count.
+    KVWithCount(Vec<u8>, Vec<u8>, u64),
+}
+
+impl Node {
+    /// Returns the key of a node carrying one.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Node::KV(key, _)
+            | Node::KVDigest(key, _)
+            | Node::KVWithSum(key, ..)
+            | Node::KVDigestWithSum(key, ..)
+            | Node::KVWithCount(key, ..)
+            | Node::KVDigestWithCount(key, ..) => Some(key),
+            Node::Hash(_)
+            | Node::KVHash(_)
+            | Node::HashWithSum(..)
+            | Node::KVHashWithSum(..)
+            | Node::HashWithCount(..)
+            | Node::KVHashWithCount(..) => None,
+        }
+    }
 }
@@ -33,7 +33,9 @@ impl MapBuilder {
     /// `Hash`).
     pub fn insert(&mut self, node: &Node) -> Result<()> {
         match node {
-            Node::KV(key, value) => {
+            Node::KV(key, value)
+            | Node::KVWithSum(key, value, _)
+            | Node::KVWithCount(key, value, _) => {
                 if let Some((prev_key, _)) = self.0.entries.last_key_value() {
                     ensure!(
                         key > prev_key,
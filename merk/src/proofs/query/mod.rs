@@ -17,7 +17,7 @@ use storage::RawIterator;
 use {super::Op, std::collections::LinkedList};
 
 use super::{tree::execute, Decoder, Node};
-use crate::tree::{Fetch, Hash as MerkHash, Link, RefWalker};
+use crate::tree::{AggregateData, Fetch, Hash as MerkHash, Link, RefWalker, TreeFeatureType};
 
 #[derive(Debug, Default, Clone)]
 pub struct SubqueryBranch {
@@ -793,10 +793,11 @@ impl From<Vec<u8>> for QueryItem {
 }
 
 impl Link {
-    /// Creates a `Node::Hash` from this link. Panics if the link is of variant
-    /// `Link::Modified` since its hash has not yet been computed.
+    /// Creates a `Node::Hash` (`Node::HashWithSum` or `Node::HashWithCount`
+    /// for sum and count trees) from this link. Panics if the link is of
+    /// variant `Link::Modified` since its hash has not yet been computed.
     #[cfg(feature = "full")]
-    const fn to_hash_node(&self) -> Node {
+    pub(crate) fn to_hash_node(&self) -> Node {
         let hash = match self {
             Link::Reference { hash, .. } => hash,
             Link::Modified { .. } => {
@@ -805,7 +806,11 @@ impl Link {
             Link::Uncommitted { hash, .. } => hash,
             Link::Loaded { hash, .. } => hash,
         };
-        Node::Hash(*hash)
+        match self.aggregate_data() {
+            None => Node::Hash(*hash),
+            Some(AggregateData::Sum(sum)) => Node::HashWithSum(*hash, sum),
+            Some(AggregateData::Count(count)) => Node::HashWithCount(*hash, count),
+        }
     }
 }
 
@@ -813,26 +818,53 @@ impl<'a, S> RefWalker<'a, S>
 where
     S: Fetch + Sized + Clone,
 {
-    /// Creates a `Node::KV` from the key/value pair of the root node.
+    /// Creates a `Node::KV` (`Node::KVWithSum` or `Node::KVWithCount` for sum
+    /// and count trees) from the
+    /// key/value pair of the root node.
     pub(crate) fn to_kv_node(&self) -> Node {
-        Node::KV(self.tree().key().to_vec(), self.tree().value().to_vec())
+        let key = self.tree().key().to_vec();
+        let value = self.tree().value().to_vec();
+        match self.tree().feature_type() {
+            TreeFeatureType::BasicMerk => Node::KV(key, value),
+            TreeFeatureType::SummedMerk(sum) => Node::KVWithSum(key, value, sum),
+            TreeFeatureType::CountedMerk(count) => Node::KVWithCount(key, value, count),
+        }
     }
 
-    /// Creates a `Node::KVHash` from the hash of the key/value pair of the root
-    /// node.
+    /// Creates a `Node::KVHash` (`Node::KVHashWithSum` or
+    /// `Node::KVHashWithCount` for sum and count trees) from the
+    /// hash of the key/value pair of the root node.
     pub(crate) fn to_kvhash_node(&self) -> Node {
-        Node::KVHash(*self.tree().kv_hash())
+        let kv_hash = *self.tree().kv_hash();
+        match self.tree().feature_type() {
+            TreeFeatureType::BasicMerk => Node::KVHash(kv_hash),
+            TreeFeatureType::SummedMerk(sum) => Node::KVHashWithSum(kv_hash, sum),
+            TreeFeatureType::CountedMerk(count) => Node::KVHashWithCount(kv_hash, count),
+        }
     }
 
-    /// Creates a `Node::KVDigest` from the key/value_hash pair of the root
-    /// node.
+    /// Creates a `Node::KVDigest` (`Node::KVDigestWithSum` or
+    /// `Node::KVDigestWithCount` for sum and count trees) from the
+    /// key/value_hash pair of the root node.
     pub(crate) fn to_kvdigest_node(&self) -> Node {
-        Node::KVDigest(self.tree().key().to_vec(), *self.tree().value_hash())
+        let key = self.tree().key().to_vec();
+        let value_hash = *self.tree().value_hash();
+        match self.tree().feature_type() {
+            TreeFeatureType::BasicMerk => Node::KVDigest(key, value_hash),
+            TreeFeatureType::SummedMerk(sum) => Node::KVDigestWithSum(key, value_hash, sum),
+            TreeFeatureType::CountedMerk(count) => Node::KVDigestWithCount(key, value_hash, count),
+        }
     }
 
-    /// Creates a `Node::Hash` from the hash of the node.
+    /// Creates a `Node::Hash` (`Node::HashWithSum` or `Node::HashWithCount`
+    /// for sum and count trees) from the hash of the node.
     pub(crate) fn to_hash_node(&self) -> CostContext<Node> {
-        self.tree().hash().map(Node::Hash)
+        let aggregate_data = self.tree().aggregate_data();
+        self.tree().hash().map(|hash| match aggregate_data {
+            None => Node::Hash(hash),
+            Some(AggregateData::Sum(sum)) => Node::HashWithSum(hash, sum),
+            Some(AggregateData::Count(count)) => Node::HashWithCount(hash, count),
+        })
     }
 
     #[cfg(feature = "full")]
@@ -1186,6 +1218,10 @@ pub fn execute_proof(
                             // is lower than the bound
                             Some(Node::KV(..)) => {}
                             Some(Node::KVDigest(..)) => {}
+                            Some(Node::KVWithSum(..)) => {}
+                            Some(Node::KVDigestWithSum(..)) => {}
+                            Some(Node::KVWithCount(..)) => {}
+                            Some(Node::KVDigestWithCount(..)) => {}
 
                             // cannot verify lower bound - we have an abridged
                             // tree so we cannot tell what the preceding key was
@@ -1210,6 +1246,10 @@ pub fn execute_proof(
                             // is greater than the bound
                             Some(Node::KV(..)) => {}
                             Some(Node::KVDigest(..)) => {}
+                            Some(Node::KVWithSum(..)) => {}
+                            Some(Node::KVDigestWithSum(..)) => {}
+                            Some(Node::KVWithCount(..)) => {}
+                            Some(Node::KVDigestWithCount(..)) => {}
 
                             // cannot verify upper bound - we have an abridged
                             // tree so we cannot tell what the previous key was
@@ -1291,9 +1331,15 @@ pub fn execute_proof(
             Ok(())
         };
 
-        if let Node::KV(key, value) = node {
+        if let Node::KV(key, value)
+        | Node::KVWithSum(key, value, _)
+        | Node::KVWithCount(key, value, _) = node
+        {
             execute_node(key, Some(value))?;
-        } else if let Node::KVDigest(key, _) = node {
+        } else if let Node::KVDigest(key, _)
+        | Node::KVDigestWithSum(key, ..)
+        | Node::KVDigestWithCount(key, ..) = node
+        {
             execute_node(key, None)?;
         } else if in_range {
             // we encountered a queried range but the proof was abridged (saw a
@@ -1317,6 +1363,10 @@ pub fn execute_proof(
                 // last node in tree was less than queried item
                 Some(Node::KV(..)) => {}
                 Some(Node::KVDigest(..)) => {}
+                Some(Node::KVWithSum(..)) => {}
+                Some(Node::KVDigestWithSum(..)) => {}
+                Some(Node::KVWithCount(..)) => {}
+                Some(Node::KVDigestWithCount(..)) => {}
 
                 // proof contains abridged data so we cannot verify absence of
                 // remaining query items
@@ -1331,6 +1381,8 @@ pub fn execute_proof(
             result_set: output,
             limit: current_limit,
             offset: current_offset,
+            sum: root.sum(),
+            count: root.count(),
         },
     ))
     .wrap_with_cost(cost)
@@ -1341,6 +1393,11 @@ pub struct ProofVerificationResult {
     pub result_set: Vec<(Vec<u8>, Vec<u8>)>,
     pub limit: Option<u16>,
     pub offset: Option<u16>,
+    /// Sum of the proven tree committed into its root hash, if it's a sum tree
+    pub sum: Option<i64>,
+    /// Count of the proven tree committed into its root hash, if it's a count
+    /// tree
+    pub count: Option<u64>,
 }
 
 /// Verifies the encoded proof with the given query and expected hash
@@ -5567,6 +5624,168 @@ mod test {
         );
     }
 
+    #[test]
+    fn verify_sum_tree_proof() {
+        let mut tree = Tree::new(vec![5], vec![5])
+            .unwrap()
+            .with_feature_type(TreeFeatureType::SummedMerk(5))
+            .attach(
+                true,
+                Some(
+                    Tree::new(vec![3], vec![3])
+                        .unwrap()
+                        .with_feature_type(TreeFeatureType::SummedMerk(3)),
+                ),
+            )
+            .attach(
+                false,
+                Some(
+                    Tree::new(vec![7], vec![7])
+                        .unwrap()
+                        .with_feature_type(TreeFeatureType::SummedMerk(-7)),
+                ),
+            );
+        tree.commit(&mut NoopCommit {})
+            .unwrap()
+            .expect("commit failed");
+        assert_eq!(tree.sum(), Some(1));
+
+        let root_hash = tree.hash().unwrap();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let (proof, ..) = walker
+            .create_full_proof(vec![QueryItem::Key(vec![7])].as_slice(), None, None, true)
+            .unwrap()
+            .expect("failed to create proof");
+        let mut iter = proof.iter();
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::HashWithSum(
+                *tree.link(true).unwrap().hash(),
+                3
+            )))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::KVHashWithSum(*tree.kv_hash(), 5)))
+        );
+        assert_eq!(iter.next(), Some(&Op::Parent));
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::KVWithSum(vec![7], vec![7], -7)))
+        );
+        assert_eq!(iter.next(), Some(&Op::Child));
+        assert!(iter.next().is_none());
+
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        let mut query = Query::new();
+        query.insert_key(vec![7]);
+        let result = verify_query(bytes.as_slice(), &query, None, None, true, root_hash)
+            .unwrap()
+            .expect("verify failed");
+        assert_eq!(result.result_set, vec![(vec![7], vec![7])]);
+        assert_eq!(result.sum, Some(1));
+
+        // A forged sum changes the root hash
+        let forged_proof = vec![
+            Op::Push(Node::HashWithSum(*tree.link(true).unwrap().hash(), 4)),
+            Op::Push(Node::KVHashWithSum(*tree.kv_hash(), 5)),
+            Op::Parent,
+            Op::Push(Node::KVWithSum(vec![7], vec![7], -7)),
+            Op::Child,
+        ];
+        let mut bytes = vec![];
+        encode_into(forged_proof.iter(), &mut bytes);
+        assert!(
+            verify_query(bytes.as_slice(), &query, None, None, true, root_hash)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn verify_count_tree_proof() {
+        let mut tree = Tree::new(vec![5], vec![5])
+            .unwrap()
+            .with_feature_type(TreeFeatureType::CountedMerk(1))
+            .attach(
+                true,
+                Some(
+                    Tree::new(vec![3], vec![3])
+                        .unwrap()
+                        .with_feature_type(TreeFeatureType::CountedMerk(1)),
+                ),
+            )
+            .attach(
+                false,
+                Some(
+                    Tree::new(vec![7], vec![7])
+                        .unwrap()
+                        .with_feature_type(TreeFeatureType::CountedMerk(4)),
+                ),
+            );
+        tree.commit(&mut NoopCommit {})
+            .unwrap()
+            .expect("commit failed");
+        assert_eq!(tree.count(), Some(6));
+        assert_eq!(tree.sum(), None);
+
+        let root_hash = tree.hash().unwrap();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let (proof, ..) = walker
+            .create_full_proof(vec![QueryItem::Key(vec![7])].as_slice(), None, None, true)
+            .unwrap()
+            .expect("failed to create proof");
+        let mut iter = proof.iter();
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::HashWithCount(
+                *tree.link(true).unwrap().hash(),
+                1
+            )))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::KVHashWithCount(*tree.kv_hash(), 1)))
+        );
+        assert_eq!(iter.next(), Some(&Op::Parent));
+        assert_eq!(
+            iter.next(),
+            Some(&Op::Push(Node::KVWithCount(vec![7], vec![7], 4)))
+        );
+        assert_eq!(iter.next(), Some(&Op::Child));
+        assert!(iter.next().is_none());
+
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        let mut query = Query::new();
+        query.insert_key(vec![7]);
+        let result = verify_query(bytes.as_slice(), &query, None, None, true, root_hash)
+            .unwrap()
+            .expect("verify failed");
+        assert_eq!(result.result_set, vec![(vec![7], vec![7])]);
+        assert_eq!(result.count, Some(6));
+        assert_eq!(result.sum, None);
+
+        // A forged count changes the root hash
+        let forged_proof = vec![
+            Op::Push(Node::HashWithCount(*tree.link(true).unwrap().hash(), 2)),
+            Op::Push(Node::KVHashWithCount(*tree.kv_hash(), 1)),
+            Op::Parent,
+            Op::Push(Node::KVWithCount(vec![7], vec![7], 4)),
+            Op::Child,
+        ];
+        let mut bytes = vec![];
+        encode_into(forged_proof.iter(), &mut bytes);
+        assert!(
+            verify_query(bytes.as_slice(), &query, None, None, true, root_hash)
+                .unwrap()
+                .is_err()
+        );
+    }
+
     #[test]
     #[should_panic(expected = "verify failed")]
     fn verify_ops_mismatched_hash() {
@@ -4,14 +4,18 @@ use costs::{
 };
 
 use super::{Node, Op};
-use crate::tree::{kv_digest_to_kv_hash, kv_hash, node_hash, Hash, NULL_HASH};
+use crate::tree::{
+    kv_digest_to_kv_hash, kv_hash, node_hash, node_hash_with_count, node_hash_with_sum,
+    AggregateData, Hash, NULL_HASH,
+};
 
-/// Contains a tree's child node and its hash. The hash can always be assumed to
-/// be up-to-date.
+/// Contains a tree's child node, its hash and aggregate (if it's a sum or a
+/// count tree). The hash can always be assumed to be up-to-date.
 #[derive(Debug)]
 pub struct Child {
     pub tree: Box<Tree>,
     pub hash: Hash,
+    pub aggregate_data: Option<AggregateData>,
 }
 
 /// A binary tree data structure used to represent a select subset of a tree
@@ -47,19 +51,66 @@ impl Tree {
     /// Gets or computes the hash for this tree node.
     pub fn hash(&self) -> CostContext<Hash> {
         fn compute_hash(tree: &Tree, kv_hash: Hash) -> CostContext<Hash> {
-            node_hash(&kv_hash, &tree.child_hash(true), &tree.child_hash(false))
+            let (left, right) = (tree.child_hash(true), tree.child_hash(false));
+            match tree.aggregate_data() {
+                None => node_hash(&kv_hash, &left, &right),
+                Some(AggregateData::Sum(sum)) => node_hash_with_sum(&kv_hash, &left, &right, sum),
+                Some(AggregateData::Count(count)) => {
+                    node_hash_with_count(&kv_hash, &left, &right, count)
+                }
+            }
         }
 
         match &self.node {
-            Node::Hash(hash) => (*hash).wrap_with_cost(Default::default()),
-            Node::KVHash(kv_hash) => compute_hash(self, *kv_hash),
-            Node::KV(key, value) => kv_hash(key.as_slice(), value.as_slice())
+            Node::Hash(hash) | Node::HashWithSum(hash, _) | Node::HashWithCount(hash, _) => {
+                (*hash).wrap_with_cost(Default::default())
+            }
+            Node::KVHash(kv_hash)
+            | Node::KVHashWithSum(kv_hash, _)
+            | Node::KVHashWithCount(kv_hash, _) => compute_hash(self, *kv_hash),
+            Node::KV(key, value)
+            | Node::KVWithSum(key, value, _)
+            | Node::KVWithCount(key, value, _) => kv_hash(key.as_slice(), value.as_slice())
                 .flat_map(|kv_hash| compute_hash(self, kv_hash)),
-            Node::KVDigest(key, value_hash) => kv_digest_to_kv_hash(key, value_hash)
+            Node::KVDigest(key, value_hash)
+            | Node::KVDigestWithSum(key, value_hash, _)
+            | Node::KVDigestWithCount(key, value_hash, _) => kv_digest_to_kv_hash(key, value_hash)
                 .flat_map(|kv_hash| compute_hash(self, kv_hash)),
         }
     }
 
+    /// Computes the aggregate of the tree if it's a sum or a count tree: the
+    /// node's own value plus aggregates of its children, the same way it's
+    /// committed into the hash.
+    pub fn aggregate_data(&self) -> Option<AggregateData> {
+        let own_data = match &self.node {
+            Node::HashWithSum(_, sum) => return Some(AggregateData::Sum(*sum)),
+            Node::HashWithCount(_, count) => return Some(AggregateData::Count(*count)),
+            Node::KVHashWithSum(_, own_sum)
+            | Node::KVWithSum(_, _, own_sum)
+            | Node::KVDigestWithSum(_, _, own_sum) => AggregateData::Sum(*own_sum),
+            Node::KVHashWithCount(_, own_count)
+            | Node::KVWithCount(_, _, own_count)
+            | Node::KVDigestWithCount(_, _, own_count) => AggregateData::Count(*own_count),
+            Node::Hash(_) | Node::KVHash(_) | Node::KV(..) | Node::KVDigest(..) => return None,
+        };
+        Some(
+            own_data
+                .add_child(self.child_aggregate_data(true))
+                .add_child(self.child_aggregate_data(false)),
+        )
+    }
+
+    /// Computes the sum of the tree if it's a sum tree.
+    pub fn sum(&self) -> Option<i64> {
+        self.aggregate_data().and_then(|data| data.sum())
+    }
+
+    /// Computes the count of the tree if it's a count tree.
+    pub fn count(&self) -> Option<u64> {
+        self.aggregate_data().and_then(|data| data.count())
+    }
+
     /// Creates an iterator that yields the in-order traversal of the nodes at
     /// the given depth.
     pub fn layer(&self, depth: usize) -> LayerIter {
@@ -128,8 +179,13 @@ impl Tree {
         self.height = self.height.max(child.height + 1);
 
         let hash = child.hash().unwrap_add_cost(&mut cost);
+        let aggregate_data = child.aggregate_data();
         let tree = Box::new(child);
-        *self.child_mut(left) = Some(Child { tree, hash });
+        *self.child_mut(left) = Some(Child {
+            tree,
+            hash,
+            aggregate_data,
+        });
 
         Ok(()).wrap_with_cost(cost)
     }
@@ -145,10 +201,26 @@ impl Tree {
         }
     }
 
+    /// Returns the aggregate of this tree node's child on the given side, if
+    /// there is a child of a sum or a count tree.
+    #[inline]
+    fn child_aggregate_data(&self, left: bool) -> Option<AggregateData> {
+        self.child(left).and_then(|c| c.aggregate_data)
+    }
+
     /// Consumes the tree node, calculates its hash, and returns a `Node::Hash`
-    /// variant.
+    /// variant (`Node::HashWithSum` or `Node::HashWithCount` for sum and count
+    /// trees).
     fn into_hash(self) -> CostContext<Self> {
-        self.hash().map(|hash| Node::Hash(hash).into())
+        let aggregate_data = self.aggregate_data();
+        self.hash().map(|hash| {
+            match aggregate_data {
+                None => Node::Hash(hash),
+                Some(AggregateData::Sum(sum)) => Node::HashWithSum(hash, sum),
+                Some(AggregateData::Count(count)) => Node::HashWithCount(hash, count),
+            }
+            .into()
+        })
     }
 
     // #[cfg(feature = "full")]
@@ -333,7 +405,9 @@ where
                 stack.push(parent);
             }
             Op::Push(node) => {
-                if let Node::KV(key, _) = &node {
+                if let Node::KV(key, _) | Node::KVWithSum(key, ..) | Node::KVWithCount(key, ..) =
+                    &node
+                {
                     // keys should always increase
                     if let Some(last_key) = &maybe_last_key {
                         if key <= last_key {
@@ -350,7 +424,9 @@ where
                 stack.push(tree);
             }
             Op::PushInverted(node) => {
-                if let Node::KV(key, _) = &node {
+                if let Node::KV(key, _) | Node::KVWithSum(key, ..) | Node::KVWithCount(key, ..) =
+                    &node
+                {
                     // keys should always increase
                     if let Some(last_key) = &maybe_last_key {
                         if key >= last_key {
@@ -1,10 +1,14 @@
+#[cfg(feature = "rocksdb_storage")]
 mod crash_merk;
+#[cfg(feature = "rocksdb_storage")]
 mod temp_merk;
 
 use std::{convert::TryInto, ops::Range};
 
+#[cfg(feature = "rocksdb_storage")]
 pub use crash_merk::CrashMerk;
 use rand::prelude::*;
+#[cfg(feature = "rocksdb_storage")]
 pub use temp_merk::TempMerk;
 
 use crate::tree::{BatchEntry, MerkBatch, NoopCommit, Op, PanicSource, Tree, Walker};
@@ -76,7 +76,10 @@ impl Tree {
 
 #[cfg(test)]
 mod tests {
-    use super::{super::Link, *};
+    use super::{
+        super::{AggregateData, Link, TreeFeatureType},
+        *,
+    };
 
     #[test]
     fn encode_leaf_tree() {
@@ -119,6 +122,7 @@ mod tests {
             [55; 32],
             Some(Link::Loaded {
                 hash: [66; 32],
+                aggregate_data: None,
                 child_heights: (123, 124),
                 tree: Tree::new(vec![2], vec![3]).unwrap(),
             }),
@@ -146,6 +150,7 @@ mod tests {
             [55; 32],
             Some(Link::Uncommitted {
                 hash: [66; 32],
+                aggregate_data: None,
                 child_heights: (123, 124),
                 tree: Tree::new(vec![2], vec![3]).unwrap(),
             }),
@@ -173,6 +178,7 @@ mod tests {
             [55; 32],
             Some(Link::Reference {
                 hash: [66; 32],
+                aggregate_data: None,
                 child_heights: (123, 124),
                 key: vec![2],
             }),
@@ -193,6 +199,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn summed_tree_roundtrip() {
+        let tree = Tree::from_fields(
+            vec![0],
+            vec![1],
+            [55; 32],
+            Some(Link::Reference {
+                hash: [66; 32],
+                aggregate_data: Some(AggregateData::Sum(-3)),
+                child_heights: (123, 124),
+                key: vec![2],
+            }),
+            None,
+        )
+        .unwrap()
+        .with_feature_type(TreeFeatureType::SummedMerk(5));
+        let bytes = tree.encode();
+        assert_eq!(bytes.len(), tree.encoding_length());
+        // the marker, own sum and the left child's sum come on top of the
+        // basic node encoding
+        assert_eq!(bytes.len(), 103 + 17);
+
+        let decoded = Tree::decode(vec![0], bytes.as_slice());
+        assert_eq!(decoded.feature_type(), TreeFeatureType::SummedMerk(5));
+        assert_eq!(decoded.link(true).unwrap().sum(), Some(-3));
+        assert_eq!(decoded.sum(), Some(2));
+        assert_eq!(decoded.hash().unwrap(), tree.hash().unwrap());
+    }
+
+    #[test]
+    fn counted_tree_roundtrip() {
+        let tree = Tree::from_fields(
+            vec![0],
+            vec![1],
+            [55; 32],
+            None,
+            Some(Link::Reference {
+                hash: [66; 32],
+                aggregate_data: Some(AggregateData::Count(3)),
+                child_heights: (123, 124),
+                key: vec![2],
+            }),
+        )
+        .unwrap()
+        .with_feature_type(TreeFeatureType::CountedMerk(1));
+        let bytes = tree.encode();
+        assert_eq!(bytes.len(), tree.encoding_length());
+        assert_eq!(bytes.len(), 103 + 17);
+
+        let decoded = Tree::decode(vec![0], bytes.as_slice());
+        assert_eq!(decoded.feature_type(), TreeFeatureType::CountedMerk(1));
+        assert_eq!(decoded.link(false).unwrap().count(), Some(3));
+        assert_eq!(decoded.count(), Some(4));
+        assert_eq!(decoded.sum(), None);
+        assert_eq!(decoded.hash().unwrap(), tree.hash().unwrap());
+    }
+
     #[test]
     fn decode_leaf_tree() {
         let bytes = vec![
@@ -222,6 +285,7 @@ mod tests {
             key,
             child_heights,
             hash,
+            ..
         }) = tree.link(true)
         {
             assert_eq!(*key, [2]);
@@ -88,3 +88,41 @@ pub fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> CostContext<Hash> {
         ..Default::default()
     })
 }
+
+/// Hashes a node of a sum tree based on the hash of its key/value pair, the
+/// hashes of its children (if any) and the sum of its subtree.
+pub fn node_hash_with_sum(kv: &Hash, left: &Hash, right: &Hash, sum: i64) -> CostContext<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(kv);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.update(&sum.to_be_bytes());
+
+    let res = hasher.finalize();
+    let mut hash: Hash = Default::default();
+    hash.copy_from_slice(res.as_bytes());
+    hash.wrap_with_cost(OperationCost {
+        hash_node_calls: 1,
+        ..Default::default()
+    })
+}
+
+/// Hashes a node of a count tree based on the hash of its key/value pair, the
+/// hashes of its children (if any) and the count of its subtree. A trailing
+/// byte keeps count tree hashes apart from sum tree ones.
+pub fn node_hash_with_count(kv: &Hash, left: &Hash, right: &Hash, count: u64) -> CostContext<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(kv);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.update(&count.to_be_bytes());
+    hasher.update(&[1]);
+
+    let res = hasher.finalize();
+    let mut hash: Hash = Default::default();
+    hash.copy_from_slice(res.as_bytes());
+    hash.wrap_with_cost(OperationCost {
+        hash_node_calls: 1,
+        ..Default::default()
+    })
+}
@@ -2,7 +2,7 @@ use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Result, Terminated};
 
-use super::{hash::Hash, Tree};
+use super::{hash::Hash, AggregateData, Tree};
 
 // TODO: optimize memory footprint
 
@@ -15,6 +15,7 @@ pub enum Link {
     /// fetched from the backing store by this key when necessary.
     Reference {
         hash: Hash,
+        aggregate_data: Option<AggregateData>,
         child_heights: (u8, u8),
         key: Vec<u8>,
     },
@@ -34,6 +35,7 @@ pub enum Link {
     // stored in the link.
     Uncommitted {
         hash: Hash,
+        aggregate_data: Option<AggregateData>,
         child_heights: (u8, u8),
         tree: Tree,
     },
@@ -42,6 +44,7 @@ pub enum Link {
     /// hash, and which is being retained in memory.
     Loaded {
         hash: Hash,
+        aggregate_data: Option<AggregateData>,
         child_heights: (u8, u8),
         tree: Tree,
     },
@@ -127,6 +130,31 @@ impl Link {
         }
     }
 
+    /// Returns the aggregate of the tree referenced by the link if it's a sum
+    /// or a count tree.
+    #[inline]
+    pub fn aggregate_data(&self) -> Option<AggregateData> {
+        match self {
+            Link::Reference { aggregate_data, .. } => *aggregate_data,
+            Link::Modified { tree, .. } => tree.aggregate_data(),
+            Link::Uncommitted { aggregate_data, .. } => *aggregate_data,
+            Link::Loaded { aggregate_data, .. } => *aggregate_data,
+        }
+    }
+
+    /// Returns the sum of the tree referenced by the link if it's a sum tree.
+    #[inline]
+    pub fn sum(&self) -> Option<i64> {
+        self.aggregate_data().and_then(|data| data.sum())
+    }
+
+    /// Returns the count of the tree referenced by the link if it's a count
+    /// tree.
+    #[inline]
+    pub fn count(&self) -> Option<u64> {
+        self.aggregate_data().and_then(|data| data.count())
+    }
+
     /// Returns the height of the children of the tree referenced by the link,
     /// if any (note: not the height of the referenced tree itself). Return
     /// value is `(left_child_height, right_child_height)`.
@@ -171,10 +199,12 @@ impl Link {
             Link::Uncommitted { .. } => panic!("Cannot prune Uncommitted tree"),
             Link::Loaded {
                 hash,
+                aggregate_data,
                 child_heights,
                 tree,
             } => Self::Reference {
                 hash,
+                aggregate_data,
                 child_heights,
                 key: tree.take_key(),
             },
@@ -213,16 +243,19 @@ impl Encode for Link {
                 hash,
                 key,
                 child_heights,
+                ..
             } => (hash, key.as_slice(), child_heights),
             Link::Loaded {
                 hash,
                 tree,
                 child_heights,
+                ..
             } => (hash, tree.key(), child_heights),
             Link::Uncommitted {
                 hash,
                 tree,
                 child_heights,
+                ..
             } => (hash, tree.key(), child_heights),
 
             Link::Modified { .. } => panic!("No encoding for Link::Modified"),
@@ -259,6 +292,7 @@ impl Link {
         Self::Reference {
             key: Vec::with_capacity(64),
             hash: Default::default(),
+            aggregate_data: None,
             child_heights: (0, 0),
         }
     }
@@ -284,6 +318,7 @@ impl Decode for Link {
             ref mut key,
             ref mut hash,
             ref mut child_heights,
+            ..
         } = self
         {
             let length = read_u8(&mut input)? as usize;
@@ -353,6 +388,7 @@ mod test {
 
         let reference = Link::Reference {
             hash,
+            aggregate_data: None,
             child_heights,
             key,
         };
@@ -363,11 +399,13 @@ mod test {
         };
         let uncommitted = Link::Uncommitted {
             hash,
+            aggregate_data: None,
             child_heights,
             tree: tree(),
         };
         let loaded = Link::Loaded {
             hash,
+            aggregate_data: None,
             child_heights,
             tree: tree(),
         };
@@ -433,6 +471,7 @@ mod test {
     fn uncommitted_into_reference() {
         Link::Uncommitted {
             hash: [1; 32],
+            aggregate_data: None,
             child_heights: (1, 1),
             tree: Tree::new(vec![0], vec![1]).unwrap(),
         }
@@ -445,6 +484,7 @@ mod test {
             key: vec![1, 2, 3],
             child_heights: (123, 124),
             hash: [55; 32],
+            aggregate_data: None,
         };
         assert_eq!(link.encoding_length().unwrap(), 38);
 
@@ -466,6 +506,7 @@ mod test {
             key: vec![123; 300],
             child_heights: (123, 124),
             hash: [55; 32],
+            aggregate_data: None,
         };
         let mut bytes = vec![];
         link.encode_into(&mut bytes).unwrap();
@@ -8,9 +8,13 @@ mod iter;
 mod kv;
 mod link;
 mod ops;
+mod tree_feature_type;
 mod walk;
 
-use std::cmp::max;
+use std::{
+    cmp::max,
+    io::{Read, Write},
+};
 
 use anyhow::Result;
 pub use commit::{Commit, NoopCommit};
@@ -18,10 +22,14 @@ use costs::{
     cost_return_on_error, cost_return_on_error_no_add, CostContext, CostsExt, OperationCost,
 };
 use ed::{Decode, Encode, Terminated};
-pub use hash::{kv_digest_to_kv_hash, kv_hash, node_hash, Hash, HASH_LENGTH, NULL_HASH};
+pub use hash::{
+    kv_digest_to_kv_hash, kv_hash, node_hash, node_hash_with_count, node_hash_with_sum, Hash,
+    HASH_LENGTH, NULL_HASH,
+};
 use kv::KV;
 pub use link::Link;
 pub use ops::{BatchEntry, MerkBatch, Op, PanicSource};
+pub use tree_feature_type::{AggregateData, TreeFeatureType};
 pub use walk::{Fetch, RefWalker, Walker};
 
 use crate::tree::hash::value_hash;
@@ -29,16 +37,136 @@ use crate::tree::hash::value_hash;
 // TODO: remove need for `TreeInner`, and just use `Box<Self>` receiver for
 // relevant methods
 
+/// Leading byte of encoded summed nodes. Encodings of basic nodes start with
+/// the left link's option tag, which is either 0 or 1.
+const SUMMED_NODE_MARKER: u8 = 2;
+/// Leading byte of encoded counted nodes.
+const COUNTED_NODE_MARKER: u8 = 3;
+
 /// The fields of the `Tree` type, stored on the heap.
-#[derive(Clone, Encode, Decode)]
+#[derive(Clone)]
 struct TreeInner {
     left: Option<Link>,
     right: Option<Link>,
     kv: KV,
+    feature_type: TreeFeatureType,
 }
 
 impl Terminated for Box<TreeInner> {}
 
+impl Encode for TreeInner {
+    fn encode_into<W: Write>(&self, out: &mut W) -> ed::Result<()> {
+        match self.feature_type {
+            TreeFeatureType::BasicMerk => {
+                self.left.encode_into(out)?;
+                self.right.encode_into(out)?;
+            }
+            TreeFeatureType::SummedMerk(_) | TreeFeatureType::CountedMerk(_) => {
+                // Summed and counted nodes also store aggregates of their
+                // children, so they could be verified without loading them
+                let marker = match self.feature_type {
+                    TreeFeatureType::CountedMerk(_) => COUNTED_NODE_MARKER,
+                    _ => SUMMED_NODE_MARKER,
+                };
+                let own_data = self
+                    .feature_type
+                    .aggregate_data()
+                    .expect("node is not basic");
+                out.write_all(&[marker])?;
+                out.write_all(&own_data.to_be_bytes())?;
+                for link in [&self.left, &self.right] {
+                    link.encode_into(out)?;
+                    if let Some(link) = link {
+                        let child_data = link.aggregate_data().map(|data| data.to_be_bytes());
+                        out.write_all(&child_data.unwrap_or_default())?;
+                    }
+                }
+            }
+        }
+        self.kv.encode_into(out)
+    }
+
+    fn encoding_length(&self) -> ed::Result<usize> {
+        let links_length = self.left.encoding_length()? + self.right.encoding_length()?;
+        let aggregates_length = match self.feature_type {
+            TreeFeatureType::BasicMerk => 0,
+            TreeFeatureType::SummedMerk(_) | TreeFeatureType::CountedMerk(_) => {
+                let children = self.left.is_some() as usize + self.right.is_some() as usize;
+                1 + 8 * (1 + children)
+            }
+        };
+        Ok(links_length + aggregates_length + self.kv.encoding_length()?)
+    }
+}
+
+impl Decode for TreeInner {
+    fn decode<R: Read>(mut input: R) -> ed::Result<Self> {
+        let mut first_byte = [0u8];
+        input.read_exact(&mut first_byte)?;
+
+        let aggregate_kind = match first_byte[0] {
+            SUMMED_NODE_MARKER => Some(AggregateData::Sum(0)),
+            COUNTED_NODE_MARKER => Some(AggregateData::Count(0)),
+            _ => None,
+        };
+        let (feature_type, left, right) = if let Some(aggregate_kind) = aggregate_kind {
+            let own_data = aggregate_kind.with_be_bytes(read_be_bytes(&mut input)?);
+            let mut links = [None, None];
+            for link in links.iter_mut() {
+                let mut tag = [0u8];
+                input.read_exact(&mut tag)?;
+                *link = decode_aggregate_link(tag[0], aggregate_kind, &mut input)?;
+            }
+            let [left, right] = links;
+            let feature_type = match own_data {
+                AggregateData::Sum(sum) => TreeFeatureType::SummedMerk(sum),
+                AggregateData::Count(count) => TreeFeatureType::CountedMerk(count),
+            };
+            (feature_type, left, right)
+        } else {
+            let left = decode_link_with_tag(first_byte[0], &mut input)?;
+            let right = Decode::decode(&mut input)?;
+            (TreeFeatureType::BasicMerk, left, right)
+        };
+
+        Ok(TreeInner {
+            left,
+            right,
+            kv: Decode::decode(input)?,
+            feature_type,
+        })
+    }
+}
+
+fn read_be_bytes<R: Read>(mut input: R) -> ed::Result<[u8; 8]> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decodes an optional link whose option tag was already read.
+fn decode_link_with_tag<R: Read>(tag: u8, input: R) -> ed::Result<Option<Link>> {
+    match tag {
+        0 => Ok(None),
+        1 => Ok(Some(Decode::decode(input)?)),
+        byte => Err(ed::Error::UnexpectedByte(byte)),
+    }
+}
+
+/// Decodes an optional link of a summed or a counted node followed by the
+/// child's aggregate of the same kind as `aggregate_kind`.
+fn decode_aggregate_link<R: Read>(
+    tag: u8,
+    aggregate_kind: AggregateData,
+    mut input: R,
+) -> ed::Result<Option<Link>> {
+    let mut link = decode_link_with_tag(tag, &mut input)?;
+    if let Some(Link::Reference { aggregate_data, .. }) = link.as_mut() {
+        *aggregate_data = Some(aggregate_kind.with_be_bytes(read_be_bytes(&mut input)?));
+    }
+    Ok(link)
+}
+
 /// A binary AVL tree data structure, with Merkle hashes.
 ///
 /// Trees' inner fields are stored on the heap so that nodes can recursively
@@ -59,6 +187,7 @@ impl Tree {
                 kv,
                 left: None,
                 right: None,
+                feature_type: TreeFeatureType::BasicMerk,
             }),
         })
     }
@@ -77,6 +206,7 @@ impl Tree {
                 kv,
                 left: None,
                 right: None,
+                feature_type: TreeFeatureType::BasicMerk,
             }),
         })
     }
@@ -95,10 +225,55 @@ impl Tree {
                 kv: KV::from_fields(key, value, kv_hash, vh),
                 left,
                 right,
+                feature_type: TreeFeatureType::BasicMerk,
             }),
         })
     }
 
+    /// Replaces the root node's feature type and returns the modified `Tree`.
+    #[inline]
+    pub fn with_feature_type(mut self, feature_type: TreeFeatureType) -> Self {
+        self.inner.feature_type = feature_type;
+        self
+    }
+
+    /// Returns the root node's feature type.
+    #[inline]
+    pub const fn feature_type(&self) -> TreeFeatureType {
+        self.inner.feature_type
+    }
+
+    /// Returns the aggregate of the tree if it's a sum or a count tree: the
+    /// root node's own value plus aggregates of its children. Aggregates wrap
+    /// around on overflow to keep hashing infallible.
+    #[inline]
+    pub fn aggregate_data(&self) -> Option<AggregateData> {
+        self.inner.feature_type.aggregate_data().map(|own_data| {
+            own_data
+                .add_child(self.child_aggregate_data(true))
+                .add_child(self.child_aggregate_data(false))
+        })
+    }
+
+    /// Returns the sum of the tree if it's a sum tree.
+    #[inline]
+    pub fn sum(&self) -> Option<i64> {
+        self.aggregate_data().and_then(|data| data.sum())
+    }
+
+    /// Returns the count of the tree if it's a count tree.
+    #[inline]
+    pub fn count(&self) -> Option<u64> {
+        self.aggregate_data().and_then(|data| data.count())
+    }
+
+    /// Returns the aggregate of the root node's child on the given side, if
+    /// there is a child of a sum or a count tree.
+    #[inline]
+    pub fn child_aggregate_data(&self, left: bool) -> Option<AggregateData> {
+        self.link(left).and_then(|link| link.aggregate_data())
+    }
+
     /// Returns the root node's key as a slice.
     #[inline]
     pub fn key(&self) -> &[u8] {
@@ -192,11 +367,16 @@ impl Tree {
     /// Computes and returns the hash of the root node.
     #[inline]
     pub fn hash(&self) -> CostContext<Hash> {
-        node_hash(
+        let (kv, left, right) = (
             self.inner.kv.hash(),
             self.child_hash(true),
             self.child_hash(false),
-        )
+        );
+        match self.aggregate_data() {
+            None => node_hash(kv, left, right),
+            Some(AggregateData::Sum(sum)) => node_hash_with_sum(kv, left, right, sum),
+            Some(AggregateData::Count(count)) => node_hash_with_count(kv, left, right, count),
+        }
     }
 
     /// Returns the number of pending writes for the child on the given side, if
@@ -395,6 +575,7 @@ impl Tree {
                 cost_return_on_error!(&mut cost, tree.commit(c));
                 self.inner.left = Some(Link::Loaded {
                     hash: tree.hash().unwrap_add_cost(&mut cost),
+                    aggregate_data: tree.aggregate_data(),
                     tree,
                     child_heights,
                 });
@@ -413,6 +594,7 @@ impl Tree {
                 cost_return_on_error!(&mut cost, tree.commit(c));
                 self.inner.right = Some(Link::Loaded {
                     hash: tree.hash().unwrap_add_cost(&mut cost),
+                    aggregate_data: tree.aggregate_data(),
                     tree,
                     child_heights,
                 });
@@ -440,12 +622,13 @@ impl Tree {
     pub fn load<S: Fetch>(&mut self, left: bool, source: &S) -> CostContext<Result<()>> {
         // TODO: return Err instead of panic?
         let link = self.link(left).expect("Expected link");
-        let (child_heights, hash) = match link {
+        let (child_heights, hash, aggregate_data) = match link {
             Link::Reference {
                 child_heights,
                 hash,
+                aggregate_data,
                 ..
-            } => (child_heights, hash),
+            } => (child_heights, hash, aggregate_data),
             _ => panic!("Expected Some(Link::Reference)"),
         };
 
@@ -455,6 +638,7 @@ impl Tree {
         *self.slot_mut(left) = Some(Link::Loaded {
             tree,
             hash: *hash,
+            aggregate_data: *aggregate_data,
             child_heights: *child_heights,
         });
         Ok(()).wrap_with_cost(cost)
@@ -4,7 +4,7 @@ use anyhow::Result;
 use costs::{cost_return_on_error, CostContext, CostsExt, OperationCost};
 use Op::*;
 
-use super::{Fetch, Link, Tree, Walker};
+use super::{Fetch, Link, Tree, TreeFeatureType, Walker};
 use crate::tree::hash::value_hash;
 
 /// Type alias to add more sense to function signatures.
@@ -15,9 +15,25 @@ type DeletedKeys = LinkedList<Vec<u8>>;
 pub enum Op {
     Put(Vec<u8>),
     PutReference(Vec<u8>, Vec<u8>),
+    /// Put a value into a node of the given type, used for sum trees
+    PutWithFeatureType(Vec<u8>, TreeFeatureType),
+    /// Put a reference into a node of the given type, used for sum trees
+    PutReferenceWithFeatureType(Vec<u8>, Vec<u8>, TreeFeatureType),
     Delete,
 }
 
+impl Op {
+    /// Returns the type of a node to be put, `None` for deletion.
+    fn feature_type(&self) -> Option<TreeFeatureType> {
+        match self {
+            Put(_) | PutReference(..) => Some(TreeFeatureType::BasicMerk),
+            PutWithFeatureType(_, feature_type)
+            | PutReferenceWithFeatureType(_, _, feature_type) => Some(*feature_type),
+            Delete => None,
+        }
+    }
+}
+
 impl fmt::Debug for Op {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
@@ -27,6 +43,12 @@ impl fmt::Debug for Op {
                 Put(value) => format!("Put({:?})", value),
                 PutReference(value, referenced_value) =>
                     format!("Put Reference({:?}) for ({:?})", value, referenced_value),
+                PutWithFeatureType(value, feature_type) =>
+                    format!("Put({:?}) as {:?}", value, feature_type),
+                PutReferenceWithFeatureType(value, referenced_value, feature_type) => format!(
+                    "Put Reference({:?}) for ({:?}) as {:?}",
+                    value, referenced_value, feature_type
+                ),
                 Delete => "Delete".to_string(),
             }
         )
@@ -109,24 +131,26 @@ where
                 };
                 return Ok(maybe_tree.map(|tree| tree.into())).wrap_with_cost(cost);
             }
-            Put(value) => value,
-            PutReference(value, _) => value,
+            Put(value) | PutWithFeatureType(value, _) => value,
+            PutReference(value, ..) | PutReferenceWithFeatureType(value, ..) => value,
         };
 
         // TODO: take from batch so we don't have to clone
 
         let mid_tree = match mid_op {
-            Put(_) => {
+            Put(_) | PutWithFeatureType(..) => {
                 Tree::new(mid_key.as_ref().to_vec(), mid_value.to_vec()).unwrap_add_cost(&mut cost)
             }
-            PutReference(_, referenced_value) => Tree::new_with_value_hash(
+            PutReference(_, referenced_value)
+            | PutReferenceWithFeatureType(_, referenced_value, _) => Tree::new_with_value_hash(
                 mid_key.as_ref().to_vec(),
                 mid_value.to_vec(),
                 value_hash(referenced_value).unwrap_add_cost(&mut cost),
             )
             .unwrap_add_cost(&mut cost),
             Delete => unreachable!("cannot get here, should return at the top"),
-        };
+        }
+        .with_feature_type(mid_op.feature_type().expect("deletion returned above"));
         let mid_walker = Walker::new(mid_tree, PanicSource {});
 
         // use walker, ignore deleted_keys since it should be empty
@@ -153,15 +177,21 @@ where
         let search = batch.binary_search_by(|(key, _op)| key.as_ref().cmp(self.tree().key()));
         let tree = if let Ok(index) = search {
             // a key matches this node's key, apply op to this node
-            match &batch[index].1 {
+            let op = &batch[index].1;
+            match op {
                 // TODO: take vec from batch so we don't need to clone
-                Put(value) => self.with_value(value.to_vec()).unwrap_add_cost(&mut cost),
-                PutReference(value, referenced_value) => self
+                Put(value) | PutWithFeatureType(value, _) => self
+                    .with_value(value.to_vec())
+                    .unwrap_add_cost(&mut cost)
+                    .with_feature_type(op.feature_type().expect("op is a put")),
+                PutReference(value, referenced_value)
+                | PutReferenceWithFeatureType(value, referenced_value, _) => self
                     .with_value_and_value_hash(
                         value.to_vec(),
                         value_hash(referenced_value).unwrap_add_cost(&mut cost),
                     )
-                    .unwrap_add_cost(&mut cost),
+                    .unwrap_add_cost(&mut cost)
+                    .with_feature_type(op.feature_type().expect("op is a put")),
                 Delete => {
                     // TODO: we shouldn't have to do this as 2 different calls to apply
                     let source = self.clone_source();
@@ -422,6 +452,7 @@ mod test {
             None,
             Some(Link::Loaded {
                 hash: [123; 32],
+                aggregate_data: None,
                 child_heights: (0, 0),
                 tree: Tree::new(b"foo2".to_vec(), b"bar2".to_vec()).unwrap(),
             }),
@@ -0,0 +1,112 @@
+//! Merk tree feature types.
+
+/// Type of a Merk tree node. Nodes of sum and count trees carry a value they
+/// contribute to the aggregate of the tree, the aggregate of a subtree is
+/// committed into the hash of its root node.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum TreeFeatureType {
+    /// Node of a regular Merk tree
+    BasicMerk,
+    /// Node of a sum tree with its own contribution to the sum
+    SummedMerk(i64),
+    /// Node of a count tree with its own contribution to the count
+    CountedMerk(u64),
+}
+
+impl TreeFeatureType {
+    /// Returns the value the node contributes to the sum of the tree, `None`
+    /// for nodes of other trees.
+    #[inline]
+    pub const fn sum(&self) -> Option<i64> {
+        match self {
+            TreeFeatureType::SummedMerk(sum) => Some(*sum),
+            _ => None,
+        }
+    }
+
+    /// Returns the value the node contributes to the count of the tree,
+    /// `None` for nodes of other trees.
+    #[inline]
+    pub const fn count(&self) -> Option<u64> {
+        match self {
+            TreeFeatureType::CountedMerk(count) => Some(*count),
+            _ => None,
+        }
+    }
+
+    /// Returns the node's own contribution to the aggregate of the tree,
+    /// `None` for basic nodes.
+    #[inline]
+    pub const fn aggregate_data(&self) -> Option<AggregateData> {
+        match self {
+            TreeFeatureType::BasicMerk => None,
+            TreeFeatureType::SummedMerk(sum) => Some(AggregateData::Sum(*sum)),
+            TreeFeatureType::CountedMerk(count) => Some(AggregateData::Count(*count)),
+        }
+    }
+}
+
+/// Aggregate of a subtree of a sum or a count tree.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum AggregateData {
+    /// Sum of a sum tree
+    Sum(i64),
+    /// Count of a count tree
+    Count(u64),
+}
+
+impl AggregateData {
+    /// Returns the sum if it's an aggregate of a sum tree.
+    #[inline]
+    pub const fn sum(&self) -> Option<i64> {
+        match self {
+            AggregateData::Sum(sum) => Some(*sum),
+            AggregateData::Count(_) => None,
+        }
+    }
+
+    /// Returns the count if it's an aggregate of a count tree.
+    #[inline]
+    pub const fn count(&self) -> Option<u64> {
+        match self {
+            AggregateData::Count(count) => Some(*count),
+            AggregateData::Sum(_) => None,
+        }
+    }
+
+    /// Adds an aggregate of a child, wrapping around on overflow to keep
+    /// hashing infallible. A missing child or a child of another kind adds
+    /// nothing.
+    #[inline]
+    pub fn add_child(self, child: Option<AggregateData>) -> Self {
+        match (self, child) {
+            (AggregateData::Sum(sum), Some(AggregateData::Sum(child_sum))) => {
+                AggregateData::Sum(sum.wrapping_add(child_sum))
+            }
+            (AggregateData::Count(count), Some(AggregateData::Count(child_count))) => {
+                AggregateData::Count(count.wrapping_add(child_count))
+            }
+            _ => self,
+        }
+    }
+
+    /// Returns the aggregate's value as big-endian bytes, the way it is
+    /// encoded and hashed.
+    #[inline]
+    pub const fn to_be_bytes(&self) -> [u8; 8] {
+        match self {
+            AggregateData::Sum(sum) => sum.to_be_bytes(),
+            AggregateData::Count(count) => count.to_be_bytes(),
+        }
+    }
+
+    /// Returns an aggregate of the same kind with a value read from
+    /// big-endian bytes.
+    #[inline]
+    pub const fn with_be_bytes(&self, bytes: [u8; 8]) -> Self {
+        match self {
+            AggregateData::Sum(_) => AggregateData::Sum(i64::from_be_bytes(bytes)),
+            AggregateData::Count(_) => AggregateData::Count(u64::from_be_bytes(bytes)),
+        }
+    }
+}
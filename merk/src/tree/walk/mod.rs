@@ -6,7 +6,7 @@ use costs::{cost_return_on_error, CostContext, CostsExt, OperationCost};
 pub use fetch::Fetch;
 pub use ref_walker::RefWalker;
 
-use super::{Link, Tree};
+use super::{Link, Tree, TreeFeatureType};
 use crate::{owner::Owner, Hash};
 
 /// Allows traversal of a `Tree`, fetching from the given source when traversing
@@ -152,6 +152,12 @@ where
         self.wrap_with_cost(cost)
     }
 
+    /// Similar to `Tree#with_feature_type`.
+    pub fn with_feature_type(mut self, feature_type: TreeFeatureType) -> Self {
+        self.tree.own(|t| t.with_feature_type(feature_type));
+        self
+    }
+
     /// Similar to `Tree#with_value_and_value_hash`.
     pub fn with_value_and_value_hash(
         mut self,
@@ -247,6 +253,7 @@ mod test {
             Default::default(),
             Some(Link::Reference {
                 hash: Default::default(),
+                aggregate_data: None,
                 key: b"foo".to_vec(),
                 child_heights: (0, 0),
             }),
@@ -2,10 +2,12 @@
 
 //! Storage abstraction for GroveDB.
 
+pub mod memory_storage;
 #[cfg(feature = "rocksdb_storage")]
 pub mod rocksdb_storage;
 mod storage;
 
 pub use crate::storage::{
-    Batch, BatchOperation, RawIterator, Storage, StorageBatch, StorageContext,
+    build_prefix_body, build_prefix_hash_count, Batch, BatchOperation, RawIterator, Storage,
+    StorageBatch, StorageBatchRawIterator, StorageContext,
 };
@@ -0,0 +1,10 @@
+//! GroveDB storage layer implemented in memory, for tests and short-lived
+//! groves which don't need to be persisted.
+mod storage;
+mod storage_context;
+#[cfg(test)]
+mod tests;
+
+pub use storage_context::{MemoryBatch, MemoryRawIterator, MemoryStorageContext};
+
+pub use self::storage::{MemoryStorage, MemoryTransaction};
@@ -0,0 +1,283 @@
+//! Implementation for a storage abstraction which keeps data in memory.
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    sync::{Mutex, RwLock},
+};
+
+use super::MemoryStorageContext;
+use crate::{BatchOperation, Storage, StorageBatch};
+
+/// Records of one namespace sorted by key.
+pub(crate) type Records<V> = BTreeMap<Vec<u8>, V>;
+
+/// Namespaces of records, the same as column families of RocksDB storage.
+#[derive(Default)]
+pub(crate) struct Namespaces<V> {
+    data: Records<V>,
+    aux: Records<V>,
+    roots: Records<V>,
+    meta: Records<V>,
+}
+
+/// Namespace selector.
+#[derive(Clone, Copy)]
+pub(crate) enum Namespace {
+    Data,
+    Aux,
+    Roots,
+    Meta,
+}
+
+impl<V> Namespaces<V> {
+    fn get(&self, namespace: Namespace) -> &Records<V> {
+        match namespace {
+            Namespace::Data => &self.data,
+            Namespace::Aux => &self.aux,
+            Namespace::Roots => &self.roots,
+            Namespace::Meta => &self.meta,
+        }
+    }
+
+    fn get_mut(&mut self, namespace: Namespace) -> &mut Records<V> {
+        match namespace {
+            Namespace::Data => &mut self.data,
+            Namespace::Aux => &mut self.aux,
+            Namespace::Roots => &mut self.roots,
+            Namespace::Meta => &mut self.meta,
+        }
+    }
+}
+
+impl BatchOperation {
+    /// Split operation into namespace, key and value (`None` for deletion).
+    fn into_parts(self) -> (Namespace, Vec<u8>, Option<Vec<u8>>) {
+        match self {
+            BatchOperation::Put { key, value } => (Namespace::Data, key, Some(value)),
+            BatchOperation::PutAux { key, value } => (Namespace::Aux, key, Some(value)),
+            BatchOperation::PutRoot { key, value } => (Namespace::Roots, key, Some(value)),
+            BatchOperation::PutMeta { key, value } => (Namespace::Meta, key, Some(value)),
+            BatchOperation::Delete { key } => (Namespace::Data, key, None),
+            BatchOperation::DeleteAux { key } => (Namespace::Aux, key, None),
+            BatchOperation::DeleteRoot { key } => (Namespace::Roots, key, None),
+            BatchOperation::DeleteMeta { key } => (Namespace::Meta, key, None),
+        }
+    }
+}
+
+/// Storage which keeps all records in memory.
+#[derive(Default)]
+pub struct MemoryStorage {
+    records: RwLock<Namespaces<Vec<u8>>>,
+}
+
+/// Transaction of in-memory storage. Changes are kept aside and are visible
+/// only through the transaction until it is committed.
+#[derive(Default)]
+pub struct MemoryTransaction {
+    /// `None` stands for a deleted record
+    changes: Mutex<Namespaces<Option<Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    /// Create an empty in-memory storage.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A helper method to build a prefix of subtree keys by path. No hashing
+    /// is needed to keep prefixes apart as segments count and lengths come
+    /// first.
+    pub fn build_prefix<'a, P>(path: P) -> Vec<u8>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+    {
+        let segments: Vec<&[u8]> = path.into_iter().collect();
+        let mut res = Vec::new();
+        res.extend((segments.len() as u32).to_be_bytes());
+        for s in segments {
+            res.extend((s.len() as u32).to_be_bytes());
+            res.extend_from_slice(s);
+        }
+        res
+    }
+
+    pub(crate) fn get(
+        &self,
+        namespace: Namespace,
+        key: &[u8],
+        transaction: Option<&MemoryTransaction>,
+    ) -> Option<Vec<u8>> {
+        if let Some(tx) = transaction {
+            if let Some(change) = tx.changes.lock().unwrap().get(namespace).get(key) {
+                return change.clone();
+            }
+        }
+        self.records
+            .read()
+            .unwrap()
+            .get(namespace)
+            .get(key)
+            .cloned()
+    }
+
+    pub(crate) fn apply<I>(&self, operations: I, transaction: Option<&MemoryTransaction>)
+    where
+        I: IntoIterator<Item = BatchOperation>,
+    {
+        let operations = operations.into_iter().map(BatchOperation::into_parts);
+        if let Some(tx) = transaction {
+            let mut changes = tx.changes.lock().unwrap();
+            for (namespace, key, value) in operations {
+                changes.get_mut(namespace).insert(key, value);
+            }
+        } else {
+            let mut records = self.records.write().unwrap();
+            for (namespace, key, value) in operations {
+                apply_change(records.get_mut(namespace), key, value);
+            }
+        }
+    }
+
+    /// Returns data records (including ones changed in the transaction and
+    /// pending in the batch) with the prefix stripped
+    pub(crate) fn data_with_prefix(
+        &self,
+        prefix: &[u8],
+        transaction: Option<&MemoryTransaction>,
+        batch: Option<&StorageBatch>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records: Records<Vec<u8>> = self
+            .records
+            .read()
+            .unwrap()
+            .data
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k[prefix.len()..].to_vec(), v.clone()))
+            .collect();
+
+        if let Some(tx) = transaction {
+            let changes = tx.changes.lock().unwrap();
+            for (key, value) in changes
+                .data
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+            {
+                apply_change(&mut records, key[prefix.len()..].to_vec(), value.clone());
+            }
+        }
+
+        if let Some(batch) = batch {
+            for (key, value) in batch.data_with_prefix(prefix) {
+                apply_change(&mut records, key, value);
+            }
+        }
+
+        records.into_iter().collect()
+    }
+}
+
+fn apply_change(records: &mut Records<Vec<u8>>, key: Vec<u8>, value: Option<Vec<u8>>) {
+    match value {
+        Some(value) => {
+            records.insert(key, value);
+        }
+        None => {
+            records.remove(&key);
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    type BatchStorageContext<'db> = MemoryStorageContext<'db>;
+    type BatchTransactionalStorageContext<'db> = MemoryStorageContext<'db>;
+    type Error = Infallible;
+    type StorageContext<'db> = MemoryStorageContext<'db>;
+    type Transaction<'db> = MemoryTransaction;
+    type TransactionalStorageContext<'db> = MemoryStorageContext<'db>;
+
+    fn start_transaction(&self) -> Self::Transaction<'_> {
+        MemoryTransaction::default()
+    }
+
+    fn commit_transaction(&self, transaction: Self::Transaction<'_>) -> Result<(), Self::Error> {
+        let changes = transaction.changes.into_inner().unwrap();
+        let mut records = self.records.write().unwrap();
+        for namespace in [
+            Namespace::Data,
+            Namespace::Aux,
+            Namespace::Roots,
+            Namespace::Meta,
+        ] {
+            for (key, value) in changes.get(namespace) {
+                apply_change(records.get_mut(namespace), key.clone(), value.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback_transaction(&self, transaction: &Self::Transaction<'_>) -> Result<(), Self::Error> {
+        *transaction.changes.lock().unwrap() = Default::default();
+        Ok(())
+    }
+
+    fn commit_multi_context_batch(
+        &self,
+        batch: StorageBatch,
+        transaction: Option<&Self::Transaction<'_>>,
+    ) -> Result<(), Self::Error> {
+        self.apply(batch, transaction);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn get_storage_context<'db, 'p, P>(&'db self, path: P) -> Self::StorageContext<'db>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+    {
+        MemoryStorageContext::new(self, Self::build_prefix(path), None, None)
+    }
+
+    fn get_transactional_storage_context<'db, 'tx, 'p, P>(
+        &'db self,
+        path: P,
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::TransactionalStorageContext<'db>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+    {
+        MemoryStorageContext::new(self, Self::build_prefix(path), Some(transaction), None)
+    }
+
+    fn get_batch_storage_context<'db, 'p, P>(
+        &'db self,
+        path: P,
+        batch: &'db StorageBatch,
+    ) -> Self::BatchStorageContext<'db>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+    {
+        MemoryStorageContext::new(self, Self::build_prefix(path), None, Some(batch))
+    }
+
+    fn get_batch_transactional_storage_context<'db, 'tx, 'p, P>(
+        &'db self,
+        path: P,
+        batch: &'db StorageBatch,
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::BatchTransactionalStorageContext<'db>
+    where
+        P: IntoIterator<Item = &'p [u8]>,
+    {
+        MemoryStorageContext::new(
+            self,
+            Self::build_prefix(path),
+            Some(transaction),
+            Some(batch),
+        )
+    }
+}
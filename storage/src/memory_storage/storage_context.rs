@@ -0,0 +1,271 @@
+//! Implementation of prefixed in-memory storage context.
+use std::convert::Infallible;
+
+use super::{
+    storage::{MemoryStorage, Namespace},
+    MemoryTransaction,
+};
+use crate::{Batch, BatchOperation, RawIterator, StorageBatch, StorageContext};
+
+fn make_prefixed_key<K: AsRef<[u8]>>(mut prefix: Vec<u8>, key: K) -> Vec<u8> {
+    prefix.extend_from_slice(key.as_ref());
+    prefix
+}
+
+/// Storage context with a prefix applied to be used in a subtree. The same
+/// context type serves transactional and batched access, writes go to the
+/// multi-context batch if there is one, then to the transaction if there is
+/// one, and to the storage otherwise. Reads, raw iterators included, look in
+/// the same places in reverse order.
+pub struct MemoryStorageContext<'db> {
+    storage: &'db MemoryStorage,
+    prefix: Vec<u8>,
+    transaction: Option<&'db MemoryTransaction>,
+    batch: Option<&'db StorageBatch>,
+}
+
+impl<'db> MemoryStorageContext<'db> {
+    /// Create a new prefixed storage context instance
+    pub fn new(
+        storage: &'db MemoryStorage,
+        prefix: Vec<u8>,
+        transaction: Option<&'db MemoryTransaction>,
+        batch: Option<&'db StorageBatch>,
+    ) -> Self {
+        MemoryStorageContext {
+            storage,
+            prefix,
+            transaction,
+            batch,
+        }
+    }
+
+    fn write(&self, operation: BatchOperation) {
+        if let Some(batch) = self.batch {
+            batch.add(operation);
+        } else {
+            self.storage.apply([operation], self.transaction);
+        }
+    }
+
+    fn read<K: AsRef<[u8]>>(&self, namespace: Namespace, key: K) -> Option<Vec<u8>> {
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        let pending = self.batch.and_then(|batch| match namespace {
+            Namespace::Data => batch.get(&key),
+            Namespace::Aux => batch.get_aux(&key),
+            Namespace::Roots => batch.get_root(&key),
+            Namespace::Meta => batch.get_meta(&key),
+        });
+        pending.unwrap_or_else(|| self.storage.get(namespace, &key, self.transaction))
+    }
+}
+
+impl<'db> StorageContext<'db> for MemoryStorageContext<'db> {
+    type Batch = MemoryBatch;
+    type Error = Infallible;
+    type RawIterator = MemoryRawIterator;
+
+    fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(BatchOperation::Put {
+            key: make_prefixed_key(self.prefix.clone(), key),
+            value: value.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(BatchOperation::PutAux {
+            key: make_prefixed_key(self.prefix.clone(), key),
+            value: value.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(BatchOperation::PutRoot {
+            key: make_prefixed_key(self.prefix.clone(), key),
+            value: value.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(BatchOperation::PutMeta {
+            key: make_prefixed_key(self.prefix.clone(), key),
+            value: value.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.write(BatchOperation::Delete {
+            key: make_prefixed_key(self.prefix.clone(), key),
+        });
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.write(BatchOperation::DeleteAux {
+            key: make_prefixed_key(self.prefix.clone(), key),
+        });
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.write(BatchOperation::DeleteRoot {
+            key: make_prefixed_key(self.prefix.clone(), key),
+        });
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.write(BatchOperation::DeleteMeta {
+            key: make_prefixed_key(self.prefix.clone(), key),
+        });
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Data, key))
+    }
+
+    fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Aux, key))
+    }
+
+    fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Roots, key))
+    }
+
+    fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Meta, key))
+    }
+
+    fn new_batch(&self) -> Self::Batch {
+        MemoryBatch {
+            prefix: self.prefix.clone(),
+            batch: StorageBatch::new(),
+        }
+    }
+
+    fn commit_batch(&self, batch: Self::Batch) -> Result<(), Self::Error> {
+        if let Some(multi_context_batch) = self.batch {
+            multi_context_batch.merge(batch.batch);
+        } else {
+            self.storage.apply(batch.batch, self.transaction);
+        }
+        Ok(())
+    }
+
+    fn raw_iter(&self) -> Self::RawIterator {
+        MemoryRawIterator {
+            records: self
+                .storage
+                .data_with_prefix(&self.prefix, self.transaction, self.batch),
+            position: None,
+        }
+    }
+}
+
+/// Batch of in-memory storage context, operations are applied at once on
+/// commit.
+pub struct MemoryBatch {
+    prefix: Vec<u8>,
+    batch: StorageBatch,
+}
+
+impl Batch for MemoryBatch {
+    type Error = Infallible;
+
+    fn put<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.batch
+            .put(make_prefixed_key(self.prefix.clone(), key), value.to_vec());
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.batch
+            .put_aux(make_prefixed_key(self.prefix.clone(), key), value.to_vec());
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.batch
+            .put_root(make_prefixed_key(self.prefix.clone(), key), value.to_vec());
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.batch
+            .delete(make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.batch
+            .delete_aux(make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.batch
+            .delete_root(make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+}
+
+/// Raw iterator over a snapshot of subtree records taken on its creation.
+pub struct MemoryRawIterator {
+    records: Vec<(Vec<u8>, Vec<u8>)>,
+    position: Option<usize>,
+}
+
+impl MemoryRawIterator {
+    fn set_position(&mut self, position: Option<usize>) {
+        self.position = position.filter(|p| *p < self.records.len());
+    }
+}
+
+impl RawIterator for MemoryRawIterator {
+    fn seek_to_first(&mut self) {
+        self.set_position(Some(0));
+    }
+
+    fn seek_to_last(&mut self) {
+        self.set_position(self.records.len().checked_sub(1));
+    }
+
+    fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        let position = self
+            .records
+            .partition_point(|(k, _)| k.as_slice() < key.as_ref());
+        self.set_position(Some(position));
+    }
+
+    fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        let position = self
+            .records
+            .partition_point(|(k, _)| k.as_slice() <= key.as_ref());
+        self.set_position(position.checked_sub(1));
+    }
+
+    fn next(&mut self) {
+        self.set_position(self.position.map(|p| p + 1));
+    }
+
+    fn prev(&mut self) {
+        self.set_position(self.position.and_then(|p| p.checked_sub(1)));
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.position.map(|p| self.records[p].1.as_slice())
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.position.map(|p| self.records[p].0.as_slice())
+    }
+
+    fn valid(&self) -> bool {
+        self.position.is_some()
+    }
+}
@@ -0,0 +1,326 @@
+use super::MemoryStorage;
+use crate::{Batch, RawIterator, Storage, StorageBatch, StorageContext};
+
+fn to_path(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::once(bytes)
+}
+
+#[test]
+fn test_namespaces_are_separated() {
+    let storage = MemoryStorage::new();
+    let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+    let context_ayyb = storage.get_storage_context(to_path(b"ayyb"));
+
+    context_ayya
+        .put(b"key1", b"data")
+        .expect("cannot insert into storage");
+    context_ayya
+        .put_aux(b"key1", b"aux")
+        .expect("cannot insert into aux storage");
+    context_ayya
+        .put_root(b"key1", b"root")
+        .expect("cannot insert into roots storage");
+    context_ayya
+        .put_meta(b"key1", b"meta")
+        .expect("cannot insert into meta storage");
+
+    assert_eq!(context_ayya.get(b"key1").unwrap(), Some(b"data".to_vec()));
+    assert_eq!(
+        context_ayya.get_aux(b"key1").unwrap(),
+        Some(b"aux".to_vec())
+    );
+    assert_eq!(
+        context_ayya.get_root(b"key1").unwrap(),
+        Some(b"root".to_vec())
+    );
+    assert_eq!(
+        context_ayya.get_meta(b"key1").unwrap(),
+        Some(b"meta".to_vec())
+    );
+    assert!(context_ayyb.get(b"key1").unwrap().is_none());
+    assert!(context_ayyb.get_aux(b"key1").unwrap().is_none());
+
+    context_ayya
+        .delete_aux(b"key1")
+        .expect("cannot delete from aux storage");
+    assert!(context_ayya.get_aux(b"key1").unwrap().is_none());
+    assert_eq!(context_ayya.get(b"key1").unwrap(), Some(b"data".to_vec()));
+}
+
+#[test]
+fn test_batch() {
+    let storage = MemoryStorage::new();
+    let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+
+    context_ayya
+        .put(b"key1", b"ayyavalue1")
+        .expect("cannot insert into storage");
+
+    let mut batch = context_ayya.new_batch();
+    batch.delete(b"key1").expect("infallible");
+    batch.put(b"key2", b"ayyavalue2").expect("infallible");
+
+    assert!(context_ayya.get(b"key1").unwrap().is_some());
+    assert!(context_ayya.get(b"key2").unwrap().is_none());
+
+    context_ayya
+        .commit_batch(batch)
+        .expect("cannot commit a batch");
+
+    assert!(context_ayya.get(b"key1").unwrap().is_none());
+    assert_eq!(
+        context_ayya.get(b"key2").unwrap(),
+        Some(b"ayyavalue2".to_vec())
+    );
+}
+
+#[test]
+fn test_multi_context_batch_delete_after_put() {
+    let storage = MemoryStorage::new();
+    let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+    context_ayya
+        .put(b"key1", b"ayyavalue1")
+        .expect("cannot insert into storage");
+
+    let batch = StorageBatch::new();
+    let context_ayya_batch = storage.get_batch_storage_context(to_path(b"ayya"), &batch);
+    context_ayya_batch.put(b"key1", b"ayyavalue2").unwrap();
+    context_ayya_batch.delete(b"key1").unwrap();
+    context_ayya_batch.put(b"key2", b"ayyavalue2").unwrap();
+    context_ayya_batch.delete(b"key2").unwrap();
+    storage
+        .commit_multi_context_batch(batch, None)
+        .expect("cannot commit batch");
+
+    assert!(context_ayya.get(b"key1").unwrap().is_none());
+    assert!(context_ayya.get(b"key2").unwrap().is_none());
+}
+
+#[test]
+fn test_raw_iterator() {
+    let storage = MemoryStorage::new();
+    let context = storage.get_storage_context(to_path(b"someprefix"));
+
+    context.put(b"key1", b"value1").unwrap();
+    context.put(b"key0", b"value0").unwrap();
+    context.put(b"key3", b"value3").unwrap();
+    context.put(b"key2", b"value2").unwrap();
+
+    // Records of other subtrees with prefixes around this one must not be seen
+    let context_before = storage.get_storage_context(to_path(b"someprefi"));
+    context_before.put(b"xkey5", b"value5").unwrap();
+    let context_after = storage.get_storage_context(to_path(b"someprefixx"));
+    context_after.put(b"key1", b"value1").unwrap();
+    let context_child = storage.get_storage_context([b"someprefix".as_ref(), b"child".as_ref()]);
+    context_child.put(b"key1", b"value1").unwrap();
+
+    let expected: [(&'static [u8], &'static [u8]); 4] = [
+        (b"key0", b"value0"),
+        (b"key1", b"value1"),
+        (b"key2", b"value2"),
+        (b"key3", b"value3"),
+    ];
+
+    let mut iter = context.raw_iter();
+    iter.seek_to_first();
+    let mut expected_iter = expected.into_iter();
+    while iter.valid() {
+        assert_eq!(
+            (iter.key().unwrap(), iter.value().unwrap()),
+            expected_iter.next().unwrap()
+        );
+        iter.next();
+    }
+    assert!(expected_iter.next().is_none());
+
+    iter.seek_to_last();
+    assert_eq!(iter.key(), Some(b"key3".as_ref()));
+    iter.next();
+    assert!(!iter.valid());
+
+    iter.seek(b"key15");
+    assert_eq!(iter.key(), Some(b"key2".as_ref()));
+    iter.seek_for_prev(b"key15");
+    assert_eq!(iter.key(), Some(b"key1".as_ref()));
+    iter.prev();
+    iter.prev();
+    assert!(!iter.valid());
+
+    let empty_context = storage.get_storage_context(to_path(b"notexist"));
+    let mut iter = empty_context.raw_iter();
+    iter.seek_to_last();
+    assert!(!iter.valid());
+    iter.next();
+    assert!(!iter.valid());
+}
+
+#[test]
+fn test_batch_context_reads_pending_writes() {
+    let storage = MemoryStorage::new();
+    let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+    context_ayya.put(b"key1", b"ayyavalue1").unwrap();
+    context_ayya.put_aux(b"key1", b"ayyavalue1").unwrap();
+
+    let batch = StorageBatch::new();
+    let context_ayya_batch = storage.get_batch_storage_context(to_path(b"ayya"), &batch);
+    context_ayya_batch.delete(b"key1").unwrap();
+    context_ayya_batch.put(b"key2", b"ayyavalue2").unwrap();
+    context_ayya_batch.put_aux(b"key1", b"ayyavalue1b").unwrap();
+    context_ayya_batch.put_root(b"key1", b"ayyavalue1").unwrap();
+
+    assert!(context_ayya_batch.get(b"key1").unwrap().is_none());
+    assert_eq!(
+        context_ayya_batch.get(b"key2").unwrap(),
+        Some(b"ayyavalue2".to_vec())
+    );
+    assert_eq!(
+        context_ayya_batch.get_aux(b"key1").unwrap(),
+        Some(b"ayyavalue1b".to_vec())
+    );
+    assert_eq!(
+        context_ayya_batch.get_root(b"key1").unwrap(),
+        Some(b"ayyavalue1".to_vec())
+    );
+
+    // The storage itself is untouched until commit
+    assert_eq!(
+        context_ayya.get(b"key1").unwrap(),
+        Some(b"ayyavalue1".to_vec())
+    );
+    assert!(context_ayya.get(b"key2").unwrap().is_none());
+}
+
+#[test]
+fn test_raw_iterator_with_batch() {
+    let storage = MemoryStorage::new();
+    let transaction = storage.start_transaction();
+    let context = storage.get_storage_context(to_path(b"someprefix"));
+    context.put(b"key0", b"value0").unwrap();
+    context.put(b"key1", b"value1").unwrap();
+    context.put(b"key2", b"value2").unwrap();
+    let context_tx =
+        storage.get_transactional_storage_context(to_path(b"someprefix"), &transaction);
+    context_tx.put(b"key3", b"value3").unwrap();
+
+    let batch = StorageBatch::new();
+    let context_batch = storage.get_batch_transactional_storage_context(
+        to_path(b"someprefix"),
+        &batch,
+        &transaction,
+    );
+    context_batch.delete(b"key0").unwrap();
+    context_batch.put(b"key2", b"value2b").unwrap();
+    context_batch.put(b"key4", b"value4").unwrap();
+    context_batch.put_aux(b"key5", b"value5").unwrap();
+    let context_other_batch = storage.get_batch_transactional_storage_context(
+        to_path(b"someprefixx"),
+        &batch,
+        &transaction,
+    );
+    context_other_batch.put(b"key1", b"value1").unwrap();
+
+    // Pending data writes of the subtree override and mask stored records
+    let expected: [(&'static [u8], &'static [u8]); 4] = [
+        (b"key1", b"value1"),
+        (b"key2", b"value2b"),
+        (b"key3", b"value3"),
+        (b"key4", b"value4"),
+    ];
+    let mut iter = context_batch.raw_iter();
+    iter.seek_to_first();
+    let mut expected_iter = expected.into_iter();
+    while iter.valid() {
+        assert_eq!(
+            (iter.key().unwrap(), iter.value().unwrap()),
+            expected_iter.next().unwrap()
+        );
+        iter.next();
+    }
+    assert!(expected_iter.next().is_none());
+
+    // Iterators of contexts without the batch don't see pending writes
+    let mut iter = context_tx.raw_iter();
+    iter.seek_to_first();
+    assert_eq!(iter.key(), Some(b"key0".as_ref()));
+    iter.seek_to_last();
+    assert_eq!(iter.key(), Some(b"key3".as_ref()));
+}
+
+#[test]
+fn test_transaction_properties() {
+    let storage = MemoryStorage::new();
+    let transaction = storage.start_transaction();
+
+    let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+    let context_ayya_tx = storage.get_transactional_storage_context(to_path(b"ayya"), &transaction);
+
+    context_ayya.put(b"key0", b"ayyavalue0").unwrap();
+    context_ayya_tx.put(b"key1", b"ayyavalue1").unwrap();
+    context_ayya_tx.delete(b"key0").unwrap();
+
+    // Changes are visible in transaction only
+    assert_eq!(
+        context_ayya_tx.get(b"key1").unwrap(),
+        Some(b"ayyavalue1".to_vec())
+    );
+    assert!(context_ayya_tx.get(b"key0").unwrap().is_none());
+    assert!(context_ayya.get(b"key1").unwrap().is_none());
+    assert!(context_ayya.get(b"key0").unwrap().is_some());
+
+    let mut iter = context_ayya_tx.raw_iter();
+    iter.seek_to_first();
+    assert_eq!(iter.key(), Some(b"key1".as_ref()));
+    iter.next();
+    assert!(!iter.valid());
+
+    // Batch data is visible neither in transaction nor outside until committed
+    let batch = StorageBatch::new();
+    let context_ayya_batch =
+        storage.get_batch_transactional_storage_context(to_path(b"ayya"), &batch, &transaction);
+    context_ayya_batch.put_aux(b"key2", b"ayyavalue2").unwrap();
+    assert!(context_ayya_tx.get_aux(b"key2").unwrap().is_none());
+
+    storage
+        .commit_multi_context_batch(batch, Some(&transaction))
+        .expect("cannot commit batch");
+    assert_eq!(
+        context_ayya_tx.get_aux(b"key2").unwrap(),
+        Some(b"ayyavalue2".to_vec())
+    );
+    assert!(context_ayya.get_aux(b"key2").unwrap().is_none());
+
+    storage
+        .commit_transaction(transaction)
+        .expect("cannot commit transaction");
+    assert_eq!(
+        context_ayya.get(b"key1").unwrap(),
+        Some(b"ayyavalue1".to_vec())
+    );
+    assert!(context_ayya.get(b"key0").unwrap().is_none());
+    assert_eq!(
+        context_ayya.get_aux(b"key2").unwrap(),
+        Some(b"ayyavalue2".to_vec())
+    );
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let storage = MemoryStorage::new();
+    let transaction = storage.start_transaction();
+    let context_tx = storage.get_transactional_storage_context(to_path(b"ayya"), &transaction);
+
+    context_tx.put(b"key1", b"ayyavalue1").unwrap();
+    storage
+        .rollback_transaction(&transaction)
+        .expect("cannot rollback transaction");
+    assert!(context_tx.get(b"key1").unwrap().is_none());
+
+    context_tx.put(b"key2", b"ayyavalue2").unwrap();
+    storage
+        .commit_transaction(transaction)
+        .expect("cannot commit transaction");
+
+    let context = storage.get_storage_context(to_path(b"ayya"));
+    assert!(context.get(b"key1").unwrap().is_none());
+    assert!(context.get(b"key2").unwrap().is_some());
+}
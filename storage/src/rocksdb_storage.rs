@@ -1,4 +1,5 @@
 //! GroveDB storage layer implemented over RocksDB backend.
+mod config;
 mod storage;
 mod storage_context;
 pub mod test_utils;
@@ -12,4 +13,7 @@ pub use storage_context::{
     PrefixedRocksDbStorageContext, PrefixedRocksDbTransactionContext,
 };
 
-pub use self::storage::RocksDbStorage;
+pub use self::{
+    config::{Compression, RocksDbConfig},
+    storage::RocksDbStorage,
+};
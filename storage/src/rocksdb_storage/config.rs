@@ -0,0 +1,92 @@
+//! Configuration of RocksDB storage.
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, Error, Options};
+
+/// Compression algorithm of RocksDB data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// No compression
+    None,
+    /// Snappy compression
+    Snappy,
+    /// Zlib compression
+    Zlib,
+    /// LZ4 compression
+    Lz4,
+    /// LZ4 high compression
+    Lz4hc,
+    /// Zstandard compression
+    Zstd,
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Zlib => DBCompressionType::Zlib,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Lz4hc => DBCompressionType::Lz4hc,
+            Compression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// RocksDB tuning options, applied to the default column family as well as
+/// to all column families used by GroveDB. Fields set to `None` are left with
+/// RocksDB defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RocksDbConfig {
+    /// Size of LRU block cache in bytes
+    pub block_cache_size: Option<usize>,
+    /// Compression of data blocks
+    pub compression: Option<Compression>,
+    /// Size of a memtable in bytes before it is flushed to disk
+    pub write_buffer_size: Option<usize>,
+    /// Number of background threads for flushes and compactions
+    pub parallelism: Option<i32>,
+    /// Use mmap to read data files, some filesystems don't support it well
+    pub allow_mmap_reads: bool,
+    /// Use mmap to write data files, some filesystems don't support it well
+    pub allow_mmap_writes: bool,
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> Self {
+        RocksDbConfig {
+            block_cache_size: None,
+            compression: None,
+            write_buffer_size: None,
+            parallelism: Some(num_cpus::get() as i32),
+            allow_mmap_reads: true,
+            allow_mmap_writes: true,
+        }
+    }
+}
+
+impl RocksDbConfig {
+    /// Builds RocksDB options out of the configuration.
+    pub(crate) fn to_options(&self) -> Result<Options, Error> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.set_atomic_flush(true);
+        opts.set_allow_mmap_reads(self.allow_mmap_reads);
+        opts.set_allow_mmap_writes(self.allow_mmap_writes);
+        if let Some(parallelism) = self.parallelism {
+            opts.increase_parallelism(parallelism);
+        }
+        if let Some(compression) = self.compression {
+            opts.set_compression_type(compression.into());
+        }
+        if let Some(write_buffer_size) = self.write_buffer_size {
+            opts.set_write_buffer_size(write_buffer_size);
+        }
+        if let Some(block_cache_size) = self.block_cache_size {
+            let cache = Cache::new_lru_cache(block_cache_size)?;
+            let mut block_opts = BlockBasedOptions::default();
+            block_opts.set_block_cache(&cache);
+            opts.set_block_based_table_factory(&block_opts);
+        }
+        Ok(opts)
+    }
+}
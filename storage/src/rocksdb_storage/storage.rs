@@ -1,17 +1,17 @@
 //! Impementation for a storage abstraction over RocksDB.
 use std::path::Path;
 
-use lazy_static::lazy_static;
 use rocksdb::{
-    ColumnFamily, ColumnFamilyDescriptor, Error, OptimisticTransactionDB, Transaction,
-    WriteBatchWithTransaction,
+    checkpoint::Checkpoint, ColumnFamily, ColumnFamilyDescriptor, Error, OptimisticTransactionDB,
+    Transaction, WriteBatchWithTransaction,
 };
 
 use super::{
-    PrefixedRocksDbBatchStorageContext, PrefixedRocksDbBatchTransactionContext,
-    PrefixedRocksDbStorageContext, PrefixedRocksDbTransactionContext,
+    config::RocksDbConfig, PrefixedRocksDbBatchStorageContext,
+    PrefixedRocksDbBatchTransactionContext, PrefixedRocksDbStorageContext,
+    PrefixedRocksDbTransactionContext,
 };
-use crate::{BatchOperation, Storage, StorageBatch};
+use crate::{build_prefix_body, BatchOperation, Storage, StorageBatch};
 
 /// Name of column family used to store auxiliary data
 pub(crate) const AUX_CF_NAME: &str = "aux";
@@ -20,19 +20,6 @@ pub(crate) const ROOTS_CF_NAME: &str = "roots";
 /// Name of column family used to store metadata
 pub(crate) const META_CF_NAME: &str = "meta";
 
-lazy_static! {
-    static ref DEFAULT_OPTS: rocksdb::Options = {
-        let mut opts = rocksdb::Options::default();
-        opts.create_if_missing(true);
-        opts.increase_parallelism(num_cpus::get() as i32);
-        opts.set_allow_mmap_writes(true);
-        opts.set_allow_mmap_reads(true);
-        opts.create_missing_column_families(true);
-        opts.set_atomic_flush(true);
-        opts
-    };
-}
-
 /// Type alias for a database
 pub(crate) type Db = OptimisticTransactionDB;
 
@@ -42,53 +29,42 @@ pub(crate) type Tx<'db> = Transaction<'db, Db>;
 /// Storage which uses RocksDB as its backend.
 pub struct RocksDbStorage {
     db: OptimisticTransactionDB,
+    config: RocksDbConfig,
 }
 
 impl RocksDbStorage {
     /// Create RocksDb storage with default parameters using `path`.
     pub fn default_rocksdb_with_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with_config(path, RocksDbConfig::default())
+    }
+
+    /// Create RocksDb storage using `path` with options built from `config`,
+    /// the same options are used for all column families.
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: RocksDbConfig) -> Result<Self, Error> {
+        let opts = config.to_options()?;
         let db = Db::open_cf_descriptors(
-            &DEFAULT_OPTS,
+            &opts,
             &path,
             [
-                ColumnFamilyDescriptor::new(AUX_CF_NAME, DEFAULT_OPTS.clone()),
-                ColumnFamilyDescriptor::new(ROOTS_CF_NAME, DEFAULT_OPTS.clone()),
-                ColumnFamilyDescriptor::new(META_CF_NAME, DEFAULT_OPTS.clone()),
+                ColumnFamilyDescriptor::new(AUX_CF_NAME, opts.clone()),
+                ColumnFamilyDescriptor::new(ROOTS_CF_NAME, opts.clone()),
+                ColumnFamilyDescriptor::new(META_CF_NAME, opts.clone()),
             ],
         )?;
 
-        Ok(RocksDbStorage { db })
+        Ok(RocksDbStorage { db, config })
     }
 
-    fn build_prefix_body<'a, P>(path: P) -> Vec<u8>
-    where
-        P: IntoIterator<Item = &'a [u8]>,
-    {
-        let segments_iter = path.into_iter();
-        let mut segments_count: usize = 0;
-        let mut res = Vec::new();
-        let mut lengthes = Vec::new();
-
-        for s in segments_iter {
-            segments_count += 1;
-            res.extend_from_slice(s);
-            lengthes.extend(s.len().to_ne_bytes());
-        }
-
-        res.extend(segments_count.to_ne_bytes());
-        res.extend(lengthes);
-        res
+    /// Configuration the storage was opened with
+    pub fn config(&self) -> &RocksDbConfig {
+        &self.config
     }
 
-    /// A helper method to figure out how many blake3 hashes are needed to build
-    /// a prefix
-    pub fn build_prefix_hash_count<'a, P>(path: P) -> u16
-    where
-        P: IntoIterator<Item = &'a [u8]>,
-    {
-        let body = Self::build_prefix_body(path);
-        // the block size of blake3 is 64
-        (body.len() as u32 / 64 + 1) as u16
+    /// Create a consistent on-disk snapshot of the storage at `path`, which
+    /// must not exist yet. The checkpoint can be opened later as a regular
+    /// RocksDb storage, preferably with the same configuration.
+    pub fn create_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        Checkpoint::new(&self.db).and_then(|x| x.create_checkpoint(path))
     }
 
     /// A helper method to build a prefix to rocksdb keys or identify a subtree
@@ -97,7 +73,7 @@ impl RocksDbStorage {
     where
         P: IntoIterator<Item = &'a [u8]>,
     {
-        let body = Self::build_prefix_body(path);
+        let body = build_prefix_body(path);
         blake3::hash(&body).as_bytes().to_vec()
     }
 
@@ -130,23 +106,23 @@ impl RocksDbStorage {
     // }
 }
 
-impl<'db> Storage<'db> for RocksDbStorage {
-    type BatchStorageContext = PrefixedRocksDbBatchStorageContext<'db>;
-    type BatchTransactionalStorageContext = PrefixedRocksDbBatchTransactionContext<'db>;
+impl Storage for RocksDbStorage {
+    type BatchStorageContext<'db> = PrefixedRocksDbBatchStorageContext<'db>;
+    type BatchTransactionalStorageContext<'db> = PrefixedRocksDbBatchTransactionContext<'db>;
     type Error = Error;
-    type StorageContext = PrefixedRocksDbStorageContext<'db>;
-    type Transaction = Tx<'db>;
-    type TransactionalStorageContext = PrefixedRocksDbTransactionContext<'db>;
+    type StorageContext<'db> = PrefixedRocksDbStorageContext<'db>;
+    type Transaction<'db> = Tx<'db>;
+    type TransactionalStorageContext<'db> = PrefixedRocksDbTransactionContext<'db>;
 
-    fn start_transaction(&'db self) -> Self::Transaction {
+    fn start_transaction(&self) -> Self::Transaction<'_> {
         self.db.transaction()
     }
 
-    fn commit_transaction(&self, transaction: Self::Transaction) -> Result<(), Self::Error> {
+    fn commit_transaction(&self, transaction: Self::Transaction<'_>) -> Result<(), Self::Error> {
         transaction.commit()
     }
 
-    fn rollback_transaction(&self, transaction: &Self::Transaction) -> Result<(), Self::Error> {
+    fn rollback_transaction(&self, transaction: &Self::Transaction<'_>) -> Result<(), Self::Error> {
         transaction.rollback()
     }
 
@@ -154,7 +130,7 @@ impl<'db> Storage<'db> for RocksDbStorage {
         self.db.flush()
     }
 
-    fn get_storage_context<'p, P>(&'db self, path: P) -> Self::StorageContext
+    fn get_storage_context<'db, 'p, P>(&'db self, path: P) -> Self::StorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>,
     {
@@ -162,11 +138,11 @@ impl<'db> Storage<'db> for RocksDbStorage {
         PrefixedRocksDbStorageContext::new(&self.db, prefix)
     }
 
-    fn get_transactional_storage_context<'p, P>(
+    fn get_transactional_storage_context<'db, 'tx, 'p, P>(
         &'db self,
         path: P,
-        transaction: &'db Self::Transaction,
-    ) -> Self::TransactionalStorageContext
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::TransactionalStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>,
     {
@@ -174,11 +150,11 @@ impl<'db> Storage<'db> for RocksDbStorage {
         PrefixedRocksDbTransactionContext::new(&self.db, transaction, prefix)
     }
 
-    fn get_batch_storage_context<'p, P>(
+    fn get_batch_storage_context<'db, 'p, P>(
         &'db self,
         path: P,
         batch: &'db StorageBatch,
-    ) -> Self::BatchStorageContext
+    ) -> Self::BatchStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>,
     {
@@ -186,12 +162,12 @@ impl<'db> Storage<'db> for RocksDbStorage {
         PrefixedRocksDbBatchStorageContext::new(&self.db, prefix, batch)
     }
 
-    fn get_batch_transactional_storage_context<'p, P>(
+    fn get_batch_transactional_storage_context<'db, 'tx, 'p, P>(
         &'db self,
         path: P,
         batch: &'db StorageBatch,
-        transaction: &'db Self::Transaction,
-    ) -> Self::BatchTransactionalStorageContext
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::BatchTransactionalStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>,
     {
@@ -202,7 +178,7 @@ impl<'db> Storage<'db> for RocksDbStorage {
     fn commit_multi_context_batch(
         &self,
         batch: StorageBatch,
-        transaction: Option<&'db Self::Transaction>,
+        transaction: Option<&Self::Transaction<'_>>,
     ) -> Result<(), Self::Error> {
         let mut db_batch = WriteBatchWithTransaction::<true>::default();
         for op in batch.into_iter() {
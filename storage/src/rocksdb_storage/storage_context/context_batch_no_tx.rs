@@ -3,11 +3,12 @@ use rocksdb::{ColumnFamily, DBRawIteratorWithThreadMode, Error};
 use super::{batch::PrefixedMultiContextBatchPart, make_prefixed_key, PrefixedRocksDbRawIterator};
 use crate::{
     rocksdb_storage::storage::{Db, AUX_CF_NAME, META_CF_NAME, ROOTS_CF_NAME},
-    StorageBatch, StorageContext,
+    StorageBatch, StorageBatchRawIterator, StorageContext,
 };
 
 /// Storage context with a prefix applied to be used in a subtree to be used
-/// outside of transaction.
+/// outside of transaction. Writes pending in the batch are read first, raw
+/// iterators included.
 pub struct PrefixedRocksDbBatchStorageContext<'db> {
     storage: &'db Db,
     prefix: Vec<u8>,
@@ -51,7 +52,8 @@ impl<'db> PrefixedRocksDbBatchStorageContext<'db> {
 impl<'db> StorageContext<'db> for PrefixedRocksDbBatchStorageContext<'db> {
     type Batch = PrefixedMultiContextBatchPart;
     type Error = Error;
-    type RawIterator = PrefixedRocksDbRawIterator<DBRawIteratorWithThreadMode<'db, Db>>;
+    type RawIterator =
+        StorageBatchRawIterator<PrefixedRocksDbRawIterator<DBRawIteratorWithThreadMode<'db, Db>>>;
 
     fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
         self.batch
@@ -102,23 +104,35 @@ impl<'db> StorageContext<'db> for PrefixedRocksDbBatchStorageContext<'db> {
     }
 
     fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.storage
-            .get(make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get(&key) {
+            Some(value) => Ok(value),
+            None => self.storage.get(key),
+        }
     }
 
     fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.storage
-            .get_cf(self.cf_aux(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_aux(&key) {
+            Some(value) => Ok(value),
+            None => self.storage.get_cf(self.cf_aux(), key),
+        }
     }
 
     fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.storage
-            .get_cf(self.cf_roots(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_root(&key) {
+            Some(value) => Ok(value),
+            None => self.storage.get_cf(self.cf_roots(), key),
+        }
     }
 
     fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.storage
-            .get_cf(self.cf_meta(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_meta(&key) {
+            Some(value) => Ok(value),
+            None => self.storage.get_cf(self.cf_meta(), key),
+        }
     }
 
     fn new_batch(&self) -> Self::Batch {
@@ -134,9 +148,13 @@ impl<'db> StorageContext<'db> for PrefixedRocksDbBatchStorageContext<'db> {
     }
 
     fn raw_iter(&self) -> Self::RawIterator {
-        PrefixedRocksDbRawIterator {
-            prefix: self.prefix.clone(),
-            raw_iterator: self.storage.raw_iterator(),
-        }
+        StorageBatchRawIterator::new(
+            PrefixedRocksDbRawIterator {
+                prefix: self.prefix.clone(),
+                raw_iterator: self.storage.raw_iterator(),
+            },
+            self.batch,
+            &self.prefix,
+        )
     }
 }
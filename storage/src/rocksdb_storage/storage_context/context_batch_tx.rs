@@ -4,11 +4,12 @@ use rocksdb::{ColumnFamily, DBRawIteratorWithThreadMode, Error};
 use super::{batch::PrefixedMultiContextBatchPart, make_prefixed_key, PrefixedRocksDbRawIterator};
 use crate::{
     rocksdb_storage::storage::{Db, Tx, AUX_CF_NAME, META_CF_NAME, ROOTS_CF_NAME},
-    StorageBatch, StorageContext,
+    StorageBatch, StorageBatchRawIterator, StorageContext,
 };
 
 /// Storage context with a prefix applied to be used in a subtree to be used in
-/// transaction.
+/// transaction. Writes pending in the batch are read first, raw iterators
+/// included.
 pub struct PrefixedRocksDbBatchTransactionContext<'db> {
     storage: &'db Db,
     transaction: &'db Tx<'db>,
@@ -59,7 +60,9 @@ impl<'db> PrefixedRocksDbBatchTransactionContext<'db> {
 impl<'db> StorageContext<'db> for PrefixedRocksDbBatchTransactionContext<'db> {
     type Batch = PrefixedMultiContextBatchPart;
     type Error = Error;
-    type RawIterator = PrefixedRocksDbRawIterator<DBRawIteratorWithThreadMode<'db, Tx<'db>>>;
+    type RawIterator = StorageBatchRawIterator<
+        PrefixedRocksDbRawIterator<DBRawIteratorWithThreadMode<'db, Tx<'db>>>,
+    >;
 
     fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
         self.batch
@@ -110,23 +113,35 @@ impl<'db> StorageContext<'db> for PrefixedRocksDbBatchTransactionContext<'db> {
     }
 
     fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.transaction
-            .get(make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get(&key) {
+            Some(value) => Ok(value),
+            None => self.transaction.get(key),
+        }
     }
 
     fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.transaction
-            .get_cf(self.cf_aux(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_aux(&key) {
+            Some(value) => Ok(value),
+            None => self.transaction.get_cf(self.cf_aux(), key),
+        }
     }
 
     fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.transaction
-            .get_cf(self.cf_roots(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_root(&key) {
+            Some(value) => Ok(value),
+            None => self.transaction.get_cf(self.cf_roots(), key),
+        }
     }
 
     fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.transaction
-            .get_cf(self.cf_meta(), make_prefixed_key(self.prefix.clone(), key))
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        match self.batch.get_meta(&key) {
+            Some(value) => Ok(value),
+            None => self.transaction.get_cf(self.cf_meta(), key),
+        }
     }
 
     fn new_batch(&self) -> Self::Batch {
@@ -142,9 +157,13 @@ impl<'db> StorageContext<'db> for PrefixedRocksDbBatchTransactionContext<'db> {
     }
 
     fn raw_iter(&self) -> Self::RawIterator {
-        PrefixedRocksDbRawIterator {
-            prefix: self.prefix.clone(),
-            raw_iterator: self.transaction.raw_iterator(),
-        }
+        StorageBatchRawIterator::new(
+            PrefixedRocksDbRawIterator {
+                prefix: self.prefix.clone(),
+                raw_iterator: self.transaction.raw_iterator(),
+            },
+            self.batch,
+            &self.prefix,
+        )
     }
 }
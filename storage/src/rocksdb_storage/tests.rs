@@ -759,7 +759,7 @@ mod transaction {
 
 mod batch_no_transaction {
     use super::*;
-    use crate::{Batch, Storage, StorageBatch, StorageContext};
+    use crate::{Batch, RawIterator, Storage, StorageBatch, StorageContext};
 
     #[test]
     fn test_various_cf_methods() {
@@ -793,9 +793,9 @@ mod batch_no_transaction {
             .put(b"key4", b"ayybvalue4")
             .expect("cannot insert data");
 
-        // There is no "staging" data for batch contexts: `get` will access only
-        // pre-batch data (thus `None` until commit).
-        assert!(context_ayya
+        // Nothing is written to the database until commit
+        assert!(storage
+            .get_storage_context(to_path(b"ayya"))
             .get_aux(b"key1")
             .expect("cannot get from aux cf")
             .is_none());
@@ -920,11 +920,12 @@ mod batch_no_transaction {
         // it in a single run to the database
         assert_eq!(batch.len(), 6);
 
-        assert!(context_ayya
+        let context_ayya_no_batch = storage.get_storage_context(to_path(b"ayya"));
+        assert!(context_ayya_no_batch
             .get(b"key1")
             .expect("cannot get data")
             .is_none());
-        assert!(context_ayya
+        assert!(context_ayya_no_batch
             .get(b"key3")
             .expect("cannot get data")
             .is_none());
@@ -943,6 +944,117 @@ mod batch_no_transaction {
             b"ayyavalue3"
         );
     }
+
+    #[test]
+    fn test_delete_after_put_in_batch() {
+        let storage = TempStorage::new();
+        let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+        context_ayya
+            .put(b"key1", b"ayyavalue1")
+            .expect("cannot insert data");
+
+        let batch = StorageBatch::new();
+        let context_ayya_batch = storage.get_batch_storage_context(to_path(b"ayya"), &batch);
+        context_ayya_batch
+            .put(b"key1", b"ayyavalue2")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .delete(b"key1")
+            .expect("cannot delete in batch");
+        context_ayya_batch
+            .put_aux(b"key2", b"ayyavalue2")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .delete_aux(b"key2")
+            .expect("cannot delete in batch");
+        storage
+            .commit_multi_context_batch(batch, None)
+            .expect("cannot commit multi context batch");
+
+        assert!(context_ayya
+            .get(b"key1")
+            .expect("cannot get data")
+            .is_none());
+        assert!(context_ayya
+            .get_aux(b"key2")
+            .expect("cannot get data")
+            .is_none());
+    }
+
+    #[test]
+    fn test_batch_context_reads_pending_writes() {
+        let storage = TempStorage::new();
+        let context_ayya = storage.get_storage_context(to_path(b"ayya"));
+        context_ayya
+            .put(b"key1", b"ayyavalue1")
+            .expect("cannot insert data");
+        context_ayya
+            .put(b"key2", b"ayyavalue2")
+            .expect("cannot insert data");
+        context_ayya
+            .put_aux(b"key1", b"ayyavalue1")
+            .expect("cannot insert into aux cf");
+
+        let batch = StorageBatch::new();
+        let context_ayya_batch = storage.get_batch_storage_context(to_path(b"ayya"), &batch);
+        context_ayya_batch
+            .delete(b"key1")
+            .expect("cannot delete in batch");
+        context_ayya_batch
+            .put(b"key3", b"ayyavalue3")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .put_aux(b"key1", b"ayyavalue1b")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .put_meta(b"key1", b"ayyavalue1")
+            .expect("cannot put into batch");
+
+        assert!(context_ayya_batch
+            .get(b"key1")
+            .expect("cannot get data")
+            .is_none());
+        assert_eq!(
+            context_ayya_batch.get(b"key2").expect("cannot get data"),
+            Some(b"ayyavalue2".to_vec())
+        );
+        assert_eq!(
+            context_ayya_batch.get(b"key3").expect("cannot get data"),
+            Some(b"ayyavalue3".to_vec())
+        );
+        assert_eq!(
+            context_ayya_batch
+                .get_aux(b"key1")
+                .expect("cannot get from aux cf"),
+            Some(b"ayyavalue1b".to_vec())
+        );
+        assert_eq!(
+            context_ayya_batch
+                .get_meta(b"key1")
+                .expect("cannot get from meta cf"),
+            Some(b"ayyavalue1".to_vec())
+        );
+
+        let mut iter = context_ayya_batch.raw_iter();
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(b"key2".as_ref()));
+        assert_eq!(iter.value(), Some(b"ayyavalue2".as_ref()));
+        iter.next();
+        assert_eq!(iter.key(), Some(b"key3".as_ref()));
+        assert_eq!(iter.value(), Some(b"ayyavalue3".as_ref()));
+        iter.next();
+        assert!(!iter.valid());
+
+        // The storage itself is untouched until commit
+        assert_eq!(
+            context_ayya.get(b"key1").expect("cannot get data"),
+            Some(b"ayyavalue1".to_vec())
+        );
+        assert!(context_ayya
+            .get(b"key3")
+            .expect("cannot get data")
+            .is_none());
+    }
 }
 
 mod batch_transaction {
@@ -1095,11 +1207,13 @@ mod batch_transaction {
 
         // All operations are in multi-context batch, but not visible in DB yet
         assert_eq!(batch.len(), 2);
-        assert!(context_ayya
+        assert!(storage
+            .get_transactional_storage_context(to_path(b"ayya"), &transaction)
             .get(b"key1")
             .expect("cannot get data")
             .is_none());
-        assert!(context_ayyb
+        assert!(storage
+            .get_transactional_storage_context(to_path(b"ayyb"), &transaction)
             .get(b"key2")
             .expect("cannot get data")
             .is_none());
@@ -1153,4 +1267,62 @@ mod batch_transaction {
             Some(b"value2".to_vec())
         );
     }
+
+    #[test]
+    fn test_batch_context_reads_pending_writes() {
+        let storage = TempStorage::new();
+        let transaction = storage.start_transaction();
+        let context_ayya_tx =
+            storage.get_transactional_storage_context(to_path(b"ayya"), &transaction);
+        context_ayya_tx
+            .put(b"key1", b"ayyavalue1")
+            .expect("cannot insert data");
+        context_ayya_tx
+            .put(b"key3", b"ayyavalue3")
+            .expect("cannot insert data");
+
+        let batch = StorageBatch::new();
+        let context_ayya_batch =
+            storage.get_batch_transactional_storage_context(to_path(b"ayya"), &batch, &transaction);
+        context_ayya_batch
+            .put(b"key2", b"ayyavalue2")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .put(b"key3", b"ayyavalue3b")
+            .expect("cannot put into batch");
+        context_ayya_batch
+            .put_root(b"key1", b"ayyavalue1")
+            .expect("cannot put into batch");
+
+        assert_eq!(
+            context_ayya_batch.get(b"key1").expect("cannot get data"),
+            Some(b"ayyavalue1".to_vec())
+        );
+        assert_eq!(
+            context_ayya_batch.get(b"key3").expect("cannot get data"),
+            Some(b"ayyavalue3b".to_vec())
+        );
+        assert_eq!(
+            context_ayya_batch
+                .get_root(b"key1")
+                .expect("cannot get from roots cf"),
+            Some(b"ayyavalue1".to_vec())
+        );
+
+        let mut iter = context_ayya_batch.raw_iter();
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(b"key3".as_ref()));
+        assert_eq!(iter.value(), Some(b"ayyavalue3b".as_ref()));
+        iter.prev();
+        assert_eq!(iter.key(), Some(b"key2".as_ref()));
+        iter.prev();
+        assert_eq!(iter.key(), Some(b"key1".as_ref()));
+        iter.prev();
+        assert!(!iter.valid());
+
+        assert!(context_ayya_tx
+            .get(b"key2")
+            .expect("cannot get data")
+            .is_none());
+    }
 }
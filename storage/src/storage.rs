@@ -1,6 +1,6 @@
 use std::{
-    cell::RefCell,
     collections::{btree_map::IntoValues, BTreeMap},
+    sync::Mutex,
 };
 
 use visualize::visualize_to_vec;
@@ -8,74 +8,86 @@ use visualize::visualize_to_vec;
 /// Top-level storage abstraction.
 /// Should be able to hold storage connection and to start transaction when
 /// needed. All query operations will be exposed using [StorageContext].
-pub trait Storage<'db> {
+/// Storage is shared between threads applying a batch to different subtrees.
+pub trait Storage: Sync {
     /// Storage transaction type
-    type Transaction;
+    type Transaction<'db>
+    where
+        Self: 'db;
 
     /// Storage context type
-    type StorageContext: StorageContext<'db, Error = Self::Error>;
+    type StorageContext<'db>: StorageContext<'db, Error = Self::Error>
+    where
+        Self: 'db;
 
     /// Storage context type for transactional data
-    type TransactionalStorageContext: StorageContext<'db, Error = Self::Error>;
+    type TransactionalStorageContext<'db>: StorageContext<'db, Error = Self::Error>
+    where
+        Self: 'db;
 
     /// Storage context type for mutli-tree batch operations
-    type BatchStorageContext;
+    type BatchStorageContext<'db>: StorageContext<'db, Error = Self::Error>
+    where
+        Self: 'db;
 
     /// Storage context type for multi-tree batch operations inside transaction
-    type BatchTransactionalStorageContext;
+    type BatchTransactionalStorageContext<'db>: StorageContext<'db, Error = Self::Error>
+    where
+        Self: 'db;
 
     /// Error type
     type Error: std::error::Error + Send + Sync + 'static;
 
     /// Starts a new transaction
-    fn start_transaction(&'db self) -> Self::Transaction;
+    fn start_transaction(&self) -> Self::Transaction<'_>;
 
     /// Consumes and commits a transaction
-    fn commit_transaction(&self, transaction: Self::Transaction) -> Result<(), Self::Error>;
+    fn commit_transaction(&self, transaction: Self::Transaction<'_>) -> Result<(), Self::Error>;
 
     /// Rollback a transaction
-    fn rollback_transaction(&self, transaction: &Self::Transaction) -> Result<(), Self::Error>;
+    fn rollback_transaction(&self, transaction: &Self::Transaction<'_>) -> Result<(), Self::Error>;
 
     /// Consumes and applies multi-context batch.
     fn commit_multi_context_batch(
         &self,
         batch: StorageBatch,
-        transaction: Option<&'db Self::Transaction>,
+        transaction: Option<&Self::Transaction<'_>>,
     ) -> Result<(), Self::Error>;
 
     /// Forces data to be written
     fn flush(&self) -> Result<(), Self::Error>;
 
     /// Make storage context for a subtree with path
-    fn get_storage_context<'p, P>(&'db self, path: P) -> Self::StorageContext
+    fn get_storage_context<'db, 'p, P>(&'db self, path: P) -> Self::StorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>;
 
-    /// Make storage context for a subtree on transactional data
-    fn get_transactional_storage_context<'p, P>(
+    /// Make storage context for a subtree on transactional data.
+    /// The context may live shorter than the transaction.
+    fn get_transactional_storage_context<'db, 'tx, 'p, P>(
         &'db self,
         path: P,
-        transaction: &'db Self::Transaction,
-    ) -> Self::TransactionalStorageContext
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::TransactionalStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>;
 
     /// Make batch storage context for a subtree with path
-    fn get_batch_storage_context<'p, P>(
+    fn get_batch_storage_context<'db, 'p, P>(
         &'db self,
         path: P,
         batch: &'db StorageBatch,
-    ) -> Self::BatchStorageContext
+    ) -> Self::BatchStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>;
 
     /// Make batch storage context for a subtree on transactional data
-    fn get_batch_transactional_storage_context<'p, P>(
+    fn get_batch_transactional_storage_context<'db, 'tx, 'p, P>(
         &'db self,
         path: P,
         batch: &'db StorageBatch,
-        transaction: &'db Self::Transaction,
-    ) -> Self::BatchTransactionalStorageContext
+        transaction: &'db Self::Transaction<'tx>,
+    ) -> Self::BatchTransactionalStorageContext<'db>
     where
         P: IntoIterator<Item = &'p [u8]>;
 }
@@ -198,12 +210,24 @@ pub trait RawIterator {
 
 /// Structure to hold deferred database operations in "batched" storage
 /// contexts.
+///
+/// Only the last operation on a key is kept, so a delete queued after a put
+/// overrides it the same way a put overrides a delete, and committing the
+/// batch has the effect of applying its operations one after another. Storage
+/// contexts sharing a batch rely on it: several Merk commits on a subtree go
+/// into the same batch, and a node written by an earlier commit may be
+/// removed by a later one.
+///
+/// Batched storage contexts read pending operations before the storage, so
+/// a batch sees its own writes, raw iterators included (see
+/// `StorageBatchRawIterator`). The batch could be shared between threads
+/// writing into different subtrees.
 #[derive(Debug)]
 pub struct StorageBatch {
-    operations: RefCell<Operations>,
+    operations: Mutex<Operations>,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct Operations {
     data: BTreeMap<Vec<u8>, BatchOperation>,
     roots: BTreeMap<Vec<u8>, BatchOperation>,
@@ -228,23 +252,78 @@ impl StorageBatch {
     /// Create empty batch.
     pub fn new() -> Self {
         StorageBatch {
-            operations: RefCell::new(Operations::default()),
+            operations: Mutex::new(Operations::default()),
         }
     }
 
     #[cfg(test)]
     pub(crate) fn len(&self) -> usize {
-        let operations = self.operations.borrow();
+        let operations = self.operations.lock().unwrap();
         operations.data.len()
             + operations.roots.len()
             + operations.aux.len()
             + operations.meta.len()
     }
 
+    /// Pending operation on `key`: `Some(Some(value))` for a put,
+    /// `Some(None)` for a delete and `None` if the batch doesn't touch the key
+    pub fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.operations
+            .lock()
+            .unwrap()
+            .data
+            .get(key)
+            .map(BatchOperation::value)
+    }
+
+    /// Pending operation on `key` of aux storage, see `get`
+    pub fn get_aux(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.operations
+            .lock()
+            .unwrap()
+            .aux
+            .get(key)
+            .map(BatchOperation::value)
+    }
+
+    /// Pending operation on `key` of subtree roots storage, see `get`
+    pub fn get_root(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.operations
+            .lock()
+            .unwrap()
+            .roots
+            .get(key)
+            .map(BatchOperation::value)
+    }
+
+    /// Pending operation on `key` of metadata storage, see `get`
+    pub fn get_meta(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.operations
+            .lock()
+            .unwrap()
+            .meta
+            .get(key)
+            .map(BatchOperation::value)
+    }
+
+    /// Pending operations on data keys with the prefix, the prefix stripped,
+    /// in keys order
+    pub(crate) fn data_with_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.operations
+            .lock()
+            .unwrap()
+            .data
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, op)| (k[prefix.len()..].to_vec(), op.value()))
+            .collect()
+    }
+
     /// Add deferred `put` operation
     pub fn put(&self, key: Vec<u8>, value: Vec<u8>) {
         self.operations
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .data
             .insert(key.clone(), BatchOperation::Put { key, value });
     }
@@ -252,7 +331,8 @@ impl StorageBatch {
     /// Add deferred `put` operation for aux storage
     pub fn put_aux(&self, key: Vec<u8>, value: Vec<u8>) {
         self.operations
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .aux
             .insert(key.clone(), BatchOperation::PutAux { key, value });
     }
@@ -260,7 +340,8 @@ impl StorageBatch {
     /// Add deferred `put` operation for subtree roots storage
     pub fn put_root(&self, key: Vec<u8>, value: Vec<u8>) {
         self.operations
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .roots
             .insert(key.clone(), BatchOperation::PutRoot { key, value });
     }
@@ -268,56 +349,66 @@ impl StorageBatch {
     /// Add deferred `put` operation for metadata storage
     pub fn put_meta(&self, key: Vec<u8>, value: Vec<u8>) {
         self.operations
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .meta
             .insert(key.clone(), BatchOperation::PutMeta { key, value });
     }
 
     /// Add deferred `delete` operation
     pub fn delete(&self, key: Vec<u8>) {
-        let operations = &mut self.operations.borrow_mut().data;
-        if operations.get(&key).is_none() {
-            operations.insert(key.clone(), BatchOperation::Delete { key });
-        }
+        self.operations
+            .lock()
+            .unwrap()
+            .data
+            .insert(key.clone(), BatchOperation::Delete { key });
     }
 
     /// Add deferred `delete` operation for aux storage
     pub fn delete_aux(&self, key: Vec<u8>) {
-        let operations = &mut self.operations.borrow_mut().aux;
-        if operations.get(&key).is_none() {
-            operations.insert(key.clone(), BatchOperation::DeleteAux { key });
-        }
+        self.operations
+            .lock()
+            .unwrap()
+            .aux
+            .insert(key.clone(), BatchOperation::DeleteAux { key });
     }
 
     /// Add deferred `delete` operation for subtree roots storage
     pub fn delete_root(&self, key: Vec<u8>) {
-        let operations = &mut self.operations.borrow_mut().roots;
-        if operations.get(&key).is_none() {
-            operations.insert(key.clone(), BatchOperation::DeleteRoot { key });
-        }
+        self.operations
+            .lock()
+            .unwrap()
+            .roots
+            .insert(key.clone(), BatchOperation::DeleteRoot { key });
     }
 
     /// Add deferred `delete` operation for metadata storage
     pub fn delete_meta(&self, key: Vec<u8>) {
-        let operations = &mut self.operations.borrow_mut().meta;
-        if operations.get(&key).is_none() {
-            operations.insert(key.clone(), BatchOperation::DeleteMeta { key });
+        self.operations
+            .lock()
+            .unwrap()
+            .meta
+            .insert(key.clone(), BatchOperation::DeleteMeta { key });
+    }
+
+    /// Add deferred operation
+    pub fn add(&self, operation: BatchOperation) {
+        match operation {
+            BatchOperation::Put { key, value } => self.put(key, value),
+            BatchOperation::PutAux { key, value } => self.put_aux(key, value),
+            BatchOperation::PutRoot { key, value } => self.put_root(key, value),
+            BatchOperation::PutMeta { key, value } => self.put_meta(key, value),
+            BatchOperation::Delete { key } => self.delete(key),
+            BatchOperation::DeleteAux { key } => self.delete_aux(key),
+            BatchOperation::DeleteRoot { key } => self.delete_root(key),
+            BatchOperation::DeleteMeta { key } => self.delete_meta(key),
         }
     }
 
     /// Merge batch into this one
     pub fn merge(&self, other: StorageBatch) {
         for op in other.into_iter() {
-            match op {
-                BatchOperation::Put { key, value } => self.put(key, value),
-                BatchOperation::PutAux { key, value } => self.put_aux(key, value),
-                BatchOperation::PutRoot { key, value } => self.put_root(key, value),
-                BatchOperation::PutMeta { key, value } => self.put_meta(key, value),
-                BatchOperation::Delete { key } => self.delete(key),
-                BatchOperation::DeleteAux { key } => self.delete_aux(key),
-                BatchOperation::DeleteRoot { key } => self.delete_root(key),
-                BatchOperation::DeleteMeta { key } => self.delete_meta(key),
-            }
+            self.add(op);
         }
     }
 }
@@ -347,7 +438,7 @@ impl IntoIterator for StorageBatch {
     type Item = BatchOperation;
 
     fn into_iter(self) -> Self::IntoIter {
-        let operations = self.operations.into_inner();
+        let operations = self.operations.into_inner().unwrap();
 
         StorageBatchIter {
             data: operations.data.into_values(),
@@ -358,15 +449,198 @@ impl IntoIterator for StorageBatch {
     }
 }
 
+impl Clone for StorageBatch {
+    fn clone(&self) -> Self {
+        StorageBatch {
+            operations: Mutex::new(self.operations.lock().unwrap().clone()),
+        }
+    }
+}
+
 impl Default for StorageBatch {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// Raw iterator of a batched storage context: data writes pending in the batch
+/// under the context prefix are merged with records of the wrapped iterator.
+/// Pending writes are taken when the iterator is created.
+pub struct StorageBatchRawIterator<I> {
+    raw_iterator: I,
+    pending: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    current: Option<BatchIteratorPosition>,
+    forward: bool,
+}
+
+/// Record the merging iterator points at: either one of the wrapped iterator
+/// or a pending write by its index
+#[derive(Clone, Copy)]
+enum BatchIteratorPosition {
+    Storage,
+    Pending(usize),
+}
+
+impl<I: RawIterator> StorageBatchRawIterator<I> {
+    /// Wrap a raw iterator over the subtree with `prefix` to see data writes
+    /// pending in the `batch`
+    pub fn new(raw_iterator: I, batch: &StorageBatch, prefix: &[u8]) -> Self {
+        StorageBatchRawIterator {
+            raw_iterator,
+            pending: batch.data_with_prefix(prefix),
+            current: None,
+            forward: true,
+        }
+    }
+
+    fn is_pending(&self, key: &[u8]) -> bool {
+        self.pending
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .is_ok()
+    }
+
+    /// Pick the least record out of the wrapped iterator position and pending
+    /// writes starting with `from`, skipping ones overridden by the batch
+    fn settle_forward(&mut self, from: usize) {
+        while self
+            .raw_iterator
+            .key()
+            .map(|key| self.is_pending(key))
+            .unwrap_or(false)
+        {
+            self.raw_iterator.next();
+        }
+        let pending = (from..self.pending.len()).find(|i| self.pending[*i].1.is_some());
+
+        self.forward = true;
+        self.current = match (self.raw_iterator.key(), pending) {
+            (Some(key), Some(i)) if self.pending[i].0.as_slice() > key => {
+                Some(BatchIteratorPosition::Storage)
+            }
+            (_, Some(i)) => Some(BatchIteratorPosition::Pending(i)),
+            (Some(_), None) => Some(BatchIteratorPosition::Storage),
+            (None, None) => None,
+        };
+    }
+
+    /// Pick the greatest record out of the wrapped iterator position and
+    /// pending writes before `to`, skipping ones overridden by the batch
+    fn settle_backward(&mut self, to: usize) {
+        while self
+            .raw_iterator
+            .key()
+            .map(|key| self.is_pending(key))
+            .unwrap_or(false)
+        {
+            self.raw_iterator.prev();
+        }
+        let pending = (0..to).rev().find(|i| self.pending[*i].1.is_some());
+
+        self.forward = false;
+        self.current = match (self.raw_iterator.key(), pending) {
+            (Some(key), Some(i)) if self.pending[i].0.as_slice() < key => {
+                Some(BatchIteratorPosition::Storage)
+            }
+            (_, Some(i)) => Some(BatchIteratorPosition::Pending(i)),
+            (Some(_), None) => Some(BatchIteratorPosition::Storage),
+            (None, None) => None,
+        };
+    }
+}
+
+impl<I: RawIterator> RawIterator for StorageBatchRawIterator<I> {
+    fn seek_to_first(&mut self) {
+        self.raw_iterator.seek_to_first();
+        self.settle_forward(0);
+    }
+
+    fn seek_to_last(&mut self) {
+        self.raw_iterator.seek_to_last();
+        self.settle_backward(self.pending.len());
+    }
+
+    fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.raw_iterator.seek(key.as_ref());
+        let from = self
+            .pending
+            .partition_point(|(k, _)| k.as_slice() < key.as_ref());
+        self.settle_forward(from);
+    }
+
+    fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.raw_iterator.seek_for_prev(key.as_ref());
+        let to = self
+            .pending
+            .partition_point(|(k, _)| k.as_slice() <= key.as_ref());
+        self.settle_backward(to);
+    }
+
+    fn next(&mut self) {
+        let key = match self.key() {
+            Some(key) => key.to_vec(),
+            None => return,
+        };
+        match self.current {
+            Some(BatchIteratorPosition::Storage) => self.raw_iterator.next(),
+            // Moving forward the wrapped iterator is already past the pending
+            // record
+            Some(BatchIteratorPosition::Pending(_)) if self.forward => {}
+            _ => {
+                self.raw_iterator.seek(&key);
+                if self.raw_iterator.key() == Some(key.as_slice()) {
+                    self.raw_iterator.next();
+                }
+            }
+        }
+        let from = self
+            .pending
+            .partition_point(|(k, _)| k.as_slice() <= key.as_slice());
+        self.settle_forward(from);
+    }
+
+    fn prev(&mut self) {
+        let key = match self.key() {
+            Some(key) => key.to_vec(),
+            None => return,
+        };
+        match self.current {
+            Some(BatchIteratorPosition::Storage) => self.raw_iterator.prev(),
+            Some(BatchIteratorPosition::Pending(_)) if !self.forward => {}
+            _ => {
+                self.raw_iterator.seek_for_prev(&key);
+                if self.raw_iterator.key() == Some(key.as_slice()) {
+                    self.raw_iterator.prev();
+                }
+            }
+        }
+        let to = self
+            .pending
+            .partition_point(|(k, _)| k.as_slice() < key.as_slice());
+        self.settle_backward(to);
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        match self.current? {
+            BatchIteratorPosition::Storage => self.raw_iterator.value(),
+            BatchIteratorPosition::Pending(i) => self.pending[i].1.as_deref(),
+        }
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        match self.current? {
+            BatchIteratorPosition::Storage => self.raw_iterator.key(),
+            BatchIteratorPosition::Pending(i) => Some(self.pending[i].0.as_slice()),
+        }
+    }
+
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+}
+
 /// Deferred storage operation.
 #[allow(missing_docs)]
-#[derive(strum::AsRefStr)]
+#[derive(strum::AsRefStr, Clone)]
 pub enum BatchOperation {
     /// Deferred put operation
     Put { key: Vec<u8>, value: Vec<u8> },
@@ -386,6 +660,22 @@ pub enum BatchOperation {
     DeleteMeta { key: Vec<u8> },
 }
 
+impl BatchOperation {
+    /// Value written by the operation, `None` for deletion
+    fn value(&self) -> Option<Vec<u8>> {
+        match self {
+            BatchOperation::Put { value, .. }
+            | BatchOperation::PutAux { value, .. }
+            | BatchOperation::PutRoot { value, .. }
+            | BatchOperation::PutMeta { value, .. } => Some(value.clone()),
+            BatchOperation::Delete { .. }
+            | BatchOperation::DeleteAux { .. }
+            | BatchOperation::DeleteRoot { .. }
+            | BatchOperation::DeleteMeta { .. } => None,
+        }
+    }
+}
+
 impl std::fmt::Debug for BatchOperation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut fmt = f.debug_struct(self.as_ref());
@@ -419,9 +709,44 @@ impl std::fmt::Debug for BatchOperation {
     }
 }
 
+/// Builds the body of a subtree prefix out of its path: segments followed by
+/// segments count and their lengths. The prefix itself is a hash of the body,
+/// which may differ between storage implementations.
+pub fn build_prefix_body<'a, P>(path: P) -> Vec<u8>
+where
+    P: IntoIterator<Item = &'a [u8]>,
+{
+    let segments_iter = path.into_iter();
+    let mut segments_count: usize = 0;
+    let mut res = Vec::new();
+    let mut lengthes = Vec::new();
+
+    for s in segments_iter {
+        segments_count += 1;
+        res.extend_from_slice(s);
+        lengthes.extend(s.len().to_ne_bytes());
+    }
+
+    res.extend(segments_count.to_ne_bytes());
+    res.extend(lengthes);
+    res
+}
+
+/// A helper method to figure out how many blake3 hashes are needed to build
+/// a prefix
+pub fn build_prefix_hash_count<'a, P>(path: P) -> u16
+where
+    P: IntoIterator<Item = &'a [u8]>,
+{
+    let body = build_prefix_body(path);
+    // the block size of blake3 is 64
+    (body.len() as u32 / 64 + 1) as u16
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory_storage::MemoryStorage;
 
     #[test]
     fn test_debug_output_batch_operation() {
@@ -442,4 +767,124 @@ mod tests {
             "DeleteRoot { key: \"[hex: 6b657931, str: key1]\" }"
         );
     }
+
+    #[test]
+    fn test_batch_pending_operations_are_readable() {
+        let batch = StorageBatch::new();
+        batch.put(b"key1".to_vec(), b"value1".to_vec());
+        batch.put_root(b"key2".to_vec(), b"value2".to_vec());
+        batch.delete_root(b"key2".to_vec());
+
+        assert_eq!(batch.get(b"key1"), Some(Some(b"value1".to_vec())));
+        assert_eq!(batch.get_root(b"key2"), Some(None));
+        assert_eq!(batch.get_aux(b"key1"), None);
+        assert_eq!(batch.get_meta(b"key2"), None);
+    }
+
+    #[test]
+    fn test_batch_delete_overrides_put_in_every_storage() {
+        let batch = StorageBatch::new();
+        batch.put_root(b"key1".to_vec(), b"value1".to_vec());
+        batch.delete_root(b"key1".to_vec());
+        batch.put_meta(b"key2".to_vec(), b"value2".to_vec());
+        batch.delete_meta(b"key2".to_vec());
+        batch.delete_aux(b"key3".to_vec());
+        batch.put_aux(b"key3".to_vec(), b"value3".to_vec());
+        batch.delete_aux(b"key3".to_vec());
+
+        assert_eq!(batch.len(), 3);
+        let operations: Vec<String> = batch.into_iter().map(|op| format!("{:?}", op)).collect();
+        assert_eq!(
+            operations,
+            vec![
+                "DeleteMeta { key: \"[hex: 6b657932, str: key2]\" }",
+                "DeleteAux { key: \"[hex: 6b657933, str: key3]\" }",
+                "DeleteRoot { key: \"[hex: 6b657931, str: key1]\" }",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_raw_iterator_merges_pending_writes() {
+        let storage = MemoryStorage::new();
+        let context = storage.get_storage_context(std::iter::once(b"ayya".as_ref()));
+        for key in [b"key1", b"key3", b"key5", b"key7"] {
+            context.put(key, b"stored").unwrap();
+        }
+
+        let prefix = MemoryStorage::build_prefix(std::iter::once(b"ayya".as_ref()));
+        let batch = StorageBatch::new();
+        let pending_key = |key: &[u8]| {
+            let mut prefixed = prefix.clone();
+            prefixed.extend_from_slice(key);
+            prefixed
+        };
+        batch.put(pending_key(b"key0"), b"pending".to_vec());
+        batch.delete(pending_key(b"key3"));
+        batch.put(pending_key(b"key4"), b"pending".to_vec());
+        batch.put(pending_key(b"key5"), b"pending".to_vec());
+        batch.delete(pending_key(b"key6"));
+        batch.put(pending_key(b"key8"), b"pending".to_vec());
+        batch.put_aux(pending_key(b"key9"), b"pending".to_vec());
+
+        let expected: [(&[u8], &[u8]); 6] = [
+            (b"key0", b"pending"),
+            (b"key1", b"stored"),
+            (b"key4", b"pending"),
+            (b"key5", b"pending"),
+            (b"key7", b"stored"),
+            (b"key8", b"pending"),
+        ];
+
+        let mut iter = StorageBatchRawIterator::new(context.raw_iter(), &batch, &prefix);
+        iter.seek_to_first();
+        for (key, value) in expected {
+            assert_eq!((iter.key(), iter.value()), (Some(key), Some(value)));
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for (key, value) in expected.into_iter().rev() {
+            assert_eq!((iter.key(), iter.value()), (Some(key), Some(value)));
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        // Changing direction on a pending record and on a stored one
+        iter.seek(b"key2");
+        assert_eq!(iter.key(), Some(b"key4".as_ref()));
+        iter.prev();
+        assert_eq!(iter.key(), Some(b"key1".as_ref()));
+        iter.next();
+        assert_eq!(iter.key(), Some(b"key4".as_ref()));
+        iter.seek_for_prev(b"key6");
+        assert_eq!(iter.key(), Some(b"key5".as_ref()));
+        iter.next();
+        assert_eq!(iter.key(), Some(b"key7".as_ref()));
+        iter.prev();
+        assert_eq!(iter.key(), Some(b"key5".as_ref()));
+        iter.seek(b"key9");
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_batch_last_operation_on_key_wins() {
+        let batch = StorageBatch::new();
+        batch.put(b"key1".to_vec(), b"value1".to_vec());
+        batch.delete(b"key1".to_vec());
+        batch.delete_aux(b"key2".to_vec());
+        batch.put_aux(b"key2".to_vec(), b"value2".to_vec());
+
+        assert_eq!(batch.len(), 2);
+        let operations: Vec<String> = batch.into_iter().map(|op| format!("{:?}", op)).collect();
+        assert_eq!(
+            operations,
+            vec![
+                "PutAux { key: \"[hex: 6b657932, str: key2]\", value: \"[hex: 76616c756532, str: \
+                 value2]\" }",
+                "Delete { key: \"[hex: 6b657931, str: key1]\" }",
+            ]
+        );
+    }
 }